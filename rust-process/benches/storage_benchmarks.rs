@@ -1,6 +1,8 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use secure_biometric::security::{Cipher, EncryptedData, EncryptionEngine, KeyManager};
+use std::sync::Arc;
 use secure_biometric::storage::TemplateVault;
-use secure_biometric::templates::{Template, TemplateMetadata, TemplateType};
+use secure_biometric::templates::{Template, TemplateType};
 use tempfile::TempDir;
 
 async fn benchmark_template_storage() {
@@ -9,15 +11,12 @@ async fn benchmark_template_storage() {
         .await
         .expect("Failed to create vault");
 
-    let template = Template::new(
-        vec![1, 2, 3, 4, 5],
-        TemplateMetadata {
-            version: "1.0".to_string(),
-            template_type: TemplateType::Face,
-            quality_score: 0.95,
-            extra: serde_json::json!({}),
-        },
-    );
+    let template = Template::builder()
+        .data(vec![1, 2, 3, 4, 5])
+        .template_type(TemplateType::Face)
+        .quality_score(0.95)
+        .build()
+        .unwrap();
 
     vault.store(template).await.expect("Failed to store template");
 }
@@ -30,5 +29,136 @@ fn storage_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, storage_benchmark);
+fn alias_benchmark(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let temp_dir = TempDir::new().expect("Failed to create temp directory");
+    let vault = rt
+        .block_on(TemplateVault::new(temp_dir.path()))
+        .expect("Failed to create vault");
+    let id = rt
+        .block_on(vault.store(Template::builder()
+            .data(vec![1, 2, 3, 4, 5])
+            .template_type(TemplateType::Face)
+            .quality_score(0.95)
+            .build()
+            .unwrap()))
+        .expect("Failed to store template");
+
+    // Each iteration binds and unbinds an alias, both transactional across trees
+    let mut counter = 0u64;
+    c.bench_function("alias_bind_remove", |b| {
+        b.iter(|| {
+            counter += 1;
+            let external_id = counter.to_string();
+            rt.block_on(async {
+                vault
+                    .add_alias(id, "bench", &external_id)
+                    .await
+                    .expect("Failed to add alias");
+                vault
+                    .remove_alias("bench", &external_id)
+                    .await
+                    .expect("Failed to remove alias");
+            });
+        });
+    });
+}
+
+fn encoding_benchmark(c: &mut Criterion) {
+    // A 1MB ciphertext container, encoded the legacy way and the current way
+    let encrypted = EncryptedData {
+        ciphertext: (0..1024 * 1024u32).map(|i| (i % 251) as u8).collect(),
+        nonce: vec![7; 12],
+        key_id: Some(1),
+        cipher: Cipher::ChaCha20Poly1305,
+        header: vec![2],
+    };
+    let json = serde_json::to_vec(&encrypted).unwrap();
+    let binary = bincode::serialize(&encrypted).unwrap();
+    println!(
+        "1MB ciphertext envelope: json {} bytes, bincode {} bytes",
+        json.len(),
+        binary.len()
+    );
+
+    c.bench_function("envelope_json_1mb", |b| {
+        b.iter(|| {
+            let encoded = serde_json::to_vec(&encrypted).unwrap();
+            serde_json::from_slice::<EncryptedData>(&encoded).unwrap()
+        });
+    });
+    c.bench_function("envelope_bincode_1mb", |b| {
+        b.iter(|| {
+            let encoded = bincode::serialize(&encrypted).unwrap();
+            bincode::deserialize::<EncryptedData>(&encoded).unwrap()
+        });
+    });
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let temp_dir = TempDir::new().expect("Failed to create temp directory");
+    let vault = rt
+        .block_on(TemplateVault::new(temp_dir.path()))
+        .expect("Failed to create vault");
+    let template = Template::builder()
+        .data(encrypted.ciphertext.clone())
+        .template_type(TemplateType::Face)
+        .quality_score(0.95)
+        .build()
+        .unwrap();
+    let id = rt
+        .block_on(vault.store(template.clone()))
+        .expect("Failed to store template");
+    let stats = rt.block_on(vault.stats()).expect("Failed to read stats");
+    println!("1MB template stored as {} ciphertext bytes", stats.ciphertext_bytes);
+
+    let mut group = c.benchmark_group("vault_1mb");
+    group.sample_size(20);
+    group.bench_function("update_get", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                vault.update(id, template.clone()).await.expect("Failed to update template");
+                vault.get(id).await.expect("Failed to retrieve template")
+            })
+        });
+    });
+    group.finish();
+}
+
+fn batch_encryption_benchmark(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let engine = EncryptionEngine::new(Arc::new(KeyManager::new().expect("Failed to create key manager")));
+    // 1000 payloads of 10KB, encrypted one after the other and as a batch
+    let payloads: Vec<Vec<u8>> = (0..1000u32)
+        .map(|i| (0..10 * 1024u32).map(|j| ((i + j) % 251) as u8).collect())
+        .collect();
+
+    let mut group = c.benchmark_group("encrypt_1000x10kb");
+    group.sample_size(20);
+    group.bench_function("sequential", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                for payload in &payloads {
+                    engine.encrypt(payload).await.expect("Failed to encrypt");
+                }
+            })
+        });
+    });
+    group.bench_function("batch", |b| {
+        // The batch takes ownership, so its copy of the payloads is made untimed
+        b.iter_batched(
+            || payloads.clone(),
+            |payloads| rt.block_on(engine.encrypt_batch(payloads)).expect("Failed to encrypt batch"),
+            BatchSize::LargeInput,
+        );
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    storage_benchmark,
+    alias_benchmark,
+    encoding_benchmark,
+    batch_encryption_benchmark
+);
 criterion_main!(benches);
@@ -0,0 +1,131 @@
+//! HTTP plumbing shared by the service's routes
+
+use crate::storage::{TemplateVault, DEFAULT_MAX_TEMPLATE_SIZE};
+use crate::templates::{Template, CBOR_CONTENT_TYPE};
+use actix_web::body::BoxBody;
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorBadRequest, ErrorPayloadTooLarge, ErrorUnsupportedMediaType};
+use actix_web::http::header::{HeaderMap, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use actix_web::web::{self, BytesMut};
+use actix_web::{FromRequest, HttpRequest, HttpResponse, Responder};
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+
+/// Room for the metadata and encoding of a template body on top of its data
+const BODY_OVERHEAD: usize = 64 * 1024;
+
+/// Template in a request or response body, as CBOR or JSON
+///
+/// Requests are decoded by their `Content-Type`: `application/cbor`, or
+/// JSON if it is `application/json` or missing. Bodies are limited by the
+/// `max_template_size` of the `TemplateVault` in the app data, or
+/// `DEFAULT_MAX_TEMPLATE_SIZE` without one. Responses are CBOR if the
+/// request's `Accept` header prefers `application/cbor` to JSON, JSON
+/// otherwise, so JSON stays available for debugging.
+#[derive(Debug, Clone)]
+pub struct TemplateBody(pub Template);
+
+/// Media type without parameters such as `charset`
+fn essence(media_type: &str) -> &str {
+    media_type.split(';').next().unwrap_or_default().trim()
+}
+
+/// Highest quality the `Accept` header gives to `media_type`, `None` if it
+/// does not list it
+fn accepted_quality(headers: &HeaderMap, media_type: &str) -> Option<f32> {
+    headers
+        .get_all(ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter(|range| essence(range).eq_ignore_ascii_case(media_type))
+        .map(|range| {
+            range
+                .split(';')
+                .skip(1)
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(1.0, |(_, q)| q.trim().parse().unwrap_or(0.0))
+        })
+        .reduce(f32::max)
+}
+
+/// Whether the `Accept` header prefers CBOR to JSON
+///
+/// A `q` of 0 marks a media type as not acceptable.
+fn accepts_cbor(headers: &HeaderMap) -> bool {
+    match accepted_quality(headers, CBOR_CONTENT_TYPE) {
+        Some(cbor) if cbor > 0.0 => {
+            cbor >= accepted_quality(headers, "application/json").unwrap_or(0.0)
+        }
+        _ => false,
+    }
+}
+
+/// Largest body accepted for a template, `None` if unlimited
+///
+/// JSON spells each data byte as up to four characters, CBOR as one.
+fn body_limit(req: &HttpRequest, json: bool) -> Option<usize> {
+    let max = match req.app_data::<web::Data<TemplateVault>>() {
+        Some(vault) => vault.max_template_size()?,
+        None => DEFAULT_MAX_TEMPLATE_SIZE,
+    };
+    let data = if json { max.saturating_mul(4) } else { max };
+    Some(data.saturating_add(BODY_OVERHEAD))
+}
+
+impl FromRequest for TemplateBody {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|value| value.to_str().map(|value| essence(value).to_ascii_lowercase()));
+        let json = !matches!(&content_type, Some(Ok(media_type)) if media_type == CBOR_CONTENT_TYPE);
+        let limit = body_limit(req, json).unwrap_or(usize::MAX);
+        let length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+        let mut payload = payload.take();
+        Box::pin(async move {
+            if length.is_some_and(|length| length > limit) {
+                return Err(ErrorPayloadTooLarge("template body too large"));
+            }
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > limit {
+                    return Err(ErrorPayloadTooLarge("template body too large"));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            let template = match content_type {
+                Some(Ok(media_type)) if media_type == CBOR_CONTENT_TYPE => {
+                    Template::from_cbor(&body).map_err(ErrorBadRequest)?
+                }
+                None => serde_json::from_slice(&body).map_err(ErrorBadRequest)?,
+                Some(Ok(media_type)) if media_type == "application/json" => {
+                    serde_json::from_slice(&body).map_err(ErrorBadRequest)?
+                }
+                _ => return Err(ErrorUnsupportedMediaType("expected CBOR or JSON")),
+            };
+            Ok(TemplateBody(template))
+        })
+    }
+}
+
+impl Responder for TemplateBody {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        if !accepts_cbor(req.headers()) {
+            return HttpResponse::Ok().json(&self.0);
+        }
+        match self.0.to_cbor() {
+            Ok(body) => HttpResponse::Ok().content_type(CBOR_CONTENT_TYPE).body(body),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        }
+    }
+}
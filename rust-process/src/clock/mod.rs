@@ -0,0 +1,118 @@
+use chrono::{DateTime, Utc};
+use std::time::Instant;
+
+/// Source of wall-clock and monotonic time
+///
+/// Components that make time-based decisions take a `Clock` so tests can
+/// control time instead of sleeping.
+pub trait Clock: Send + Sync {
+    /// Current wall-clock time
+    fn now_utc(&self) -> DateTime<Utc>;
+
+    /// Current monotonic instant
+    fn instant(&self) -> Instant;
+}
+
+/// Clock backed by the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+pub use mock::MockClock;
+
+#[cfg(any(test, feature = "test-utils"))]
+mod mock {
+    use super::Clock;
+    use chrono::{DateTime, Duration, Utc};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// Manually driven clock for tests
+    pub struct MockClock {
+        state: Mutex<State>,
+    }
+
+    struct State {
+        now: DateTime<Utc>,
+        base: Instant,
+        elapsed: std::time::Duration,
+    }
+
+    impl MockClock {
+        /// Create a clock frozen at `now`
+        pub fn new(now: DateTime<Utc>) -> Self {
+            Self {
+                state: Mutex::new(State {
+                    now,
+                    base: Instant::now(),
+                    elapsed: std::time::Duration::ZERO,
+                }),
+            }
+        }
+
+        /// Move the clock forward by `by`
+        pub fn advance(&self, by: Duration) {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.now += by;
+            state.elapsed += by.to_std().unwrap_or_default();
+        }
+
+        /// Jump the wall clock to `now`; the monotonic clock never goes back
+        pub fn set(&self, now: DateTime<Utc>) {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if let Ok(forward) = (now - state.now).to_std() {
+                state.elapsed += forward;
+            }
+            state.now = now;
+        }
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self::new(Utc::now())
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now_utc(&self) -> DateTime<Utc> {
+            self.state.lock().unwrap_or_else(|e| e.into_inner()).now
+        }
+
+        fn instant(&self) -> Instant {
+            let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.base + state.elapsed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_mock_clock() {
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let clock = MockClock::new(start);
+        let instant = clock.instant();
+
+        clock.advance(Duration::hours(2));
+        assert_eq!(clock.now_utc(), start + Duration::hours(2));
+        assert_eq!(clock.instant() - instant, std::time::Duration::from_secs(7200));
+
+        // Setting the wall clock backwards leaves the monotonic clock alone
+        clock.set(start);
+        assert_eq!(clock.now_utc(), start);
+        assert_eq!(clock.instant() - instant, std::time::Duration::from_secs(7200));
+    }
+}
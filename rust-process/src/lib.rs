@@ -1,3 +1,5 @@
+pub mod api;
+pub mod clock;
 pub mod logging;
 pub mod security;
 pub mod storage;
@@ -7,11 +9,9 @@ pub mod templates;
 mod tests {
     use crate::security::{EncryptionEngine, KeyManager};
     use crate::storage::TemplateVault;
-    use crate::templates::{Template, TemplateMetadata, TemplateType};
+    use crate::templates::{Template, TemplateType};
     use std::sync::Arc;
     use tempfile::TempDir;
-    use tokio;
-    use serde_json;
 
     #[tokio::test]
     async fn test_full_template_lifecycle() -> Result<(), Box<dyn std::error::Error>> {
@@ -20,20 +20,18 @@ mod tests {
         let vault = TemplateVault::new(temp_dir.path()).await?;
 
         // Create test template
-        let template = Template::new(
-            vec![1, 2, 3, 4],
-            TemplateMetadata {
-                version: "1.0".to_string(),
-                template_type: TemplateType::Face,
-                quality_score: 0.95,
-                extra: serde_json::json!({}),
-            },
-        );
+        let template = Template::builder()
+            .data(vec![1, 2, 3, 4])
+            .template_type(TemplateType::Face)
+            .quality_score(0.95)
+            .build()
+            .unwrap();
 
         // Test template storage and retrieval
         let id = vault.store(template.clone()).await?;
         let retrieved = vault.get(id).await?;
         assert_eq!(retrieved.data, template.data);
+        assert_eq!(retrieved.id, Some(id));
 
         // Test key rotation
         vault.rotate_key().await?;
@@ -41,6 +39,7 @@ mod tests {
         // Verify template can still be retrieved after rotation
         let retrieved = vault.get(id).await?;
         assert_eq!(retrieved.data, template.data);
+        assert_eq!(retrieved.id, Some(id));
 
         // Test template deletion
         vault.delete(id).await?;
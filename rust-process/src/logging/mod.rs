@@ -1,8 +1,21 @@
 use log::{Level, LevelFilter, Metadata, Record};
-use std::sync::Once;
+use thiserror::Error;
 use time::OffsetDateTime;
 
-static INIT: Once = Once::new();
+/// Returned when another global logger has already been installed
+#[derive(Debug, Error)]
+#[error("A global logger is already initialized")]
+pub struct AlreadyInitialized;
+
+/// Flushes the global logger when dropped
+#[must_use = "dropping the guard flushes the logger immediately"]
+pub struct InitGuard;
+
+impl Drop for InitGuard {
+    fn drop(&mut self) {
+        log::logger().flush();
+    }
+}
 
 /// Custom logger implementation with detailed formatting
 pub struct SecurityLogger;
@@ -40,27 +53,64 @@ impl log::Log for SecurityLogger {
     fn flush(&self) {}
 }
 
-/// Initialize the logging system
-pub fn init(level: LevelFilter) {
-    INIT.call_once(|| {
-        log::set_boxed_logger(Box::new(SecurityLogger))
-            .map(|()| log::set_max_level(level))
-            .expect("Failed to initialize logger");
-    });
+/// Install the security logger as the global logger
+///
+/// Never panics: if the host application already installed a logger, our
+/// events keep flowing to it and `AlreadyInitialized` is returned.
+pub fn try_init(level: LevelFilter) -> Result<InitGuard, AlreadyInitialized> {
+    match log::set_boxed_logger(Box::new(SecurityLogger)) {
+        Ok(()) => {
+            log::set_max_level(level);
+            Ok(InitGuard)
+        }
+        Err(_) => {
+            log::warn!("Global logger already set; security logger not installed");
+            Err(AlreadyInitialized)
+        }
+    }
 }
 
-/// Initialize test logging with appropriate level
-pub fn init_test_logging() {
-    let level = match std::env::var("RUST_LOG") {
+/// Read the log level from `RUST_LOG`, falling back to `default`
+pub fn level_from_env(default: LevelFilter) -> LevelFilter {
+    match std::env::var("RUST_LOG") {
         Ok(level) => match level.to_lowercase().as_str() {
             "trace" => LevelFilter::Trace,
             "debug" => LevelFilter::Debug,
             "info" => LevelFilter::Info,
             "warn" => LevelFilter::Warn,
             "error" => LevelFilter::Error,
-            _ => LevelFilter::Debug,
+            _ => default,
         },
-        Err(_) => LevelFilter::Debug,
-    };
-    init(level);
+        Err(_) => default,
+    }
+}
+
+/// Initialize the logging system
+#[deprecated(note = "use `logging::try_init`, which reports double initialization")]
+pub fn init(level: LevelFilter) {
+    if let Ok(guard) = try_init(level) {
+        std::mem::forget(guard);
+    }
+}
+
+/// Initialize test logging with appropriate level
+#[deprecated(note = "use `logging::try_init(logging::level_from_env(..))`")]
+pub fn init_test_logging() {
+    if let Ok(guard) = try_init(level_from_env(LevelFilter::Debug)) {
+        std::mem::forget(guard);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_double_init_does_not_panic() {
+        let _first = try_init(LevelFilter::Info);
+        assert!(try_init(LevelFilter::Info).is_err());
+
+        #[allow(deprecated)]
+        init(LevelFilter::Info);
+    }
 }
@@ -1,19 +1,40 @@
-mod security;
-mod storage;
-mod templates;
-
 use actix_web::{web, App, HttpServer};
-use log::info;
+use log::{info, LevelFilter};
+use secure_biometric::security::{KeySource, LocalFileKeyProvider};
+use secure_biometric::{logging, storage};
+use std::sync::Arc;
+
+/// Environment variable holding the master key as 64 hex digits
+const MASTER_KEY_VAR: &str = "VAULT_MASTER_KEY";
+/// Environment variable naming a file holding the master key, for development
+const KEY_FILE_VAR: &str = "VAULT_KEY_FILE";
+
+/// Master key source configured in the environment
+///
+/// Fails rather than falling back to an in-memory key, which would leave
+/// the stored templates unreadable after a restart.
+fn key_source() -> Result<KeySource, String> {
+    if let Some(path) = std::env::var_os(KEY_FILE_VAR) {
+        let provider = LocalFileKeyProvider::open(path).map_err(|e| e.to_string())?;
+        return Ok(KeySource::Provider(Arc::new(provider)));
+    }
+    if std::env::var_os(MASTER_KEY_VAR).is_some() {
+        return Ok(KeySource::Env(MASTER_KEY_VAR.into()));
+    }
+    Err(format!("set {} or {}", MASTER_KEY_VAR, KEY_FILE_VAR))
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Initialize logging
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let _logging = logging::try_init(logging::level_from_env(LevelFilter::Info));
     
     info!("Starting secure biometric system...");
     
-    // Initialize template vault
-    let vault = storage::TemplateVault::new("data/templates")
+    // Initialize template vault; its data key stays wrapped by the master
+    // key, so templates survive restarts
+    let key_source = key_source().expect("No master key configured");
+    let vault = storage::TemplateVault::open_with_key("data/templates", key_source)
         .await
         .expect("Failed to initialize template vault");
     let vault = web::Data::new(vault);
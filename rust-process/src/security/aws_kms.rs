@@ -0,0 +1,103 @@
+use super::error::SecurityError;
+use super::provider::MasterKeyProvider;
+use super::secret::SecretBytes;
+use super::Result;
+use async_trait::async_trait;
+use aws_sdk_kms::error::{DisplayErrorContext, SdkError};
+use aws_sdk_kms::primitives::Blob;
+use aws_sdk_kms::types::DataKeySpec;
+use aws_sdk_kms::Client;
+
+/// Key of the KMS encryption context carrying the wrapping context
+const CONTEXT_KEY: &str = "slot";
+
+/// Provider whose master key is an AWS KMS key
+///
+/// Data keys are generated, wrapped and unwrapped by KMS, so the master
+/// key never leaves it.
+#[derive(Clone)]
+pub struct AwsKmsKeyProvider {
+    client: Client,
+    key_id: String,
+}
+
+impl AwsKmsKeyProvider {
+    /// Use the KMS key `key_id`, given as key ID, ARN or alias
+    pub fn new(client: Client, key_id: impl Into<String>) -> Self {
+        Self {
+            client,
+            key_id: key_id.into(),
+        }
+    }
+}
+
+/// Map a KMS failure: errors returned by KMS itself mean the request was
+/// refused, anything else that KMS could not be reached
+fn kms_error<E, R>(refused: fn(String) -> SecurityError, e: SdkError<E, R>) -> SecurityError
+where
+    E: std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    let message = format!("AWS KMS: {}", DisplayErrorContext(&e));
+    match e {
+        SdkError::ServiceError(_) => refused(message),
+        _ => SecurityError::ProviderUnavailable(message),
+    }
+}
+
+fn data_key(plaintext: Option<&Blob>) -> Result<SecretBytes> {
+    plaintext
+        .filter(|blob| blob.as_ref().len() == 32)
+        .map(|blob| SecretBytes::from(blob.as_ref()))
+        .ok_or_else(|| SecurityError::InvalidKey("AWS KMS returned no 32-byte data key".into()))
+}
+
+#[async_trait]
+impl MasterKeyProvider for AwsKmsKeyProvider {
+    async fn wrap_key(&self, key: &SecretBytes, context: &str) -> Result<Vec<u8>> {
+        let mut request = self
+            .client
+            .encrypt()
+            .key_id(&self.key_id)
+            .plaintext(Blob::new(key.expose()));
+        if !context.is_empty() {
+            request = request.encryption_context(CONTEXT_KEY, context);
+        }
+        let output = request
+            .send()
+            .await
+            .map_err(|e| kms_error(SecurityError::Encryption, e))?;
+        output
+            .ciphertext_blob()
+            .map(|blob| blob.as_ref().to_vec())
+            .ok_or_else(|| SecurityError::Encryption("AWS KMS returned no ciphertext".into()))
+    }
+
+    async fn unwrap_key(&self, wrapped: &[u8], context: &str) -> Result<SecretBytes> {
+        let mut request = self
+            .client
+            .decrypt()
+            .key_id(&self.key_id)
+            .ciphertext_blob(Blob::new(wrapped));
+        if !context.is_empty() {
+            request = request.encryption_context(CONTEXT_KEY, context);
+        }
+        let output = request
+            .send()
+            .await
+            .map_err(|e| kms_error(SecurityError::InvalidKey, e))?;
+        data_key(output.plaintext())
+    }
+
+    async fn generate_data_key(&self) -> Result<SecretBytes> {
+        let output = self
+            .client
+            .generate_data_key()
+            .key_id(&self.key_id)
+            .key_spec(DataKeySpec::Aes256)
+            .send()
+            .await
+            .map_err(|e| kms_error(SecurityError::KeyGeneration, e))?;
+        data_key(output.plaintext())
+    }
+}
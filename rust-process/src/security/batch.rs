@@ -0,0 +1,88 @@
+use super::encryption::{EncryptedData, EncryptionEngine};
+use super::error::SecurityError;
+use super::Result;
+use std::sync::Arc;
+use zeroize::Zeroize;
+
+/// One item of a batch encrypted by `EncryptionEngine::encrypt_batch_with_header_and_aad`
+///
+/// The plaintext is wiped once the item is dropped.
+pub struct BatchItem {
+    /// Data to encrypt
+    pub data: Vec<u8>,
+    /// Readable metadata authenticated along with the data
+    pub header: Vec<u8>,
+    /// Associated data the result only decrypts with
+    pub aad: Vec<u8>,
+}
+
+impl From<Vec<u8>> for BatchItem {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            header: Vec::new(),
+            aad: Vec::new(),
+        }
+    }
+}
+
+impl Drop for BatchItem {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+impl EncryptionEngine {
+    /// Encrypt every item, returning the results in the same order
+    ///
+    /// Equivalent to calling `encrypt` on each item, but the items are
+    /// split across blocking threads, one chunk per available core.
+    pub async fn encrypt_batch(&self, items: Vec<Vec<u8>>) -> Result<Vec<EncryptedData>> {
+        self.encrypt_batch_with_header_and_aad(items.into_iter().map(BatchItem::from).collect())
+            .await
+    }
+
+    /// Encrypt every item with its own header and associated data, as
+    /// `encrypt_with_header_and_aad` does, returning the results in order
+    ///
+    /// The whole batch is encrypted with the key current when it starts,
+    /// each item under its own random nonce.
+    pub async fn encrypt_batch_with_header_and_aad(
+        &self,
+        items: Vec<BatchItem>,
+    ) -> Result<Vec<EncryptedData>> {
+        if items.is_empty() {
+            return Ok(Vec::new());
+        }
+        let key = Arc::new(self.sealing_key().await?);
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_len = items.len().div_ceil(threads);
+
+        let mut items = items.into_iter();
+        let chunks = std::iter::from_fn(|| {
+            let chunk: Vec<_> = items.by_ref().take(chunk_len).collect();
+            (!chunk.is_empty()).then_some(chunk)
+        });
+        let tasks: Vec<_> = chunks
+            .map(|chunk| {
+                let key = key.clone();
+                let key_manager = self.key_manager().clone();
+                tokio::task::spawn_blocking(move || {
+                    chunk
+                        .iter()
+                        .map(|item| key.seal(&key_manager, &item.data, &item.header, &item.aad))
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect();
+
+        let mut encrypted = Vec::new();
+        for task in tasks {
+            let chunk = task
+                .await
+                .map_err(|e| SecurityError::Encryption(format!("batch task failed: {}", e)))??;
+            encrypted.extend(chunk);
+        }
+        Ok(encrypted)
+    }
+}
@@ -1,27 +1,72 @@
 use super::error::SecurityError;
-use super::key_manager::KeyManager;
+use super::key_manager::{KeyId, KeyManager};
+use super::throttle::{Throttle, ThrottlePolicy, ThrottleStatus};
 use super::Result;
-use ring::aead::{Aad, Nonce, CHACHA20_POLY1305};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use ring::aead::{Aad, LessSafeKey, Nonce, MAX_TAG_LEN};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::sync::Arc;
+use zeroize::Zeroizing;
+
+/// Length of the authentication tag ending every ciphertext
+const TAG_LEN: usize = MAX_TAG_LEN;
+
+/// AEAD construction used to encrypt data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    /// ChaCha20-Poly1305 with random 12-byte nonces
+    #[default]
+    ChaCha20Poly1305,
+    /// XChaCha20-Poly1305 with random 24-byte nonces, which stay unlikely
+    /// to collide over far more encryptions under one key
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Length of the cipher's nonces, in bytes
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Cipher::ChaCha20Poly1305 => 12,
+            Cipher::XChaCha20Poly1305 => 24,
+        }
+    }
+}
 
 /// Encryption engine for secure template storage
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedData {
     /// Encrypted data bytes
     pub ciphertext: Vec<u8>,
-    /// Nonce used for encryption
-    pub nonce: [u8; 12],
+    /// Nonce used for encryption, of `cipher.nonce_len()` bytes
+    pub nonce: Vec<u8>,
+    /// ID of the key used for encryption, absent from data encrypted
+    /// before key IDs existed
+    #[serde(default)]
+    pub key_id: Option<KeyId>,
+    /// Construction the data was encrypted with
+    #[serde(default)]
+    pub cipher: Cipher,
+    /// Readable metadata authenticated along with the ciphertext, so
+    /// changing it makes decryption fail
+    #[serde(default)]
+    pub header: Vec<u8>,
 }
 
 pub struct EncryptionEngine {
     key_manager: Arc<KeyManager>,
+    cipher: Cipher,
+    /// Failed decryptions, if they are throttled
+    throttle: Option<Arc<Throttle>>,
 }
 
 impl Clone for EncryptionEngine {
     fn clone(&self) -> Self {
         Self {
             key_manager: self.key_manager.clone(),
+            cipher: self.cipher,
+            throttle: self.throttle.clone(),
         }
     }
 }
@@ -29,58 +74,181 @@ impl Clone for EncryptionEngine {
 impl EncryptionEngine {
     /// Create a new encryption engine with a key manager
     pub fn new(key_manager: Arc<KeyManager>) -> Self {
-        Self { key_manager }
+        Self {
+            key_manager,
+            cipher: Cipher::default(),
+            throttle: None,
+        }
+    }
+
+    /// Encrypt with `cipher` from now on
+    ///
+    /// Data is decrypted with the cipher it was encrypted with, whatever
+    /// the engine's.
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Cipher new data is encrypted with
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// Refuse decryptions with `SecurityError::TooManyAttempts` after
+    /// repeated authentication failures, as `policy` sets out
+    ///
+    /// Failures are counted per key the ciphertext names, timed by the key
+    /// manager's clock, and shared with clones of the engine. A successful
+    /// decryption clears its key's count.
+    pub fn with_throttle(mut self, policy: ThrottlePolicy) -> Self {
+        self.throttle = Some(Arc::new(Throttle::new(policy, self.key_manager.clock())));
+        self
+    }
+
+    /// Failed decryptions of every context still counted by the throttle
+    pub fn throttle_status(&self) -> Vec<ThrottleStatus> {
+        self.throttle.as_ref().map_or_else(Vec::new, |throttle| throttle.status())
+    }
+
+    /// Key manager holding the engine's keys
+    pub fn key_manager(&self) -> &Arc<KeyManager> {
+        &self.key_manager
     }
 
-    /// Encrypt data using ChaCha20-Poly1305
+    /// Encrypt data with the engine's cipher
     pub async fn encrypt(&self, data: &[u8]) -> Result<EncryptedData> {
-        let nonce_bytes = self.key_manager.generate_nonce()?;
-        let nonce = Nonce::assume_unique_for_key(nonce_bytes);
+        self.encrypt_with_aad(data, &[]).await
+    }
 
-        let key = self.key_manager.current_key().await?;
-        let mut in_out = data.to_vec();
-        key.seal_in_place_append_tag(nonce, Aad::empty(), &mut in_out)
-            .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+    /// Encrypt data, authenticating `aad` along with it
+    ///
+    /// The result only decrypts with `decrypt_with_aad` given the same `aad`.
+    pub async fn encrypt_with_aad(&self, data: &[u8], aad: &[u8]) -> Result<EncryptedData> {
+        self.encrypt_with_header_and_aad(data, &[], aad).await
+    }
 
-        Ok(EncryptedData {
-            ciphertext: in_out,
-            nonce: nonce_bytes,
+    /// Encrypt data, storing `header` in the clear but authenticated
+    ///
+    /// Decryption fails if `EncryptedData::header` was changed.
+    pub async fn encrypt_with_header(&self, data: &[u8], header: &[u8]) -> Result<EncryptedData> {
+        self.encrypt_with_header_and_aad(data, header, &[]).await
+    }
+
+    /// Encrypt data with both an authenticated `header` and `aad`
+    pub async fn encrypt_with_header_and_aad(
+        &self,
+        data: &[u8],
+        header: &[u8],
+        aad: &[u8],
+    ) -> Result<EncryptedData> {
+        self.sealing_key().await?.seal(&self.key_manager, data, header, aad)
+    }
+
+    /// Current key, resolved once for sealing many items with the engine's
+    /// cipher, possibly off the async runtime
+    pub(crate) async fn sealing_key(&self) -> Result<SealingKey> {
+        Ok(match self.cipher {
+            Cipher::ChaCha20Poly1305 => {
+                let key = self.key_manager.current_key().await?;
+                // Read under the key's lock, which rotation holds while changing both
+                let id = self.key_manager.current_key_id();
+                SealingKey {
+                    id,
+                    key: CipherKey::ChaCha20Poly1305(Box::new(key.clone())),
+                }
+            }
+            Cipher::XChaCha20Poly1305 => {
+                let (id, key) = self.key_manager.current_key_bytes();
+                SealingKey {
+                    id,
+                    key: CipherKey::XChaCha20Poly1305(key),
+                }
+            }
         })
     }
 
-    /// Decrypt data using ChaCha20-Poly1305
+    /// Decrypt data encrypted by `encrypt`
     pub async fn decrypt(&self, encrypted: &EncryptedData) -> Result<Vec<u8>> {
+        self.decrypt_with_aad(encrypted, &[]).await
+    }
+
+    /// Decrypt data encrypted with `encrypt_with_aad`
+    ///
+    /// Fails with `SecurityError::AuthenticationFailed` unless `aad`
+    /// matches the one used to encrypt and the data is unchanged, with
+    /// `SecurityError::KeyUnavailable` once its key was discarded, and with
+    /// `SecurityError::MalformedCiphertext` if it cannot hold a tag.
+    ///
+    /// When throttled, fails with `SecurityError::TooManyAttempts` while the
+    /// key `encrypted` names is locked out.
+    pub async fn decrypt_with_aad(&self, encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
+        let Some(throttle) = &self.throttle else {
+            return self.open(encrypted, aad).await;
+        };
+        throttle.check(encrypted.key_id)?;
+        let result = self.open(encrypted, aad).await;
+        match &result {
+            Ok(_) => throttle.record_success(encrypted.key_id),
+            Err(SecurityError::AuthenticationFailed) => throttle.record_failure(encrypted.key_id),
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Decrypt a value read back from storage, bypassing the throttle
+    ///
+    /// A stored value that fails to authenticate is corrupt or tampered
+    /// with at rest rather than a guess, and must not lock out its key.
+    pub(crate) async fn decrypt_stored(&self, encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
+        self.open(encrypted, aad).await
+    }
+
+    async fn open(&self, encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
+        check_shape(encrypted)?;
+        let aad = &*associated_data(&encrypted.header, aad);
+        let Some(key_id) = encrypted.key_id else {
+            return self.decrypt_unkeyed(encrypted, aad).await;
+        };
+
+        let plaintext = match encrypted.cipher {
+            Cipher::ChaCha20Poly1305 => {
+                open_with_key(&self.key_manager.key_by_id(key_id).await?, encrypted, aad)?
+            }
+            Cipher::XChaCha20Poly1305 => {
+                open_extended(&*self.key_manager.key_bytes_by_id(key_id)?, encrypted, aad)?
+            }
+        };
+        if key_id == self.key_manager.current_key_id() {
+            self.key_manager.record_decryption(plaintext.len());
+        }
+        Ok(plaintext)
+    }
+
+    /// Decrypt data without a key ID, trying the current then the old keys,
+    /// newest first
+    ///
+    /// Only ChaCha20-Poly1305 data predates key IDs.
+    async fn decrypt_unkeyed(&self, encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
+        if encrypted.cipher != Cipher::ChaCha20Poly1305 {
+            return Err(SecurityError::Decryption("encrypted data names no key".into()));
+        }
         let key = self.key_manager.current_key().await?;
 
         // Try with current key first
-        let mut in_out = encrypted.ciphertext.clone();
-        match key.open_in_place(
-            Nonce::assume_unique_for_key(encrypted.nonce),
-            Aad::empty(),
-            &mut in_out,
-        ) {
-            Ok(_) => {
-                in_out.truncate(in_out.len() - CHACHA20_POLY1305.tag_len());
-                return Ok(in_out);
-            }
-            Err(_) => {
-                // Try with old key if available
-                if let Some(old_key) = &*self.key_manager.old_key().await? {
-                    let mut in_out = encrypted.ciphertext.clone();
-                    old_key
-                        .open_in_place(
-                            Nonce::assume_unique_for_key(encrypted.nonce),
-                            Aad::empty(),
-                            &mut in_out,
-                        )
-                        .map_err(|e| SecurityError::Decryption(e.to_string()))?;
-                    in_out.truncate(in_out.len() - CHACHA20_POLY1305.tag_len());
-                    return Ok(in_out);
-                }
+        if let Ok(plaintext) = open_with_key(&key, encrypted, aad) {
+            self.key_manager.record_decryption(plaintext.len());
+            return Ok(plaintext);
+        }
+
+        // Try with old keys if available
+        for old_key in self.key_manager.old_keys().await?.values().rev() {
+            if let Ok(plaintext) = open_with_key(old_key, encrypted, aad) {
+                return Ok(plaintext);
             }
         }
 
-        Err(SecurityError::Decryption("Failed to decrypt data".into()))
+        Err(SecurityError::AuthenticationFailed)
     }
 
     /// Start key rotation process
@@ -101,3 +269,124 @@ impl EncryptionEngine {
         Ok(())
     }
 }
+
+/// A key ready to seal data, with the ID stored alongside its ciphertexts
+pub(crate) struct SealingKey {
+    id: KeyId,
+    key: CipherKey,
+}
+
+enum CipherKey {
+    ChaCha20Poly1305(Box<LessSafeKey>),
+    XChaCha20Poly1305(Zeroizing<[u8; 32]>),
+}
+
+impl SealingKey {
+    /// Encrypt `data` under a fresh nonce, authenticating `header` and `aad`
+    pub(crate) fn seal(
+        &self,
+        key_manager: &KeyManager,
+        data: &[u8],
+        header: &[u8],
+        aad: &[u8],
+    ) -> Result<EncryptedData> {
+        let aad = &*associated_data(header, aad);
+        // Wiped if sealing fails and the buffer still holds plaintext
+        let mut in_out = Zeroizing::new(data.to_vec());
+        let (nonce, cipher) = match &self.key {
+            CipherKey::ChaCha20Poly1305(key) => {
+                let nonce_bytes: [u8; 12] = key_manager.generate_nonce()?;
+                key.seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key(nonce_bytes),
+                    Aad::from(aad),
+                    &mut *in_out,
+                )
+                .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+                (nonce_bytes.to_vec(), Cipher::ChaCha20Poly1305)
+            }
+            CipherKey::XChaCha20Poly1305(key) => {
+                let nonce_bytes: [u8; 24] = key_manager.generate_nonce()?;
+                XChaCha20Poly1305::new(key.as_ref().into())
+                    .encrypt_in_place(XNonce::from_slice(&nonce_bytes), aad, &mut *in_out)
+                    .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+                (nonce_bytes.to_vec(), Cipher::XChaCha20Poly1305)
+            }
+        };
+        key_manager.record_encryption(data.len());
+
+        Ok(EncryptedData {
+            ciphertext: std::mem::take(&mut *in_out),
+            nonce,
+            key_id: Some(self.id),
+            cipher,
+            header: header.to_vec(),
+        })
+    }
+}
+
+/// AEAD associated data authenticating `header` and `aad`
+///
+/// Without a header this is `aad` itself, as for data encrypted before
+/// headers existed; otherwise the header is length-prefixed so it cannot
+/// be confused with the start of `aad`.
+fn associated_data<'a>(header: &[u8], aad: &'a [u8]) -> Cow<'a, [u8]> {
+    if header.is_empty() {
+        return Cow::Borrowed(aad);
+    }
+    let mut data = Vec::with_capacity(4 + header.len() + aad.len());
+    data.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    data.extend_from_slice(header);
+    data.extend_from_slice(aad);
+    Cow::Owned(data)
+}
+
+fn invalid_nonce(encrypted: &EncryptedData) -> SecurityError {
+    SecurityError::MalformedCiphertext(format!(
+        "{:?} nonce of {} bytes",
+        encrypted.cipher,
+        encrypted.nonce.len()
+    ))
+}
+
+/// Fail with `SecurityError::MalformedCiphertext` if `encrypted` cannot
+/// have been produced by its cipher
+fn check_shape(encrypted: &EncryptedData) -> Result<()> {
+    if encrypted.nonce.len() != encrypted.cipher.nonce_len() {
+        return Err(invalid_nonce(encrypted));
+    }
+    if encrypted.ciphertext.len() < TAG_LEN {
+        return Err(SecurityError::MalformedCiphertext(format!(
+            "ciphertext of {} bytes is shorter than its tag",
+            encrypted.ciphertext.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Decrypt ChaCha20-Poly1305 `encrypted` with `key`, wiping the buffer if
+/// authentication fails
+fn open_with_key(key: &LessSafeKey, encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
+    let nonce =
+        Nonce::try_assume_unique_for_key(&encrypted.nonce).map_err(|_| invalid_nonce(encrypted))?;
+    let mut in_out = Zeroizing::new(encrypted.ciphertext.clone());
+    let len = key
+        .open_in_place(nonce, Aad::from(aad), &mut in_out)
+        .map_err(|_| SecurityError::AuthenticationFailed)?
+        .len();
+    let mut plaintext = std::mem::take(&mut *in_out);
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+/// Decrypt XChaCha20-Poly1305 `encrypted` with `key`, wiping the buffer if
+/// authentication fails
+fn open_extended(key: &[u8; 32], encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
+    if encrypted.nonce.len() != Cipher::XChaCha20Poly1305.nonce_len() {
+        return Err(invalid_nonce(encrypted));
+    }
+    let mut in_out = Zeroizing::new(encrypted.ciphertext.clone());
+    XChaCha20Poly1305::new(key.into())
+        .decrypt_in_place(XNonce::from_slice(&encrypted.nonce), aad, &mut *in_out)
+        .map_err(|_| SecurityError::AuthenticationFailed)?;
+    Ok(std::mem::take(&mut *in_out))
+}
@@ -5,15 +5,41 @@ pub enum SecurityError {
     #[error("Encryption error: {0}")]
     Encryption(String),
 
+    /// A decryption failure not covered by the variants below
     #[error("Decryption error: {0}")]
     Decryption(String),
 
+    /// The ciphertext, its AAD or its header was changed, or it was
+    /// encrypted under another key with the same ID
+    #[error("Authentication failed: data was corrupted or tampered with")]
+    AuthenticationFailed,
+
+    /// The ciphertext is too short to hold an authentication tag, or its
+    /// nonce has the wrong length
+    #[error("Malformed ciphertext: {0}")]
+    MalformedCiphertext(String),
+
+    /// Too many decryptions failed authentication; refused until the
+    /// lockout ends
+    #[error("Too many failed decryption attempts; retry in {retry_after:?}")]
+    TooManyAttempts { retry_after: std::time::Duration },
+
     #[error("Key generation error: {0}")]
     KeyGeneration(String),
 
     #[error("Invalid key: {0}")]
     InvalidKey(String),
 
+    /// No key with this ID is held, such as a key retired too early
+    #[error("Key {key_id} is not available")]
+    KeyUnavailable { key_id: u32 },
+
+    #[error("Invalid key shares: {0}")]
+    InvalidShares(String),
+
+    #[error("Key provider unavailable: {0}")]
+    ProviderUnavailable(String),
+
     #[error("Integrity check failed: {0}")]
     IntegrityError(String),
 }
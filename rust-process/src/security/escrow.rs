@@ -0,0 +1,139 @@
+use super::error::SecurityError;
+use super::key_manager::{KeyId, KeyManager};
+use super::secret::SecretBytes;
+use super::Result;
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+use serde::{Deserialize, Serialize};
+use sharks::{Share, Sharks};
+use std::collections::BTreeSet;
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Version of the encoding of the keys split into shares
+const SPLIT_VERSION: u8 = 1;
+/// Key ID and key bytes
+const KEY_ENTRY_LEN: usize = 4 + 32;
+
+/// One of the shares the keys of a `KeyManager` are split into
+///
+/// Fewer shares than the threshold reveal nothing about the keys. Shares
+/// are wiped from memory when dropped and never shown by `Debug`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyShare {
+    /// Number of shares needed to reconstruct the keys
+    pub threshold: u8,
+    /// Position of the share, distinct for every share of a split
+    pub index: u8,
+    /// Share of the keys
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for KeyShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyShare({} of {})", self.index, self.threshold)
+    }
+}
+
+impl Drop for KeyShare {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+fn invalid(message: impl Into<String>) -> SecurityError {
+    SecurityError::InvalidShares(message.into())
+}
+
+impl KeyManager {
+    /// Split the current and old keys into `shares` shares, any `threshold`
+    /// of which rebuild them with `from_shares`
+    ///
+    /// `threshold` must be at least 2, so no single share holder can
+    /// unlock the keys, and at most `shares`, which is at most 255.
+    pub fn split_key(&self, shares: u8, threshold: u8) -> Result<Vec<KeyShare>> {
+        if threshold < 2 || threshold > shares {
+            return Err(invalid(format!(
+                "threshold must be between 2 and {}, got {}",
+                shares, threshold
+            )));
+        }
+
+        let material = self.key_material();
+        let mut secret = Zeroizing::new(Vec::with_capacity(
+            5 + KEY_ENTRY_LEN * (1 + material.old.len()) + SHA256_OUTPUT_LEN,
+        ));
+        secret.push(SPLIT_VERSION);
+        let keys = std::iter::once((material.current_id, &material.current))
+            .chain(material.old.iter().map(|(id, key)| (*id, key)));
+        for (id, key) in keys {
+            secret.extend_from_slice(&id.to_be_bytes());
+            secret.extend_from_slice(key);
+        }
+        // Lets reconstruction tell a corrupted share from a valid one
+        let checksum = digest(&SHA256, &secret);
+        secret.extend_from_slice(checksum.as_ref());
+
+        Ok(Sharks(threshold)
+            .dealer(&secret)
+            .take(shares as usize)
+            .map(|share| {
+                let bytes = Zeroizing::new(Vec::from(&share));
+                KeyShare {
+                    threshold,
+                    index: bytes[0],
+                    data: bytes[1..].to_vec(),
+                }
+            })
+            .collect())
+    }
+
+    /// Rebuild a key manager from shares made by `split_key`
+    ///
+    /// Fails with `SecurityError::InvalidShares` with fewer distinct shares
+    /// than the threshold, or if a share is corrupted or from another split.
+    pub fn from_shares(shares: &[KeyShare]) -> Result<Self> {
+        let threshold = shares.first().ok_or_else(|| invalid("no shares given"))?.threshold;
+        if shares.iter().any(|share| share.threshold != threshold) {
+            return Err(invalid("shares come from different splits"));
+        }
+        let indexes: BTreeSet<u8> = shares.iter().map(|share| share.index).collect();
+        if indexes.len() != shares.len() {
+            return Err(invalid("the same share was given twice"));
+        }
+        if shares.len() < threshold as usize {
+            return Err(invalid(format!(
+                "{} shares are needed, got {}",
+                threshold,
+                shares.len()
+            )));
+        }
+
+        let shares = shares
+            .iter()
+            .map(|share| {
+                let mut bytes = Zeroizing::new(vec![share.index]);
+                bytes.extend_from_slice(&share.data);
+                Share::try_from(&bytes[..]).map_err(invalid)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let secret = Zeroizing::new(Sharks(threshold).recover(&shares).map_err(invalid)?);
+
+        let corrupted = || invalid("a share is corrupted or from another split");
+        let body_len = secret.len().checked_sub(SHA256_OUTPUT_LEN).ok_or_else(corrupted)?;
+        let (body, checksum) = secret.split_at(body_len);
+        if digest(&SHA256, body).as_ref() != checksum {
+            return Err(corrupted());
+        }
+        let (&version, entries) = body.split_first().ok_or_else(corrupted)?;
+        if version != SPLIT_VERSION || entries.is_empty() || entries.len() % KEY_ENTRY_LEN != 0 {
+            return Err(corrupted());
+        }
+
+        let mut keys = entries.chunks(KEY_ENTRY_LEN).map(|entry| {
+            let id = KeyId::from_be_bytes(entry[..4].try_into().expect("4-byte key ID"));
+            (id, SecretBytes::from(&entry[4..]))
+        });
+        let current = keys.next().ok_or_else(corrupted)?;
+        Self::from_keys(current, keys)
+    }
+}
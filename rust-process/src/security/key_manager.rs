@@ -1,14 +1,176 @@
 use super::error::SecurityError;
+use super::passphrase::{derive_key, Argon2Params};
+use super::provider::MasterKeyProvider;
+use super::secret::SecretBytes;
 use super::Result;
+use crate::clock::{Clock, SystemClock};
+use chrono::{DateTime, Duration, Utc};
 use ring::aead::{LessSafeKey, UnboundKey, CHACHA20_POLY1305};
 use ring::rand::{SecureRandom, SystemRandom};
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::sync::RwLock;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Limits on how much a single key should be used before rotation
+///
+/// Used both as a soft budget, which warns once exceeded, and as a hard
+/// limit, past which vaults rotate the key before their next write.
+#[derive(Debug, Clone, Default)]
+pub struct KeyBudget {
+    /// Maximum number of encrypt and decrypt operations
+    pub max_operations: Option<u64>,
+    /// Maximum number of bytes encrypted and decrypted
+    pub max_bytes: Option<u64>,
+    /// Maximum age of the key
+    pub max_age: Option<Duration>,
+}
+
+/// Snapshot of the current key's usage
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyStatus {
+    /// ID of the current key
+    pub key_id: KeyId,
+    /// When the current key was generated
+    pub created_at: DateTime<Utc>,
+    /// Number of encryptions performed with the current key
+    pub encryptions: u64,
+    /// Number of decryptions performed with the current key
+    pub decryptions: u64,
+    /// Plaintext bytes encrypted with the current key
+    pub bytes_encrypted: u64,
+    /// Plaintext bytes recovered with the current key
+    pub bytes_decrypted: u64,
+    /// Whether any limit of the configured budget has been reached
+    pub budget_exceeded: bool,
+    /// Whether any hard limit has been reached, so the key must be rotated
+    pub limit_reached: bool,
+}
+
+/// Usage counters of one key, as persisted alongside the keyring
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct KeyUsageRecord {
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) encryptions: u64,
+    pub(crate) decryptions: u64,
+    pub(crate) bytes_encrypted: u64,
+    pub(crate) bytes_decrypted: u64,
+}
+
+/// Usage counters for the current key, updated with relaxed atomics
+struct KeyUsage {
+    created_at: Mutex<DateTime<Utc>>,
+    encryptions: AtomicU64,
+    decryptions: AtomicU64,
+    bytes_encrypted: AtomicU64,
+    bytes_decrypted: AtomicU64,
+    alerted: AtomicBool,
+}
+
+impl KeyUsage {
+    fn new(created_at: DateTime<Utc>) -> Self {
+        Self {
+            created_at: Mutex::new(created_at),
+            encryptions: AtomicU64::new(0),
+            decryptions: AtomicU64::new(0),
+            bytes_encrypted: AtomicU64::new(0),
+            bytes_decrypted: AtomicU64::new(0),
+            alerted: AtomicBool::new(false),
+        }
+    }
+
+    fn reset(&self, created_at: DateTime<Utc>) {
+        self.restore(&KeyUsageRecord {
+            created_at,
+            encryptions: 0,
+            decryptions: 0,
+            bytes_encrypted: 0,
+            bytes_decrypted: 0,
+        });
+    }
+
+    fn restore(&self, record: &KeyUsageRecord) {
+        *self.created_at.lock().unwrap_or_else(|e| e.into_inner()) = record.created_at;
+        self.encryptions.store(record.encryptions, Ordering::Relaxed);
+        self.decryptions.store(record.decryptions, Ordering::Relaxed);
+        self.bytes_encrypted.store(record.bytes_encrypted, Ordering::Relaxed);
+        self.bytes_decrypted.store(record.bytes_decrypted, Ordering::Relaxed);
+        self.alerted.store(false, Ordering::Relaxed);
+    }
+
+    fn record(&self) -> KeyUsageRecord {
+        KeyUsageRecord {
+            created_at: *self.created_at.lock().unwrap_or_else(|e| e.into_inner()),
+            encryptions: self.encryptions.load(Ordering::Relaxed),
+            decryptions: self.decryptions.load(Ordering::Relaxed),
+            bytes_encrypted: self.bytes_encrypted.load(Ordering::Relaxed),
+            bytes_decrypted: self.bytes_decrypted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl KeyBudget {
+    /// Whether `usage` reaches any limit of the budget at `now`
+    fn is_reached_by(&self, usage: &KeyUsageRecord, now: DateTime<Utc>) -> bool {
+        let operations = usage.encryptions + usage.decryptions;
+        let bytes = usage.bytes_encrypted + usage.bytes_decrypted;
+        self.max_operations.is_some_and(|max| operations >= max)
+            || self.max_bytes.is_some_and(|max| bytes >= max)
+            || self.max_age.is_some_and(|max| now - usage.created_at >= max)
+    }
+}
+
+/// Identifier of a data key, assigned in the order keys are generated
+pub type KeyId = u32;
+
+/// ID of the first key of a key manager
+const FIRST_KEY_ID: KeyId = 1;
+
+/// Raw bytes and IDs of the current and old keys, kept for persistence
+#[derive(Clone)]
+pub(crate) struct KeyMaterial {
+    pub(crate) current: [u8; 32],
+    pub(crate) current_id: KeyId,
+    pub(crate) old: BTreeMap<KeyId, [u8; 32]>,
+}
+
+impl Drop for KeyMaterial {
+    fn drop(&mut self) {
+        self.current.zeroize();
+        self.old.values_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+impl KeyMaterial {
+    /// ID for the key generated after the current one
+    fn next_id(&self) -> Result<KeyId> {
+        self.current_id
+            .checked_add(1)
+            .ok_or_else(|| SecurityError::KeyGeneration("key IDs exhausted".into()))
+    }
+}
+
+/// Build a ChaCha20-Poly1305 key from raw bytes
+fn aead_key(key_bytes: &[u8]) -> Result<LessSafeKey> {
+    let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, key_bytes)
+        .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
+    Ok(LessSafeKey::new(unbound_key))
+}
 
 /// Manages encryption keys and provides secure key rotation
 pub struct KeyManager {
     current_key: Arc<RwLock<LessSafeKey>>,
-    old_key: Arc<RwLock<Option<LessSafeKey>>>,
+    /// Keys replaced by rotations, still accepted for decryption
+    old_keys: Arc<RwLock<BTreeMap<KeyId, LessSafeKey>>>,
+    material: Arc<Mutex<KeyMaterial>>,
+    usage: Arc<KeyUsage>,
+    budget: KeyBudget,
+    limit: KeyBudget,
+    clock: Arc<dyn Clock>,
+    /// Source of new data keys, if not the local RNG
+    provider: Option<Arc<dyn MasterKeyProvider>>,
     rng: SystemRandom,
 }
 
@@ -16,7 +178,13 @@ impl Clone for KeyManager {
     fn clone(&self) -> Self {
         Self {
             current_key: self.current_key.clone(),
-            old_key: self.old_key.clone(),
+            old_keys: self.old_keys.clone(),
+            material: self.material.clone(),
+            usage: self.usage.clone(),
+            budget: self.budget.clone(),
+            limit: self.limit.clone(),
+            clock: self.clock.clone(),
+            provider: self.provider.clone(),
             rng: SystemRandom::new(),
         }
     }
@@ -25,71 +193,351 @@ impl Clone for KeyManager {
 impl KeyManager {
     /// Create a new key manager with a fresh encryption key
     pub fn new() -> Result<Self> {
-        let rng = SystemRandom::new();
-        let mut key_bytes = [0u8; 32];
-        rng.fill(&mut key_bytes)
-            .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
+        Self::from_keys((FIRST_KEY_ID, SecretBytes::random(32)?), None)
+    }
 
-        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &key_bytes)
-            .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
-        let key = LessSafeKey::new(unbound_key);
+    /// Create a key manager whose key is derived from `passphrase`
+    ///
+    /// The same passphrase, `salt` and `params` always give the same key;
+    /// data encrypted under another passphrase fails to decrypt.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8], params: Argon2Params) -> Result<Self> {
+        Self::from_keys((FIRST_KEY_ID, derive_key(passphrase, salt, &params)?), None)
+    }
+
+    /// Create a key manager with a data key generated by `provider`
+    ///
+    /// Key rotations request their new keys from `provider` as well.
+    pub async fn from_provider(provider: Arc<dyn MasterKeyProvider>) -> Result<Self> {
+        let key_bytes = provider.generate_data_key().await?;
+        Ok(Self::from_keys((FIRST_KEY_ID, key_bytes), None)?.with_provider(provider))
+    }
+
+    /// Create a key manager from existing keys and their IDs
+    ///
+    /// `old` are keys replaced by earlier rotations, still accepted for
+    /// decryption until they are retired. Keys must be 32 bytes.
+    pub fn from_keys(
+        (current_id, current): (KeyId, SecretBytes),
+        old: impl IntoIterator<Item = (KeyId, SecretBytes)>,
+    ) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let mut material = KeyMaterial {
+            current: *current.to_key()?,
+            current_id,
+            old: BTreeMap::new(),
+        };
+        let mut old_keys = BTreeMap::new();
+        for (id, key) in old {
+            let key = key.to_key()?;
+            old_keys.insert(id, aead_key(&*key)?);
+            material.old.insert(id, *key);
+        }
 
         Ok(Self {
-            current_key: Arc::new(RwLock::new(key)),
-            old_key: Arc::new(RwLock::new(None)),
+            current_key: Arc::new(RwLock::new(aead_key(&material.current)?)),
+            old_keys: Arc::new(RwLock::new(old_keys)),
+            material: Arc::new(Mutex::new(material)),
+            usage: Arc::new(KeyUsage::new(Utc::now())),
+            budget: KeyBudget::default(),
+            limit: KeyBudget::default(),
+            clock: Arc::new(SystemClock),
+            provider: None,
             rng,
         })
     }
+
+    /// Use `clock` for key ages instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.usage.reset(clock.now_utc());
+        self.clock = clock;
+        self
+    }
+
+    /// Request new keys from `provider` instead of generating them locally
+    pub fn with_provider(mut self, provider: Arc<dyn MasterKeyProvider>) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// Set the usage budget that triggers a warning once exceeded
+    pub fn with_budget(mut self, budget: KeyBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Set the hard usage limit, reported by `KeyStatus::limit_reached`
+    ///
+    /// The key manager keeps using the key; a `TemplateVault` rotates it
+    /// before its next write.
+    pub fn with_limit(mut self, limit: KeyBudget) -> Self {
+        self.limit = limit;
+        self
+    }
     
-    /// Start key rotation by generating a new key and preserving the old one
+    /// Start key rotation by generating a new key
+    ///
+    /// The replaced key joins the old keys, which keep decrypting until
+    /// retired, so rotations can start before earlier ones are finished.
     pub async fn start_rotation(&self) -> Result<()> {
-        let mut old_key = self.old_key.write().await;
-        let current_key = self.current_key.read().await;
-        *old_key = Some(current_key.clone());
+        // Generate the new key first: a provider may be slow or unavailable
+        let key_bytes = self.generate_key().await?.to_key()?;
+        let new_key = aead_key(&*key_bytes)?;
 
-        // Generate new key
-        let mut key_bytes = [0u8; 32];
-        self.rng
-            .fill(&mut key_bytes)
-            .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
-
-        let unbound_key = UnboundKey::new(&CHACHA20_POLY1305, &key_bytes)
-            .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
-        let new_key = LessSafeKey::new(unbound_key);
+        let mut old_keys = self.old_keys.write().await;
+        let current_key = self.current_key.read().await;
+        let new_id = self.key_material().next_id()?;
+        old_keys.insert(self.current_key_id(), current_key.clone());
 
         // Update current key
         drop(current_key);
         let mut current = self.current_key.write().await;
         *current = new_key;
+        {
+            let mut material = self.material.lock().unwrap_or_else(|e| e.into_inner());
+            let (current_id, current) = (material.current_id, material.current);
+            material.old.insert(current_id, current);
+            material.current = *key_bytes;
+            material.current_id = new_id;
+            self.usage.reset(self.clock.now_utc());
+        }
 
         Ok(())
     }
 
-    /// Finish key rotation by clearing the old key
+    /// Finish key rotation by retiring every old key
+    ///
+    /// Only call this once no data encrypted with an old key is left.
     pub async fn finish_rotation(&self) -> Result<()> {
-        let mut old_key = self.old_key.write().await;
-        *old_key = None;
+        let mut old_keys = self.old_keys.write().await;
+        old_keys.clear();
+        self.material.lock().unwrap_or_else(|e| e.into_inner()).old.clear();
+        Ok(())
+    }
+
+    /// Retire the old key `id`, so data encrypted with it no longer decrypts
+    ///
+    /// Fails with `SecurityError::KeyUnavailable` if no old key has that ID;
+    /// the current key cannot be retired.
+    pub async fn retire_key(&self, id: KeyId) -> Result<()> {
+        let mut old_keys = self.old_keys.write().await;
+        if old_keys.remove(&id).is_none() {
+            return Err(if id == self.current_key_id() {
+                SecurityError::InvalidKey("the current key cannot be retired".into())
+            } else {
+                SecurityError::KeyUnavailable { key_id: id }
+            });
+        }
+        self.material.lock().unwrap_or_else(|e| e.into_inner()).old.remove(&id);
         Ok(())
     }
     
+    /// Replace all keys with a fresh one, discarding the current and old keys
+    ///
+    /// Data encrypted before the reset can no longer be decrypted.
+    pub async fn reset(&self) -> Result<()> {
+        let key_bytes = self.generate_key().await?.to_key()?;
+        let new_key = aead_key(&*key_bytes)?;
+
+        let mut old_keys = self.old_keys.write().await;
+        let mut current = self.current_key.write().await;
+        let new_id = self.key_material().next_id()?;
+        old_keys.clear();
+        *current = new_key;
+        let mut material = self.material.lock().unwrap_or_else(|e| e.into_inner());
+        *material = KeyMaterial {
+            current: *key_bytes,
+            current_id: new_id,
+            old: BTreeMap::new(),
+        };
+        self.usage.reset(self.clock.now_utc());
+        drop(material);
+        Ok(())
+    }
+
     /// Get the current encryption key
     pub async fn current_key(&self) -> Result<tokio::sync::RwLockReadGuard<'_, LessSafeKey>> {
         Ok(self.current_key.read().await)
     }
 
-    /// Get the old encryption key if it exists
-    pub async fn old_key(
+    /// Get the old encryption keys, by ID
+    pub async fn old_keys(
         &self,
-    ) -> Result<tokio::sync::RwLockReadGuard<'_, Option<LessSafeKey>>> {
-        Ok(self.old_key.read().await)
+    ) -> Result<tokio::sync::RwLockReadGuard<'_, BTreeMap<KeyId, LessSafeKey>>> {
+        Ok(self.old_keys.read().await)
     }
     
-    /// Generate a random nonce for encryption
-    pub fn generate_nonce(&self) -> Result<[u8; 12]> {
-        let mut nonce = [0u8; 12];
+    /// Bytes of a new data key, from the provider if there is one
+    async fn generate_key(&self) -> Result<SecretBytes> {
+        match &self.provider {
+            Some(provider) => provider.generate_data_key().await,
+            None => SecretBytes::random(32),
+        }
+    }
+
+    /// Get the key with ID `id`, if it is the current or an old key
+    ///
+    /// Fails with `SecurityError::KeyUnavailable` once the key is retired.
+    pub async fn key_by_id(&self, id: KeyId) -> Result<LessSafeKey> {
+        // Same lock order as `start_rotation`, so the IDs match the keys
+        let old_keys = self.old_keys.read().await;
+        let current_key = self.current_key.read().await;
+        if self.current_key_id() == id {
+            return Ok(current_key.clone());
+        }
+        old_keys.get(&id).cloned().ok_or(SecurityError::KeyUnavailable { key_id: id })
+    }
+
+    /// ID of the current encryption key
+    pub fn current_key_id(&self) -> KeyId {
+        self.material.lock().unwrap_or_else(|e| e.into_inner()).current_id
+    }
+
+    /// IDs of the old keys, oldest first
+    pub fn old_key_ids(&self) -> Vec<KeyId> {
+        let material = self.material.lock().unwrap_or_else(|e| e.into_inner());
+        material.old.keys().copied().collect()
+    }
+
+    /// ID and raw bytes of the current key
+    pub(crate) fn current_key_bytes(&self) -> (KeyId, Zeroizing<[u8; 32]>) {
+        let material = self.material.lock().unwrap_or_else(|e| e.into_inner());
+        (material.current_id, Zeroizing::new(material.current))
+    }
+
+    /// Raw bytes of the key with ID `id`, if it is the current or an old key
+    pub(crate) fn key_bytes_by_id(&self, id: KeyId) -> Result<Zeroizing<[u8; 32]>> {
+        let material = self.material.lock().unwrap_or_else(|e| e.into_inner());
+        if material.current_id == id {
+            return Ok(Zeroizing::new(material.current));
+        }
+        material
+            .old
+            .get(&id)
+            .map(|key| Zeroizing::new(*key))
+            .ok_or(SecurityError::KeyUnavailable { key_id: id })
+    }
+
+    /// Raw bytes of the current and old keys
+    pub(crate) fn key_material(&self) -> KeyMaterial {
+        self.material.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Clock the key manager times keys with
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    /// Get usage counters for the current key
+    pub fn key_status(&self) -> KeyStatus {
+        let (key_id, usage) = self.key_usage();
+        let now = self.clock.now_utc();
+        KeyStatus {
+            key_id,
+            created_at: usage.created_at,
+            encryptions: usage.encryptions,
+            decryptions: usage.decryptions,
+            bytes_encrypted: usage.bytes_encrypted,
+            bytes_decrypted: usage.bytes_decrypted,
+            budget_exceeded: self.budget.is_reached_by(&usage, now),
+            limit_reached: self.limit.is_reached_by(&usage, now),
+        }
+    }
+
+    /// ID and usage counters of the current key, for persistence
+    pub(crate) fn key_usage(&self) -> (KeyId, KeyUsageRecord) {
+        // Read under the material lock, which rotation holds while resetting
+        let material = self.material.lock().unwrap_or_else(|e| e.into_inner());
+        (material.current_id, self.usage.record())
+    }
+
+    /// Continue counting the usage of key `id` from `record`, if it is the
+    /// current key
+    pub(crate) fn restore_usage(&self, id: KeyId, record: &KeyUsageRecord) {
+        let material = self.material.lock().unwrap_or_else(|e| e.into_inner());
+        if material.current_id == id {
+            self.usage.restore(record);
+        }
+    }
+
+    /// Record an encryption performed with the current key
+    pub(crate) fn record_encryption(&self, bytes: usize) {
+        self.usage.encryptions.fetch_add(1, Ordering::Relaxed);
+        self.usage.bytes_encrypted.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.check_budget();
+    }
+
+    /// Take back the encryption of `bytes` recorded when key rotation
+    /// re-encrypted a stored value
+    ///
+    /// Re-encryption moves existing values to the new key rather than
+    /// adding to them, so it does not count toward the key's budget;
+    /// otherwise a limit below the number of stored values would have every
+    /// rotation leave the new key spent.
+    pub(crate) fn discount_reencryption(&self, bytes: usize) {
+        let sub = |count: &AtomicU64, by: u64| {
+            let _ = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n.saturating_sub(by)));
+        };
+        sub(&self.usage.encryptions, 1);
+        sub(&self.usage.bytes_encrypted, bytes as u64);
+    }
+
+    /// Record a decryption performed with the current key
+    pub(crate) fn record_decryption(&self, bytes: usize) {
+        self.usage.decryptions.fetch_add(1, Ordering::Relaxed);
+        self.usage.bytes_decrypted.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.check_budget();
+    }
+
+    /// Warn once per key when its usage budget is exceeded
+    fn check_budget(&self) {
+        if self.usage.alerted.load(Ordering::Relaxed) {
+            return;
+        }
+        let status = self.key_status();
+        if status.budget_exceeded && !self.usage.alerted.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "Encryption key budget exceeded ({} encryptions, {} decryptions, {} bytes); rotation recommended",
+                status.encryptions,
+                status.decryptions,
+                status.bytes_encrypted + status.bytes_decrypted
+            );
+        }
+    }
+
+    /// Generate a random `N`-byte nonce for encryption
+    pub fn generate_nonce<const N: usize>(&self) -> Result<[u8; N]> {
+        let mut nonce = [0u8; N];
         self.rng
             .fill(&mut nonce)
             .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
         Ok(nonce)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[tokio::test]
+    async fn test_key_age_budget_with_mock_clock() -> Result<()> {
+        let clock = Arc::new(MockClock::default());
+        let key_manager = KeyManager::new()?
+            .with_clock(clock.clone())
+            .with_budget(KeyBudget {
+                max_age: Some(Duration::days(90)),
+                ..Default::default()
+            });
+
+        assert!(!key_manager.key_status().budget_exceeded);
+        clock.advance(Duration::days(91));
+        assert!(key_manager.key_status().budget_exceeded);
+
+        // A rotated key starts its lifetime at the mocked time
+        key_manager.start_rotation().await?;
+        assert_eq!(key_manager.key_status().created_at, clock.now_utc());
+        assert!(!key_manager.key_status().budget_exceeded);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use super::error::SecurityError;
+use super::passphrase::{derive_key, Argon2Params};
+use super::provider::MasterKeyProvider;
+use super::secret::SecretBytes;
+use super::Result;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use zeroize::{Zeroize, Zeroizing};
+
+/// PBKDF2 iterations for passphrase-derived master keys
+const PASSPHRASE_ITERATIONS: u32 = 100_000;
+const NONCE_LEN: usize = 12;
+
+/// Where the vault master key comes from
+///
+/// The master key never encrypts templates itself: it wraps the data key
+/// stored in the vault, so rotating the data key leaves it unchanged.
+#[derive(Clone)]
+pub enum KeySource {
+    /// A raw 32-byte key
+    Bytes(Vec<u8>),
+    /// An environment variable holding the key as 64 hex digits
+    Env(String),
+    /// A passphrase, stretched with PBKDF2 and a per-vault salt
+    Passphrase(String),
+    /// A passphrase, stretched with Argon2id and a per-vault salt
+    Argon2Passphrase(String, Argon2Params),
+    /// A provider holding the master key, such as a KMS
+    Provider(Arc<dyn MasterKeyProvider>),
+}
+
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::Bytes(_) => f.write_str("KeySource::Bytes(..)"),
+            KeySource::Env(var) => write!(f, "KeySource::Env({:?})", var),
+            KeySource::Passphrase(_) => f.write_str("KeySource::Passphrase(..)"),
+            KeySource::Argon2Passphrase(_, params) => {
+                write!(f, "KeySource::Argon2Passphrase(.., {:?})", params)
+            }
+            KeySource::Provider(_) => f.write_str("KeySource::Provider(..)"),
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Drop for KeySource {
+    fn drop(&mut self) {
+        match self {
+            KeySource::Bytes(bytes) => bytes.zeroize(),
+            KeySource::Passphrase(passphrase) | KeySource::Argon2Passphrase(passphrase, _) => {
+                passphrase.zeroize()
+            }
+            KeySource::Env(_) | KeySource::Provider(_) => {}
+        }
+    }
+}
+
+impl KeySource {
+    /// Provider of the master key, using `salt` for passphrases
+    pub(crate) fn provider(&self, salt: &[u8]) -> Result<Arc<dyn MasterKeyProvider>> {
+        match self {
+            KeySource::Provider(provider) => Ok(provider.clone()),
+            _ => Ok(Arc::new(self.master_key(salt)?)),
+        }
+    }
+
+    /// Derive the master key, using `salt` for passphrases
+    pub(crate) fn master_key(&self, salt: &[u8]) -> Result<MasterKey> {
+        let key_bytes = match self {
+            KeySource::Bytes(bytes) => Zeroizing::new(bytes.clone()),
+            KeySource::Env(var) => {
+                let value = Zeroizing::new(std::env::var(var).map_err(|e| {
+                    SecurityError::InvalidKey(format!("cannot read {}: {}", var, e))
+                })?);
+                Zeroizing::new(decode_hex(value.trim()).ok_or_else(|| {
+                    SecurityError::InvalidKey(format!("{} is not a hex-encoded key", var))
+                })?)
+            }
+            KeySource::Passphrase(passphrase) => {
+                let mut key = Zeroizing::new(vec![0u8; 32]);
+                pbkdf2::derive(
+                    pbkdf2::PBKDF2_HMAC_SHA256,
+                    NonZeroU32::new(PASSPHRASE_ITERATIONS).expect("iterations are non-zero"),
+                    salt,
+                    passphrase.as_bytes(),
+                    &mut key,
+                );
+                key
+            }
+            KeySource::Argon2Passphrase(passphrase, params) => {
+                Zeroizing::new(derive_key(passphrase, salt, params)?.expose().to_vec())
+            }
+            KeySource::Provider(_) => {
+                return Err(SecurityError::InvalidKey(
+                    "provider master keys cannot be exported".into(),
+                ))
+            }
+        };
+
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key_bytes).map_err(|_| {
+            SecurityError::InvalidKey(format!(
+                "master key must be 32 bytes, got {}",
+                key_bytes.len()
+            ))
+        })?;
+        Ok(MasterKey {
+            key: LessSafeKey::new(key),
+            rng: SystemRandom::new(),
+            from_passphrase: matches!(
+                self,
+                KeySource::Passphrase(_) | KeySource::Argon2Passphrase(..)
+            ),
+        })
+    }
+}
+
+/// Key-encryption key protecting the stored data keys
+pub(crate) struct MasterKey {
+    key: LessSafeKey,
+    rng: SystemRandom,
+    /// Whether a mismatch means the passphrase is wrong
+    from_passphrase: bool,
+}
+
+impl MasterKey {
+    /// Encrypt a data key for storage: nonce followed by ciphertext
+    ///
+    /// `aad` is authenticated with the key, and must be given to `unwrap`.
+    pub(crate) fn wrap(&self, data_key: &SecretBytes, aad: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
+
+        let mut in_out = Zeroizing::new(data_key.expose().to_vec());
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut *in_out)
+            .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend_from_slice(&in_out);
+        Ok(wrapped)
+    }
+
+    /// Decrypt a data key written by `wrap` with the same `aad`
+    ///
+    /// Fails with `SecurityError::InvalidKey` if the master key or `aad` is
+    /// wrong.
+    pub(crate) fn unwrap(&self, wrapped: &[u8], aad: &[u8]) -> Result<SecretBytes> {
+        let wrong_key = || {
+            SecurityError::InvalidKey(if self.from_passphrase {
+                "wrong passphrase for this vault".into()
+            } else {
+                "master key does not match this vault".into()
+            })
+        };
+        if wrapped.len() < NONCE_LEN {
+            return Err(wrong_key());
+        }
+        let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| wrong_key())?;
+
+        let mut in_out = Zeroizing::new(ciphertext.to_vec());
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::from(aad), &mut in_out)
+            .map_err(|_| wrong_key())?;
+        if plaintext.len() != 32 {
+            return Err(wrong_key());
+        }
+        Ok(SecretBytes::from(&*plaintext))
+    }
+}
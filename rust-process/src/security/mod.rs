@@ -1,9 +1,32 @@
+#[cfg(feature = "aws-kms")]
+mod aws_kms;
+mod batch;
 mod encryption;
 mod error;
+mod escrow;
 mod key_manager;
+mod key_source;
+mod passphrase;
+mod provider;
+mod secret;
+mod stream;
+mod throttle;
+mod wrapped;
 
-pub use encryption::{EncryptedData, EncryptionEngine};
+#[cfg(feature = "aws-kms")]
+pub use aws_kms::AwsKmsKeyProvider;
+pub use batch::BatchItem;
+pub use encryption::{Cipher, EncryptedData, EncryptionEngine};
 pub use error::SecurityError;
-pub use key_manager::KeyManager;
+pub use escrow::KeyShare;
+pub use key_manager::{KeyBudget, KeyId, KeyManager, KeyStatus};
+pub(crate) use key_manager::KeyUsageRecord;
+pub use key_source::KeySource;
+pub use passphrase::{generate_salt, Argon2Params, SALT_LEN};
+pub use provider::{LocalFileKeyProvider, MasterKeyProvider};
+pub use secret::SecretBytes;
+pub use stream::STREAM_FRAME_LEN;
+pub use throttle::{ThrottlePolicy, ThrottleStatus};
+pub use wrapped::{WrappedKey, WrappedKeySet};
 
 pub type Result<T> = std::result::Result<T, SecurityError>;
@@ -0,0 +1,58 @@
+use super::error::SecurityError;
+use super::secret::SecretBytes;
+use super::Result;
+use argon2::{Algorithm, Argon2, Params, Version};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+/// Length of the salts made by `generate_salt`
+pub const SALT_LEN: usize = 16;
+
+/// Cost of deriving a key from a passphrase with Argon2id
+///
+/// A key is only derived again from the same passphrase and salt with the
+/// same parameters, so they must not change once data is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Argon2Params {
+    /// Memory used, in KiB
+    pub memory_kib: u32,
+    /// Number of passes over the memory
+    pub iterations: u32,
+    /// Number of lanes
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Params {
+    /// The OWASP recommendation: 19 MiB, 2 passes, 1 lane
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Generate a random salt for passphrase derivation
+///
+/// The salt is not secret, but must be stored to derive the key again.
+pub fn generate_salt() -> Result<[u8; SALT_LEN]> {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
+    Ok(salt)
+}
+
+/// Derive a 32-byte key from `passphrase` with Argon2id
+pub(crate) fn derive_key(passphrase: &str, salt: &[u8], params: &Argon2Params) -> Result<SecretBytes> {
+    let invalid = |e: argon2::Error| SecurityError::KeyGeneration(format!("Argon2: {}", e));
+    let params = Params::new(params.memory_kib, params.iterations, params.parallelism, Some(32))
+        .map_err(invalid)?;
+
+    let mut key = SecretBytes::new(vec![0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.expose_mut())
+        .map_err(invalid)?;
+    Ok(key)
+}
@@ -0,0 +1,114 @@
+use super::error::SecurityError;
+use super::key_source::{KeySource, MasterKey};
+use super::secret::SecretBytes;
+use super::Result;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+
+/// Holder of the master key protecting a vault's data keys
+///
+/// Data keys are only ever stored wrapped by the provider; the master key
+/// itself may never leave it, as with a KMS.
+///
+/// Each key is wrapped for a `context` naming where it is stored, such as
+/// `current/3` for data key 3 in the current slot. Providers must
+/// authenticate the context, so a wrapped key moved to another slot no
+/// longer unwraps.
+#[async_trait]
+pub trait MasterKeyProvider: Send + Sync {
+    /// Encrypt a data key for storage under `context`
+    async fn wrap_key(&self, key: &SecretBytes, context: &str) -> Result<Vec<u8>>;
+
+    /// Decrypt a data key written by `wrap_key` with the same `context`
+    ///
+    /// Fails with `SecurityError::InvalidKey` if the key was wrapped by
+    /// another master key or for another context.
+    async fn unwrap_key(&self, wrapped: &[u8], context: &str) -> Result<SecretBytes>;
+
+    /// Generate a new 32-byte data key
+    async fn generate_data_key(&self) -> Result<SecretBytes>;
+}
+
+#[async_trait]
+impl MasterKeyProvider for MasterKey {
+    async fn wrap_key(&self, key: &SecretBytes, context: &str) -> Result<Vec<u8>> {
+        self.wrap(key, context.as_bytes())
+    }
+
+    async fn unwrap_key(&self, wrapped: &[u8], context: &str) -> Result<SecretBytes> {
+        self.unwrap(wrapped, context.as_bytes())
+    }
+
+    async fn generate_data_key(&self) -> Result<SecretBytes> {
+        SecretBytes::random(32)
+    }
+}
+
+/// Provider keeping the master key in a local file, for development
+///
+/// The file holds the raw 32-byte key and is created on first use. Anyone
+/// who can read it can decrypt the vault.
+pub struct LocalFileKeyProvider {
+    path: PathBuf,
+    master_key: MasterKey,
+}
+
+impl LocalFileKeyProvider {
+    /// Load the master key from `path`, generating it if the file is missing
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let unavailable =
+            |e: std::io::Error| SecurityError::ProviderUnavailable(format!("{}: {}", path.display(), e));
+
+        let key = match std::fs::read(path) {
+            Ok(key) => key,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = SecretBytes::random(32)?;
+                write_private(path, key.expose()).map_err(unavailable)?;
+                key.expose().to_vec()
+            }
+            Err(e) => return Err(unavailable(e)),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            master_key: KeySource::Bytes(key).master_key(&[])?,
+        })
+    }
+
+    /// File holding the master key
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Create `path` readable by its owner only
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+#[async_trait]
+impl MasterKeyProvider for LocalFileKeyProvider {
+    async fn wrap_key(&self, key: &SecretBytes, context: &str) -> Result<Vec<u8>> {
+        self.master_key.wrap_key(key, context).await
+    }
+
+    async fn unwrap_key(&self, wrapped: &[u8], context: &str) -> Result<SecretBytes> {
+        self.master_key.unwrap_key(wrapped, context).await
+    }
+
+    async fn generate_data_key(&self) -> Result<SecretBytes> {
+        self.master_key.generate_data_key().await
+    }
+}
@@ -0,0 +1,90 @@
+use super::error::SecurityError;
+use super::Result;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Secret bytes such as a raw key, wiped from memory when dropped
+///
+/// Deliberately not `Clone`, so every secret has a single owner to wipe
+/// it; `Debug` never shows the bytes.
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    /// Generate `len` random bytes
+    pub fn random(len: usize) -> Result<Self> {
+        let mut bytes = Self(vec![0u8; len]);
+        SystemRandom::new()
+            .fill(&mut bytes.0)
+            .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    /// The secret itself
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub(crate) fn expose_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Copy of the secret as a 32-byte key, wiped when dropped
+    ///
+    /// Fails with `SecurityError::InvalidKey` if the length is not 32.
+    pub(crate) fn to_key(&self) -> Result<Zeroizing<[u8; 32]>> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        if self.0.len() != key.len() {
+            return Err(SecurityError::InvalidKey(format!(
+                "key must be 32 bytes, got {}",
+                self.0.len()
+            )));
+        }
+        key.copy_from_slice(&self.0);
+        Ok(key)
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(bytes: &[u8]) -> Self {
+        Self::new(bytes.to_vec())
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes({} bytes)", self.0.len())
+    }
+}
+
+impl Zeroize for SecretBytes {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SecretBytes {}
@@ -0,0 +1,185 @@
+use super::encryption::EncryptionEngine;
+use super::error::SecurityError;
+use super::key_manager::KeyId;
+use super::Result;
+use ring::aead::{Aad, Nonce, NONCE_LEN};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroizing;
+
+/// Plaintext bytes sealed per frame of an encrypted stream
+///
+/// Every frame but the last holds exactly this many bytes; the last holds
+/// fewer, possibly none, which marks the end of the stream.
+pub const STREAM_FRAME_LEN: usize = 64 * 1024;
+
+const STREAM_VERSION: u8 = 1;
+const TAG_LEN: usize = 16;
+const PREFIX_LEN: usize = 8;
+/// Version, key ID and nonce prefix
+const HEADER_LEN: usize = 1 + 4 + PREFIX_LEN;
+
+/// Nonce of frame `index`: the stream's random prefix then the index
+fn frame_nonce(prefix: &[u8; PREFIX_LEN], index: u32) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    nonce[PREFIX_LEN..].copy_from_slice(&index.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Associated data of frame `index`: the header, the index and whether it
+/// is the last frame, so frames cannot be moved, dropped or swapped
+/// between streams
+fn frame_aad(header: &[u8; HEADER_LEN], index: u32, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(HEADER_LEN + 5);
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+/// Read until `buf` is full or the input ends, returning the bytes read
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn stream_error(kind: fn(String) -> SecurityError) -> impl Fn(std::io::Error) -> SecurityError {
+    move |e| kind(format!("stream I/O: {}", e))
+}
+
+fn next_index(index: u32) -> Result<u32> {
+    index
+        .checked_add(1)
+        .ok_or_else(|| SecurityError::Encryption("stream has too many frames".into()))
+}
+
+impl EncryptionEngine {
+    /// Encrypt everything read from `reader` into `writer`, one frame of
+    /// `STREAM_FRAME_LEN` bytes at a time
+    ///
+    /// Only a frame is held in memory, so payloads of any size can be
+    /// encrypted. Returns the number of plaintext bytes encrypted.
+    pub async fn encrypt_stream<R, W>(&self, mut reader: R, mut writer: W) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let io_error = stream_error(SecurityError::Encryption);
+        let key_manager = self.key_manager();
+        let (key_id, key) = {
+            let key = key_manager.current_key().await?;
+            // Read under the key's lock, which rotation holds while changing both
+            (key_manager.current_key_id(), key.clone())
+        };
+
+        let prefix: [u8; PREFIX_LEN] = key_manager.generate_nonce()?;
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = STREAM_VERSION;
+        header[1..5].copy_from_slice(&key_id.to_be_bytes());
+        header[5..].copy_from_slice(&prefix);
+        writer.write_all(&header).await.map_err(&io_error)?;
+
+        let mut frame = Zeroizing::new(Vec::with_capacity(STREAM_FRAME_LEN + TAG_LEN));
+        let mut index = 0u32;
+        let mut total = 0u64;
+        loop {
+            frame.resize(STREAM_FRAME_LEN, 0);
+            let len = read_full(&mut reader, &mut frame).await.map_err(&io_error)?;
+            frame.truncate(len);
+            let last = len < STREAM_FRAME_LEN;
+
+            key.seal_in_place_append_tag(
+                frame_nonce(&prefix, index),
+                Aad::from(frame_aad(&header, index, last)),
+                &mut *frame,
+            )
+            .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+            writer.write_all(&frame).await.map_err(&io_error)?;
+            key_manager.record_encryption(len);
+            total += len as u64;
+
+            if last {
+                break;
+            }
+            index = next_index(index)?;
+        }
+
+        writer.flush().await.map_err(&io_error)?;
+        Ok(total)
+    }
+
+    /// Decrypt a stream written by `encrypt_stream` from `reader` into `writer`
+    ///
+    /// Frames are written out as soon as they are authenticated, so on error
+    /// whatever was written must be discarded: the stream was truncated,
+    /// reordered or tampered with. Returns the number of plaintext bytes.
+    pub async fn decrypt_stream<R, W>(&self, mut reader: R, mut writer: W) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let io_error = stream_error(SecurityError::Decryption);
+        let key_manager = self.key_manager();
+
+        let mut header = [0u8; HEADER_LEN];
+        if read_full(&mut reader, &mut header).await.map_err(&io_error)? < HEADER_LEN {
+            return Err(SecurityError::MalformedCiphertext("stream header truncated".into()));
+        }
+        if header[0] != STREAM_VERSION {
+            return Err(SecurityError::Decryption(format!(
+                "unsupported stream version {}",
+                header[0]
+            )));
+        }
+        let key_id = KeyId::from_be_bytes(header[1..5].try_into().expect("4-byte slice"));
+        let prefix: [u8; PREFIX_LEN] = header[5..].try_into().expect("8-byte slice");
+        let key = key_manager.key_by_id(key_id).await?;
+        let is_current = key_id == key_manager.current_key_id();
+
+        let mut frame = Zeroizing::new(Vec::with_capacity(STREAM_FRAME_LEN + TAG_LEN));
+        let mut index = 0u32;
+        let mut total = 0u64;
+        loop {
+            frame.resize(STREAM_FRAME_LEN + TAG_LEN, 0);
+            let len = read_full(&mut reader, &mut frame).await.map_err(&io_error)?;
+            if len < TAG_LEN {
+                // Every stream ends with a short frame, but even an empty
+                // one has its tag
+                return Err(SecurityError::MalformedCiphertext("stream truncated".into()));
+            }
+            frame.truncate(len);
+            let last = len < STREAM_FRAME_LEN + TAG_LEN;
+
+            let plaintext = key
+                .open_in_place(
+                    frame_nonce(&prefix, index),
+                    Aad::from(frame_aad(&header, index, last)),
+                    &mut frame,
+                )
+                .map_err(|_| SecurityError::AuthenticationFailed)?;
+            writer.write_all(plaintext).await.map_err(&io_error)?;
+            if is_current {
+                key_manager.record_decryption(plaintext.len());
+            }
+            total += plaintext.len() as u64;
+
+            if last {
+                break;
+            }
+            index = next_index(index)?;
+        }
+
+        let mut trailing = [0u8; 1];
+        if read_full(&mut reader, &mut trailing).await.map_err(&io_error)? != 0 {
+            return Err(SecurityError::MalformedCiphertext("data after the last frame".into()));
+        }
+        writer.flush().await.map_err(&io_error)?;
+        Ok(total)
+    }
+}
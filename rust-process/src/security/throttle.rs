@@ -0,0 +1,252 @@
+use super::error::SecurityError;
+use super::key_manager::KeyId;
+use super::Result;
+use crate::clock::Clock;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Most keys whose failures are tracked at once; the least recently failed
+/// is forgotten first
+const MAX_TRACKED: usize = 1024;
+
+/// Limits on failed decryptions before further attempts are refused
+///
+/// Once `max_failures` consecutive failures fall within `window`, attempts
+/// are refused for `base_lockout`, doubled with every further failure up to
+/// `max_lockout`. Setting both lockouts equal gives a fixed lockout.
+#[derive(Debug, Clone)]
+pub struct ThrottlePolicy {
+    /// Consecutive failures allowed before locking out
+    pub max_failures: u32,
+    /// Failures further apart than this start the count over
+    pub window: Duration,
+    /// Lockout after `max_failures` failures
+    pub base_lockout: Duration,
+    /// Longest lockout, however many failures follow
+    pub max_lockout: Duration,
+}
+
+impl Default for ThrottlePolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            window: Duration::from_secs(15 * 60),
+            base_lockout: Duration::from_secs(1),
+            max_lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// Snapshot of the failed decryptions under one key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ThrottleStatus {
+    /// Key the ciphertexts named; `None` for data sealed without a key ID
+    pub key_id: Option<KeyId>,
+    /// Consecutive failures within the window
+    pub failures: u32,
+    /// Time left before attempts are accepted again, if locked out
+    pub retry_after: Option<Duration>,
+}
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    /// Whether the failures still count or the lockout still holds at `now`
+    fn is_live(&self, now: Instant, window: Duration) -> bool {
+        now - self.last_failure <= window || self.locked_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Failed decryptions by the key the ciphertext names, shared by clones of
+/// an engine
+///
+/// Key IDs the key manager does not hold fail before authentication and are
+/// never counted, so callers cannot spread guesses over made-up keys.
+pub(crate) struct Throttle {
+    policy: ThrottlePolicy,
+    clock: Arc<dyn Clock>,
+    attempts: Mutex<BTreeMap<Option<KeyId>, Attempts>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(policy: ThrottlePolicy, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            policy,
+            clock,
+            attempts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Fail with `SecurityError::TooManyAttempts` if `key_id` is locked out
+    pub(crate) fn check(&self, key_id: Option<KeyId>) -> Result<()> {
+        let now = self.clock.instant();
+        let attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        match attempts.get(&key_id).and_then(|a| a.locked_until) {
+            Some(until) if until > now => Err(SecurityError::TooManyAttempts {
+                retry_after: until - now,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Forget the failures under `key_id` after a successful decryption
+    pub(crate) fn record_success(&self, key_id: Option<KeyId>) {
+        self.attempts.lock().unwrap_or_else(|e| e.into_inner()).remove(&key_id);
+    }
+
+    /// Count a failed decryption under `key_id`, locking it out once the
+    /// policy's limit is reached
+    pub(crate) fn record_failure(&self, key_id: Option<KeyId>) {
+        let now = self.clock.instant();
+        let policy = &self.policy;
+        let mut attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        // Drop keys whose failures have all expired, so the map stays small
+        attempts.retain(|_, a| a.is_live(now, policy.window));
+        if attempts.len() >= MAX_TRACKED && !attempts.contains_key(&key_id) {
+            let oldest = attempts
+                .iter()
+                .min_by_key(|(_, a)| a.last_failure)
+                .map(|(&key_id, _)| key_id);
+            if let Some(oldest) = oldest {
+                attempts.remove(&oldest);
+            }
+        }
+
+        let entry = attempts.entry(key_id).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if now - entry.last_failure > policy.window {
+            entry.failures = 0;
+        }
+        entry.failures = entry.failures.saturating_add(1);
+        entry.last_failure = now;
+        if let Some(excess) = entry.failures.checked_sub(policy.max_failures) {
+            let lockout = policy
+                .base_lockout
+                .checked_mul(2u32.saturating_pow(excess))
+                .map_or(policy.max_lockout, |lockout| lockout.min(policy.max_lockout));
+            entry.locked_until = Some(now + lockout);
+            log::warn!(
+                "{} failed decryptions under key {:?}; refusing attempts for {:?}",
+                entry.failures,
+                key_id,
+                lockout
+            );
+        }
+    }
+
+    /// Keys with failures still counted, ordered by ID
+    pub(crate) fn status(&self) -> Vec<ThrottleStatus> {
+        let now = self.clock.instant();
+        let attempts = self.attempts.lock().unwrap_or_else(|e| e.into_inner());
+        attempts
+            .iter()
+            .filter(|(_, a)| a.is_live(now, self.policy.window))
+            .map(|(&key_id, a)| ThrottleStatus {
+                key_id,
+                failures: a.failures,
+                retry_after: a.locked_until.filter(|&until| until > now).map(|until| until - now),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::security::{EncryptionEngine, KeyManager};
+
+    #[tokio::test]
+    async fn test_decrypt_throttling_with_mock_clock() -> Result<()> {
+        let clock = Arc::new(MockClock::default());
+        let key_manager = Arc::new(KeyManager::new()?.with_clock(clock.clone()));
+        let engine = EncryptionEngine::new(key_manager.clone()).with_throttle(ThrottlePolicy {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            base_lockout: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(40),
+        });
+        let encrypted = engine.encrypt_with_aad(b"test data", b"right").await?;
+        let advance = |secs| clock.advance(chrono::Duration::seconds(secs));
+
+        // Failures up to the limit are reported as they are
+        for _ in 0..3 {
+            let result = engine.decrypt_with_aad(&encrypted, b"wrong").await;
+            assert!(matches!(result, Err(SecurityError::AuthenticationFailed)));
+        }
+        let status = engine.throttle_status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].key_id, Some(key_manager.current_key_id()));
+        assert_eq!(status[0].failures, 3);
+        assert_eq!(status[0].retry_after, Some(Duration::from_secs(10)));
+
+        // Even the right AAD is refused until the lockout ends
+        let result = engine.decrypt_with_aad(&encrypted, b"right").await;
+        assert!(matches!(
+            result,
+            Err(SecurityError::TooManyAttempts { retry_after }) if retry_after == Duration::from_secs(10)
+        ));
+        // Other keys are counted separately
+        engine.rotate_key().await?;
+        let fresh = engine.encrypt_with_aad(b"fresh data", b"right").await?;
+        assert_eq!(engine.decrypt_with_aad(&fresh, b"right").await?, b"fresh data");
+
+        // Stored values failing to authenticate, and keys the manager does
+        // not hold, are never counted
+        let result = engine.decrypt_stored(&fresh, b"wrong").await;
+        assert!(matches!(result, Err(SecurityError::AuthenticationFailed)));
+        let mut unknown = fresh.clone();
+        unknown.key_id = Some(999);
+        let result = engine.decrypt_with_aad(&unknown, b"right").await;
+        assert!(matches!(result, Err(SecurityError::KeyUnavailable { key_id: 999 })));
+        assert_eq!(engine.throttle_status().len(), 1);
+
+        // Every failure after the limit doubles the lockout, up to the maximum
+        for lockout in [10, 20, 40, 40] {
+            advance(lockout);
+            let result = engine.decrypt_with_aad(&encrypted, b"wrong").await;
+            assert!(matches!(result, Err(SecurityError::AuthenticationFailed)));
+            let retry_after = engine.throttle_status()[0].retry_after;
+            assert_eq!(retry_after, Some(Duration::from_secs((lockout * 2).min(40) as u64)));
+        }
+
+        // Success once the lockout ends clears the count
+        advance(40);
+        assert_eq!(engine.decrypt_with_aad(&encrypted, b"right").await?, b"test data");
+        assert!(engine.throttle_status().is_empty());
+
+        // Failures further apart than the window never lock out
+        for _ in 0..3 {
+            let result = engine.decrypt_with_aad(&encrypted, b"wrong").await;
+            assert!(matches!(result, Err(SecurityError::AuthenticationFailed)));
+            advance(61);
+        }
+        assert_eq!(engine.decrypt_with_aad(&encrypted, b"right").await?, b"test data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tracked_keys_are_capped() {
+        let clock = Arc::new(MockClock::default());
+        let throttle = Throttle::new(ThrottlePolicy::default(), clock.clone());
+        for key_id in 0..=MAX_TRACKED as KeyId {
+            throttle.record_failure(Some(key_id));
+            clock.advance(chrono::Duration::milliseconds(1));
+        }
+
+        // The least recently failed key is forgotten first
+        let status = throttle.status();
+        assert_eq!(status.len(), MAX_TRACKED);
+        assert_eq!(status[0].key_id, Some(1));
+    }
+}
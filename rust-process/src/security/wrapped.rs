@@ -0,0 +1,110 @@
+use super::error::SecurityError;
+use super::key_manager::{KeyId, KeyManager};
+use super::secret::SecretBytes;
+use super::Result;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// Keys of a `KeyManager` encrypted under a key-encryption key, for moving
+/// them to another process or machine
+///
+/// Safe to store next to the vault: without the key-encryption key the
+/// set reveals only the key IDs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedKeySet {
+    /// ID of the key new data is encrypted with
+    pub current_id: KeyId,
+    /// Every key, current and old, by ID
+    pub keys: Vec<WrappedKey>,
+}
+
+/// A key encrypted with ChaCha20-Poly1305: nonce followed by ciphertext
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub id: KeyId,
+    pub wrapped: Vec<u8>,
+}
+
+/// Associated data of a wrapped key: the set's current key ID and its own,
+/// so keys cannot be relabelled or moved between sets with other IDs
+fn wrapping_aad(current_id: KeyId, id: KeyId) -> [u8; 8] {
+    let mut aad = [0u8; 8];
+    aad[..4].copy_from_slice(&current_id.to_be_bytes());
+    aad[4..].copy_from_slice(&id.to_be_bytes());
+    aad
+}
+
+fn kek_key(kek: &[u8; 32]) -> Result<LessSafeKey> {
+    let key = UnboundKey::new(&CHACHA20_POLY1305, kek)
+        .map_err(|e| SecurityError::InvalidKey(e.to_string()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+impl KeyManager {
+    /// Encrypt the current and old keys under `kek`
+    ///
+    /// `import_wrapped` with the same `kek` rebuilds a key manager that
+    /// decrypts everything this one does.
+    pub fn export_wrapped(&self, kek: &[u8; 32]) -> Result<WrappedKeySet> {
+        let kek = kek_key(kek)?;
+        let material = self.key_material();
+        let keys = std::iter::once((material.current_id, &material.current))
+            .chain(material.old.iter().map(|(id, key)| (*id, key)))
+            .map(|(id, key)| {
+                let nonce: [u8; NONCE_LEN] = self.generate_nonce()?;
+                let mut in_out = Zeroizing::new(key.to_vec());
+                kek.seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::from(wrapping_aad(material.current_id, id)),
+                    &mut *in_out,
+                )
+                .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+                let mut wrapped = nonce.to_vec();
+                wrapped.extend_from_slice(&in_out);
+                Ok(WrappedKey { id, wrapped })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(WrappedKeySet {
+            current_id: material.current_id,
+            keys,
+        })
+    }
+
+    /// Rebuild a key manager from keys exported by `export_wrapped`
+    ///
+    /// Fails with `SecurityError::InvalidKey` if `kek` is not the key the
+    /// set was exported with, or the set was tampered with.
+    pub fn import_wrapped(kek: &[u8; 32], set: WrappedKeySet) -> Result<Self> {
+        let invalid = |message: &str| SecurityError::InvalidKey(message.to_string());
+        let kek = kek_key(kek)?;
+
+        let mut current = None;
+        let mut old = Vec::with_capacity(set.keys.len().saturating_sub(1));
+        for WrappedKey { id, wrapped } in set.keys {
+            if wrapped.len() < NONCE_LEN {
+                return Err(invalid("wrapped key too short"));
+            }
+            let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+            let nonce = Nonce::try_assume_unique_for_key(nonce)
+                .map_err(|_| invalid("wrapped key too short"))?;
+            let mut in_out = Zeroizing::new(ciphertext.to_vec());
+            let key = kek
+                .open_in_place(nonce, Aad::from(wrapping_aad(set.current_id, id)), &mut in_out)
+                .map_err(|_| invalid("key-encryption key does not match this key set"))?;
+            let key = SecretBytes::from(&*key);
+
+            if id == set.current_id {
+                if current.replace((id, key)).is_some() {
+                    return Err(invalid("key set holds the current key twice"));
+                }
+            } else {
+                old.push((id, key));
+            }
+        }
+
+        let current = current.ok_or_else(|| invalid("key set has no current key"))?;
+        Self::from_keys(current, old)
+    }
+}
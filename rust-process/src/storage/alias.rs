@@ -0,0 +1,225 @@
+use super::audit::{AuditBatch, AuditContext, AuditOperation};
+use super::error::{CodecError, StorageError};
+use super::vault::TemplateVault;
+use super::Result;
+use ring::hmac;
+use sled::transaction::{ConflictableTransactionError, TransactionResult, Transactional};
+use sled::IVec;
+use uuid::Uuid;
+
+/// Separator between namespace and external id in alias plaintexts
+const SEPARATOR: u8 = 0;
+
+/// An alias as indexed: `aliases` maps its tag to the template ID and
+/// `alias_refs` maps template ID + tag to the sealed alias, so neither the
+/// namespace nor the external id is stored in plaintext
+pub(super) struct AliasEntry {
+    pub(super) tag: Vec<u8>,
+    pub(super) sealed: Vec<u8>,
+}
+
+/// Reject pairs that cannot be encoded unambiguously
+fn check_alias(namespace: &str, external_id: &str) -> Result<()> {
+    if namespace.is_empty() || external_id.is_empty() {
+        return Err(StorageError::InvalidAlias(
+            "namespace and external id must not be empty".into(),
+        ));
+    }
+    if namespace.as_bytes().contains(&SEPARATOR) {
+        return Err(StorageError::InvalidAlias(
+            "namespace must not contain NUL bytes".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Encode a (namespace, external id) pair
+fn alias_plaintext(namespace: &str, external_id: &str) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(namespace.len() + external_id.len() + 1);
+    plaintext.extend_from_slice(namespace.as_bytes());
+    plaintext.push(SEPARATOR);
+    plaintext.extend_from_slice(external_id.as_bytes());
+    plaintext
+}
+
+/// Split an encoded alias back into its namespace and external id
+fn split_alias(plaintext: &[u8]) -> Option<(String, String)> {
+    let pos = plaintext.iter().position(|b| *b == SEPARATOR)?;
+    let namespace = String::from_utf8(plaintext[..pos].to_vec()).ok()?;
+    let external_id = String::from_utf8(plaintext[pos + 1..].to_vec()).ok()?;
+    Some((namespace, external_id))
+}
+
+/// Key in the reverse index: template id followed by the alias tag
+pub(super) fn ref_key(id: Uuid, tag: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(16 + tag.len());
+    key.extend_from_slice(id.as_bytes());
+    key.extend_from_slice(tag);
+    key
+}
+
+impl TemplateVault {
+    /// Bind an external identifier in `namespace` to a stored template
+    ///
+    /// Binding an alias that already points at the same template is a no-op.
+    pub async fn add_alias(&self, id: Uuid, namespace: &str, external_id: &str) -> Result<()> {
+        self.ensure_writable()?;
+        let entry = self.alias_entry(namespace, external_id).await?;
+        let db = self.db.write().await;
+        let mut head = self.audit.lock_head();
+        let batch = head.chain(
+            [(AuditOperation::AddAlias, Some(id), None)],
+            &AuditContext::default(),
+            self.clock.now_utc(),
+        )?;
+
+        let result: TransactionResult<(), StorageError> = (&**db, &self.aliases, &self.alias_refs, self.audit.tree())
+            .transaction(|(templates, aliases, alias_refs, audit)| {
+                if templates.get(id.as_bytes())?.is_none() {
+                    return Err(ConflictableTransactionError::Abort(StorageError::NotFound(id)));
+                }
+                if let Some(current) = aliases.get(&entry.tag)? {
+                    let existing = Uuid::from_slice(&current).unwrap_or_default();
+                    if existing != id {
+                        return Err(ConflictableTransactionError::Abort(
+                            StorageError::AliasConflict {
+                                namespace: namespace.to_string(),
+                                external_id: external_id.to_string(),
+                                existing,
+                            },
+                        ));
+                    }
+                }
+                aliases.insert(entry.tag.as_slice(), &id.as_bytes()[..])?;
+                alias_refs.insert(ref_key(id, &entry.tag), entry.sealed.as_slice())?;
+                batch.write(audit)?;
+                Ok(())
+            });
+        result?;
+        head.advance(&batch);
+        Ok(())
+    }
+
+    /// Look up the template bound to an external identifier
+    ///
+    /// Aliases of soft-deleted or expired templates resolve to nothing, but
+    /// are kept for when the template is restored.
+    pub async fn resolve_alias(&self, namespace: &str, external_id: &str) -> Result<Option<Uuid>> {
+        let tag = self.alias_tag(namespace, external_id)?;
+        let db = self.db.read().await;
+        let Some(id) = self.aliases.get(tag)?.and_then(|value| Uuid::from_slice(&value).ok()) else {
+            return Ok(None);
+        };
+        if !self.is_live(&db, id)? {
+            return Ok(None);
+        }
+        self.record_audit(AuditOperation::Find, Some(id), &AuditContext::default())?;
+        Ok(Some(id))
+    }
+
+    /// Remove an alias binding, returning whether one existed
+    pub async fn remove_alias(&self, namespace: &str, external_id: &str) -> Result<bool> {
+        self.ensure_writable()?;
+        let tag = self.alias_tag(namespace, external_id)?;
+        let _db = self.db.write().await;
+        let mut head = self.audit.lock_head();
+        let now = self.clock.now_utc();
+
+        let result: TransactionResult<Option<AuditBatch>, StorageError> =
+            (&self.aliases, &self.alias_refs, self.audit.tree()).transaction(|(aliases, alias_refs, audit)| {
+                let Some(value) = aliases.remove(tag.as_slice())? else {
+                    return Ok(None);
+                };
+                let id = Uuid::from_slice(&value).ok();
+                if let Some(id) = id {
+                    alias_refs.remove(ref_key(id, &tag))?;
+                }
+                let batch = head
+                    .chain([(AuditOperation::RemoveAlias, id, None)], &AuditContext::default(), now)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                batch.write(audit)?;
+                Ok(Some(batch))
+            });
+        let Some(batch) = result? else {
+            return Ok(false);
+        };
+        head.advance(&batch);
+        Ok(true)
+    }
+
+    /// List all (namespace, external id) pairs bound to a template
+    pub async fn aliases_of(&self, id: Uuid) -> Result<Vec<(String, String)>> {
+        let refs = {
+            let _db = self.db.read().await;
+            self.alias_refs
+                .scan_prefix(id.as_bytes())
+                .values()
+                .collect::<std::result::Result<Vec<IVec>, _>>()?
+        };
+        let mut aliases = Vec::with_capacity(refs.len());
+        for sealed in refs {
+            let plaintext = self.open(&sealed).await?;
+            aliases.push(split_alias(&plaintext).ok_or(StorageError::corrupt(CodecError::InvalidAlias))?);
+        }
+        Ok(aliases)
+    }
+
+    /// Keyed hash identifying an alias in the index
+    pub(super) fn alias_tag(&self, namespace: &str, external_id: &str) -> Result<Vec<u8>> {
+        check_alias(namespace, external_id)?;
+        let mut ctx = hmac::Context::with_key(&self.hash_key());
+        ctx.update(b"alias\0");
+        ctx.update(&alias_plaintext(namespace, external_id));
+        Ok(ctx.sign().as_ref().to_vec())
+    }
+
+    /// Prepare the index records of an alias outside of a transaction
+    pub(super) async fn alias_entry(&self, namespace: &str, external_id: &str) -> Result<AliasEntry> {
+        Ok(AliasEntry {
+            tag: self.alias_tag(namespace, external_id)?,
+            sealed: self.seal(&alias_plaintext(namespace, external_id)).await?,
+        })
+    }
+
+    /// Re-index aliases written before they were keyed by tag, whose keys
+    /// held the namespace and external id in plaintext
+    pub(super) async fn sync_alias_index(&self) -> Result<()> {
+        let legacy = {
+            let _db = self.db.read().await;
+            let mut legacy = Vec::new();
+            for item in self.alias_refs.iter() {
+                let (key, value) = item?;
+                if value.is_empty() && key.len() > 16 {
+                    legacy.push(key);
+                }
+            }
+            legacy
+        };
+        if legacy.is_empty() {
+            return Ok(());
+        }
+
+        let mut aliases = sled::Batch::default();
+        let mut refs = sled::Batch::default();
+        for key in legacy {
+            let (id, plaintext) = key.split_at(16);
+            let (Ok(id), Some((namespace, external_id))) = (Uuid::from_slice(id), split_alias(plaintext)) else {
+                continue;
+            };
+            let entry = self.alias_entry(&namespace, &external_id).await?;
+            aliases.remove(plaintext);
+            aliases.insert(entry.tag.as_slice(), id.as_bytes());
+            refs.remove(&*key);
+            refs.insert(ref_key(id, &entry.tag), entry.sealed);
+        }
+
+        let _db = self.db.write().await;
+        let result: TransactionResult<(), StorageError> =
+            (&self.aliases, &self.alias_refs).transaction(|(alias_tree, ref_tree)| {
+                alias_tree.apply_batch(&aliases)?;
+                ref_tree.apply_batch(&refs)?;
+                Ok(())
+            });
+        Ok(result?)
+    }
+}
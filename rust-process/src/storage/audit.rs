@@ -0,0 +1,307 @@
+use super::error::StorageError;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::Provenance;
+use chrono::{DateTime, Utc};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use std::ops::RangeBounds;
+use std::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// `prev_hash` of the first entry in the chain
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Operation recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    Store,
+    Read,
+    Update,
+    Delete,
+    SoftDelete,
+    Restore,
+    Rotate,
+    Wipe,
+    /// A finder or alias lookup returned the template
+    Find,
+    /// `identify` ranked the template among its best matches
+    Identify,
+    Export,
+    Import,
+    AddAlias,
+    RemoveAlias,
+    SetExpiry,
+}
+
+/// Caller information attached to audited operations
+#[derive(Debug, Clone, Default)]
+pub struct AuditContext {
+    /// Who performed the operation, e.g. an authenticated user name
+    pub actor: Option<String>,
+}
+
+impl AuditContext {
+    /// Context attributing operations to `actor`
+    pub fn actor(actor: impl Into<String>) -> Self {
+        Self {
+            actor: Some(actor.into()),
+        }
+    }
+}
+
+/// One hash-chained audit log entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Position in the log, starting at 0
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub operation: AuditOperation,
+    pub template_id: Option<Uuid>,
+    pub actor: Option<String>,
+    /// Provenance of the template written by store and update operations,
+    /// if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    /// Hash of the previous entry
+    pub prev_hash: String,
+    /// SHA-256 over `prev_hash` and the fields above; entries without
+    /// provenance hash as they did before it was recorded
+    pub hash: String,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> String {
+        let mut body = serde_json::json!([
+            self.seq,
+            self.timestamp,
+            self.operation,
+            self.template_id,
+            self.actor,
+            self.prev_hash,
+        ]);
+        if let (Some(provenance), Some(fields)) = (&self.provenance, body.as_array_mut()) {
+            fields.push(serde_json::json!(provenance));
+        }
+        digest(&SHA256, body.to_string().as_bytes())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Audit entries chained onto the head of the log, to be written by the
+/// transaction that commits the operations they record
+pub(super) struct AuditBatch {
+    /// Entries with their serialized form
+    entries: Vec<(AuditEntry, Vec<u8>)>,
+}
+
+impl AuditBatch {
+    /// Insert the entries inside a transaction over the audit tree
+    pub(super) fn write(&self, tree: &TransactionalTree) -> std::result::Result<(), UnabortableTransactionError> {
+        for (entry, value) in &self.entries {
+            tree.insert(&entry.seq.to_be_bytes(), value.as_slice())?;
+        }
+        Ok(())
+    }
+}
+
+/// Exclusive hold on the head of the chain while a transaction writes
+/// entries onto it
+pub(super) struct AuditHead<'a> {
+    head: MutexGuard<'a, (u64, String)>,
+}
+
+impl AuditHead<'_> {
+    /// Chain one entry per `(operation, template, provenance)` record
+    ///
+    /// Nothing is written; the same batch results however often a retried
+    /// transaction calls this.
+    pub(super) fn chain(
+        &self,
+        records: impl IntoIterator<Item = (AuditOperation, Option<Uuid>, Option<Provenance>)>,
+        context: &AuditContext,
+        timestamp: DateTime<Utc>,
+    ) -> Result<AuditBatch> {
+        let (mut seq, mut prev_hash) = (self.head.0, self.head.1.clone());
+        let mut entries = Vec::new();
+        for (operation, template_id, provenance) in records {
+            let mut entry = AuditEntry {
+                seq,
+                timestamp,
+                operation,
+                template_id,
+                actor: context.actor.clone(),
+                provenance,
+                prev_hash,
+                hash: String::new(),
+            };
+            entry.hash = entry.compute_hash();
+            let value = serde_json::to_vec(&entry).map_err(StorageError::encode)?;
+            (seq, prev_hash) = (seq + 1, entry.hash.clone());
+            entries.push((entry, value));
+        }
+        Ok(AuditBatch { entries })
+    }
+
+    /// Move the head past a batch whose transaction committed
+    pub(super) fn advance(&mut self, batch: &AuditBatch) {
+        if let Some((last, _)) = batch.entries.last() {
+            *self.head = (last.seq + 1, last.hash.clone());
+        }
+    }
+}
+
+/// Append-only, hash-chained record of vault operations
+///
+/// Each entry embeds the hash of its predecessor, so editing or removing
+/// an entry breaks every later link and is caught by `verify_chain`.
+pub struct AuditLog {
+    tree: sled::Tree,
+    /// Sequence number and hash for the next entry to chain onto
+    head: Mutex<(u64, String)>,
+}
+
+impl AuditLog {
+    /// Open the audit log stored in `tree`, continuing its chain
+    pub fn open(tree: sled::Tree) -> Result<Self> {
+        let head = match tree.last()? {
+            Some((_, value)) => {
+                let last: AuditEntry = serde_json::from_slice(&value).map_err(StorageError::corrupt)?;
+                (last.seq + 1, last.hash)
+            }
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        Ok(Self {
+            tree,
+            head: Mutex::new(head),
+        })
+    }
+
+    /// Hold the head of the chain so a transaction can append entries
+    ///
+    /// Other appends wait until the hold is dropped.
+    pub(super) fn lock_head(&self) -> AuditHead<'_> {
+        AuditHead {
+            head: self.head.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+
+    /// The tree entries are stored in, for transactions writing a batch
+    pub(super) fn tree(&self) -> &sled::Tree {
+        &self.tree
+    }
+
+    /// Append an entry
+    pub fn record(
+        &self,
+        operation: AuditOperation,
+        template_id: Option<Uuid>,
+        context: &AuditContext,
+        timestamp: DateTime<Utc>,
+    ) -> Result<AuditEntry> {
+        self.record_with_provenance(operation, template_id, context, timestamp, None)
+    }
+
+    /// Append an entry recording the provenance of the template concerned
+    pub fn record_with_provenance(
+        &self,
+        operation: AuditOperation,
+        template_id: Option<Uuid>,
+        context: &AuditContext,
+        timestamp: DateTime<Utc>,
+        provenance: Option<Provenance>,
+    ) -> Result<AuditEntry> {
+        let mut head = self.lock_head();
+        let batch = head.chain([(operation, template_id, provenance)], context, timestamp)?;
+        let (entry, value) = &batch.entries[0];
+        self.tree.insert(entry.seq.to_be_bytes(), value.as_slice())?;
+        head.advance(&batch);
+        Ok(entry.clone())
+    }
+
+    /// Check every entry's hash and link to its predecessor
+    ///
+    /// Fails with `StorageError::AuditChainBroken` naming the first entry
+    /// that was altered, removed or cannot be read.
+    pub fn verify_chain(&self) -> Result<()> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        for (seq, item) in self.tree.iter().enumerate() {
+            let seq = seq as u64;
+            let (key, value) = item?;
+            let entry: AuditEntry = serde_json::from_slice(&value)
+                .map_err(|_| StorageError::AuditChainBroken { seq })?;
+            if key.as_ref() != seq.to_be_bytes()
+                || entry.seq != seq
+                || entry.prev_hash != prev_hash
+                || entry.hash != entry.compute_hash()
+            {
+                return Err(StorageError::AuditChainBroken { seq });
+            }
+            prev_hash = entry.hash;
+        }
+        Ok(())
+    }
+
+    /// Entries whose sequence numbers fall in `range`
+    pub fn export(&self, range: impl RangeBounds<u64>) -> Result<Vec<AuditEntry>> {
+        let start = range.start_bound().map(|seq| seq.to_be_bytes());
+        let end = range.end_bound().map(|seq| seq.to_be_bytes());
+        let mut entries = Vec::new();
+        for item in self.tree.range::<[u8; 8], _>((start, end)) {
+            let (_, value) = item?;
+            entries.push(serde_json::from_slice(&value).map_err(StorageError::corrupt)?);
+        }
+        Ok(entries)
+    }
+}
+
+impl TemplateVault {
+    /// The vault's audit log
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// Audit entries whose sequence numbers fall in `range`
+    pub async fn export_audit(&self, range: impl RangeBounds<u64>) -> Result<Vec<AuditEntry>> {
+        self.audit.export(range)
+    }
+
+    /// Append an audit entry stamped with the vault clock
+    pub(super) fn record_audit(
+        &self,
+        operation: AuditOperation,
+        template_id: Option<Uuid>,
+        context: &AuditContext,
+    ) -> Result<()> {
+        self.audit
+            .record(operation, template_id, context, self.clock.now_utc())
+            .map(|_| ())
+    }
+
+    /// Append one audit entry per template in `ids`, atomically
+    pub(super) fn record_audit_all(
+        &self,
+        operation: AuditOperation,
+        ids: &[Uuid],
+        context: &AuditContext,
+    ) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let mut head = self.audit.lock_head();
+        let records = ids.iter().map(|id| (operation, Some(*id), None));
+        let batch = head.chain(records, context, self.clock.now_utc())?;
+        let mut writes = sled::Batch::default();
+        for (entry, value) in &batch.entries {
+            writes.insert(&entry.seq.to_be_bytes(), value.as_slice());
+        }
+        self.audit.tree.apply_batch(writes)?;
+        head.advance(&batch);
+        Ok(())
+    }
+}
@@ -0,0 +1,325 @@
+use super::alias::ref_key;
+use super::audit::{AuditBatch, AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::events::VaultEvent;
+use super::retention::{decode_expiry, encode_expiry};
+use super::revision::{revision_of, FIRST_REVISION};
+use super::vault::TemplateVault;
+use super::Result;
+use crate::security::SecurityError;
+use crate::templates::Template;
+use chrono::{DateTime, Utc};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionResult, Transactional};
+use std::num::NonZeroU32;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Leading bytes identifying a vault backup archive
+const MAGIC: &[u8; 4] = b"SBVX";
+/// Archive layout version
+const FORMAT_VERSION: u8 = 1;
+/// PBKDF2 iterations used for new archives
+const KDF_ITERATIONS: u32 = 100_000;
+/// Iteration counts accepted on import; outside this range an archive
+/// would either be trivially brute-forced or stall the import
+const KDF_ITERATION_RANGE: std::ops::RangeInclusive<u32> = KDF_ITERATIONS / 10..=KDF_ITERATIONS * 10;
+/// Largest archive `import` reads unless configured otherwise
+pub const DEFAULT_MAX_ARCHIVE_SIZE: u64 = 1 << 30;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// magic + version + iterations + salt + nonce
+const HEADER_LEN: usize = 4 + 1 + 4 + SALT_LEN + NONCE_LEN;
+
+/// How `TemplateVault::import` treats templates that are already stored
+#[derive(Debug, Clone, Copy)]
+pub struct ImportOptions {
+    /// Replace existing templates instead of skipping them, along with
+    /// their aliases
+    pub overwrite: bool,
+    /// Largest archive read, in bytes; larger archives are rejected
+    pub max_archive_size: u64,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            overwrite: false,
+            max_archive_size: DEFAULT_MAX_ARCHIVE_SIZE,
+        }
+    }
+}
+
+/// A single template as written to a backup archive
+#[derive(Serialize, Deserialize)]
+struct ArchiveEntry {
+    id: Uuid,
+    template: Template,
+    expires_at: Option<DateTime<Utc>>,
+    aliases: Vec<(String, String)>,
+    /// Missing from archives written before subjects were exported
+    #[serde(default)]
+    subject: Option<String>,
+}
+
+/// Derive the archive key from a passphrase
+fn derive_key(passphrase: &str, salt: &[u8], iterations: NonZeroU32) -> Result<LessSafeKey> {
+    let mut key_bytes = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key_bytes,
+    );
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key_bytes)
+        .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+impl TemplateVault {
+    /// Write every stored template to `dest` as a passphrase-encrypted archive
+    ///
+    /// The archive is independent of the vault's own key, so it can be
+    /// restored into any vault with `import`.
+    pub async fn export<W: AsyncWrite + Unpin>(&self, mut dest: W, passphrase: &str) -> Result<usize> {
+        let raw = {
+            let db = self.db.read().await;
+            let mut raw = Vec::new();
+            for item in db.iter() {
+                let (key, value) = item?;
+                if let Ok(id) = Uuid::from_slice(&key) {
+                    let expires_at = self.expiry.get(&key)?.and_then(|v| decode_expiry(&v));
+                    raw.push((id, value, expires_at));
+                }
+            }
+            raw
+        };
+
+        let mut entries = Vec::with_capacity(raw.len());
+        for (id, value, expires_at) in raw {
+            entries.push(ArchiveEntry {
+                id,
+                template: self.decode_template(id, &value).await?,
+                expires_at,
+                aliases: self.aliases_of(id).await?,
+                subject: self.subject_of(id).await?,
+            });
+        }
+
+        let mut payload = Zeroizing::new(serde_json::to_vec(&entries)
+            .map_err(StorageError::encode)?);
+        let ids: Vec<Uuid> = entries.iter().map(|entry| entry.id).collect();
+        self.record_audit_all(AuditOperation::Export, &ids, &AuditContext::default())?;
+
+        let rng = SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut salt)
+            .and_then(|_| rng.fill(&mut nonce))
+            .map_err(|e| SecurityError::KeyGeneration(e.to_string()))?;
+
+        let iterations = NonZeroU32::new(KDF_ITERATIONS).expect("iterations are non-zero");
+        derive_key(passphrase, &salt, iterations)?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(MAGIC), &mut *payload)
+            .map_err(|e| SecurityError::Encryption(e.to_string()))?;
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(FORMAT_VERSION);
+        header.extend_from_slice(&KDF_ITERATIONS.to_be_bytes());
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&nonce);
+
+        dest.write_all(&header).await?;
+        dest.write_all(&payload).await?;
+        dest.flush().await?;
+        Ok(entries.len())
+    }
+
+    /// Restore templates from an archive written by `export`
+    ///
+    /// The whole archive is decrypted and every template checked against
+    /// the vault's size limit and validation policy before anything is
+    /// written, and all entries are committed in one transaction, so a wrong
+    /// passphrase, an invalid template or a conflicting alias leaves the
+    /// vault untouched. Archives larger than `options.max_archive_size` or
+    /// naming an implausible KDF iteration count are rejected. Returns the
+    /// number of templates written.
+    pub async fn import<R: AsyncRead + Unpin>(
+        &self,
+        mut src: R,
+        passphrase: &str,
+        options: ImportOptions,
+    ) -> Result<usize> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation_guard().await?;
+        let mut archive = Zeroizing::new(Vec::new());
+        let max = options.max_archive_size;
+        (&mut src).take(max.saturating_add(1)).read_to_end(&mut archive).await?;
+        if archive.len() as u64 > max {
+            return Err(StorageError::InvalidArchive(format!("archive exceeds {} bytes", max)));
+        }
+
+        if archive.len() < HEADER_LEN || &archive[..4] != MAGIC {
+            return Err(StorageError::InvalidArchive("not a vault backup".into()));
+        }
+        if archive[4] != FORMAT_VERSION {
+            return Err(StorageError::InvalidArchive(format!(
+                "unsupported format version {}",
+                archive[4]
+            )));
+        }
+        let iterations = u32::from_be_bytes(archive[5..9].try_into().expect("slice has 4 bytes"));
+        if !KDF_ITERATION_RANGE.contains(&iterations) {
+            return Err(StorageError::InvalidArchive(format!(
+                "unsupported KDF iteration count {}",
+                iterations
+            )));
+        }
+        let iterations = NonZeroU32::new(iterations).expect("iterations are non-zero");
+        let salt = &archive[9..9 + SALT_LEN];
+        let nonce: [u8; NONCE_LEN] = archive[9 + SALT_LEN..HEADER_LEN]
+            .try_into()
+            .expect("slice has nonce length");
+
+        let mut payload = Zeroizing::new(archive[HEADER_LEN..].to_vec());
+        let plaintext = derive_key(passphrase, salt, iterations)?
+            .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(MAGIC), &mut payload)
+            .map_err(|_| SecurityError::Decryption("wrong passphrase or corrupted archive".into()))?;
+        let entries: Vec<ArchiveEntry> = serde_json::from_slice(plaintext)
+            .map_err(|e| StorageError::InvalidArchive(e.to_string()))?;
+
+        // Encrypt everything with the vault key before taking the write lock
+        let mut prepared = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            self.check_template(&entry.template)?;
+            let mut aliases = Vec::with_capacity(entry.aliases.len());
+            for (namespace, external_id) in &entry.aliases {
+                aliases.push(self.alias_entry(namespace, external_id).await?);
+            }
+            prepared.push((
+                entry,
+                self.encode_template(entry.id, &entry.template).await?,
+                self.encode_metadata(&entry.template.metadata).await?,
+                self.index_entries(entry.id, &entry.template.metadata).await?,
+                self.content_hash(&entry.template),
+                self.lookup_hash(&entry.template),
+                self.subject_entry(entry.subject.as_deref()).await?,
+                aliases,
+            ));
+        }
+
+        let db = self.db.write().await;
+        // Aliases bound to the imported IDs, replaced by those of the archive
+        let mut alias_keys = Vec::with_capacity(prepared.len());
+        for (entry, ..) in &prepared {
+            let keys = self.alias_refs.scan_prefix(entry.id.as_bytes()).keys();
+            alias_keys.push(keys.collect::<std::result::Result<Vec<_>, _>>()?);
+        }
+        let mut head = self.audit.lock_head();
+        let now = self.clock.now_utc();
+        let result: TransactionResult<(Vec<VaultEvent>, AuditBatch), StorageError> = (
+            &**db,
+            &self.metadata,
+            &self.type_index,
+            &self.extra_index,
+            &self.expiry,
+            &self.tombstones,
+            &self.revisions,
+            &self.aliases,
+            &self.alias_refs,
+            &self.content_index,
+            &self.content_hashes,
+            &self.subjects,
+            &self.subject_refs,
+            self.audit.tree(),
+        )
+            .transaction(|(templates, metadata, type_index, extra_index, expiry, tombstones, revisions, aliases, alias_refs, content_index, content_hashes, subjects, subject_refs, audit)| {
+                let mut written = Vec::new();
+                let mut records = Vec::new();
+                for ((entry, value, metadata_value, indexes, hash, lookup_hash, subject, alias_entries), old_aliases) in
+                    prepared.iter().zip(&alias_keys)
+                {
+                    let id = entry.id;
+                    let exists = templates.get(id.as_bytes())?.is_some();
+                    if exists && !options.overwrite {
+                        continue;
+                    }
+                    // A soft-deleted template must not be restored over the
+                    // imported one
+                    tombstones.remove(id.as_bytes())?;
+                    for key in old_aliases {
+                        aliases.remove(&key[16..])?;
+                        alias_refs.remove(key)?;
+                    }
+                    let revision = match revisions.get(id.as_bytes())? {
+                        Some(current) => revision_of(Some(current)) + 1,
+                        None => FIRST_REVISION,
+                    };
+                    revisions.insert(id.as_bytes(), &revision.to_be_bytes())?;
+
+                    templates.insert(id.as_bytes(), value.as_slice())?;
+                    metadata.insert(id.as_bytes(), metadata_value.as_slice())?;
+                    self.write_indexes(type_index, extra_index, id, indexes)?;
+                    self.clear_content_hash(content_index, content_hashes, id)?;
+                    if let Some(hash) = hash {
+                        self.write_content_hash(content_index, content_hashes, id, hash)?;
+                    }
+                    match lookup_hash {
+                        Some(lookup_hash) => {
+                            self.write_lookup_hash(content_index, content_hashes, id, lookup_hash)?
+                        }
+                        None => self.clear_lookup_hash(content_index, content_hashes, id)?,
+                    }
+                    self.clear_subject(subjects, subject_refs, id)?;
+                    if let Some(subject) = subject {
+                        self.write_subject(subjects, subject_refs, id, subject)?;
+                    }
+                    match entry.expires_at {
+                        Some(expires_at) => expiry.insert(id.as_bytes(), &encode_expiry(expires_at))?,
+                        None => expiry.remove(id.as_bytes())?,
+                    };
+
+                    for (alias, (namespace, external_id)) in alias_entries.iter().zip(&entry.aliases) {
+                        if let Some(existing) = aliases.get(alias.tag.as_slice())? {
+                            let existing = Uuid::from_slice(&existing).unwrap_or_default();
+                            if existing != id {
+                                return Err(ConflictableTransactionError::Abort(
+                                    StorageError::AliasConflict {
+                                        namespace: namespace.clone(),
+                                        external_id: external_id.clone(),
+                                        existing,
+                                    },
+                                ));
+                            }
+                        }
+                        aliases.insert(alias.tag.as_slice(), id.as_bytes())?;
+                        alias_refs.insert(ref_key(id, &alias.tag), alias.sealed.as_slice())?;
+                    }
+                    written.push(if exists {
+                        VaultEvent::Updated(id)
+                    } else {
+                        VaultEvent::Stored(id)
+                    });
+                    records.push((AuditOperation::Import, Some(id), entry.template.metadata.provenance.clone()));
+                }
+                let batch = head
+                    .chain(records, &AuditContext::default(), now)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                batch.write(audit)?;
+                Ok((written, batch))
+            });
+        let (written, batch) = result?;
+        head.advance(&batch);
+        self.reset_entries(&db);
+        for event in &written {
+            self.emit(*event);
+        }
+        Ok(written.len())
+    }
+}
@@ -0,0 +1,100 @@
+use super::error::{CodecError, StorageError};
+use super::Result;
+use std::io::Read;
+use zeroize::Zeroizing;
+
+/// zstd level used for templates: fast, and most of the ratio of higher levels
+const ZSTD_LEVEL: i32 = 3;
+
+/// Room left for the ID and metadata of a record on top of its data
+const RECORD_OVERHEAD: usize = 1024 * 1024;
+
+/// Largest decompressed record of a vault limiting template data to
+/// `max_template_size` bytes, `None` if it sets no limit
+pub(super) fn record_limit(max_template_size: Option<usize>) -> Option<usize> {
+    max_template_size.map(|max| max.saturating_add(RECORD_OVERHEAD))
+}
+
+/// Compression applied to template plaintexts before encryption
+///
+/// The algorithm is recorded in each entry, so entries written with
+/// different settings, or before compression was enabled, all stay readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgo {
+    /// Better ratio, for large and rarely written templates
+    Zstd,
+    /// Faster, for write-heavy vaults
+    Lz4,
+}
+
+impl CompressionAlgo {
+    pub(super) fn compress(self, data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let compressed = match self {
+            CompressionAlgo::Zstd => zstd::encode_all(data, ZSTD_LEVEL)
+                .map_err(|e| StorageError::encode(CodecError::Compression(e.to_string())))?,
+            CompressionAlgo::Lz4 => lz4_flex::compress_prepend_size(data),
+        };
+        Ok(Zeroizing::new(compressed))
+    }
+
+    /// Decompress `data`, failing rather than expanding it past `limit` bytes
+    pub(super) fn decompress(self, data: &[u8], limit: Option<usize>) -> Result<Zeroizing<Vec<u8>>> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let too_large = || format!("decompresses to more than {} bytes", limit);
+        let decompressed = match self {
+            CompressionAlgo::Zstd => zstd::Decoder::new(data)
+                .and_then(|decoder| {
+                    let mut out = Vec::new();
+                    decoder.take((limit as u64).saturating_add(1)).read_to_end(&mut out)?;
+                    Ok(out)
+                })
+                .map_err(|e| e.to_string())
+                .and_then(|out| match out.len() > limit {
+                    true => Err(too_large()),
+                    false => Ok(out),
+                }),
+            CompressionAlgo::Lz4 => match lz4_flex::block::uncompressed_size(data) {
+                Ok((size, _)) if size > limit => Err(too_large()),
+                Ok(_) => lz4_flex::decompress_size_prepended(data).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+        };
+        decompressed
+            .map(Zeroizing::new)
+            .map_err(|e| StorageError::corrupt(CodecError::Decompression(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_compressed_data_is_corrupt() {
+        let data = vec![7u8; 4096];
+        for algo in [CompressionAlgo::Zstd, CompressionAlgo::Lz4] {
+            let compressed = algo.compress(&data).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(*algo.decompress(&compressed, None).unwrap(), data);
+
+            let truncated = &compressed[..compressed.len() / 2];
+            assert!(matches!(
+                algo.decompress(truncated, None),
+                Err(StorageError::Corrupt(CodecError::Decompression(_)))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_decompression_is_bounded() {
+        let data = vec![7u8; 4096];
+        for algo in [CompressionAlgo::Zstd, CompressionAlgo::Lz4] {
+            let compressed = algo.compress(&data).unwrap();
+            assert_eq!(*algo.decompress(&compressed, Some(4096)).unwrap(), data);
+            assert!(matches!(
+                algo.decompress(&compressed, Some(4095)),
+                Err(StorageError::Corrupt(CodecError::Decompression(_)))
+            ));
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use super::compression::CompressionAlgo;
+use super::error::StorageError;
+use super::metrics::VaultMetrics;
+use super::rotation::ROTATION_CHUNK;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::security::{Cipher, KeyBudget, KeySource};
+use crate::templates::{Template, ValidationPolicy};
+
+/// Largest template payload accepted unless configured otherwise
+pub const DEFAULT_MAX_TEMPLATE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Settings for opening a `TemplateVault`
+///
+/// The defaults match `TemplateVault::new`: sled's high-throughput mode,
+/// a flush every second, a 128MB cache, templates of up to 4MB and an
+/// in-memory key.
+#[derive(Debug, Clone)]
+pub struct VaultConfig {
+    pub(super) cache_capacity: u64,
+    pub(super) flush_every_ms: Option<u64>,
+    pub(super) mode: sled::Mode,
+    pub(super) max_template_size: Option<usize>,
+    pub(super) read_only: bool,
+    pub(super) key_source: Option<KeySource>,
+    pub(super) key_budget: KeyBudget,
+    pub(super) key_limit: KeyBudget,
+    pub(super) extra_index: Vec<String>,
+    pub(super) metrics: Option<VaultMetrics>,
+    pub(super) deduplicate: bool,
+    pub(super) rotation_chunk_size: usize,
+    pub(super) compression: Option<CompressionAlgo>,
+    pub(super) cipher: Cipher,
+    pub(super) validation: ValidationPolicy,
+    pub(super) lookup_key: Option<ring::hmac::Key>,
+    pub(super) upgrade_metadata: bool,
+}
+
+impl Default for VaultConfig {
+    fn default() -> Self {
+        Self {
+            cache_capacity: 1024 * 1024 * 128,
+            flush_every_ms: Some(1000),
+            mode: sled::Mode::HighThroughput,
+            max_template_size: Some(DEFAULT_MAX_TEMPLATE_SIZE),
+            read_only: false,
+            key_source: None,
+            key_budget: KeyBudget::default(),
+            key_limit: KeyBudget::default(),
+            extra_index: Vec::new(),
+            metrics: None,
+            deduplicate: false,
+            rotation_chunk_size: ROTATION_CHUNK,
+            compression: None,
+            cipher: Cipher::default(),
+            validation: ValidationPolicy::default(),
+            lookup_key: None,
+            upgrade_metadata: false,
+        }
+    }
+}
+
+impl VaultConfig {
+    /// Start from the default settings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Size of sled's page cache in bytes
+    pub fn cache_capacity(mut self, bytes: u64) -> Self {
+        self.cache_capacity = bytes;
+        self
+    }
+
+    /// Interval of sled's background flush; `None` flushes only on demand
+    pub fn flush_every_ms(mut self, interval: Option<u64>) -> Self {
+        self.flush_every_ms = interval;
+        self
+    }
+
+    /// Trade disk space against write throughput
+    pub fn mode(mut self, mode: sled::Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Reject templates whose data is larger than `bytes`
+    ///
+    /// Defaults to `DEFAULT_MAX_TEMPLATE_SIZE`; `None` lifts the limit.
+    pub fn max_template_size(mut self, bytes: impl Into<Option<usize>>) -> Self {
+        self.max_template_size = bytes.into();
+        self
+    }
+
+    /// Refuse every operation that modifies stored templates
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Protect the vault's data key with a master key
+    ///
+    /// See `TemplateVault::open_with_key`.
+    pub fn key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = Some(key_source);
+        self
+    }
+
+    /// Warn once the data key is used past `budget`
+    ///
+    /// See `KeyManager::with_budget`.
+    pub fn key_budget(mut self, budget: KeyBudget) -> Self {
+        self.key_budget = budget;
+        self
+    }
+
+    /// Rotate the data key before the first write after it is used past
+    /// `limit`
+    ///
+    /// The rotation re-encrypts every entry, as `TemplateVault::rotate_key`
+    /// does, while that write waits; the re-encryption does not count
+    /// toward the new key's usage.
+    pub fn key_limit(mut self, limit: KeyBudget) -> Self {
+        self.key_limit = limit;
+        self
+    }
+
+    /// Metadata `extra` keys searchable with `find_by_extra`
+    pub fn extra_index<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.extra_index = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Update `metrics` around vault operations
+    pub fn metrics(mut self, metrics: VaultMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Return the existing ID when storing data identical to a stored template
+    ///
+    /// See `TemplateVault::store_with_outcome`.
+    pub fn deduplicate(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+        self
+    }
+
+    /// Compress templates with `algo` before encrypting them
+    ///
+    /// Applies to templates written from now on; existing entries keep
+    /// their encoding and stay readable.
+    pub fn compression(mut self, algo: CompressionAlgo) -> Self {
+        self.compression = Some(algo);
+        self
+    }
+
+    /// Encrypt values with `cipher`, ChaCha20-Poly1305 by default
+    ///
+    /// Applies to values written from now on; existing entries record
+    /// their cipher and stay readable.
+    pub fn cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Check stored templates against `policy`, which by default only
+    /// requires data and a quality score within 0.0..=1.0
+    pub fn validation(mut self, policy: ValidationPolicy) -> Self {
+        self.validation = policy;
+        self
+    }
+
+    /// Index templates by `Template::secure_hash(key)` for
+    /// `TemplateVault::find_by_hash`
+    ///
+    /// The index is rebuilt on open whenever the key differs from the one it
+    /// was built with, or templates were stored while no key was set.
+    pub fn lookup_key(mut self, key: &[u8]) -> Self {
+        self.lookup_key = Some(ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key));
+        self
+    }
+
+    /// Write back templates `TemplateVault::get` read with metadata of an
+    /// older schema, in the latest one
+    ///
+    /// Reads always migrate such metadata; without this, they do so every
+    /// time and never write. Ignored by read-only vaults.
+    pub fn upgrade_metadata(mut self, upgrade: bool) -> Self {
+        self.upgrade_metadata = upgrade;
+        self
+    }
+
+    /// Entries key rotation re-encrypts per batch, 256 by default
+    ///
+    /// Bounds the memory a rotation uses; smaller chunks also let reads in
+    /// more often. A size of 0 is treated as 1.
+    pub fn rotation_chunk_size(mut self, entries: usize) -> Self {
+        self.rotation_chunk_size = entries.max(1);
+        self
+    }
+
+    /// sled configuration for a vault at `path`
+    pub(super) fn sled_config(&self, path: &std::path::Path) -> sled::Config {
+        sled::Config::new()
+            .mode(self.mode)
+            .flush_every_ms(self.flush_every_ms)
+            .cache_capacity(self.cache_capacity)
+            .path(path)
+    }
+}
+
+impl TemplateVault {
+    /// Fail with `StorageError::ReadOnly` if the vault was opened read-only
+    pub(super) fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(StorageError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Largest template data the vault accepts, `None` if unlimited
+    pub fn max_template_size(&self) -> Option<usize> {
+        self.max_template_size
+    }
+
+    /// Reject a template before it is written
+    ///
+    /// Fails with `StorageError::TemplateTooLarge` if its data exceeds the
+    /// size limit, or `StorageError::InvalidTemplate` if `Template::validate`
+    /// rejects it under the vault's validation policy.
+    pub(super) fn check_template(&self, template: &Template) -> Result<()> {
+        let size = template.data.len();
+        if let Some(max) = self.max_template_size {
+            if size > max {
+                return Err(StorageError::TemplateTooLarge { size, max });
+            }
+        }
+        template.validate(&self.validation)?;
+        Ok(())
+    }
+}
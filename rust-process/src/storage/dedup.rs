@@ -0,0 +1,79 @@
+use super::audit::AuditContext;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::Template;
+use ring::hmac;
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use uuid::Uuid;
+
+/// Domain separation for content hashes, so they never equal the subject
+/// hashes taken with the same key
+const CONTENT_HASH_CONTEXT: &[u8] = b"content\0";
+
+/// Result of storing a template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreOutcome {
+    /// ID under which the template is stored
+    pub id: Uuid,
+    /// Whether identical data was already stored under `id`
+    pub deduplicated: bool,
+}
+
+impl TemplateVault {
+    /// Store a template, reporting whether it was deduplicated
+    ///
+    /// With `VaultConfig::deduplicate` enabled, storing data identical to a
+    /// stored template of the same type returns that template's ID, writing
+    /// only an audit entry for it. Soft-deleted and expired templates, and templates
+    /// stored while deduplication was off, are not matched.
+    pub async fn store_with_outcome(&self, template: Template) -> Result<StoreOutcome> {
+        self.store_entry(template, None, None, &AuditContext::default()).await
+    }
+
+    /// Keyed hash of the type and data of a template, if deduplication is
+    /// enabled
+    ///
+    /// The HMAC key never leaves the vault, so equal hashes reveal nothing
+    /// about the data to anyone reading the index. The same bytes stored as
+    /// another template type hash differently.
+    pub(super) fn content_hash(&self, template: &Template) -> Option<Vec<u8>> {
+        self.deduplicate.then(|| {
+            let mut ctx = hmac::Context::with_key(&self.hash_key());
+            ctx.update(CONTENT_HASH_CONTEXT);
+            ctx.update(template.metadata.template_type.as_str().as_bytes());
+            ctx.update(b"\0");
+            ctx.update(&template.data);
+            ctx.sign().as_ref().to_vec()
+        })
+    }
+
+    /// Drop the content hash of a template whose data is replaced or removed
+    pub(super) fn clear_content_hash(
+        &self,
+        content_index: &TransactionalTree,
+        content_hashes: &TransactionalTree,
+        id: Uuid,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        if let Some(hash) = content_hashes.remove(id.as_bytes())? {
+            if content_index.get(&hash)?.as_deref() == Some(id.as_bytes()) {
+                content_index.remove(hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index the content hash of a template, unless another one has it
+    pub(super) fn write_content_hash(
+        &self,
+        content_index: &TransactionalTree,
+        content_hashes: &TransactionalTree,
+        id: Uuid,
+        hash: &[u8],
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        if content_index.get(hash)?.is_none() {
+            content_index.insert(hash, id.as_bytes())?;
+            content_hashes.insert(id.as_bytes(), hash)?;
+        }
+        Ok(())
+    }
+}
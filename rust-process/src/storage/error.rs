@@ -1,21 +1,214 @@
 use crate::security::SecurityError;
+use crate::templates::TemplateError;
+use chrono::{DateTime, Utc};
+use sled::transaction::TransactionError;
+use std::path::PathBuf;
 use thiserror::Error;
 use uuid::Uuid;
 
+/// Failure of one of the codecs used for stored values
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Bincode(#[from] bincode::Error),
+
+    #[error(transparent)]
+    Metadata(#[from] TemplateError),
+
+    #[error("unknown format byte {0:#04x}")]
+    UnknownFormat(u8),
+
+    #[error("empty value")]
+    Empty,
+
+    #[error("compression failed: {0}")]
+    Compression(String),
+
+    #[error("decompression failed: {0}")]
+    Decompression(String),
+
+    #[error("nonce of {0} bytes")]
+    InvalidNonce(usize),
+
+    #[error("key ID of {0} bytes")]
+    KeyIdLength(usize),
+
+    #[error("key ID {0} out of range")]
+    InvalidKeyId(i64),
+
+    #[error("unknown cipher {0}")]
+    UnknownCipher(i16),
+
+    #[error("value is bound to a template ID that is not known")]
+    MissingBinding,
+
+    #[error("alias is not a namespace and external id")]
+    InvalidAlias,
+}
+
 #[derive(Error, Debug)]
 pub enum StorageError {
     #[error("Template not found: {0}")]
     NotFound(Uuid),
 
+    #[error("Template {id} is at revision {actual}, expected {expected}")]
+    Conflict { id: Uuid, expected: u64, actual: u64 },
+
+    #[error("Template {id} has no version {version}")]
+    VersionNotFound { id: Uuid, version: u64 },
+
     #[error("Encryption error: {0}")]
     Encryption(#[from] SecurityError),
 
-    #[error("Serialization error: {0}")]
-    Serialization(#[from] Box<bincode::ErrorKind>),
+    /// A stored template was changed since it was written
+    #[error("Template {id} failed authentication: corrupted or tampered with")]
+    AuthenticationFailed { id: Uuid },
+
+    /// A stored template needs a key the vault no longer has
+    #[error("Template {id} was encrypted with key {key_id}, which is not available")]
+    KeyUnavailable { id: Uuid, key_id: u32 },
+
+    /// A stored template's ciphertext is cut short
+    #[error("Template {id} has a malformed ciphertext: {reason}")]
+    MalformedCiphertext { id: Uuid, reason: String },
+
+    /// A value could not be serialized for storage
+    #[error("Failed to encode value: {0}")]
+    Encode(#[source] CodecError),
+
+    /// A stored value could not be deserialized
+    #[error("Stored value is corrupt: {0}")]
+    Corrupt(#[source] CodecError),
 
     #[error("Storage error: {0}")]
     Storage(#[from] sled::Error),
 
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+
+    #[cfg(feature = "postgres")]
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Alias {namespace}/{external_id} is already bound to template {existing}")]
+    AliasConflict {
+        namespace: String,
+        external_id: String,
+        existing: Uuid,
+    },
+
+    #[error("Template {id} expired at {expired_at}")]
+    Expired {
+        id: Uuid,
+        expired_at: DateTime<Utc>,
+    },
+
+    #[error("Metadata extra key is not indexed: {0}")]
+    NotIndexed(String),
+
+    #[error("Hash lookups need a key; see VaultConfig::lookup_key")]
+    NoLookupKey,
+
+    #[error("Audit log chain broken at entry {seq}")]
+    AuditChainBroken { seq: u64 },
+
+    #[error("Invalid backup archive: {0}")]
+    InvalidArchive(String),
+
+    #[error("Template of {size} bytes exceeds the limit of {max} bytes")]
+    TemplateTooLarge { size: usize, max: usize },
+
+    #[error("Invalid template: {0}")]
+    InvalidTemplate(#[from] TemplateError),
+
+    #[error("Vault is opened read-only")]
+    ReadOnly,
+
+    #[error("Operation requires a vault protected by a master key")]
+    NoMasterKey,
+
+    #[error("Key {key_id} may still be needed by {entries} stored values")]
+    KeyInUse { key_id: u32, entries: u64 },
+
+    #[error("Invalid namespace: {0}")]
+    InvalidNamespace(String),
+
+    #[error("Invalid alias: {0}")]
+    InvalidAlias(String),
+
+    #[error("Vault at {} is already open{}", path.display(), holder_pid.map(|pid| format!(" by process {}", pid)).unwrap_or_default())]
+    Locked {
+        path: PathBuf,
+        holder_pid: Option<u32>,
+    },
+}
+
+impl StorageError {
+    /// Wrap a failure to serialize a value for storage
+    pub(super) fn encode(error: impl Into<CodecError>) -> Self {
+        StorageError::Encode(error.into())
+    }
+
+    /// Wrap a failure to deserialize a stored value
+    pub(super) fn corrupt(error: impl Into<CodecError>) -> Self {
+        StorageError::Corrupt(error.into())
+    }
+
+    /// Attribute a failure to decrypt stored template `id` to its cause
+    pub(super) fn for_template(self, id: Uuid) -> Self {
+        match self {
+            StorageError::Encryption(SecurityError::AuthenticationFailed) => {
+                StorageError::AuthenticationFailed { id }
+            }
+            StorageError::Encryption(SecurityError::KeyUnavailable { key_id }) => {
+                StorageError::KeyUnavailable { id, key_id }
+            }
+            StorageError::Encryption(SecurityError::MalformedCiphertext(reason)) => {
+                StorageError::MalformedCiphertext { id, reason }
+            }
+            other => other,
+        }
+    }
+}
+
+impl From<TransactionError<StorageError>> for StorageError {
+    fn from(error: TransactionError<StorageError>) -> Self {
+        match error {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => StorageError::Storage(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_codec_errors_keep_their_source() {
+        let json = serde_json::from_slice::<serde_json::Value>(b"{").unwrap_err();
+        let message = json.to_string();
+        let error = StorageError::corrupt(json);
+
+        assert_eq!(error.to_string(), format!("Stored value is corrupt: {}", message));
+        let source = error.source().expect("corrupt error has a source");
+        assert!(matches!(
+            source.downcast_ref::<CodecError>(),
+            Some(CodecError::Json(_))
+        ));
+        // The codec error is transparent over the original serde_json error
+        assert_eq!(source.to_string(), message);
+
+        let error = StorageError::corrupt(CodecError::UnknownFormat(7));
+        assert_eq!(error.to_string(), "Stored value is corrupt: unknown format byte 0x07");
+
+        let bincode = bincode::deserialize::<u64>(&[1]).unwrap_err();
+        let error = StorageError::encode(bincode);
+        assert!(error.to_string().starts_with("Failed to encode value: "));
+        assert!(matches!(error, StorageError::Encode(CodecError::Bincode(_))));
+    }
 }
@@ -0,0 +1,37 @@
+use super::vault::TemplateVault;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Events buffered per subscriber before it starts lagging
+pub(super) const EVENT_CAPACITY: usize = 1024;
+
+/// Change to the vault's templates, sent to subscribers once committed
+///
+/// Templates in namespaces are not reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VaultEvent {
+    /// A template was stored, imported or restored from a tombstone
+    Stored(Uuid),
+    /// A stored template was replaced
+    Updated(Uuid),
+    /// A template was deleted, soft-deleted, purged or wiped
+    Deleted(Uuid),
+    /// The data key was rotated and every entry re-encrypted
+    KeyRotated,
+}
+
+impl TemplateVault {
+    /// Receive an event for every change committed from now on
+    ///
+    /// Writes never wait for subscribers: one that falls more than
+    /// 1024 events behind gets `RecvError::Lagged` and misses the oldest.
+    pub fn subscribe(&self) -> broadcast::Receiver<VaultEvent> {
+        self.events.subscribe()
+    }
+
+    /// Send `event` to current subscribers, if any
+    pub(super) fn emit(&self, event: VaultEvent) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+}
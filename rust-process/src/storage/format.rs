@@ -0,0 +1,279 @@
+//! On-disk encoding of vault values
+//!
+//! Stored values are an envelope around the ciphertext: a format byte
+//! followed by bincode-encoded `EncryptedData`. The format byte also tells
+//! whether the ciphertext is bound to a template ID, passed as AAD, so that
+//! values moved under another ID, or into another namespace, fail to
+//! decrypt. Template plaintexts carry
+//! their own format byte, so re-encryption can move them unchanged; it
+//! also names the compression, if any, applied to the plaintext. Since
+//! envelopes have an authenticated header, that byte is kept there rather
+//! than encrypted, and put back in front of the plaintext on decryption.
+//! Entries written before the format byte existed are JSON throughout and
+//! always start with `{`; they stay readable until `migrate_format`.
+//! Template metadata, JSON in every format, is migrated to the latest
+//! schema whenever it is decoded.
+
+use super::compression::{record_limit, CompressionAlgo};
+use super::error::{CodecError, StorageError};
+use super::namespace::NAMESPACE_PREFIX;
+use super::tombstone::TOMBSTONE_HEADER_LEN;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::security::EncryptedData;
+use crate::templates::{MetadataVersion, Template, TemplateMetadata};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use zeroize::{Zeroize, Zeroizing};
+
+/// First byte of JSON-encoded legacy values
+const LEGACY_JSON: u8 = b'{';
+/// Envelope: bincode `EncryptedData`
+const ENVELOPE_V2: u8 = 2;
+/// Envelope: bincode `EncryptedData` with the template ID as AAD
+const ENVELOPE_BOUND: u8 = 3;
+/// Template plaintext: bincode `TemplateRecord`
+const TEMPLATE_V2: u8 = 2;
+/// Template plaintext: zstd-compressed bincode `TemplateRecord`
+const TEMPLATE_ZSTD: u8 = 3;
+/// Template plaintext: lz4-compressed bincode `TemplateRecord`
+const TEMPLATE_LZ4: u8 = 4;
+
+/// Binary form of a template
+///
+/// Metadata stays JSON because its free-form fields cannot be encoded
+/// with bincode; the payload, usually the bulk of the bytes, is raw.
+#[derive(Serialize, Deserialize)]
+struct TemplateRecord {
+    id: Option<Uuid>,
+    data: Vec<u8>,
+    metadata: Vec<u8>,
+}
+
+/// Template as encoded in legacy JSON values, its metadata of any schema
+#[derive(Deserialize)]
+struct LegacyTemplate {
+    id: Option<Uuid>,
+    data: Vec<u8>,
+    metadata: serde_json::Value,
+}
+
+fn unknown_format(byte: Option<&u8>) -> StorageError {
+    StorageError::corrupt(match byte {
+        Some(byte) => CodecError::UnknownFormat(*byte),
+        None => CodecError::Empty,
+    })
+}
+
+/// Whether a stored value uses the legacy JSON envelope
+pub(super) fn is_legacy(value: &[u8]) -> bool {
+    value.first() == Some(&LEGACY_JSON)
+}
+
+/// Whether a stored value is bound to the template ID it is stored under
+pub(super) fn is_bound(value: &[u8]) -> bool {
+    value.first() == Some(&ENVELOPE_BOUND)
+}
+
+/// Template ID a bound value under `key` is bound to
+///
+/// Values of template, history and tombstone trees are keyed by the
+/// template ID, optionally followed by a suffix such as the version.
+pub(super) fn binding_of(key: &[u8]) -> Result<Uuid> {
+    key.get(..16)
+        .and_then(|id| Uuid::from_slice(id).ok())
+        .ok_or_else(|| StorageError::corrupt(CodecError::MissingBinding))
+}
+
+/// Additional authenticated data of a value bound to template `id`
+///
+/// Values of a namespace are bound to its template tree as well, so they
+/// do not decrypt once moved to another namespace or out of it.
+pub(super) fn binding_aad(id: Uuid, namespace: Option<&str>) -> Vec<u8> {
+    let mut aad = id.as_bytes().to_vec();
+    if let Some(namespace) = namespace {
+        aad.extend_from_slice(NAMESPACE_PREFIX.as_bytes());
+        aad.extend_from_slice(namespace.as_bytes());
+    }
+    aad
+}
+
+pub(super) fn encode_envelope(encrypted: &EncryptedData, bound: bool) -> Result<Vec<u8>> {
+    let mut value = vec![if bound { ENVELOPE_BOUND } else { ENVELOPE_V2 }];
+    bincode::serialize_into(&mut value, encrypted).map_err(StorageError::encode)?;
+    Ok(value)
+}
+
+pub(super) fn decode_envelope(value: &[u8]) -> Result<EncryptedData> {
+    match value.first() {
+        Some(&LEGACY_JSON) => serde_json::from_slice(value).map_err(StorageError::corrupt),
+        Some(&ENVELOPE_V2 | &ENVELOPE_BOUND) => bincode::deserialize(&value[1..]).map_err(StorageError::corrupt),
+        other => Err(unknown_format(other)),
+    }
+}
+
+pub(super) fn encode_template_plaintext(
+    template: &Template,
+    compression: Option<CompressionAlgo>,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let mut record = TemplateRecord {
+        id: template.id,
+        data: template.data.clone(),
+        metadata: serde_json::to_vec(&template.metadata).map_err(StorageError::encode)?,
+    };
+    let serialized = bincode::serialize(&record).map_err(StorageError::encode);
+    record.data.zeroize();
+    record.metadata.zeroize();
+    let record = Zeroizing::new(serialized?);
+    let (format, body) = match compression {
+        None => (TEMPLATE_V2, record),
+        Some(algo @ CompressionAlgo::Zstd) => (TEMPLATE_ZSTD, algo.compress(&record)?),
+        Some(algo @ CompressionAlgo::Lz4) => (TEMPLATE_LZ4, algo.compress(&record)?),
+    };
+    let mut plaintext = Zeroizing::new(Vec::with_capacity(body.len() + 1));
+    plaintext.push(format);
+    plaintext.extend_from_slice(&body);
+    Ok(plaintext)
+}
+
+/// Split a template plaintext into its format byte, to be sealed as the
+/// envelope header, and the bytes to encrypt
+pub(super) fn split_template_plaintext(plaintext: &[u8]) -> (&[u8], &[u8]) {
+    plaintext.split_at(plaintext.len().min(1))
+}
+
+pub(super) fn decode_template_plaintext(plaintext: &[u8], limit: Option<usize>) -> Result<Template> {
+    decode_versioned_template_plaintext(plaintext, limit).map(|(template, _)| template)
+}
+
+/// Decode a template plaintext, migrating its metadata to the latest schema
+///
+/// Also returns the schema version the metadata was stored in. Compressed
+/// records expanding past `limit` bytes are rejected as corrupt.
+pub(super) fn decode_versioned_template_plaintext(
+    plaintext: &[u8],
+    limit: Option<usize>,
+) -> Result<(Template, MetadataVersion)> {
+    let record = match plaintext.first() {
+        Some(&LEGACY_JSON) => {
+            let legacy: LegacyTemplate = serde_json::from_slice(plaintext).map_err(StorageError::corrupt)?;
+            let (metadata, version) = migrate_metadata(legacy.metadata)?;
+            let template = Template {
+                id: legacy.id,
+                data: legacy.data,
+                metadata,
+            };
+            return Ok((template, version));
+        }
+        Some(&TEMPLATE_V2) => decode_record(&plaintext[1..])?,
+        Some(&TEMPLATE_ZSTD) => decode_record(&CompressionAlgo::Zstd.decompress(&plaintext[1..], limit)?)?,
+        Some(&TEMPLATE_LZ4) => decode_record(&CompressionAlgo::Lz4.decompress(&plaintext[1..], limit)?)?,
+        other => return Err(unknown_format(other)),
+    };
+    let metadata_json = Zeroizing::new(record.metadata);
+    let (metadata, version) = decode_metadata_json(&metadata_json)?;
+    let template = Template {
+        id: record.id,
+        data: record.data,
+        metadata,
+    };
+    Ok((template, version))
+}
+
+/// Deserialize stored metadata, migrating it to the latest schema
+///
+/// Also returns the schema version it was stored in.
+pub(super) fn decode_metadata_json(bytes: &[u8]) -> Result<(TemplateMetadata, MetadataVersion)> {
+    migrate_metadata(serde_json::from_slice(bytes).map_err(StorageError::corrupt)?)
+}
+
+fn migrate_metadata(value: serde_json::Value) -> Result<(TemplateMetadata, MetadataVersion)> {
+    TemplateMetadata::migrate_with_version(value).map_err(StorageError::corrupt)
+}
+
+fn decode_record(bytes: &[u8]) -> Result<TemplateRecord> {
+    bincode::deserialize(bytes).map_err(StorageError::corrupt)
+}
+
+impl TemplateVault {
+    /// Rewrite entries still in the legacy JSON format, and bind template
+    /// values not yet bound to their ID
+    ///
+    /// Returns the number of values rewritten. Entries changed by a
+    /// concurrent write while being migrated are left for the next run.
+    pub async fn migrate_format(&self) -> Result<usize> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation_guard().await?;
+        let templates: sled::Tree = (**self.db.read().await).clone();
+        let mut migrated = 0;
+        for tree in [&templates, &self.history] {
+            migrated += self.migrate_tree(tree, 0, true, None).await?;
+        }
+        migrated += self.migrate_tree(&self.tombstones, TOMBSTONE_HEADER_LEN, true, None).await?;
+        for namespace in self.namespace_trees().await? {
+            let name = namespace.namespace.as_deref();
+            migrated += self.migrate_tree(&namespace.templates, 0, true, name).await?;
+        }
+        for tree in [&self.metadata, &self.extra_index] {
+            migrated += self.migrate_tree(tree, 0, false, None).await?;
+        }
+        Ok(migrated)
+    }
+
+    /// Migrate the legacy values of one tree, binding template values to
+    /// `namespace` if given
+    async fn migrate_tree(
+        &self,
+        tree: &sled::Tree,
+        header_len: usize,
+        templates: bool,
+        namespace: Option<&str>,
+    ) -> Result<usize> {
+        let legacy = {
+            let _db = self.db.read().await;
+            let mut legacy = Vec::new();
+            for item in tree.iter() {
+                let (key, value) = item?;
+                if value.len() > header_len {
+                    let sealed = &value[header_len..];
+                    if is_legacy(sealed) || (templates && !is_bound(sealed)) {
+                        legacy.push((key, value));
+                    }
+                }
+            }
+            legacy
+        };
+
+        let mut rewritten = Vec::with_capacity(legacy.len());
+        for (key, value) in legacy {
+            let (header, sealed) = value.split_at(header_len);
+            let mut plaintext = self.open(sealed).await?;
+            let binding = if templates {
+                Some(binding_of(&key)?)
+            } else {
+                None
+            };
+            if templates && plaintext.first() == Some(&LEGACY_JSON) {
+                plaintext = encode_template_plaintext(
+                    &decode_template_plaintext(&plaintext, record_limit(self.max_template_size))?,
+                    self.compression,
+                )?;
+            }
+            let mut new_value = header.to_vec();
+            new_value.extend_from_slice(&match binding {
+                Some(id) => self.seal_template(&plaintext, id, namespace).await?,
+                None => self.seal(&plaintext).await?,
+            });
+            rewritten.push((key, value, new_value));
+        }
+
+        let _db = self.db.write().await;
+        let mut migrated = 0;
+        for (key, old, new) in rewritten {
+            if tree.compare_and_swap(key, Some(old), Some(new))?.is_ok() {
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
+    }
+}
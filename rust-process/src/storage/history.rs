@@ -0,0 +1,88 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::Template;
+use sled::IVec;
+use uuid::Uuid;
+
+/// Key in the history tree: template id followed by the big-endian version
+fn version_key(id: Uuid, version: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(24);
+    key.extend_from_slice(id.as_bytes());
+    key.extend_from_slice(&version.to_be_bytes());
+    key
+}
+
+fn version_of(key: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(key.get(16..24)?.try_into().ok()?))
+}
+
+/// History changes to apply alongside an update
+pub(super) struct HistoryPlan {
+    /// Key under which the replaced value is kept
+    pub(super) key: Vec<u8>,
+    /// Oldest versions dropped to stay within `max_versions`
+    pub(super) evict: Vec<IVec>,
+}
+
+impl TemplateVault {
+    /// Keep up to `max_versions` replaced versions of each template
+    ///
+    /// Every `update` then saves the previous template under the next
+    /// version number, starting at 1; the oldest versions are evicted first.
+    pub fn with_history(mut self, max_versions: usize) -> Self {
+        self.max_versions = Some(max_versions);
+        self
+    }
+
+    /// Version numbers of the kept history of a template, oldest first
+    pub async fn list_versions(&self, id: Uuid) -> Result<Vec<u64>> {
+        let _db = self.db.read().await;
+        Ok(self.history_keys(id)?.iter().filter_map(|key| version_of(key)).collect())
+    }
+
+    /// Retrieve a replaced version of a template
+    pub async fn get_version(&self, id: Uuid, version: u64) -> Result<Template> {
+        let value = {
+            let _db = self.db.read().await;
+            self.history
+                .get(version_key(id, version))?
+                .ok_or(StorageError::VersionNotFound { id, version })?
+        };
+        let template = self.decode_template(id, &value).await?;
+        self.record_audit(AuditOperation::Read, Some(id), &AuditContext::default())?;
+        Ok(template)
+    }
+
+    /// History keys of a template, oldest first
+    pub(super) fn history_keys(&self, id: Uuid) -> Result<Vec<IVec>> {
+        Ok(self
+            .history
+            .scan_prefix(id.as_bytes())
+            .keys()
+            .collect::<std::result::Result<Vec<IVec>, _>>()?)
+    }
+
+    /// Work out where an update stores the replaced value
+    ///
+    /// The caller must hold the database write lock so the version numbers
+    /// cannot change before the update commits.
+    pub(super) fn plan_history(&self, id: Uuid) -> Result<Option<HistoryPlan>> {
+        let Some(max_versions) = self.max_versions else {
+            return Ok(None);
+        };
+        if max_versions == 0 {
+            return Ok(None);
+        }
+
+        let mut keys = self.history_keys(id)?;
+        let next = keys.last().and_then(|key| version_of(key)).unwrap_or(0) + 1;
+        let excess = (keys.len() + 1).saturating_sub(max_versions);
+        keys.truncate(excess);
+        Ok(Some(HistoryPlan {
+            key: version_key(id, next),
+            evict: keys,
+        }))
+    }
+}
@@ -0,0 +1,62 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::{MatchScore, Matcher, Template};
+use uuid::Uuid;
+
+/// Candidates decrypted and scored per lock acquisition
+const IDENTIFY_CHUNK: usize = 64;
+
+/// Sort by descending score, then ID so ties rank the same on every call
+fn rank(candidates: &mut [(Uuid, MatchScore)]) {
+    candidates.sort_by(|(a_id, a), (b_id, b)| b.0.total_cmp(&a.0).then(a_id.cmp(b_id)));
+}
+
+impl TemplateVault {
+    /// Find the `top_k` stored templates best matching `probe`, best first
+    ///
+    /// Only templates of the probe's type are decrypted and scored, a chunk
+    /// at a time, so memory stays bounded by the chunk and `top_k` rather
+    /// than the vault. Templates the matcher cannot compare with the probe
+    /// are skipped.
+    pub async fn identify(
+        &self,
+        probe: &Template,
+        matcher: &dyn Matcher,
+        top_k: usize,
+    ) -> Result<Vec<(Uuid, MatchScore)>> {
+        if top_k == 0 {
+            return Ok(Vec::new());
+        }
+        let template_type = probe.metadata.template_type;
+        let ids = self.scan_type_index(template_type).await?;
+
+        let mut best = Vec::with_capacity(top_k * 2);
+        for chunk in ids.chunks(IDENTIFY_CHUNK) {
+            let templates = self.read_batch(chunk).await?;
+            for (&id, template) in chunk.iter().zip(templates) {
+                // Deleted or expired since the index was read
+                let Some(template) = template else {
+                    continue;
+                };
+                if template.metadata.template_type != template_type {
+                    continue;
+                }
+                if let Some(score) = matcher.score(probe, &template).filter(|s| !s.0.is_nan()) {
+                    best.push((id, score));
+                }
+            }
+            // Keep at most twice `top_k` between chunks
+            if best.len() >= top_k * 2 {
+                rank(&mut best);
+                best.truncate(top_k);
+            }
+        }
+
+        rank(&mut best);
+        best.truncate(top_k);
+        let matched: Vec<Uuid> = best.iter().map(|(id, _)| *id).collect();
+        self.record_audit_all(AuditOperation::Identify, &matched, &AuditContext::default())?;
+        Ok(best)
+    }
+}
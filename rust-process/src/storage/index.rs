@@ -0,0 +1,276 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::{TemplateMetadata, TemplateType};
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use uuid::Uuid;
+
+/// Marker recording that the type index covers every stored template
+const TYPE_INDEX_BUILT: &[u8] = b"\0built";
+
+/// Key of a template in the type index: `<type>:<uuid bytes>`
+fn type_key(template_type: TemplateType, id: Uuid) -> Vec<u8> {
+    let name = template_type.as_str().as_bytes();
+    let mut key = Vec::with_capacity(name.len() + 17);
+    key.extend_from_slice(name);
+    key.push(b':');
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+/// Extra index name under which provenance device IDs are indexed; the NUL
+/// keeps it apart from the names of `extra` keys
+const DEVICE_INDEX: &str = "\0provenance.device_id";
+
+/// Prefix of all extra index keys for one metadata key
+fn extra_prefix(name: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(name.len() + 2);
+    prefix.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    prefix.extend_from_slice(name.as_bytes());
+    prefix
+}
+
+/// Key of a template in the extra index: length-prefixed key name, then the id
+fn extra_key(name: &str, id: Uuid) -> Vec<u8> {
+    let mut key = extra_prefix(name);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+/// Index entries of one template, prepared before taking the write lock
+pub(super) struct IndexEntries {
+    type_key: Vec<u8>,
+    /// (extra index key, encrypted JSON value)
+    extra: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl TemplateVault {
+    /// Build the index entries for a template's metadata
+    pub(super) async fn index_entries(
+        &self,
+        id: Uuid,
+        metadata: &TemplateMetadata,
+    ) -> Result<IndexEntries> {
+        let mut extra = Vec::new();
+        for name in self.extra_keys.iter() {
+            if let Some(value) = metadata.extra.get(name) {
+                let value = serde_json::to_vec(value)
+                    .map_err(StorageError::encode)?;
+                extra.push((extra_key(name, id), self.seal(&value).await?));
+            }
+        }
+        let device_id = metadata.provenance.as_ref().and_then(|p| p.device_id.as_ref());
+        if let Some(device_id) = device_id {
+            let value = serde_json::to_vec(device_id).map_err(StorageError::encode)?;
+            extra.push((extra_key(DEVICE_INDEX, id), self.seal(&value).await?));
+        }
+        Ok(IndexEntries {
+            type_key: type_key(metadata.template_type, id),
+            extra,
+        })
+    }
+
+    /// Replace every index entry of a template inside a transaction
+    pub(super) fn write_indexes(
+        &self,
+        type_index: &TransactionalTree,
+        extra_index: &TransactionalTree,
+        id: Uuid,
+        entries: &IndexEntries,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        self.clear_indexes(type_index, extra_index, id)?;
+        type_index.insert(entries.type_key.as_slice(), &[])?;
+        for (key, value) in &entries.extra {
+            extra_index.insert(key.as_slice(), value.as_slice())?;
+        }
+        Ok(())
+    }
+
+    /// Remove every index entry of a template inside a transaction
+    ///
+    /// The previous metadata is unknown without decrypting, so every
+    /// possible key is cleared.
+    pub(super) fn clear_indexes(
+        &self,
+        type_index: &TransactionalTree,
+        extra_index: &TransactionalTree,
+        id: Uuid,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        for template_type in TemplateType::ALL {
+            type_index.remove(type_key(template_type, id))?;
+        }
+        for name in self.extra_keys.iter() {
+            extra_index.remove(extra_key(name, id))?;
+        }
+        extra_index.remove(extra_key(DEVICE_INDEX, id))?;
+        Ok(())
+    }
+
+    /// List the IDs of templates whose metadata `extra[key]` equals `value`
+    ///
+    /// Only keys declared with `TemplateVault::new_with_extra_index` can be
+    /// searched; values are compared as whole JSON values. Soft-deleted and
+    /// expired templates are not listed.
+    pub async fn find_by_extra(&self, key: &str, value: &serde_json::Value) -> Result<Vec<Uuid>> {
+        if !self.extra_keys.iter().any(|name| name == key) {
+            return Err(StorageError::NotIndexed(key.to_string()));
+        }
+        let ids = self.scan_extra_index(key, value).await?;
+        self.record_audit_all(AuditOperation::Find, &ids, &AuditContext::default())?;
+        Ok(ids)
+    }
+
+    /// List the IDs of templates captured by device `device_id`, as recorded
+    /// in their provenance
+    ///
+    /// Device IDs are always indexed, encrypted like `extra` values.
+    /// Soft-deleted and expired templates are not listed.
+    pub async fn find_by_device(&self, device_id: &str) -> Result<Vec<Uuid>> {
+        let ids = self.scan_extra_index(DEVICE_INDEX, &serde_json::Value::from(device_id)).await?;
+        self.record_audit_all(AuditOperation::Find, &ids, &AuditContext::default())?;
+        Ok(ids)
+    }
+
+    /// IDs of the entries of the extra index `name` whose value is `value`
+    async fn scan_extra_index(&self, name: &str, value: &serde_json::Value) -> Result<Vec<Uuid>> {
+        let prefix = extra_prefix(name);
+        let candidates = {
+            let _db = self.db.read().await;
+            self.extra_index
+                .scan_prefix(&prefix)
+                .collect::<std::result::Result<Vec<_>, _>>()?
+        };
+
+        let mut matches = Vec::new();
+        for (index_key, sealed) in candidates {
+            let stored: serde_json::Value = serde_json::from_slice(&self.open(&sealed).await?)
+                .map_err(StorageError::corrupt)?;
+            if stored == *value {
+                if let Ok(id) = Uuid::from_slice(&index_key[prefix.len()..]) {
+                    matches.push(id);
+                }
+            }
+        }
+
+        let db = self.db.read().await;
+        let mut ids = Vec::with_capacity(matches.len());
+        for id in matches {
+            if self.is_live(&db, id)? {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Bring the extra index in line with the configured keys
+    ///
+    /// Entries of keys no longer configured are dropped and newly
+    /// configured keys are indexed from the stored metadata.
+    pub(super) async fn sync_extra_index(&self) -> Result<()> {
+        let mut indexed = Vec::new();
+        for name in self.extra_index_keys.iter().keys() {
+            indexed.push(String::from_utf8_lossy(&name?).into_owned());
+        }
+
+        for name in indexed.iter().filter(|name| !self.extra_keys.contains(name)) {
+            let _db = self.db.write().await;
+            for key in self.extra_index.scan_prefix(extra_prefix(name)).keys() {
+                self.extra_index.remove(key?)?;
+            }
+            self.extra_index_keys.remove(name.as_bytes())?;
+        }
+
+        let added: Vec<&String> = self.extra_keys.iter().filter(|name| !indexed.contains(name)).collect();
+        if added.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = sled::Batch::default();
+        let mut offset = 0;
+        loop {
+            let page = self.metadata_page(offset, 256).await?;
+            if page.is_empty() {
+                break;
+            }
+            offset += page.len();
+            for (id, metadata) in page {
+                for name in &added {
+                    if let Some(value) = metadata.extra.get(name.as_str()) {
+                        let value = serde_json::to_vec(value)
+                            .map_err(StorageError::encode)?;
+                        batch.insert(extra_key(name, id), self.seal(&value).await?);
+                    }
+                }
+            }
+        }
+
+        let _db = self.db.write().await;
+        self.extra_index.apply_batch(batch)?;
+        for name in added {
+            self.extra_index_keys.insert(name.as_bytes(), &[])?;
+        }
+        Ok(())
+    }
+
+    /// List the IDs of all templates of one modality
+    ///
+    /// Soft-deleted and expired templates are not listed.
+    pub async fn find_by_type(&self, template_type: TemplateType) -> Result<Vec<Uuid>> {
+        let ids = self.scan_type_index(template_type).await?;
+        self.record_audit_all(AuditOperation::Find, &ids, &AuditContext::default())?;
+        Ok(ids)
+    }
+
+    /// IDs of the live templates of one modality, without auditing the lookup
+    pub(super) async fn scan_type_index(&self, template_type: TemplateType) -> Result<Vec<Uuid>> {
+        if !self.type_index.contains_key(TYPE_INDEX_BUILT)? {
+            self.rebuild_type_index().await?;
+        }
+
+        let db = self.db.read().await;
+        let mut prefix = template_type.as_str().as_bytes().to_vec();
+        prefix.push(b':');
+
+        let mut ids = Vec::new();
+        for key in self.type_index.scan_prefix(&prefix).keys() {
+            if let Ok(id) = Uuid::from_slice(&key?[prefix.len()..]) {
+                if self.is_live(&db, id)? {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Mark the type index as complete when opening an empty vault
+    pub(super) fn init_type_index(&self, db: &sled::Db) -> Result<()> {
+        if db.is_empty() {
+            self.type_index.insert(TYPE_INDEX_BUILT, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild the type index from stored metadata
+    ///
+    /// Vaults created before the index existed are indexed on first use.
+    async fn rebuild_type_index(&self) -> Result<()> {
+        let mut offset = 0;
+        let mut batch = sled::Batch::default();
+        loop {
+            let page = self.metadata_page(offset, 256).await?;
+            if page.is_empty() {
+                break;
+            }
+            offset += page.len();
+            for (id, metadata) in page {
+                batch.insert(type_key(metadata.template_type, id), &[]);
+            }
+        }
+        batch.insert(TYPE_INDEX_BUILT, &[]);
+
+        let _db = self.db.write().await;
+        self.type_index.apply_batch(batch)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+use super::error::{CodecError, StorageError};
+use super::vault::TemplateVault;
+use super::Result;
+use crate::security::{
+    generate_salt, KeyId, KeyManager, KeySource, KeyUsageRecord, MasterKeyProvider, SecretBytes, SecurityError,
+};
+use ring::hmac;
+use std::sync::Arc;
+
+/// Salt for passphrase-derived master keys
+const SALT: &[u8] = b"salt";
+/// Wrapped current data key
+const CURRENT: &[u8] = b"current";
+/// Wrapped key of an unfinished rotation, as written before several old
+/// keys could be kept
+const PREVIOUS: &[u8] = b"previous";
+/// Prefix of the wrapped old keys, followed by the big-endian key ID
+const OLD_KEY_PREFIX: &[u8] = b"old_key/";
+/// ID of the current data key, big-endian
+const CURRENT_ID: &[u8] = b"current_id";
+/// ID of the `PREVIOUS` key, big-endian
+const PREVIOUS_ID: &[u8] = b"previous_id";
+/// Progress of an unfinished rotation
+pub(super) const ROTATION_JOURNAL: &[u8] = b"rotation";
+/// Wrapped key of the hashes indexing template contents and subject IDs
+const HASH_KEY: &[u8] = b"hash_key";
+/// Present once every wrapped key is bound to its slot; keys of older
+/// keyrings were wrapped without a context
+const BOUND_SLOTS: &[u8] = b"bound_slots";
+/// Prefix of the usage counters of held data keys, followed by the
+/// big-endian key ID
+const USAGE_PREFIX: &[u8] = b"usage/";
+
+/// Wrapping context binding a key to its slot and, for data keys, its ID
+pub(super) fn slot_context(slot: &[u8], id: Option<KeyId>) -> String {
+    let slot = String::from_utf8_lossy(slot.strip_suffix(b"/").unwrap_or(slot));
+    match id {
+        Some(id) => format!("{}/{}", slot, id),
+        None => slot.into_owned(),
+    }
+}
+
+/// Build the key manager of a vault from its keyring
+///
+/// The data keys are unwrapped by the key source's provider, which also
+/// generates the keys of new vaults and rotations. Without a key source
+/// the vault gets a fresh in-memory key, which is refused if the keyring
+/// shows the vault is protected by a master key. A key source is refused
+/// for a vault that holds templates but no data key, since they were
+/// encrypted with an in-memory key that is gone. Keys wrapped before they
+/// were bound to their slot are rewrapped.
+pub(super) async fn load_keys(
+    keyring: &sled::Tree,
+    key_source: Option<&KeySource>,
+    holds_data: bool,
+) -> Result<(KeyManager, Option<Arc<dyn MasterKeyProvider>>)> {
+    let Some(key_source) = key_source else {
+        if keyring.contains_key(CURRENT)? {
+            return Err(StorageError::Encryption(SecurityError::InvalidKey(
+                "vault is protected by a master key".into(),
+            )));
+        }
+        return Ok((KeyManager::new()?, None));
+    };
+    if holds_data && !keyring.contains_key(CURRENT)? {
+        return Err(StorageError::Encryption(SecurityError::InvalidKey(
+            "vault holds templates encrypted with an in-memory key".into(),
+        )));
+    }
+
+    let salt = match keyring.get(SALT)? {
+        Some(salt) => salt.to_vec(),
+        None => {
+            let salt = generate_salt()?.to_vec();
+            keyring.insert(SALT, salt.as_slice())?;
+            salt
+        }
+    };
+    let provider = key_source.provider(&salt)?;
+
+    let key_manager = match keyring.get(CURRENT)? {
+        Some(wrapped) => {
+            let bound = keyring.contains_key(BOUND_SLOTS)?;
+            let context = |slot, id| if bound { slot_context(slot, id) } else { String::new() };
+            // Keyrings written before key IDs existed count from 1
+            let current_id = read_key_id(keyring, CURRENT_ID)?.unwrap_or(1);
+            let current = provider.unwrap_key(&wrapped, &context(CURRENT, Some(current_id))).await?;
+            let mut old = Vec::new();
+            if let Some(wrapped) = keyring.get(PREVIOUS)? {
+                let old_id = read_key_id(keyring, PREVIOUS_ID)?.unwrap_or(current_id.saturating_sub(1));
+                old.push((old_id, provider.unwrap_key(&wrapped, &context(PREVIOUS, Some(old_id))).await?));
+            }
+            for entry in keyring.scan_prefix(OLD_KEY_PREFIX) {
+                let (name, wrapped) = entry?;
+                let old_id = parse_key_id(&name[OLD_KEY_PREFIX.len()..])?;
+                old.push((old_id, provider.unwrap_key(&wrapped, &context(OLD_KEY_PREFIX, Some(old_id))).await?));
+            }
+            if !bound {
+                bind_slots(keyring, provider.as_ref(), (current_id, &current), &old).await?;
+            }
+            KeyManager::from_keys((current_id, current), old)?.with_provider(provider.clone())
+        }
+        None => {
+            let key_manager = KeyManager::from_provider(provider.clone()).await?;
+            let material = key_manager.key_material();
+            let current = SecretBytes::from(&material.current[..]);
+            let mut batch = sled::Batch::default();
+            batch.insert(
+                CURRENT,
+                provider.wrap_key(&current, &slot_context(CURRENT, Some(material.current_id))).await?,
+            );
+            batch.insert(CURRENT_ID, &material.current_id.to_be_bytes());
+            batch.insert(BOUND_SLOTS, &[]);
+            keyring.apply_batch(batch)?;
+            keyring.flush()?;
+            key_manager
+        }
+    };
+    Ok((key_manager, Some(provider)))
+}
+
+/// Rewrap the keys of a keyring written before keys were bound to their
+/// slot, moving the `PREVIOUS` key to the old keys
+async fn bind_slots(
+    keyring: &sled::Tree,
+    provider: &dyn MasterKeyProvider,
+    (current_id, current): (KeyId, &SecretBytes),
+    old: &[(KeyId, SecretBytes)],
+) -> Result<()> {
+    let mut batch = sled::Batch::default();
+    batch.insert(CURRENT, provider.wrap_key(current, &slot_context(CURRENT, Some(current_id))).await?);
+    batch.remove(PREVIOUS);
+    batch.remove(PREVIOUS_ID);
+    for (old_id, key) in old {
+        batch.insert(
+            old_key_name(*old_id),
+            provider.wrap_key(key, &slot_context(OLD_KEY_PREFIX, Some(*old_id))).await?,
+        );
+    }
+    if let Some(wrapped) = keyring.get(HASH_KEY)? {
+        let hash_key = provider.unwrap_key(&wrapped, "").await?;
+        batch.insert(HASH_KEY, provider.wrap_key(&hash_key, &slot_context(HASH_KEY, None)).await?);
+    }
+    batch.insert(BOUND_SLOTS, &[]);
+    keyring.apply_batch(batch)?;
+    keyring.flush()?;
+    Ok(())
+}
+
+fn read_key_id(keyring: &sled::Tree, name: &[u8]) -> Result<Option<KeyId>> {
+    keyring.get(name)?.map(|bytes| parse_key_id(&bytes)).transpose()
+}
+
+pub(super) fn parse_key_id(bytes: &[u8]) -> Result<KeyId> {
+    <[u8; 4]>::try_from(bytes)
+        .map(KeyId::from_be_bytes)
+        .map_err(|_| StorageError::corrupt(CodecError::KeyIdLength(bytes.len())))
+}
+
+fn old_key_name(id: KeyId) -> Vec<u8> {
+    let mut name = OLD_KEY_PREFIX.to_vec();
+    name.extend_from_slice(&id.to_be_bytes());
+    name
+}
+
+fn usage_name(id: KeyId) -> Vec<u8> {
+    let mut name = USAGE_PREFIX.to_vec();
+    name.extend_from_slice(&id.to_be_bytes());
+    name
+}
+
+/// Continue counting the usage of the current data key from the keyring
+///
+/// Keyrings written before usage was persisted start from zero.
+pub(super) fn load_usage(keyring: &sled::Tree, key_manager: &KeyManager) -> Result<()> {
+    let key_id = key_manager.current_key_id();
+    if let Some(value) = keyring.get(usage_name(key_id))? {
+        let record: KeyUsageRecord = bincode::deserialize(&value).map_err(StorageError::corrupt)?;
+        key_manager.restore_usage(key_id, &record);
+    }
+    Ok(())
+}
+
+/// Overwrite every wrapped key in the keyring with zeros and flush
+///
+/// Run before a wipe clears anything, so the keys are gone from the
+/// keyring's current pages before the values they protect are touched.
+/// Leaves the vault unable to decrypt until new keys are persisted.
+pub(super) fn shred_keys(keyring: &sled::Tree) -> Result<()> {
+    let mut batch = sled::Batch::default();
+    for entry in keyring.iter() {
+        let (name, value) = entry?;
+        let wrapped = [CURRENT, PREVIOUS, HASH_KEY].contains(&&*name) || name.starts_with(OLD_KEY_PREFIX);
+        if wrapped {
+            batch.insert(name, vec![0; value.len()]);
+        }
+    }
+    keyring.apply_batch(batch)?;
+    keyring.flush()?;
+    Ok(())
+}
+
+/// Load the hashing key, creating it on first use
+///
+/// Vaults with an in-memory data key get an in-memory hashing key too.
+pub(super) async fn load_hash_key(
+    keyring: &sled::Tree,
+    provider: Option<&Arc<dyn MasterKeyProvider>>,
+) -> Result<hmac::Key> {
+    let stored = match provider {
+        Some(provider) => match keyring.get(HASH_KEY)? {
+            Some(wrapped) => Some(provider.unwrap_key(&wrapped, &slot_context(HASH_KEY, None)).await?),
+            None => None,
+        },
+        None => None,
+    };
+    match stored {
+        Some(key) => Ok(hmac::Key::new(hmac::HMAC_SHA256, key.expose())),
+        None => generate_hash_key(keyring, provider).await,
+    }
+}
+
+/// Generate a new hashing key, replacing any stored one
+///
+/// Vaults with a key provider store it wrapped; others keep it in memory.
+pub(super) async fn generate_hash_key(
+    keyring: &sled::Tree,
+    provider: Option<&Arc<dyn MasterKeyProvider>>,
+) -> Result<hmac::Key> {
+    let key = SecretBytes::random(32)?;
+    if let Some(provider) = provider {
+        keyring.insert(HASH_KEY, provider.wrap_key(&key, &slot_context(HASH_KEY, None)).await?)?;
+        keyring.flush()?;
+    }
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, key.expose()))
+}
+
+impl TemplateVault {
+    /// Write the data keys, wrapped by the key provider, to the keyring
+    ///
+    /// Retired keys are removed, and once no old key is left the rotation
+    /// journal too, in the same batch. Does nothing for vaults with an
+    /// in-memory key.
+    pub(super) async fn persist_keys(&self) -> Result<()> {
+        let Some(provider) = &self.key_provider else {
+            return Ok(());
+        };
+        let material = self.encryption.key_manager().key_material();
+
+        let mut batch = sled::Batch::default();
+        let current = SecretBytes::from(&material.current[..]);
+        let context = slot_context(CURRENT, Some(material.current_id));
+        batch.insert(CURRENT, provider.wrap_key(&current, &context).await?);
+        batch.insert(CURRENT_ID, &material.current_id.to_be_bytes());
+        batch.remove(PREVIOUS);
+        batch.remove(PREVIOUS_ID);
+        for (old_id, old) in &material.old {
+            let context = slot_context(OLD_KEY_PREFIX, Some(*old_id));
+            batch.insert(old_key_name(*old_id), provider.wrap_key(&SecretBytes::from(&old[..]), &context).await?);
+        }
+        if material.old.is_empty() {
+            batch.remove(ROTATION_JOURNAL);
+        }
+
+        let _db = self.db.write().await;
+        for name in self.keyring.scan_prefix(OLD_KEY_PREFIX).keys() {
+            let name = name?;
+            if !material.old.contains_key(&parse_key_id(&name[OLD_KEY_PREFIX.len()..])?) {
+                batch.remove(name);
+            }
+        }
+        for name in self.keyring.scan_prefix(USAGE_PREFIX).keys() {
+            let name = name?;
+            let key_id = parse_key_id(&name[USAGE_PREFIX.len()..])?;
+            if key_id != material.current_id && !material.old.contains_key(&key_id) {
+                batch.remove(name);
+            }
+        }
+        let (key_id, usage) = self.encryption.key_manager().key_usage();
+        batch.insert(usage_name(key_id), bincode::serialize(&usage).map_err(StorageError::encode)?);
+        self.keyring.apply_batch(batch)?;
+        self.keyring.flush_async().await?;
+        Ok(())
+    }
+
+    /// Write the usage counters of the current data key to the keyring
+    ///
+    /// Counting is kept off the keyring on the hot path; the counters are
+    /// written when the vault is flushed or dropped and when keys change,
+    /// so a crash loses the usage since then. Does nothing for vaults with
+    /// an in-memory key, whose key does not outlive them, or read-only ones.
+    pub(super) fn persist_usage(&self) -> Result<()> {
+        if self.key_provider.is_none() || self.read_only {
+            return Ok(());
+        }
+        let (key_id, usage) = self.encryption.key_manager().key_usage();
+        self.keyring
+            .insert(usage_name(key_id), bincode::serialize(&usage).map_err(StorageError::encode)?)?;
+        Ok(())
+    }
+}
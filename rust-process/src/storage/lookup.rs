@@ -0,0 +1,144 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::Template;
+use ring::hmac;
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use uuid::Uuid;
+
+/// Prefix of lookup entries, kept in the content trees next to the
+/// deduplication hashes: `content_index` maps prefix + hash + id to nothing
+/// and `content_hashes` maps prefix + id to the hash
+const LOOKUP_PREFIX: &[u8] = b"lookup:";
+
+/// `content_hashes` key of the check value of the key the index was built
+/// with
+const LOOKUP_KEY_CHECK: &[u8] = b"lookup-key";
+
+fn lookup_index_key(hash: &[u8], id: Uuid) -> Vec<u8> {
+    let mut key = Vec::with_capacity(LOOKUP_PREFIX.len() + hash.len() + 16);
+    key.extend_from_slice(LOOKUP_PREFIX);
+    key.extend_from_slice(hash);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+fn lookup_ref_key(id: Uuid) -> Vec<u8> {
+    let mut key = LOOKUP_PREFIX.to_vec();
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+/// Value identifying `key` without revealing it
+fn key_check(key: &hmac::Key) -> hmac::Tag {
+    hmac::sign(key, b"secure-biometric lookup key check")
+}
+
+impl TemplateVault {
+    /// List the IDs of stored templates whose `Template::secure_hash` under
+    /// the configured lookup key is `hash`
+    ///
+    /// Finds templates without decrypting any, ordered by ID. Hashes taken
+    /// under another key, or of the same data under another template type,
+    /// match nothing. Fails with `StorageError::NoLookupKey` unless the vault
+    /// was opened with `VaultConfig::lookup_key`.
+    pub async fn find_by_hash(&self, hash: &[u8; 32]) -> Result<Vec<Uuid>> {
+        if self.lookup_key.is_none() {
+            return Err(StorageError::NoLookupKey);
+        }
+
+        let mut prefix = LOOKUP_PREFIX.to_vec();
+        prefix.extend_from_slice(hash);
+        let db = self.db.read().await;
+        let mut ids = Vec::new();
+        for key in self.content_index.scan_prefix(&prefix).keys() {
+            let Ok(id) = Uuid::from_slice(&key?[prefix.len()..]) else {
+                continue;
+            };
+            if self.is_live(&db, id)? {
+                ids.push(id);
+            }
+        }
+        self.record_audit_all(AuditOperation::Find, &ids, &AuditContext::default())?;
+        Ok(ids)
+    }
+
+    /// Lookup hash of a template, if a lookup key is configured
+    pub(super) fn lookup_hash(&self, template: &Template) -> Option<[u8; 32]> {
+        self.lookup_key.as_ref().map(|key| template.secure_hash_with(key))
+    }
+
+    /// Drop the lookup entry of a template whose data is replaced or removed
+    pub(super) fn clear_lookup_hash(
+        &self,
+        content_index: &TransactionalTree,
+        content_hashes: &TransactionalTree,
+        id: Uuid,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        if let Some(hash) = content_hashes.remove(lookup_ref_key(id))? {
+            content_index.remove(lookup_index_key(&hash, id))?;
+        }
+        Ok(())
+    }
+
+    /// Index a template under its lookup hash, replacing any previous entry
+    pub(super) fn write_lookup_hash(
+        &self,
+        content_index: &TransactionalTree,
+        content_hashes: &TransactionalTree,
+        id: Uuid,
+        hash: &[u8; 32],
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        self.clear_lookup_hash(content_index, content_hashes, id)?;
+        content_index.insert(lookup_index_key(hash, id), &[])?;
+        content_hashes.insert(lookup_ref_key(id), hash)?;
+        Ok(())
+    }
+
+    /// Record that the lookup index covers every stored template under the
+    /// configured key, or that it must be rebuilt if no key is configured
+    pub(super) fn init_lookup_index(&self) -> Result<()> {
+        match &self.lookup_key {
+            Some(key) => self.content_hashes.insert(LOOKUP_KEY_CHECK, key_check(key).as_ref())?,
+            None => self.content_hashes.remove(LOOKUP_KEY_CHECK)?,
+        };
+        Ok(())
+    }
+
+    /// Rebuild the lookup index if it was built with another key or missed
+    /// templates stored without one
+    ///
+    /// Every stored template is decrypted once to hash it.
+    pub(super) async fn sync_lookup_index(&self) -> Result<()> {
+        let Some(key) = &self.lookup_key else {
+            return self.init_lookup_index();
+        };
+        let check = self.content_hashes.get(LOOKUP_KEY_CHECK)?;
+        if check.as_deref() == Some(key_check(key).as_ref()) {
+            return Ok(());
+        }
+
+        let mut batch = sled::Batch::default();
+        let mut refs = sled::Batch::default();
+        for key in self.content_index.scan_prefix(LOOKUP_PREFIX).keys() {
+            batch.remove(key?);
+        }
+        for key in self.content_hashes.scan_prefix(LOOKUP_PREFIX).keys() {
+            refs.remove(key?);
+        }
+        for id in self.list_ids().await? {
+            let Some(value) = self.db.read().await.get(id.as_bytes())? else {
+                continue;
+            };
+            let hash = self.decode_template(id, &value).await?.secure_hash_with(key);
+            batch.insert(lookup_index_key(&hash, id), &[]);
+            refs.insert(lookup_ref_key(id), &hash);
+        }
+
+        let _db = self.db.write().await;
+        self.content_index.apply_batch(batch)?;
+        self.content_hashes.apply_batch(refs)?;
+        self.init_lookup_index()
+    }
+}
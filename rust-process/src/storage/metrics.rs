@@ -0,0 +1,150 @@
+use super::vault::TemplateVault;
+use crate::security::KeyId;
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry,
+};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Prometheus metrics of vault operations
+///
+/// Cloning is cheap; clones update the same metrics.
+#[derive(Clone)]
+pub struct VaultMetrics {
+    operations: IntCounterVec,
+    latency: HistogramVec,
+    template_size: Histogram,
+    entries: IntGauge,
+    key_operations: IntGaugeVec,
+    key_bytes: IntGaugeVec,
+    /// Key IDs the key gauges have a value for
+    key_ids: Arc<Mutex<BTreeSet<KeyId>>>,
+}
+
+impl VaultMetrics {
+    /// Create the vault metrics and register them with `registry`
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let operations = IntCounterVec::new(
+            Opts::new("vault_operations_total", "Completed vault operations"),
+            &["operation"],
+        )?;
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "vault_operation_duration_seconds",
+                "Latency of completed vault operations",
+            ),
+            &["operation"],
+        )?;
+        let template_size = Histogram::with_opts(
+            HistogramOpts::new("vault_template_size_bytes", "Size of stored template payloads")
+                .buckets(exponential_buckets(64.0, 4.0, 8)?),
+        )?;
+        let entries = IntGauge::new("vault_entries", "Templates stored outside namespaces")?;
+        let key_operations = IntGaugeVec::new(
+            Opts::new("vault_key_operations", "Encryptions and decryptions with each held data key"),
+            &["key_id"],
+        )?;
+        let key_bytes = IntGaugeVec::new(
+            Opts::new("vault_key_bytes", "Bytes encrypted and decrypted with each held data key"),
+            &["key_id"],
+        )?;
+
+        registry.register(Box::new(operations.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        registry.register(Box::new(template_size.clone()))?;
+        registry.register(Box::new(entries.clone()))?;
+        registry.register(Box::new(key_operations.clone()))?;
+        registry.register(Box::new(key_bytes.clone()))?;
+
+        Ok(Self {
+            operations,
+            latency,
+            template_size,
+            entries,
+            key_operations,
+            key_bytes,
+            key_ids: Arc::new(Mutex::new(BTreeSet::new())),
+        })
+    }
+}
+
+impl fmt::Debug for VaultMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VaultMetrics")
+            .field("entries", &self.entries.get())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TemplateVault {
+    /// Count a completed operation and its latency
+    pub(super) fn observe_operation(&self, operation: &str, started: Instant) {
+        if let Some(metrics) = &self.metrics {
+            metrics.operations.with_label_values(&[operation]).inc();
+            metrics
+                .latency
+                .with_label_values(&[operation])
+                .observe(started.elapsed().as_secs_f64());
+            self.observe_key_usage(metrics);
+        }
+    }
+
+    /// Publish the usage of the current data key
+    fn observe_key_usage(&self, metrics: &VaultMetrics) {
+        let (key_id, usage) = self.encryption.key_manager().key_usage();
+        let label = key_id.to_string();
+        metrics
+            .key_operations
+            .with_label_values(&[&label])
+            .set((usage.encryptions + usage.decryptions) as i64);
+        metrics
+            .key_bytes
+            .with_label_values(&[&label])
+            .set((usage.bytes_encrypted + usage.bytes_decrypted) as i64);
+        metrics.key_ids.lock().unwrap_or_else(|e| e.into_inner()).insert(key_id);
+    }
+
+    /// Drop the usage gauges of keys the key manager no longer holds, so
+    /// their number stays bounded by the keys kept for decryption
+    pub(super) fn observe_retired_keys(&self) {
+        if let Some(metrics) = &self.metrics {
+            let key_manager = self.encryption.key_manager();
+            let mut held = key_manager.old_key_ids();
+            held.push(key_manager.current_key_id());
+            let mut key_ids = metrics.key_ids.lock().unwrap_or_else(|e| e.into_inner());
+            key_ids.retain(|key_id| {
+                if held.contains(key_id) {
+                    return true;
+                }
+                let label = key_id.to_string();
+                let _ = metrics.key_operations.remove_label_values(&[&label]);
+                let _ = metrics.key_bytes.remove_label_values(&[&label]);
+                false
+            });
+        }
+    }
+
+    /// Record the payload size of a stored template
+    pub(super) fn observe_template_size(&self, size: usize) {
+        if let Some(metrics) = &self.metrics {
+            metrics.template_size.observe(size as f64);
+        }
+    }
+
+    /// Adjust the entry count by `delta` templates
+    pub(super) fn observe_entries(&self, delta: i64) {
+        if let Some(metrics) = &self.metrics {
+            metrics.entries.add(delta);
+        }
+    }
+
+    /// Set the entry count from the stored templates
+    pub(super) fn reset_entries(&self, db: &sled::Db) {
+        if let Some(metrics) = &self.metrics {
+            metrics.entries.set(db.len() as i64);
+        }
+    }
+}
@@ -1,7 +1,54 @@
+mod alias;
+mod audit;
+mod backup;
+mod compression;
+mod config;
+mod dedup;
 mod error;
+mod events;
+mod format;
+mod history;
+mod identify;
+mod index;
+mod keyring;
+mod lookup;
+mod metrics;
+mod namespace;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod retention;
+mod revision;
+mod rotation;
+mod scrub;
+mod snapshot;
+mod stats;
+mod store;
+mod store_vault;
+mod stream;
+mod subject;
+mod tombstone;
+mod upgrade;
 mod vault;
+mod wipe;
 
-pub use error::StorageError;
+pub use audit::{AuditContext, AuditEntry, AuditLog, AuditOperation};
+pub use backup::{ImportOptions, DEFAULT_MAX_ARCHIVE_SIZE};
+pub use compression::CompressionAlgo;
+pub use config::{VaultConfig, DEFAULT_MAX_TEMPLATE_SIZE};
+pub use dedup::StoreOutcome;
+pub use error::{CodecError, StorageError};
+pub use events::VaultEvent;
+pub use metrics::VaultMetrics;
+pub use namespace::NamespaceHandle;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresTemplateStore;
+pub use revision::VersionedTemplate;
+pub use rotation::{RotationProgress, RotationStatus};
+pub use scrub::IntegrityReport;
+pub use snapshot::SnapshotInfo;
+pub use stats::VaultStats;
+pub use store::{SledTemplateStore, StoreOp, StoredTemplate, TemplateStore};
+pub use store_vault::StoreVault;
 pub use vault::TemplateVault;
 
 pub type Result<T> = std::result::Result<T, StorageError>;
@@ -0,0 +1,187 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::events::VaultEvent;
+use super::vault::{EntryTrees, TemplateVault};
+use super::Result;
+use crate::templates::Template;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Prefix of the sled tree names holding namespaces
+pub(super) const NAMESPACE_PREFIX: &str = "ns:";
+/// Prefix of the sled tree names holding the metadata, index, expiry and
+/// other entries of namespaces, followed by the tree kind and the name
+const ENTRY_PREFIX: &str = "ns-entries:";
+
+fn tree_name(name: &str) -> Result<String> {
+    if name.is_empty() {
+        return Err(StorageError::InvalidNamespace("namespace must not be empty".into()));
+    }
+    Ok(format!("{}{}", NAMESPACE_PREFIX, name))
+}
+
+/// Names of the namespaces in `db`
+fn namespace_names(db: &sled::Db) -> Vec<String> {
+    db.tree_names()
+        .iter()
+        .filter_map(|name| {
+            std::str::from_utf8(name)
+                .ok()?
+                .strip_prefix(NAMESPACE_PREFIX)
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+/// Open the trees of namespace `name`, creating them if needed
+fn open_entry_trees(db: &sled::Db, name: &str) -> Result<EntryTrees> {
+    let tree = |kind: &str| db.open_tree(format!("{}{}:{}", ENTRY_PREFIX, kind, name));
+    Ok(EntryTrees {
+        namespace: Some(name.to_string()),
+        templates: db.open_tree(tree_name(name)?)?,
+        metadata: tree("metadata")?,
+        type_index: tree("type_index")?,
+        extra_index: tree("extra_index")?,
+        expiry: tree("expiry")?,
+        tombstones: tree("tombstones")?,
+        history: tree("history")?,
+        revisions: tree("revisions")?,
+        aliases: tree("aliases")?,
+        alias_refs: tree("alias_refs")?,
+        content_index: tree("content_index")?,
+        content_hashes: tree("content_hashes")?,
+        subjects: tree("subjects")?,
+        subject_refs: tree("subject_refs")?,
+    })
+}
+
+/// Templates of one tenant, kept in dedicated trees of a shared vault
+///
+/// Namespaced entries share the vault's encryption key and are written
+/// like the vault's own, with metadata, index and revision entries, audit
+/// records, metrics and events, into trees of their own. Deduplication
+/// only matches templates of the same namespace.
+#[derive(Clone)]
+pub struct NamespaceHandle {
+    vault: TemplateVault,
+    trees: EntryTrees,
+    name: String,
+}
+
+impl TemplateVault {
+    /// Open a namespace, creating it if needed
+    pub async fn namespace(&self, name: &str) -> Result<NamespaceHandle> {
+        let trees = open_entry_trees(&*self.db.read().await, name)?;
+        Ok(NamespaceHandle {
+            vault: self.clone(),
+            trees,
+            name: name.to_string(),
+        })
+    }
+
+    /// Names of all namespaces
+    pub async fn list_namespaces(&self) -> Result<Vec<String>> {
+        Ok(namespace_names(&*self.db.read().await))
+    }
+
+    /// Delete a namespace and every template in it
+    ///
+    /// Returns `false` if the namespace did not exist. Every template it
+    /// held is audited and reported to subscribers as deleted. Handles to a
+    /// dropped namespace must not be used afterwards.
+    pub async fn drop_namespace(&self, name: &str) -> Result<bool> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation_guard().await?;
+        let tree_name = tree_name(name)?;
+        let db = self.db.write().await;
+        let existed = db.tree_names().iter().any(|existing| existing == tree_name.as_bytes());
+        let trees = open_entry_trees(&db, name)?;
+        let mut dropped = Vec::new();
+        for key in trees.templates.iter().keys() {
+            if let Ok(id) = Uuid::from_slice(&key?) {
+                dropped.push(id);
+            }
+        }
+        for tree in trees.all() {
+            db.drop_tree(tree.name())?;
+        }
+        drop(db);
+
+        self.record_audit_all(AuditOperation::Delete, &dropped, &AuditContext::default())?;
+        for id in dropped {
+            self.emit(VaultEvent::Deleted(id));
+        }
+        Ok(existed)
+    }
+
+    /// Trees of all namespaces, ordered by name, for key rotation and wipes
+    pub(super) async fn namespace_trees(&self) -> Result<Vec<EntryTrees>> {
+        let db = self.db.read().await;
+        let mut names = namespace_names(&db);
+        names.sort();
+        names.iter().map(|name| open_entry_trees(&db, name)).collect()
+    }
+}
+
+impl NamespaceHandle {
+    /// Name of this namespace
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Store a template in this namespace
+    pub async fn store(&self, template: Template) -> Result<Uuid> {
+        self.vault
+            .store_entry_in(Some(&self.trees), template, None, None, &AuditContext::default())
+            .await
+            .map(|outcome| outcome.id)
+    }
+
+    /// Retrieve a template of this namespace by ID
+    pub async fn get(&self, id: Uuid) -> Result<Template> {
+        let started = Instant::now();
+        let encrypted_data = {
+            let _db = self.vault.db.read().await;
+            let encrypted_data = self
+                .trees
+                .templates
+                .get(id.as_bytes())?
+                .ok_or(StorageError::NotFound(id))?;
+            self.vault.check_expiry_in(&self.trees.expiry, id)?;
+            encrypted_data
+        };
+        let template = self.vault.decode_template_in(Some(&self.name), id, &encrypted_data).await?;
+        self.vault.record_audit(AuditOperation::Read, Some(id), &AuditContext::default())?;
+        self.vault.observe_operation("get", started);
+        Ok(template)
+    }
+
+    /// Delete a template of this namespace by ID
+    ///
+    /// Returns `false` if nothing was stored under the ID.
+    pub async fn delete(&self, id: Uuid) -> Result<bool> {
+        let started = Instant::now();
+        let _rotation = self.vault.rotation_guard().await?;
+        let _db = self.vault.db.write().await;
+        let removed = self.vault.remove_entries_in(&self.trees, &[id], &AuditContext::default())?;
+        self.vault.observe_operation("delete", started);
+        Ok(!removed.is_empty())
+    }
+
+    /// List all template IDs of this namespace
+    pub async fn list_ids(&self) -> Result<Vec<Uuid>> {
+        let _db = self.vault.db.read().await;
+        let mut ids = Vec::new();
+        for key in self.trees.templates.iter().keys() {
+            if let Ok(id) = Uuid::from_slice(&key?) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Rotate the shared key, re-encrypting every namespace
+    pub async fn rotate_key(&self) -> Result<()> {
+        self.vault.rotate_key().await
+    }
+}
@@ -0,0 +1,194 @@
+use super::error::{CodecError, StorageError};
+use super::store::{StoreOp, StoredTemplate, TemplateStore};
+use super::Result;
+use crate::security::{Cipher, EncryptedData};
+use async_trait::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use sqlx::postgres::{PgArguments, PgPool, PgPoolOptions, PgRow};
+use sqlx::query::Query;
+use sqlx::{Postgres, Row};
+use uuid::Uuid;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS templates (
+    id uuid PRIMARY KEY,
+    ciphertext bytea NOT NULL,
+    nonce bytea NOT NULL,
+    metadata jsonb NOT NULL,
+    key_id bigint,
+    cipher smallint NOT NULL DEFAULT 0,
+    header bytea NOT NULL DEFAULT ''
+)";
+/// Keyring of the wrapped data keys
+const CREATE_KEYS: &str = "CREATE TABLE IF NOT EXISTS template_keys (
+    name text PRIMARY KEY,
+    value bytea NOT NULL
+)";
+const UPSERT: &str = "INSERT INTO templates (id, ciphertext, nonce, metadata, key_id, cipher, header)
+    VALUES ($1, $2, $3, $4, $5, $6, $7)
+    ON CONFLICT (id) DO UPDATE
+    SET ciphertext = EXCLUDED.ciphertext, nonce = EXCLUDED.nonce, metadata = EXCLUDED.metadata,
+        key_id = EXCLUDED.key_id, cipher = EXCLUDED.cipher, header = EXCLUDED.header";
+const UPDATE: &str = "UPDATE templates
+    SET ciphertext = $2, nonce = $3, metadata = $4, key_id = $5, cipher = $6, header = $7
+    WHERE id = $1";
+const SELECT_ONE: &str =
+    "SELECT id, ciphertext, nonce, metadata, key_id, cipher, header FROM templates WHERE id = $1";
+const SELECT_ALL: &str =
+    "SELECT id, ciphertext, nonce, metadata, key_id, cipher, header FROM templates ORDER BY id";
+const DELETE: &str = "DELETE FROM templates WHERE id = $1";
+const COUNT: &str = "SELECT count(*) FROM templates";
+const SELECT_KEY: &str = "SELECT value FROM template_keys WHERE name = $1";
+const UPSERT_KEY: &str = "INSERT INTO template_keys (name, value) VALUES ($1, $2)
+    ON CONFLICT (name) DO UPDATE SET value = EXCLUDED.value";
+
+/// `TemplateStore` keeping templates in the Postgres table `templates`,
+/// and its keyring in `template_keys`
+///
+/// The table is looked up through the connection's `search_path`, so
+/// several vaults can share a database in separate schemas.
+#[derive(Clone)]
+pub struct PostgresTemplateStore {
+    pool: PgPool,
+}
+
+impl PostgresTemplateStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Connect to the database at `url`
+    pub async fn connect(url: &str) -> Result<Self> {
+        let pool = PgPoolOptions::new().connect(url).await?;
+        Ok(Self::new(pool))
+    }
+
+    /// Create the `templates` and `template_keys` tables if they do not
+    /// exist yet
+    pub async fn migrate(&self) -> Result<()> {
+        sqlx::query(CREATE_TABLE).execute(&self.pool).await?;
+        sqlx::query(CREATE_KEYS).execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+/// Value of the `cipher` column for `cipher`
+fn cipher_code(cipher: Cipher) -> i16 {
+    match cipher {
+        Cipher::ChaCha20Poly1305 => 0,
+        Cipher::XChaCha20Poly1305 => 1,
+    }
+}
+
+fn cipher_of(code: i16) -> Result<Cipher> {
+    match code {
+        0 => Ok(Cipher::ChaCha20Poly1305),
+        1 => Ok(Cipher::XChaCha20Poly1305),
+        _ => Err(StorageError::corrupt(CodecError::UnknownCipher(code))),
+    }
+}
+
+/// `statement`, `UPSERT` or `UPDATE`, with the columns of `template` bound
+fn write<'q>(
+    statement: &'q str,
+    id: Uuid,
+    template: &'q StoredTemplate,
+) -> Query<'q, Postgres, PgArguments> {
+    sqlx::query(statement)
+        .bind(id)
+        .bind(&template.encrypted.ciphertext)
+        .bind(&template.encrypted.nonce[..])
+        .bind(&template.metadata)
+        .bind(template.encrypted.key_id.map(i64::from))
+        .bind(cipher_code(template.encrypted.cipher))
+        .bind(&template.encrypted.header)
+}
+
+fn decode_row(row: PgRow) -> Result<(Uuid, StoredTemplate)> {
+    let cipher = cipher_of(row.try_get("cipher")?)?;
+    let nonce: Vec<u8> = row.try_get("nonce")?;
+    if nonce.len() != cipher.nonce_len() {
+        return Err(StorageError::corrupt(CodecError::InvalidNonce(nonce.len())));
+    }
+    let key_id: Option<i64> = row.try_get("key_id")?;
+    let key_id = key_id
+        .map(|id| u32::try_from(id).map_err(|_| StorageError::corrupt(CodecError::InvalidKeyId(id))))
+        .transpose()?;
+    let template = StoredTemplate {
+        encrypted: EncryptedData {
+            ciphertext: row.try_get("ciphertext")?,
+            nonce,
+            key_id,
+            cipher,
+            header: row.try_get("header")?,
+        },
+        metadata: row.try_get("metadata")?,
+    };
+    Ok((row.try_get("id")?, template))
+}
+
+#[async_trait]
+impl TemplateStore for PostgresTemplateStore {
+    async fn put(&self, id: Uuid, template: StoredTemplate) -> Result<()> {
+        write(UPSERT, id, &template).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn replace(&self, id: Uuid, template: StoredTemplate) -> Result<bool> {
+        let result = write(UPDATE, id, &template).execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get(&self, id: Uuid) -> Result<Option<StoredTemplate>> {
+        let row = sqlx::query(SELECT_ONE)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(decode_row).transpose()?.map(|(_, template)| template))
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<bool> {
+        let result = sqlx::query(DELETE).bind(id).execute(&self.pool).await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    fn iter(&self) -> BoxStream<'_, Result<(Uuid, StoredTemplate)>> {
+        sqlx::query(SELECT_ALL)
+            .fetch(&self.pool)
+            .map(|row| decode_row(row?))
+            .boxed()
+    }
+
+    async fn apply_batch(&self, ops: Vec<StoreOp>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for op in &ops {
+            match op {
+                StoreOp::Put(id, template) => write(UPSERT, *id, template).execute(&mut *tx).await?,
+                StoreOp::Replace(id, template) => write(UPDATE, *id, template).execute(&mut *tx).await?,
+                StoreOp::Delete(id) => sqlx::query(DELETE).bind(id).execute(&mut *tx).await?,
+            };
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<usize> {
+        let count: i64 = sqlx::query_scalar(COUNT).fetch_one(&self.pool).await?;
+        Ok(count as usize)
+    }
+
+    async fn get_key(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(sqlx::query_scalar(SELECT_KEY)
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn put_keys(&self, entries: Vec<(&str, Vec<u8>)>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for (name, value) in entries {
+            sqlx::query(UPSERT_KEY).bind(name).bind(value).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
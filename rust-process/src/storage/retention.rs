@@ -0,0 +1,126 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::Template;
+use chrono::{DateTime, TimeZone, Utc};
+use sled::transaction::{ConflictableTransactionError, TransactionResult, Transactional};
+use uuid::Uuid;
+
+/// Encode an expiry time as big-endian milliseconds since the epoch
+pub(super) fn encode_expiry(expires_at: DateTime<Utc>) -> [u8; 8] {
+    expires_at.timestamp_millis().to_be_bytes()
+}
+
+pub(super) fn decode_expiry(bytes: &[u8]) -> Option<DateTime<Utc>> {
+    let millis = i64::from_be_bytes(bytes.try_into().ok()?);
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+impl TemplateVault {
+    /// Store a template that must be deleted after `expires_at`
+    pub async fn store_with_expiry(
+        &self,
+        template: Template,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Uuid> {
+        self.store_entry(template, Some(expires_at), None, &AuditContext::default())
+            .await
+            .map(|outcome| outcome.id)
+    }
+
+    /// Set or clear the expiry time of a stored template
+    pub async fn set_expiry(&self, id: Uuid, expires_at: Option<DateTime<Utc>>) -> Result<()> {
+        self.ensure_writable()?;
+        let db = self.db.write().await;
+        let mut head = self.audit.lock_head();
+        let batch = head.chain(
+            [(AuditOperation::SetExpiry, Some(id), None)],
+            &AuditContext::default(),
+            self.clock.now_utc(),
+        )?;
+        let result: TransactionResult<(), StorageError> =
+            (&**db, &self.expiry, self.audit.tree()).transaction(|(templates, expiry, audit)| {
+                if templates.get(id.as_bytes())?.is_none() {
+                    return Err(ConflictableTransactionError::Abort(StorageError::NotFound(id)));
+                }
+                match expires_at {
+                    Some(expires_at) => expiry.insert(id.as_bytes(), &encode_expiry(expires_at))?,
+                    None => expiry.remove(id.as_bytes())?,
+                };
+                batch.write(audit)?;
+                Ok(())
+            });
+        result?;
+        head.advance(&batch);
+        Ok(())
+    }
+
+    /// Expiry time of a stored template, if it has one
+    pub async fn expires_at(&self, id: Uuid) -> Result<Option<DateTime<Utc>>> {
+        let _db = self.db.read().await;
+        Ok(self.expiry.get(id.as_bytes())?.and_then(|v| decode_expiry(&v)))
+    }
+
+    /// Delete every template whose expiry time has passed
+    ///
+    /// Returns the number of templates removed.
+    pub async fn purge_expired(&self) -> Result<usize> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation_guard().await?;
+        let now = self.clock.now_utc();
+        let db = self.db.write().await;
+
+        let mut expired = Vec::new();
+        for item in self.expiry.iter() {
+            let (key, value) = item?;
+            if decode_expiry(&value).is_some_and(|at| at <= now) {
+                if let Ok(id) = Uuid::from_slice(&key) {
+                    expired.push(id);
+                }
+            }
+        }
+
+        for id in &expired {
+            self.remove_entry(&db, *id, &AuditContext::default())?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Whether template `id` is stored and neither soft-deleted nor expired
+    ///
+    /// Finders return only live templates, since soft-deleted and expired
+    /// ones keep their lookup, subject and alias entries. The caller must
+    /// hold the database lock.
+    pub(super) fn is_live(&self, db: &sled::Tree, id: Uuid) -> Result<bool> {
+        if !db.contains_key(id.as_bytes())? {
+            return Ok(false);
+        }
+        match self.check_expiry(id) {
+            Ok(()) => Ok(true),
+            Err(StorageError::Expired { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fail with `StorageError::Expired` if the template is past due
+    ///
+    /// The caller must hold the database lock.
+    pub(super) fn check_expiry(&self, id: Uuid) -> Result<()> {
+        self.check_expiry_in(&self.expiry, id)
+    }
+
+    /// Check the expiry of a template recorded in the `expiry` tree given
+    pub(super) fn check_expiry_in(&self, expiry: &sled::Tree, id: Uuid) -> Result<()> {
+        let expired_at = match expiry.get(id.as_bytes())? {
+            Some(value) => decode_expiry(&value),
+            None => None,
+        };
+        match expired_at {
+            Some(expired_at) if expired_at <= self.clock.now_utc() => {
+                Err(StorageError::Expired { id, expired_at })
+            }
+            _ => Ok(()),
+        }
+    }
+}
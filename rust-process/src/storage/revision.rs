@@ -0,0 +1,59 @@
+use super::audit::AuditContext;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::Template;
+use sled::IVec;
+use std::ops::Deref;
+use uuid::Uuid;
+
+/// Revision given to a newly stored template
+pub(super) const FIRST_REVISION: u64 = 1;
+
+/// Decode a stored revision counter
+///
+/// Entries written before revisions were tracked report revision 0.
+pub(super) fn revision_of(value: Option<IVec>) -> u64 {
+    value
+        .and_then(|v| v.as_ref().try_into().ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or(0)
+}
+
+/// A template together with the revision it was read at
+#[derive(Debug, Clone)]
+pub struct VersionedTemplate {
+    pub template: Template,
+    /// Incremented on every write; pass it to `compare_and_swap`
+    pub revision: u64,
+}
+
+impl VersionedTemplate {
+    /// Discard the revision
+    pub fn into_inner(self) -> Template {
+        self.template
+    }
+}
+
+impl Deref for VersionedTemplate {
+    type Target = Template;
+
+    fn deref(&self) -> &Template {
+        &self.template
+    }
+}
+
+impl TemplateVault {
+    /// Replace a template only if it is still at `expected_revision`
+    ///
+    /// Returns the new revision, or `StorageError::Conflict` if another
+    /// write landed since the template was read.
+    pub async fn compare_and_swap(
+        &self,
+        id: Uuid,
+        expected_revision: u64,
+        template: Template,
+    ) -> Result<u64> {
+        self.replace_entry(id, template, Some(expected_revision), &AuditContext::default())
+            .await
+    }
+}
@@ -0,0 +1,496 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::events::VaultEvent;
+use super::format::decode_envelope;
+use super::keyring::ROTATION_JOURNAL;
+use super::vault::{EncryptedTree, TemplateVault};
+use super::Result;
+use crate::security::{KeyId, KeyStatus};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{TransactionResult, Transactional};
+use sled::IVec;
+use std::time::Instant;
+use tokio::sync::RwLockReadGuard;
+
+/// Default number of entries re-encrypted and committed together with a
+/// journal update
+pub(super) const ROTATION_CHUNK: usize = 256;
+
+/// Whether a key rotation was left unfinished, e.g. by a crash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationStatus {
+    /// No rotation is pending
+    Idle,
+    /// A rotation was interrupted; `resume_rotation` finishes it
+    Interrupted {
+        /// Entries already re-encrypted with the new key
+        entries_done: u64,
+    },
+}
+
+/// Progress of the running or most recent key rotation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RotationProgress {
+    /// Entries re-encrypted with the new key, including those of an
+    /// interrupted run
+    pub entries_done: u64,
+    /// Entries to re-encrypt in total
+    pub total: u64,
+    /// Most entries held in memory at once
+    pub largest_chunk: usize,
+}
+
+/// Progress of a rotation, stored in the keyring after every chunk
+#[derive(Debug, Serialize, Deserialize)]
+struct RotationJournal {
+    /// Name of the tree being re-encrypted
+    tree: Vec<u8>,
+    /// Last key of `tree` already re-encrypted
+    resume_after: Vec<u8>,
+    /// Entries re-encrypted over all trees
+    entries_done: u64,
+}
+
+impl TemplateVault {
+    /// Rotate encryption key and re-encrypt all templates, in every namespace
+    pub async fn rotate_key(&self) -> Result<()> {
+        self.rotate_key_with_context(&AuditContext::default()).await
+    }
+
+    /// Rotate the key, attributing the operation in the audit log
+    ///
+    /// Writes of encrypted values wait until the rotation has finished, so
+    /// every entry ends up sealed with the new key. An interrupted rotation
+    /// is finished first.
+    pub async fn rotate_key_with_context(&self, context: &AuditContext) -> Result<()> {
+        let started = Instant::now();
+        self.ensure_writable()?;
+        let _rotation = self.rotation.write().await;
+        self.rotate_locked(context).await?;
+        self.observe_operation("rotate", started);
+        Ok(())
+    }
+
+    /// Rotate the key while holding the rotation lock exclusively
+    async fn rotate_locked(&self, context: &AuditContext) -> Result<()> {
+        if self.rotation_pending() {
+            self.complete_rotation(self.load_journal()?).await?;
+        }
+
+        // Keep the final usage of the old key, which rotation resets
+        self.persist_usage()?;
+        // Start key rotation, keeping the old key recoverable until done
+        self.encryption.rotate_key().await
+            .map_err(StorageError::Encryption)?;
+        self.persist_keys().await?;
+        self.complete_rotation(None).await?;
+
+        self.record_audit(AuditOperation::Rotate, None, context)?;
+        self.emit(VaultEvent::KeyRotated);
+        Ok(())
+    }
+
+    /// Report whether a key rotation was left unfinished
+    pub async fn rotation_status(&self) -> Result<RotationStatus> {
+        let _rotation = self.rotation.read().await;
+        if !self.rotation_pending() {
+            return Ok(RotationStatus::Idle);
+        }
+        let entries_done = self.load_journal()?.map_or(0, |journal| journal.entries_done);
+        Ok(RotationStatus::Interrupted { entries_done })
+    }
+
+    /// Finish an interrupted key rotation
+    ///
+    /// Entries recorded as done in the rotation journal are skipped. Returns
+    /// `false` if no rotation was pending.
+    pub async fn resume_rotation(&self) -> Result<bool> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation.write().await;
+        if !self.rotation_pending() {
+            return Ok(false);
+        }
+        self.complete_rotation(self.load_journal()?).await?;
+        self.record_audit(AuditOperation::Rotate, None, &AuditContext::default())?;
+        self.emit(VaultEvent::KeyRotated);
+        Ok(true)
+    }
+
+    /// Retire the old data key `key_id` once no stored value needs it
+    ///
+    /// Old keys outlive a rotation only when it was interrupted. Fails with
+    /// `StorageError::KeyInUse` while values encrypted with the key remain;
+    /// values written before key IDs existed count as needing every old key.
+    pub async fn retire_key(&self, key_id: KeyId) -> Result<()> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation.write().await;
+        let key_manager = self.encryption.key_manager();
+        if !key_manager.old_key_ids().contains(&key_id) {
+            // Fails, naming why the key cannot be retired
+            key_manager.retire_key(key_id).await?;
+        }
+
+        let mut entries = 0;
+        for EncryptedTree { tree, header_len, .. } in self.encrypted_trees().await? {
+            for item in tree.iter() {
+                let (_, value) = item?;
+                let encrypted = decode_envelope(&value[header_len.min(value.len())..])?;
+                if encrypted.key_id.is_none_or(|id| id == key_id) {
+                    entries += 1;
+                }
+            }
+        }
+        if entries > 0 {
+            return Err(StorageError::KeyInUse { key_id, entries });
+        }
+
+        key_manager.retire_key(key_id).await?;
+        self.persist_keys().await?;
+        self.observe_retired_keys();
+        Ok(())
+    }
+
+    /// Progress of the running key rotation, or of the last one to finish
+    ///
+    /// `None` until a rotation runs. Unlike `rotation_status`, this does not
+    /// wait for a running rotation.
+    pub fn rotation_progress(&self) -> Option<RotationProgress> {
+        *self.rotation_progress.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Usage of the current data key
+    ///
+    /// Counts survive reopening vaults protected by a master key.
+    pub fn key_status(&self) -> KeyStatus {
+        self.encryption.key_manager().key_status()
+    }
+
+    /// Keep key rotation from starting until the returned guard is dropped
+    ///
+    /// Writes hold it from sealing a value until the value is committed.
+    /// Acquire it before the database lock. If the current key has reached
+    /// its hard limit, it is rotated first.
+    pub(super) async fn rotation_guard(&self) -> Result<RwLockReadGuard<'_, ()>> {
+        let key_manager = self.encryption.key_manager();
+        if !self.read_only && key_manager.key_status().limit_reached {
+            let _rotation = self.rotation.write().await;
+            // Another write may have rotated the key while this one waited
+            let status = key_manager.key_status();
+            if status.limit_reached {
+                log::warn!(
+                    "Encryption key {} reached its usage limit; rotating it",
+                    status.key_id
+                );
+                self.rotate_locked(&AuditContext::default()).await?;
+            }
+        }
+        Ok(self.rotation.read().await)
+    }
+
+    /// Whether the key manager still holds keys of an unfinished rotation
+    fn rotation_pending(&self) -> bool {
+        !self.encryption.key_manager().old_key_ids().is_empty()
+    }
+
+    fn load_journal(&self) -> Result<Option<RotationJournal>> {
+        self.keyring
+            .get(ROTATION_JOURNAL)?
+            .map(|value| bincode::deserialize(&value).map_err(StorageError::corrupt))
+            .transpose()
+    }
+
+    /// Trees whose values are all encrypted, in rotation order
+    async fn encrypted_trees(&self) -> Result<Vec<EncryptedTree>> {
+        let mut trees = self.entry_trees(&*self.db.read().await).encrypted();
+        for namespace in self.namespace_trees().await? {
+            trees.extend(namespace.encrypted());
+        }
+        Ok(trees)
+    }
+
+    /// Re-encrypt every entry not yet covered by `journal`, then retire the
+    /// old keys
+    async fn complete_rotation(&self, journal: Option<RotationJournal>) -> Result<()> {
+        let trees = self.encrypted_trees().await?;
+
+        // Trees before the journaled one are done; if it no longer exists,
+        // start over, since re-encrypting an entry twice is harmless
+        let (start, mut resume_after, mut entries_done) = match journal {
+            Some(journal) => match trees.iter().position(|t| t.tree.name() == journal.tree) {
+                Some(start) => (start, Some(journal.resume_after), journal.entries_done),
+                None => (0, None, 0),
+            },
+            None => (0, None, 0),
+        };
+        let mut remaining = 0;
+        for encrypted in &trees[start + 1..] {
+            remaining += encrypted.tree.len() as u64;
+        }
+        if let Some(encrypted) = trees.get(start) {
+            remaining += Self::range_after(&encrypted.tree, resume_after.as_deref()).count() as u64;
+        }
+        self.set_rotation_progress(RotationProgress {
+            entries_done,
+            total: entries_done + remaining,
+            largest_chunk: 0,
+        });
+
+        for encrypted in &trees[start..] {
+            self.reencrypt_tree(encrypted, resume_after.take(), &mut entries_done)
+                .await?;
+        }
+        self.db.write().await.flush()?;
+
+        // Finish key rotation
+        self.encryption.finish_rotation().await
+            .map_err(StorageError::Encryption)?;
+        self.persist_keys().await?;
+        self.observe_retired_keys();
+        let now = self.clock.now_utc();
+        self.activity.record_flush(now);
+        self.activity.record_rotation(now);
+        Ok(())
+    }
+
+    /// Re-encrypt the values of a tree after `resume_after` with the
+    /// current key
+    ///
+    /// Values are read and committed in chunks of the configured rotation
+    /// chunk size, so memory use does not grow with the vault. The header
+    /// of each value is plaintext and kept as is. The caller must hold the
+    /// rotation lock exclusively, so no write lands between reading a chunk
+    /// and applying it.
+    async fn reencrypt_tree(
+        &self,
+        encrypted: &EncryptedTree,
+        mut resume_after: Option<Vec<u8>>,
+        entries_done: &mut u64,
+    ) -> Result<()> {
+        let EncryptedTree { tree, header_len, namespace } = encrypted;
+        loop {
+            let chunk = {
+                let _db = self.db.read().await;
+                Self::range_after(tree, resume_after.as_deref())
+                    .take(self.rotation_chunk_size)
+                    .collect::<sled::Result<Vec<_>>>()?
+            };
+            let Some((last, _)) = chunk.last() else {
+                return Ok(());
+            };
+            resume_after = Some(last.to_vec());
+
+            // Decrypt with the old key and re-encrypt with the new one
+            let mut sealed = Vec::with_capacity(chunk.len());
+            for (key, value) in &chunk {
+                let (header, old) = value.split_at((*header_len).min(value.len()));
+                let mut value = header.to_vec();
+                value.extend_from_slice(&self.reseal(key, old, namespace.as_deref()).await?);
+                sealed.push((key.clone(), value));
+            }
+            *entries_done += chunk.len() as u64;
+            self.commit_reencrypted(tree, sealed, *entries_done).await?;
+            self.record_rotation_chunk(*entries_done, chunk.len());
+
+            // Let reads waiting on the database lock in between chunks
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Entries of `tree` after `after`, or all of them
+    fn range_after(tree: &sled::Tree, after: Option<&[u8]>) -> sled::Iter {
+        match after {
+            Some(after) => tree.range::<&[u8], _>((
+                std::ops::Bound::Excluded(after),
+                std::ops::Bound::Unbounded,
+            )),
+            None => tree.iter(),
+        }
+    }
+
+    fn set_rotation_progress(&self, progress: RotationProgress) {
+        *self.rotation_progress.lock().unwrap_or_else(|e| e.into_inner()) = Some(progress);
+    }
+
+    fn record_rotation_chunk(&self, entries_done: u64, chunk_len: usize) {
+        let mut progress = self.rotation_progress.lock().unwrap_or_else(|e| e.into_inner());
+        let progress = progress.get_or_insert_with(RotationProgress::default);
+        progress.entries_done = entries_done;
+        progress.total = progress.total.max(entries_done);
+        progress.largest_chunk = progress.largest_chunk.max(chunk_len);
+    }
+
+    /// Apply re-encrypted values and record them in the rotation journal
+    ///
+    /// The journal is only kept for vaults whose keys survive a restart.
+    pub(super) async fn commit_reencrypted(
+        &self,
+        tree: &sled::Tree,
+        sealed: Vec<(IVec, Vec<u8>)>,
+        entries_done: u64,
+    ) -> Result<()> {
+        let Some((last, _)) = sealed.last() else {
+            return Ok(());
+        };
+        let journal = RotationJournal {
+            tree: tree.name().to_vec(),
+            resume_after: last.to_vec(),
+            entries_done,
+        };
+        let journal = bincode::serialize(&journal).map_err(StorageError::encode)?;
+
+        let _db = self.db.write().await;
+        let result: TransactionResult<(), StorageError> =
+            (tree, &self.keyring).transaction(|(tree, keyring)| {
+                for (key, value) in &sealed {
+                    tree.insert(key, value.as_slice())?;
+                }
+                if self.key_provider.is_some() {
+                    keyring.insert(ROTATION_JOURNAL, journal.as_slice())?;
+                }
+                Ok(())
+            });
+        result?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::{KeySource, SecurityError};
+    use crate::templates::{Template, TemplateType};
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn key() -> KeySource {
+        KeySource::Bytes(vec![9; 32])
+    }
+
+    /// Open the vault again, waiting for sled to release the previous lock
+    async fn reopen(path: &Path) -> Result<TemplateVault> {
+        for _ in 0..100 {
+            match TemplateVault::open_with_key(path, key()).await {
+                Err(StorageError::Locked { .. }) => {
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await
+                }
+                result => return result,
+            }
+        }
+        TemplateVault::open_with_key(path, key()).await
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_rotation_resumes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let vault = TemplateVault::open_with_key(temp_dir.path(), key()).await?;
+        let mut stored = Vec::new();
+        for i in 0..10u8 {
+            let template = Template::builder()
+                .data(vec![i; 8])
+                .template_type(TemplateType::Face)
+                .quality_score(0.9)
+                .build()
+                .unwrap();
+            stored.push((vault.store(template).await?, vec![i; 8]));
+        }
+        assert_eq!(vault.rotation_status().await?, RotationStatus::Idle);
+
+        // Rotate only half of the templates, as if the process died midway
+        vault.encryption.rotate_key().await?;
+        vault.persist_keys().await?;
+        let templates: sled::Tree = (**vault.db.read().await).clone();
+        let mut sealed = Vec::new();
+        for item in templates.iter().take(5) {
+            let (key, value) = item?;
+            let value = vault.reseal(&key, &value, None).await?;
+            sealed.push((key, value));
+        }
+        vault.commit_reencrypted(&templates, sealed, 5).await?;
+        vault.db.read().await.flush()?;
+        drop(templates);
+        drop(vault);
+
+        // Both keys survive the restart, so every entry still decrypts
+        let vault = reopen(temp_dir.path()).await?;
+        assert_eq!(
+            vault.rotation_status().await?,
+            RotationStatus::Interrupted { entries_done: 5 }
+        );
+        for (id, data) in &stored {
+            assert_eq!(&vault.get(*id).await?.data, data);
+        }
+
+        assert!(vault.resume_rotation().await?);
+        assert_eq!(vault.rotation_status().await?, RotationStatus::Idle);
+        assert!(!vault.resume_rotation().await?);
+        drop(vault);
+
+        // Only the new key is kept now, so every entry must use it
+        let vault = reopen(temp_dir.path()).await?;
+        assert_eq!(vault.rotation_status().await?, RotationStatus::Idle);
+        for (id, data) in &stored {
+            assert_eq!(&vault.get(*id).await?.data, data);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_old_keys_retired_once_unused() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let vault = TemplateVault::open_with_key(temp_dir.path(), key()).await?;
+        let mut stored = Vec::new();
+        for i in 0..4u8 {
+            let template = Template::builder()
+                .data(vec![i; 8])
+                .template_type(TemplateType::Iris)
+                .quality_score(0.9)
+                .build()
+                .unwrap();
+            stored.push((vault.store(template).await?, vec![i; 8]));
+        }
+        let first = vault.encryption.key_manager().current_key_id();
+
+        // Two rotations interrupted before re-encrypting anything
+        vault.encryption.rotate_key().await?;
+        vault.encryption.rotate_key().await?;
+        vault.persist_keys().await?;
+        let second = vault.encryption.key_manager().old_key_ids()[1];
+        drop(vault);
+
+        // Every generation survives the restart
+        let vault = reopen(temp_dir.path()).await?;
+        assert_eq!(vault.encryption.key_manager().old_key_ids(), vec![first, second]);
+        for (id, data) in &stored {
+            assert_eq!(&vault.get(*id).await?.data, data);
+        }
+
+        // The first key still encrypts every template and its metadata
+        assert!(matches!(
+            vault.retire_key(first).await,
+            Err(StorageError::KeyInUse { key_id, entries: 8 }) if key_id == first
+        ));
+
+        // Nothing was encrypted with the second key, so it can go
+        vault.retire_key(second).await?;
+        assert!(matches!(
+            vault.retire_key(second).await,
+            Err(StorageError::Encryption(SecurityError::KeyUnavailable { .. }))
+        ));
+
+        // Re-encrypt without finishing the rotation, then retire the first
+        let mut entries_done = 0;
+        for encrypted in vault.encrypted_trees().await? {
+            vault.reencrypt_tree(&encrypted, None, &mut entries_done).await?;
+        }
+        vault.retire_key(first).await?;
+        assert!(vault.encryption.key_manager().old_key_ids().is_empty());
+        drop(vault);
+
+        let vault = reopen(temp_dir.path()).await?;
+        assert_eq!(vault.rotation_status().await?, RotationStatus::Idle);
+        for (id, data) in &stored {
+            assert_eq!(&vault.get(*id).await?.data, data);
+        }
+        Ok(())
+    }
+}
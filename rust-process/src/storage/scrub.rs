@@ -0,0 +1,136 @@
+use super::error::StorageError;
+use super::format::binding_of;
+use super::tombstone::TOMBSTONE_HEADER_LEN;
+use super::vault::{EntryTrees, TemplateVault};
+use super::Result;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// Outcome of checking every stored entry
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IntegrityReport {
+    /// Number of stored values checked: templates of every namespace,
+    /// their history revisions and soft-deleted templates
+    pub checked: usize,
+    /// Templates whose ciphertext failed AEAD authentication
+    pub auth_failures: Vec<Uuid>,
+    /// Templates whose stored value or plaintext could not be deserialized,
+    /// or whose ciphertext is cut short
+    pub malformed: Vec<Uuid>,
+    /// Templates encrypted with a key the vault no longer has
+    pub key_unavailable: Vec<Uuid>,
+    /// Templates that failed to decrypt for another reason, such as a key
+    /// provider error, which says nothing about the stored value
+    pub decryption_errors: Vec<Uuid>,
+}
+
+impl IntegrityReport {
+    /// Whether every entry verified
+    pub fn is_clean(&self) -> bool {
+        self.auth_failures.is_empty()
+            && self.malformed.is_empty()
+            && self.key_unavailable.is_empty()
+            && self.decryption_errors.is_empty()
+    }
+}
+
+impl TemplateVault {
+    /// Decrypt every template and its metadata, discarding the plaintext
+    ///
+    /// Covers every namespace, history revisions and soft-deleted
+    /// templates, which are reported under their template ID. Corrupted
+    /// entries are reported rather than failing the whole scan.
+    pub async fn verify_all(&self) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+        let trees = self.entry_trees(&*self.db.read().await);
+        self.verify_trees(&trees, &mut report).await?;
+        for trees in self.namespace_trees().await? {
+            self.verify_trees(&trees, &mut report).await?;
+        }
+        Ok(report)
+    }
+
+    /// Check the templates, history and tombstones of one set of trees
+    async fn verify_trees(&self, trees: &EntryTrees, report: &mut IntegrityReport) -> Result<()> {
+        let entries = {
+            let _db = self.db.read().await;
+            let mut entries = Vec::new();
+            for item in trees.templates.iter() {
+                let (key, value) = item?;
+                if let Ok(id) = Uuid::from_slice(&key) {
+                    entries.push((id, value.to_vec(), trees.metadata.get(&key)?));
+                }
+            }
+            for item in trees.history.iter() {
+                let (key, value) = item?;
+                if let Ok(id) = binding_of(&key) {
+                    entries.push((id, value.to_vec(), None));
+                }
+            }
+            for item in trees.tombstones.iter() {
+                let (key, value) = item?;
+                if let Ok(id) = Uuid::from_slice(&key) {
+                    entries.push((id, value.get(TOMBSTONE_HEADER_LEN..).unwrap_or_default().to_vec(), None));
+                }
+            }
+            entries
+        };
+
+        let namespace = trees.namespace.as_deref();
+        for (id, value, metadata) in entries {
+            report.checked += 1;
+            let mut result = self.decode_template_in(namespace, id, &value).await.map(|_| ());
+            if let (Ok(()), Some(metadata)) = (&result, metadata) {
+                result = self.decode_metadata(&metadata).await.map(|_| ());
+            }
+            match result.map_err(|e| e.for_template(id)) {
+                Ok(()) => {}
+                Err(StorageError::AuthenticationFailed { .. }) => report.auth_failures.push(id),
+                Err(StorageError::MalformedCiphertext { .. } | StorageError::Corrupt(_)) => {
+                    report.malformed.push(id)
+                }
+                Err(StorageError::KeyUnavailable { .. }) => report.key_unavailable.push(id),
+                Err(StorageError::Encryption(e)) => {
+                    log::warn!("Integrity scrub could not decrypt template {}: {}", id, e);
+                    report.decryption_errors.push(id)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `verify_all` every `interval` in a background task
+    ///
+    /// Failures are logged; abort the returned handle to stop scrubbing.
+    /// The task keeps the vault open while it runs.
+    pub fn start_scrubber(&self, interval: Duration) -> JoinHandle<()> {
+        let vault = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match vault.verify_all().await {
+                    Ok(report) if report.is_clean() => {
+                        log::debug!("Integrity scrub checked {} templates", report.checked);
+                    }
+                    Ok(report) => log::error!(
+                        "Integrity scrub found {} corrupted, {} malformed, {} templates with a missing key and {} that failed to decrypt: {:?} {:?} {:?} {:?}",
+                        report.auth_failures.len(),
+                        report.malformed.len(),
+                        report.key_unavailable.len(),
+                        report.decryption_errors.len(),
+                        report.auth_failures,
+                        report.malformed,
+                        report.key_unavailable,
+                        report.decryption_errors
+                    ),
+                    Err(e) => log::error!("Integrity scrub failed: {}", e),
+                }
+            }
+        })
+    }
+}
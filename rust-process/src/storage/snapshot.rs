@@ -0,0 +1,90 @@
+use super::config::VaultConfig;
+use super::error::StorageError;
+use super::vault::TemplateVault;
+use super::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Entries copied per batch while taking a snapshot
+const SNAPSHOT_BATCH: usize = 1024;
+
+/// Description of a snapshot written by `TemplateVault::snapshot`
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotInfo {
+    /// Directory holding the snapshot
+    pub path: PathBuf,
+    /// Number of templates in the snapshot
+    pub entries: usize,
+    /// When the snapshot was taken
+    pub created_at: DateTime<Utc>,
+    /// Size of the snapshot as reported by sled
+    pub size_on_disk: u64,
+}
+
+impl TemplateVault {
+    /// Copy the vault into a new database at `dest_dir`
+    ///
+    /// Writes and key rotations wait while the copy is taken, so the
+    /// snapshot is consistent. It carries the data keys wrapped by the
+    /// master key and opens with the same key source via `restore_from`,
+    /// however often the source vault is rotated later. Vaults with an
+    /// in-memory key cannot be snapshotted.
+    pub async fn snapshot(&self, dest_dir: &Path) -> Result<SnapshotInfo> {
+        if self.key_provider.is_none() {
+            return Err(StorageError::NoMasterKey);
+        }
+        if dest_dir.exists() && std::fs::read_dir(dest_dir)?.next().is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("snapshot directory {} is not empty", dest_dir.display()),
+            )
+            .into());
+        }
+
+        let _rotation = self.rotation.write().await;
+        let db = self.db.write().await;
+        let created_at = self.clock.now_utc();
+        let dest = sled::Config::new().path(dest_dir).open()?;
+
+        for name in db.tree_names() {
+            let source = db.open_tree(&name)?;
+            let target = dest.open_tree(&name)?;
+            let mut batch = sled::Batch::default();
+            let mut pending = 0;
+            for item in source.iter() {
+                let (key, value) = item?;
+                batch.insert(key, value);
+                pending += 1;
+                if pending == SNAPSHOT_BATCH {
+                    target.apply_batch(std::mem::take(&mut batch))?;
+                    pending = 0;
+                }
+            }
+            target.apply_batch(batch)?;
+        }
+        let entries = db.len();
+        drop(db);
+
+        dest.flush_async().await?;
+        let size_on_disk = dest.size_on_disk()?;
+        Ok(SnapshotInfo {
+            path: dest_dir.to_path_buf(),
+            entries,
+            created_at,
+            size_on_disk,
+        })
+    }
+
+    /// Open a snapshot taken by `snapshot` as a vault
+    ///
+    /// `config` must supply the master key of the vault the snapshot was
+    /// taken from. The snapshot directory becomes the vault's storage, so
+    /// copy it first to keep the snapshot itself unchanged.
+    pub async fn restore_from<P: AsRef<Path>>(src_dir: P, config: VaultConfig) -> Result<Self> {
+        if config.key_source.is_none() {
+            return Err(StorageError::NoMasterKey);
+        }
+        Self::with_config(src_dir, config).await
+    }
+}
@@ -0,0 +1,67 @@
+use super::vault::TemplateVault;
+use super::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// Size and activity figures for a vault
+#[derive(Debug, Clone, Serialize)]
+pub struct VaultStats {
+    /// Number of stored templates
+    pub entries: usize,
+    /// Total size of encrypted template and metadata values
+    pub ciphertext_bytes: u64,
+    /// Size of the database directory as reported by sled
+    pub size_on_disk: u64,
+    /// Last explicit flush by this process, including during key rotation
+    pub last_flush: Option<DateTime<Utc>>,
+    /// Last completed key rotation by this process
+    pub last_rotation: Option<DateTime<Utc>>,
+}
+
+/// Timestamps of vault operations, shared between clones of a vault
+#[derive(Debug, Default)]
+pub(super) struct Activity {
+    last_flush: Mutex<Option<DateTime<Utc>>>,
+    last_rotation: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl Activity {
+    pub(super) fn record_flush(&self, at: DateTime<Utc>) {
+        *self.last_flush.lock().unwrap_or_else(|e| e.into_inner()) = Some(at);
+    }
+
+    pub(super) fn record_rotation(&self, at: DateTime<Utc>) {
+        *self.last_rotation.lock().unwrap_or_else(|e| e.into_inner()) = Some(at);
+    }
+
+    fn last_flush(&self) -> Option<DateTime<Utc>> {
+        *self.last_flush.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn last_rotation(&self) -> Option<DateTime<Utc>> {
+        *self.last_rotation.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl TemplateVault {
+    /// Gather entry counts and storage sizes
+    pub async fn stats(&self) -> Result<VaultStats> {
+        let db = self.db.read().await;
+
+        let mut ciphertext_bytes = 0u64;
+        for tree in [&**db, &self.metadata] {
+            for value in tree.iter().values() {
+                ciphertext_bytes += value?.len() as u64;
+            }
+        }
+
+        Ok(VaultStats {
+            entries: db.len(),
+            ciphertext_bytes,
+            size_on_disk: db.size_on_disk()?,
+            last_flush: self.activity.last_flush(),
+            last_rotation: self.activity.last_rotation(),
+        })
+    }
+}
@@ -0,0 +1,191 @@
+use super::error::StorageError;
+use super::Result;
+use crate::security::EncryptedData;
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sled::transaction::TransactionResult;
+use uuid::Uuid;
+
+/// Encrypted template as persisted by a `TemplateStore`
+///
+/// Stores only ever see ciphertext; `metadata` holds the unencrypted
+/// descriptor written by `StoreVault`, currently just the modality.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredTemplate {
+    pub encrypted: EncryptedData,
+    pub metadata: serde_json::Value,
+}
+
+/// One write of a batch applied with `TemplateStore::apply_batch`
+#[derive(Debug, Clone)]
+pub enum StoreOp {
+    Put(Uuid, StoredTemplate),
+    /// Replace the template stored under the ID; nothing is written if it
+    /// was deleted in the meantime
+    Replace(Uuid, StoredTemplate),
+    Delete(Uuid),
+}
+
+/// Persistence of encrypted templates, keyed by template ID
+#[async_trait]
+pub trait TemplateStore: Send + Sync {
+    /// Insert or replace the template stored under `id`
+    async fn put(&self, id: Uuid, template: StoredTemplate) -> Result<()>;
+
+    async fn get(&self, id: Uuid) -> Result<Option<StoredTemplate>>;
+
+    /// Replace the template stored under `id`, returning whether it existed
+    ///
+    /// Nothing is written if no template is stored under `id`.
+    async fn replace(&self, id: Uuid, template: StoredTemplate) -> Result<bool>;
+
+    /// Remove the template stored under `id`, returning whether it existed
+    async fn delete(&self, id: Uuid) -> Result<bool>;
+
+    /// Stream every stored template, ordered by ID
+    fn iter(&self) -> BoxStream<'_, Result<(Uuid, StoredTemplate)>>;
+
+    /// Apply all of `ops` atomically
+    async fn apply_batch(&self, ops: Vec<StoreOp>) -> Result<()>;
+
+    /// Number of stored templates
+    async fn count(&self) -> Result<usize>;
+
+    /// Read the keyring entry `name`
+    ///
+    /// The keyring holds the wrapped data keys of `StoreVault`, kept apart
+    /// from the templates.
+    async fn get_key(&self, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Write keyring entries atomically, replacing existing ones
+    async fn put_keys(&self, entries: Vec<(&str, Vec<u8>)>) -> Result<()>;
+}
+
+/// `TemplateStore` keeping templates in a sled tree
+#[derive(Clone)]
+pub struct SledTemplateStore {
+    tree: sled::Tree,
+    keyring: sled::Tree,
+}
+
+impl SledTemplateStore {
+    pub fn new(tree: sled::Tree, keyring: sled::Tree) -> Self {
+        Self { tree, keyring }
+    }
+
+    /// Open the store in the tree `name` of `db`, with its keyring in the
+    /// tree `<name>_keyring`
+    pub fn open(db: &sled::Db, name: &str) -> Result<Self> {
+        Ok(Self::new(db.open_tree(name)?, db.open_tree(format!("{}_keyring", name))?))
+    }
+}
+
+/// `StoredTemplate` with its metadata as JSON text, which bincode can encode
+#[derive(Serialize, Deserialize)]
+struct SledRecord {
+    encrypted: EncryptedData,
+    metadata: Vec<u8>,
+}
+
+fn encode_record(template: &StoredTemplate) -> Result<Vec<u8>> {
+    let record = SledRecord {
+        encrypted: template.encrypted.clone(),
+        metadata: serde_json::to_vec(&template.metadata).map_err(StorageError::encode)?,
+    };
+    bincode::serialize(&record).map_err(StorageError::encode)
+}
+
+fn decode_record(value: &[u8]) -> Result<StoredTemplate> {
+    let record: SledRecord = bincode::deserialize(value).map_err(StorageError::corrupt)?;
+    Ok(StoredTemplate {
+        encrypted: record.encrypted,
+        metadata: serde_json::from_slice(&record.metadata).map_err(StorageError::corrupt)?,
+    })
+}
+
+#[async_trait]
+impl TemplateStore for SledTemplateStore {
+    async fn put(&self, id: Uuid, template: StoredTemplate) -> Result<()> {
+        self.tree.insert(id.as_bytes(), encode_record(&template)?)?;
+        Ok(())
+    }
+
+    async fn get(&self, id: Uuid) -> Result<Option<StoredTemplate>> {
+        self.tree
+            .get(id.as_bytes())?
+            .map(|value| decode_record(&value))
+            .transpose()
+    }
+
+    async fn replace(&self, id: Uuid, template: StoredTemplate) -> Result<bool> {
+        let value = encode_record(&template)?;
+        let result: TransactionResult<bool, StorageError> = self.tree.transaction(|tree| {
+            if tree.get(id.as_bytes())?.is_none() {
+                return Ok(false);
+            }
+            tree.insert(id.as_bytes(), value.as_slice())?;
+            Ok(true)
+        });
+        Ok(result?)
+    }
+
+    async fn delete(&self, id: Uuid) -> Result<bool> {
+        Ok(self.tree.remove(id.as_bytes())?.is_some())
+    }
+
+    fn iter(&self) -> BoxStream<'_, Result<(Uuid, StoredTemplate)>> {
+        let entries = self.tree.iter().filter_map(|item| match item {
+            Ok((key, value)) => {
+                let id = Uuid::from_slice(&key).ok()?;
+                Some(decode_record(&value).map(|template| (id, template)))
+            }
+            Err(e) => Some(Err(e.into())),
+        });
+        stream::iter(entries).boxed()
+    }
+
+    async fn apply_batch(&self, ops: Vec<StoreOp>) -> Result<()> {
+        let mut encoded = Vec::with_capacity(ops.len());
+        for op in &ops {
+            encoded.push(match op {
+                StoreOp::Put(id, template) => (*id, Some(encode_record(template)?), false),
+                StoreOp::Replace(id, template) => (*id, Some(encode_record(template)?), true),
+                StoreOp::Delete(id) => (*id, None, false),
+            });
+        }
+        let result: TransactionResult<(), StorageError> = self.tree.transaction(|tree| {
+            for (id, value, only_existing) in &encoded {
+                match value {
+                    Some(_) if *only_existing && tree.get(id.as_bytes())?.is_none() => {}
+                    Some(value) => {
+                        tree.insert(id.as_bytes(), value.as_slice())?;
+                    }
+                    None => {
+                        tree.remove(id.as_bytes())?;
+                    }
+                }
+            }
+            Ok(())
+        });
+        Ok(result?)
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Ok(self.tree.len())
+    }
+
+    async fn get_key(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.keyring.get(name)?.map(|value| value.to_vec()))
+    }
+
+    async fn put_keys(&self, entries: Vec<(&str, Vec<u8>)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (name, value) in entries {
+            batch.insert(name, value);
+        }
+        self.keyring.apply_batch(batch)?;
+        self.keyring.flush_async().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use super::compression::record_limit;
+use super::config::DEFAULT_MAX_TEMPLATE_SIZE;
+use super::error::StorageError;
+use super::format::{decode_template_plaintext, encode_template_plaintext, split_template_plaintext};
+use super::keyring::{parse_key_id, slot_context};
+use super::rotation::ROTATION_CHUNK;
+use super::store::{StoreOp, StoredTemplate, TemplateStore};
+use super::Result;
+use crate::security::{
+    generate_salt, BatchItem, EncryptionEngine, KeyId, KeyManager, KeySource, MasterKeyProvider, SecretBytes,
+    SecurityError,
+};
+use crate::templates::Template;
+use futures::stream::{Stream, StreamExt, TryStreamExt};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Keyring entry holding the salt for passphrase-derived master keys
+const SALT: &str = "salt";
+/// Keyring entry holding the wrapped current data key
+const CURRENT: &str = "current";
+/// Keyring entry holding the ID of the current data key, big-endian
+const CURRENT_ID: &str = "current_id";
+/// Keyring entry holding the wrapped keys of an unfinished rotation
+const OLD_KEYS: &str = "old_keys";
+
+/// Template vault persisting through a pluggable `TemplateStore`
+///
+/// Templates are encrypted before they reach the store, so it only ever
+/// sees ciphertext, bound to the template ID so rows cannot be swapped. Aliases, history, indexes and the other features of
+/// `TemplateVault` remain specific to its sled database.
+pub struct StoreVault<S> {
+    store: S,
+    encryption: Arc<EncryptionEngine>,
+    /// Wraps the data keys in the store's keyring; `None` for vaults with
+    /// an in-memory key
+    key_provider: Option<Arc<dyn MasterKeyProvider>>,
+    /// Shared by writes, held exclusively by key rotation
+    rotation: RwLock<()>,
+}
+
+impl<S: TemplateStore> StoreVault<S> {
+    /// Create a vault on `store` with a fresh in-memory key
+    pub fn new(store: S) -> Result<Self> {
+        let key_manager = KeyManager::new().map_err(StorageError::Encryption)?;
+        Ok(Self::with_encryption(
+            store,
+            Arc::new(EncryptionEngine::new(Arc::new(key_manager))),
+        ))
+    }
+
+    /// Open a vault on `store` whose data key is protected by a master key
+    ///
+    /// The first open generates the data key and stores it in the store's
+    /// keyring, wrapped by the master key; later opens fail with
+    /// `SecurityError::InvalidKey` if `key_source` yields a different
+    /// master key. A store holding templates but no data key is refused,
+    /// since they were encrypted with an in-memory key that is gone.
+    pub async fn open_with_key(store: S, key_source: KeySource) -> Result<Self> {
+        let salt = match store.get_key(SALT).await? {
+            Some(salt) => salt,
+            None => {
+                let salt = generate_salt()?.to_vec();
+                store.put_keys(vec![(SALT, salt.clone())]).await?;
+                salt
+            }
+        };
+        let provider = key_source.provider(&salt)?;
+
+        let (key_manager, created) = match store.get_key(CURRENT).await? {
+            Some(wrapped) => {
+                let current_id = parse_key_id(&store.get_key(CURRENT_ID).await?.unwrap_or_default())?;
+                let current = provider
+                    .unwrap_key(&wrapped, &slot_context(CURRENT.as_bytes(), Some(current_id)))
+                    .await?;
+                let mut old = Vec::new();
+                if let Some(wrapped) = store.get_key(OLD_KEYS).await? {
+                    let wrapped: Vec<(KeyId, Vec<u8>)> =
+                        bincode::deserialize(&wrapped).map_err(StorageError::corrupt)?;
+                    for (old_id, key) in wrapped {
+                        let context = slot_context(OLD_KEYS.as_bytes(), Some(old_id));
+                        old.push((old_id, provider.unwrap_key(&key, &context).await?));
+                    }
+                }
+                let key_manager = KeyManager::from_keys((current_id, current), old)?.with_provider(provider.clone());
+                (key_manager, false)
+            }
+            None if store.count().await? > 0 => {
+                return Err(StorageError::Encryption(SecurityError::InvalidKey(
+                    "store holds templates encrypted with an in-memory key".into(),
+                )));
+            }
+            None => (KeyManager::from_provider(provider.clone()).await?, true),
+        };
+
+        let vault = Self {
+            store,
+            encryption: Arc::new(EncryptionEngine::new(Arc::new(key_manager))),
+            key_provider: Some(provider),
+            rotation: RwLock::new(()),
+        };
+        if created {
+            vault.persist_keys().await?;
+        }
+        Ok(vault)
+    }
+
+    /// Create a vault on `store` encrypting with `encryption`
+    pub fn with_encryption(store: S, encryption: Arc<EncryptionEngine>) -> Self {
+        Self {
+            store,
+            encryption,
+            key_provider: None,
+            rotation: RwLock::new(()),
+        }
+    }
+
+    /// The underlying store
+    pub fn store_ref(&self) -> &S {
+        &self.store
+    }
+
+    /// Store a template under a new ID
+    pub async fn store(&self, mut template: Template) -> Result<Uuid> {
+        let _rotation = self.rotation.read().await;
+        let id = Uuid::new_v4();
+        template.id = Some(id);
+        self.store.put(id, self.seal(id, &template).await?).await?;
+        Ok(id)
+    }
+
+    /// Store several templates atomically, returning their IDs in order
+    pub async fn store_batch(&self, templates: Vec<Template>) -> Result<Vec<Uuid>> {
+        let _rotation = self.rotation.read().await;
+        let mut ids = Vec::with_capacity(templates.len());
+        let mut items = Vec::with_capacity(templates.len());
+        let mut metadata = Vec::with_capacity(templates.len());
+        for mut template in templates {
+            let id = Uuid::new_v4();
+            template.id = Some(id);
+            items.push(sealing_item(id, &template)?);
+            metadata.push(stored_metadata(&template));
+            ids.push(id);
+        }
+        // Encrypted in parallel, which dominates for large batches
+        let encrypted = self
+            .encryption
+            .encrypt_batch_with_header_and_aad(items)
+            .await
+            .map_err(StorageError::Encryption)?;
+        let ops = ids
+            .iter()
+            .zip(encrypted.into_iter().zip(metadata))
+            .map(|(&id, (encrypted, metadata))| StoreOp::Put(id, StoredTemplate { encrypted, metadata }))
+            .collect();
+        self.store.apply_batch(ops).await?;
+        Ok(ids)
+    }
+
+    /// Retrieve a template by ID
+    pub async fn get(&self, id: Uuid) -> Result<Template> {
+        let stored = self.store.get(id).await?.ok_or(StorageError::NotFound(id))?;
+        self.open(id, &stored).await
+    }
+
+    /// Replace an existing template
+    ///
+    /// Fails with `StorageError::NotFound` if it is not stored, including
+    /// when it is deleted concurrently.
+    pub async fn update(&self, id: Uuid, mut template: Template) -> Result<()> {
+        let _rotation = self.rotation.read().await;
+        template.id = Some(id);
+        if !self.store.replace(id, self.seal(id, &template).await?).await? {
+            return Err(StorageError::NotFound(id));
+        }
+        Ok(())
+    }
+
+    /// Delete a template, returning whether it existed
+    pub async fn delete(&self, id: Uuid) -> Result<bool> {
+        let _rotation = self.rotation.read().await;
+        self.store.delete(id).await
+    }
+
+    /// Number of stored templates
+    pub async fn count(&self) -> Result<usize> {
+        self.store.count().await
+    }
+
+    /// Stream every template with its ID, ordered by ID
+    pub fn iter(&self) -> impl Stream<Item = Result<(Uuid, Template)>> + '_ {
+        self.store.iter().then(move |entry| async move {
+            let (id, stored) = entry?;
+            Ok((id, self.open(id, &stored).await?))
+        })
+    }
+
+    /// Rotate the encryption key and re-encrypt every template
+    ///
+    /// Templates are re-encrypted in chunks, each applied as one batch;
+    /// a template deleted meanwhile is not written back. Writes wait until
+    /// the rotation has finished. The keyring keeps the old key until then,
+    /// so an interrupted rotation is completed by the next one.
+    pub async fn rotate_key(&self) -> Result<()> {
+        let _rotation = self.rotation.write().await;
+        self.encryption.rotate_key().await.map_err(StorageError::Encryption)?;
+        self.persist_keys().await?;
+
+        let mut entries = self.store.iter().try_chunks(ROTATION_CHUNK);
+        while let Some(chunk) = entries.next().await {
+            let chunk = chunk.map_err(|e| e.1)?;
+            let mut ops = Vec::with_capacity(chunk.len());
+            for (id, stored) in chunk {
+                let body = self.decrypt(id, &stored).await?;
+                let encrypted = self
+                    .encryption
+                    .encrypt_with_header_and_aad(&body, &stored.encrypted.header, id.as_bytes())
+                    .await
+                    .map_err(StorageError::Encryption)?;
+                ops.push(StoreOp::Replace(id, StoredTemplate { encrypted, ..stored }));
+            }
+            self.store.apply_batch(ops).await?;
+        }
+
+        self.encryption.finish_rotation().await.map_err(StorageError::Encryption)?;
+        self.persist_keys().await
+    }
+
+    /// Write the data keys, wrapped by the key provider, to the keyring
+    ///
+    /// Does nothing for vaults with an in-memory key.
+    async fn persist_keys(&self) -> Result<()> {
+        let Some(provider) = &self.key_provider else {
+            return Ok(());
+        };
+        let material = self.encryption.key_manager().key_material();
+        let current = SecretBytes::from(&material.current[..]);
+        let context = slot_context(CURRENT.as_bytes(), Some(material.current_id));
+        let mut old = Vec::with_capacity(material.old.len());
+        for (old_id, key) in &material.old {
+            let context = slot_context(OLD_KEYS.as_bytes(), Some(*old_id));
+            old.push((*old_id, provider.wrap_key(&SecretBytes::from(&key[..]), &context).await?));
+        }
+        self.store
+            .put_keys(vec![
+                (CURRENT, provider.wrap_key(&current, &context).await?),
+                (CURRENT_ID, material.current_id.to_be_bytes().to_vec()),
+                (OLD_KEYS, bincode::serialize(&old).map_err(StorageError::encode)?),
+            ])
+            .await
+    }
+
+    async fn seal(&self, id: Uuid, template: &Template) -> Result<StoredTemplate> {
+        let item = sealing_item(id, template)?;
+        let encrypted = self
+            .encryption
+            .encrypt_with_header_and_aad(&item.data, &item.header, &item.aad)
+            .await
+            .map_err(StorageError::Encryption)?;
+        Ok(StoredTemplate {
+            encrypted,
+            metadata: stored_metadata(template),
+        })
+    }
+
+    /// Decrypt a stored template, without its header
+    async fn decrypt(&self, id: Uuid, stored: &StoredTemplate) -> Result<Zeroizing<Vec<u8>>> {
+        self.encryption
+            .decrypt_stored(&stored.encrypted, id.as_bytes())
+            .await
+            .map(Zeroizing::new)
+            .map_err(|e| StorageError::Encryption(e).for_template(id))
+    }
+
+    async fn open(&self, id: Uuid, stored: &StoredTemplate) -> Result<Template> {
+        let body = self.decrypt(id, stored).await?;
+        let mut plaintext = Zeroizing::new(stored.encrypted.header.clone());
+        plaintext.extend_from_slice(&body);
+        // Records written here are never compressed; bound any that are
+        let limit = record_limit(Some(DEFAULT_MAX_TEMPLATE_SIZE));
+        let mut template = decode_template_plaintext(&plaintext, limit)?;
+        template.id = Some(id);
+        Ok(template)
+    }
+}
+
+/// Plaintext of template `id` to encrypt, bound to its ID
+///
+/// The format byte stays readable in the header, but authenticated.
+fn sealing_item(id: Uuid, template: &Template) -> Result<BatchItem> {
+    let plaintext = encode_template_plaintext(template, None)?;
+    let (header, body) = split_template_plaintext(&plaintext);
+    Ok(BatchItem {
+        data: body.to_vec(),
+        header: header.to_vec(),
+        aad: id.as_bytes().to_vec(),
+    })
+}
+
+/// Readable metadata stored next to a template's ciphertext
+fn stored_metadata(template: &Template) -> serde_json::Value {
+    serde_json::json!({ "template_type": template.metadata.template_type })
+}
@@ -0,0 +1,97 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::{Template, TemplateMetadata};
+use futures::stream::{self, Stream};
+use sled::IVec;
+use std::collections::VecDeque;
+use std::ops::Bound;
+use uuid::Uuid;
+
+/// Entries read under one lock acquisition while streaming
+const STREAM_CHUNK: usize = 64;
+
+/// Stored bytes of one entry, not yet decrypted
+struct RawEntry {
+    id: Uuid,
+    value: IVec,
+    metadata: Option<IVec>,
+}
+
+/// Position of a stream in the template tree
+#[derive(Default)]
+struct Cursor {
+    after: Option<IVec>,
+    buffer: VecDeque<RawEntry>,
+    exhausted: bool,
+}
+
+impl TemplateVault {
+    /// Stream all templates in ID order, decrypting each as it is polled
+    ///
+    /// Entries are read a chunk at a time and the lock is released between
+    /// chunks, so writes may interleave; an entry written behind the cursor
+    /// is not seen. Expired entries are skipped. The stream ends after the
+    /// first error.
+    pub fn iter(&self) -> impl Stream<Item = Result<(Uuid, Template)>> + '_ {
+        stream::try_unfold(Cursor::default(), move |mut cursor| async move {
+            let Some(entry) = self.next_raw(&mut cursor, false).await? else {
+                return Ok(None);
+            };
+            let template = self.decode_template(entry.id, &entry.value).await?;
+            self.record_audit(AuditOperation::Read, Some(entry.id), &AuditContext::default())?;
+            Ok(Some(((entry.id, template), cursor)))
+        })
+    }
+
+    /// Stream the metadata of all templates, like `iter` but without
+    /// decrypting payloads
+    pub fn iter_metadata(&self) -> impl Stream<Item = Result<(Uuid, TemplateMetadata)>> + '_ {
+        stream::try_unfold(Cursor::default(), move |mut cursor| async move {
+            let Some(entry) = self.next_raw(&mut cursor, true).await? else {
+                return Ok(None);
+            };
+            let metadata = match &entry.metadata {
+                Some(metadata) => self.decode_metadata(metadata).await?,
+                // Entries written before the metadata tree existed
+                None => self.decode_template(entry.id, &entry.value).await?.metadata,
+            };
+            self.record_audit(AuditOperation::Read, Some(entry.id), &AuditContext::default())?;
+            Ok(Some(((entry.id, metadata), cursor)))
+        })
+    }
+
+    /// Next entry of a stream, reading another chunk when the buffer is empty
+    async fn next_raw(&self, cursor: &mut Cursor, with_metadata: bool) -> Result<Option<RawEntry>> {
+        while cursor.buffer.is_empty() && !cursor.exhausted {
+            let db = self.db.read().await;
+            let start = match &cursor.after {
+                Some(after) => Bound::Excluded(after.clone()),
+                None => Bound::Unbounded,
+            };
+
+            let mut read = 0;
+            for item in db.range::<IVec, _>((start, Bound::Unbounded)).take(STREAM_CHUNK) {
+                let (key, value) = item?;
+                read += 1;
+                cursor.after = Some(key.clone());
+                let Ok(id) = Uuid::from_slice(&key) else {
+                    continue;
+                };
+                match self.check_expiry(id) {
+                    Ok(()) => {}
+                    Err(StorageError::Expired { .. }) => continue,
+                    Err(e) => return Err(e),
+                }
+                let metadata = match with_metadata {
+                    true => self.metadata.get(&key)?,
+                    false => None,
+                };
+                cursor.buffer.push_back(RawEntry { id, value, metadata });
+            }
+            cursor.exhausted = read < STREAM_CHUNK;
+        }
+        Ok(cursor.buffer.pop_front())
+    }
+}
@@ -0,0 +1,136 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::Template;
+use ring::hmac;
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+use uuid::Uuid;
+
+/// Length of the keyed hash identifying a subject in index keys
+const TAG_LEN: usize = 32;
+
+/// Index records binding a template to its subject
+pub(super) struct SubjectEntry {
+    /// Keyed hash of the subject ID, so index keys do not reveal it
+    tag: Vec<u8>,
+    /// The subject ID, encrypted with the data key
+    sealed: Vec<u8>,
+}
+
+/// Key in the subject index: subject tag followed by the template id
+fn subject_key(tag: &[u8], id: Uuid) -> Vec<u8> {
+    let mut key = Vec::with_capacity(TAG_LEN + 16);
+    key.extend_from_slice(tag);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+impl TemplateVault {
+    /// Store a template belonging to a subject, e.g. a person
+    ///
+    /// With deduplication enabled, data already stored yields the existing
+    /// ID, which keeps the subject it was stored for.
+    pub async fn store_for_subject(&self, subject_id: &str, template: Template) -> Result<Uuid> {
+        self.store_entry(template, None, Some(subject_id), &AuditContext::default())
+            .await
+            .map(|outcome| outcome.id)
+    }
+
+    /// List the IDs of a subject's templates
+    ///
+    /// Soft-deleted and expired templates are not listed.
+    pub async fn find_by_subject(&self, subject_id: &str) -> Result<Vec<Uuid>> {
+        let tag = self.subject_tag(subject_id);
+        let db = self.db.read().await;
+        let mut ids = Vec::new();
+        for key in self.subjects.scan_prefix(&tag).keys() {
+            let key = key?;
+            if let Ok(id) = Uuid::from_slice(&key[TAG_LEN..]) {
+                if self.is_live(&db, id)? {
+                    ids.push(id);
+                }
+            }
+        }
+        self.record_audit_all(AuditOperation::Find, &ids, &AuditContext::default())?;
+        Ok(ids)
+    }
+
+    /// Delete every template of a subject, including soft-deleted ones
+    ///
+    /// All templates are removed in one transaction; returns their IDs.
+    pub async fn delete_subject(&self, subject_id: &str) -> Result<Vec<Uuid>> {
+        self.ensure_writable()?;
+        let tag = self.subject_tag(subject_id);
+        let _rotation = self.rotation_guard().await?;
+        let db = self.db.write().await;
+
+        let mut ids = Vec::new();
+        for key in self.subjects.scan_prefix(&tag).keys() {
+            if let Ok(id) = Uuid::from_slice(&key?[TAG_LEN..]) {
+                ids.push(id);
+            }
+        }
+        self.remove_entries(&db, &ids, &AuditContext::default())
+    }
+
+    /// Keyed hash of a subject ID
+    fn subject_tag(&self, subject_id: &str) -> Vec<u8> {
+        let mut ctx = hmac::Context::with_key(&self.hash_key());
+        ctx.update(b"subject\0");
+        ctx.update(subject_id.as_bytes());
+        ctx.sign().as_ref().to_vec()
+    }
+
+    /// Subject ID a template was stored for, if any
+    pub(super) async fn subject_of(&self, id: Uuid) -> Result<Option<String>> {
+        let sealed = {
+            let _db = self.db.read().await;
+            let Some(tag) = self.subject_refs.get(id.as_bytes())? else {
+                return Ok(None);
+            };
+            self.subjects.get(subject_key(&tag, id))?
+        };
+        let Some(sealed) = sealed else {
+            return Ok(None);
+        };
+        let subject_id = self.open(&sealed).await?;
+        Ok(Some(String::from_utf8_lossy(&subject_id).into_owned()))
+    }
+
+    /// Prepare the index records of a subject outside of a transaction
+    pub(super) async fn subject_entry(&self, subject_id: Option<&str>) -> Result<Option<SubjectEntry>> {
+        let Some(subject_id) = subject_id else {
+            return Ok(None);
+        };
+        Ok(Some(SubjectEntry {
+            tag: self.subject_tag(subject_id),
+            sealed: self.seal(subject_id.as_bytes()).await?,
+        }))
+    }
+
+    /// Bind a template to its subject inside a transaction
+    pub(super) fn write_subject(
+        &self,
+        subjects: &TransactionalTree,
+        subject_refs: &TransactionalTree,
+        id: Uuid,
+        entry: &SubjectEntry,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        subjects.insert(subject_key(&entry.tag, id), entry.sealed.as_slice())?;
+        subject_refs.insert(id.as_bytes(), entry.tag.as_slice())?;
+        Ok(())
+    }
+
+    /// Remove the subject binding of a deleted template inside a transaction
+    pub(super) fn clear_subject(
+        &self,
+        subjects: &TransactionalTree,
+        subject_refs: &TransactionalTree,
+        id: Uuid,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        if let Some(tag) = subject_refs.remove(id.as_bytes())? {
+            subjects.remove(subject_key(&tag, id))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::events::VaultEvent;
+use super::vault::TemplateVault;
+use super::Result;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use sled::transaction::{ConflictableTransactionError, TransactionResult, Transactional};
+use uuid::Uuid;
+
+/// Plaintext deletion timestamp preceding the stored value in a tombstone
+pub(super) const TOMBSTONE_HEADER_LEN: usize = 8;
+
+fn deleted_at(tombstone: &[u8]) -> Option<DateTime<Utc>> {
+    let millis = i64::from_be_bytes(tombstone.get(..TOMBSTONE_HEADER_LEN)?.try_into().ok()?);
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+impl TemplateVault {
+    /// Hide a template until it is restored or its tombstone is purged
+    ///
+    /// The encrypted template moves to the tombstone tree unchanged; it
+    /// disappears from `get`, listings and indexes, while aliases, subject,
+    /// lookup and expiry entries stay in place for `restore`; every finder
+    /// and `resolve_alias` skip them meanwhile.
+    pub async fn soft_delete(&self, id: Uuid) -> Result<()> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation_guard().await?;
+        let now = self.clock.now_utc();
+        let db = self.db.write().await;
+        let mut head = self.audit.lock_head();
+        let batch = head.chain([(AuditOperation::SoftDelete, Some(id), None)], &AuditContext::default(), now)?;
+        let trees = (&**db, &self.metadata, &self.type_index, &self.extra_index, &self.tombstones, self.audit.tree());
+        let result: TransactionResult<(), StorageError> =
+            trees.transaction(
+                |(templates, metadata, type_index, extra_index, tombstones, audit)| {
+                    let value = templates
+                        .remove(id.as_bytes())?
+                        .ok_or(ConflictableTransactionError::Abort(StorageError::NotFound(id)))?;
+                    metadata.remove(id.as_bytes())?;
+                    self.clear_indexes(type_index, extra_index, id)?;
+
+                    let mut tombstone = now.timestamp_millis().to_be_bytes().to_vec();
+                    tombstone.extend_from_slice(&value);
+                    tombstones.insert(id.as_bytes(), tombstone)?;
+                    batch.write(audit)?;
+                    Ok(())
+                },
+            );
+        result?;
+        head.advance(&batch);
+        self.observe_entries(-1);
+        self.emit(VaultEvent::Deleted(id));
+        Ok(())
+    }
+
+    /// Bring back a soft-deleted template with its original stored bytes
+    pub async fn restore(&self, id: Uuid) -> Result<()> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation_guard().await?;
+        let tombstone = {
+            let _db = self.db.read().await;
+            self.tombstones
+                .get(id.as_bytes())?
+                .ok_or(StorageError::NotFound(id))?
+        };
+        let value = tombstone[TOMBSTONE_HEADER_LEN..].to_vec();
+        let template = self.decode_template(id, &value).await?;
+        let metadata_value = self.encode_metadata(&template.metadata).await?;
+        let indexes = self.index_entries(id, &template.metadata).await?;
+
+        let db = self.db.write().await;
+        let mut head = self.audit.lock_head();
+        let batch = head.chain(
+            [(AuditOperation::Restore, Some(id), None)],
+            &AuditContext::default(),
+            self.clock.now_utc(),
+        )?;
+        let trees = (&**db, &self.metadata, &self.type_index, &self.extra_index, &self.tombstones, self.audit.tree());
+        let result: TransactionResult<(), StorageError> =
+            trees.transaction(
+                |(templates, metadata, type_index, extra_index, tombstones, audit)| {
+                    // A concurrent rotation or purge changed the tombstone
+                    if tombstones.remove(id.as_bytes())?.as_deref() != Some(&tombstone[..]) {
+                        return Err(ConflictableTransactionError::Abort(StorageError::NotFound(id)));
+                    }
+                    templates.insert(id.as_bytes(), value.as_slice())?;
+                    metadata.insert(id.as_bytes(), metadata_value.as_slice())?;
+                    self.write_indexes(type_index, extra_index, id, &indexes)?;
+                    batch.write(audit)?;
+                    Ok(())
+                },
+            );
+        result?;
+        head.advance(&batch);
+        self.observe_entries(1);
+        self.emit(VaultEvent::Stored(id));
+        Ok(())
+    }
+
+    /// Permanently remove templates soft-deleted at least `older_than` ago
+    ///
+    /// Returns the number of templates removed.
+    pub async fn purge_tombstones(&self, older_than: Duration) -> Result<usize> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation_guard().await?;
+        let cutoff = self.clock.now_utc() - older_than;
+        let db = self.db.write().await;
+
+        let mut purged = Vec::new();
+        for item in self.tombstones.iter() {
+            let (key, value) = item?;
+            if deleted_at(&value).is_some_and(|at| at <= cutoff) {
+                if let Ok(id) = Uuid::from_slice(&key) {
+                    purged.push(id);
+                }
+            }
+        }
+
+        for id in &purged {
+            self.remove_entry(&db, *id, &AuditContext::default())?;
+        }
+        Ok(purged.len())
+    }
+}
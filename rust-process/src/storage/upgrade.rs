@@ -0,0 +1,37 @@
+use super::error::StorageError;
+use super::vault::TemplateVault;
+use super::Result;
+use crate::templates::Template;
+use sled::transaction::{TransactionResult, Transactional};
+use uuid::Uuid;
+
+impl TemplateVault {
+    /// Rewrite a template whose metadata was read in an older schema in the
+    /// latest one, unless its stored value changed since it was read
+    ///
+    /// Only the encoding changes, not the template, so neither its revision
+    /// nor the audit log do and no event is sent. Returns whether the value
+    /// was rewritten.
+    pub(super) async fn upgrade_entry(&self, id: Uuid, stored: &[u8], template: &Template) -> Result<bool> {
+        let _rotation = self.rotation_guard().await?;
+        let storage_data = self.encode_template(id, template).await?;
+        let metadata = self.encode_metadata(&template.metadata).await?;
+        let indexes = self.index_entries(id, &template.metadata).await?;
+
+        let db = self.db.write().await;
+        let result: TransactionResult<bool, StorageError> =
+            (&**db, &self.metadata, &self.type_index, &self.extra_index).transaction(
+                |(templates, metadata_tree, type_index, extra_index)| {
+                    if templates.get(id.as_bytes())?.as_deref() != Some(stored) {
+                        return Ok(false);
+                    }
+                    templates.insert(id.as_bytes(), storage_data.as_slice())?;
+                    metadata_tree.insert(id.as_bytes(), metadata.as_slice())?;
+                    // Migrating `extra` may add indexed keys
+                    self.write_indexes(type_index, extra_index, id, &indexes)?;
+                    Ok(true)
+                },
+            );
+        Ok(result?)
+    }
+}
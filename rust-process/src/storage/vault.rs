@@ -1,93 +1,1032 @@
-use super::error::StorageError;
+use super::audit::{AuditBatch, AuditContext, AuditLog, AuditOperation};
+use super::error::{CodecError, StorageError};
+use super::retention::{decode_expiry, encode_expiry};
+use super::revision::{revision_of, VersionedTemplate, FIRST_REVISION};
+use super::stats::Activity;
+use super::tombstone::TOMBSTONE_HEADER_LEN;
 use super::Result;
-use crate::security::{EncryptedData, EncryptionEngine, KeyManager};
-use crate::templates::Template;
-use sled::Db;
+use crate::clock::{Clock, SystemClock};
+use super::format::{
+    binding_aad, binding_of, decode_envelope, decode_metadata_json, decode_versioned_template_plaintext,
+    encode_envelope, encode_template_plaintext, is_bound, split_template_plaintext,
+};
+use super::compression::{record_limit, CompressionAlgo};
+use super::config::VaultConfig;
+use super::dedup::StoreOutcome;
+use super::events::{VaultEvent, EVENT_CAPACITY};
+use super::keyring::{load_hash_key, load_keys, load_usage};
+use super::metrics::VaultMetrics;
+use super::rotation::RotationProgress;
+use crate::security::{EncryptionEngine, KeySource, MasterKeyProvider};
+use crate::templates::{MetadataVersion, Template, TemplateMetadata, ValidationPolicy};
+use sled::transaction::{ConflictableTransactionError, TransactionResult, Transactional};
+use chrono::{DateTime, Utc};
+use sled::{Db, IVec};
 use std::path::Path;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{broadcast, RwLock};
 use uuid::Uuid;
-use serde_json;
+use zeroize::Zeroizing;
 
 /// Secure storage for biometric templates
 #[derive(Clone)]
 pub struct TemplateVault {
-    db: Arc<RwLock<Db>>,
-    encryption: Arc<EncryptionEngine>,
+    pub(super) db: Arc<RwLock<Db>>,
+    pub(super) encryption: Arc<EncryptionEngine>,
+    /// template id -> encrypted metadata, so listings skip payload decryption
+    pub(super) metadata: sled::Tree,
+    /// `<type>:<template id>` keys for filtering by modality
+    pub(super) type_index: sled::Tree,
+    /// metadata extra key + template id -> encrypted extra value
+    pub(super) extra_index: sled::Tree,
+    /// Names of the extra keys currently present in `extra_index`
+    pub(super) extra_index_keys: sled::Tree,
+    /// Metadata extra keys searchable with `find_by_extra`
+    pub(super) extra_keys: Arc<Vec<String>>,
+    /// (namespace, external id) -> template id
+    pub(super) aliases: sled::Tree,
+    /// template id + (namespace, external id), for cleanup on delete
+    pub(super) alias_refs: sled::Tree,
+    /// template id -> expiry timestamp, for entries with a retention period
+    pub(super) expiry: sled::Tree,
+    /// template id -> deletion timestamp + stored value of soft-deleted templates
+    pub(super) tombstones: sled::Tree,
+    /// template id + version -> stored value replaced by an update
+    pub(super) history: sled::Tree,
+    /// template id -> revision, incremented on every write
+    pub(super) revisions: sled::Tree,
+    /// content hash -> template id, for deduplication; also holds the
+    /// lookup hash entries of `find_by_hash`
+    pub(super) content_index: sled::Tree,
+    /// template id -> content hash, for cleanup on update and delete
+    pub(super) content_hashes: sled::Tree,
+    /// subject hash + template id -> encrypted subject id
+    pub(super) subjects: sled::Tree,
+    /// template id -> subject hash, for cleanup on delete
+    pub(super) subject_refs: sled::Tree,
+    /// Data keys wrapped by the master key, and the passphrase salt
+    pub(super) keyring: sled::Tree,
+    /// Holder of the key protecting the keyring; `None` for vaults with an
+    /// in-memory key
+    pub(super) key_provider: Option<Arc<dyn MasterKeyProvider>>,
+    /// Hash-chained record of operations on templates
+    pub(super) audit: Arc<AuditLog>,
+    /// Number of replaced versions kept per template, if history is enabled
+    pub(super) max_versions: Option<usize>,
+    /// Largest accepted template payload, in bytes
+    pub(super) max_template_size: Option<usize>,
+    /// Whether operations modifying templates are refused
+    pub(super) read_only: bool,
+    /// Shared by writes of encrypted values, held exclusively by key rotation
+    pub(super) rotation: Arc<RwLock<()>>,
+    /// Entries key rotation re-encrypts per batch
+    pub(super) rotation_chunk_size: usize,
+    /// Progress of the running or last key rotation
+    pub(super) rotation_progress: Arc<Mutex<Option<RotationProgress>>>,
+    /// Prometheus metrics updated by operations, if configured
+    pub(super) metrics: Option<VaultMetrics>,
+    /// Key of the hashes indexing template contents and subject IDs,
+    /// replaced by `wipe`
+    pub(super) hash_key: Arc<std::sync::RwLock<ring::hmac::Key>>,
+    /// Whether stores of data already stored return the existing ID
+    pub(super) deduplicate: bool,
+    /// Key of the hashes `find_by_hash` looks templates up by, if enabled
+    pub(super) lookup_key: Option<ring::hmac::Key>,
+    /// Whether `get` writes back metadata it migrated
+    pub(super) upgrade_metadata: bool,
+    /// Rules templates are checked against before they are written
+    pub(super) validation: ValidationPolicy,
+    /// Compression of template plaintexts written from now on
+    pub(super) compression: Option<CompressionAlgo>,
+    /// Change notifications for subscribers
+    pub(super) events: broadcast::Sender<VaultEvent>,
+    /// Time source for retention checks
+    pub(super) clock: Arc<dyn Clock>,
+    /// Timestamps of the last flush and key rotation
+    pub(super) activity: Arc<Activity>,
 }
 
+/// Trees holding templates and the entries kept alongside them, either
+/// the vault's own or those of a namespace
+#[derive(Clone)]
+pub(super) struct EntryTrees {
+    /// Name of the namespace, `None` for the vault's own trees
+    pub(super) namespace: Option<String>,
+    pub(super) templates: sled::Tree,
+    pub(super) metadata: sled::Tree,
+    pub(super) type_index: sled::Tree,
+    pub(super) extra_index: sled::Tree,
+    pub(super) expiry: sled::Tree,
+    pub(super) tombstones: sled::Tree,
+    pub(super) history: sled::Tree,
+    pub(super) revisions: sled::Tree,
+    pub(super) aliases: sled::Tree,
+    pub(super) alias_refs: sled::Tree,
+    pub(super) content_index: sled::Tree,
+    pub(super) content_hashes: sled::Tree,
+    pub(super) subjects: sled::Tree,
+    pub(super) subject_refs: sled::Tree,
+}
+
+impl EntryTrees {
+    /// Every tree
+    pub(super) fn all(&self) -> [&sled::Tree; 14] {
+        [
+            &self.templates,
+            &self.metadata,
+            &self.type_index,
+            &self.extra_index,
+            &self.expiry,
+            &self.tombstones,
+            &self.history,
+            &self.revisions,
+            &self.aliases,
+            &self.alias_refs,
+            &self.content_index,
+            &self.content_hashes,
+            &self.subjects,
+            &self.subject_refs,
+        ]
+    }
+
+    /// Trees whose values are all encrypted, in rotation order
+    pub(super) fn encrypted(&self) -> Vec<EncryptedTree> {
+        let tree = |tree: &sled::Tree, header_len| EncryptedTree {
+            tree: tree.clone(),
+            header_len,
+            namespace: self.namespace.clone(),
+        };
+        vec![
+            tree(&self.templates, 0),
+            tree(&self.metadata, 0),
+            tree(&self.extra_index, 0),
+            tree(&self.history, 0),
+            tree(&self.subjects, 0),
+            tree(&self.alias_refs, 0),
+            tree(&self.tombstones, TOMBSTONE_HEADER_LEN),
+        ]
+    }
+}
+
+/// A tree whose values are all encrypted
+pub(super) struct EncryptedTree {
+    pub(super) tree: sled::Tree,
+    /// Length of the plaintext header preceding each envelope
+    pub(super) header_len: usize,
+    /// Namespace the bound values of the tree are bound to
+    pub(super) namespace: Option<String>,
+}
+
+/// File inside the vault directory recording the process that opened it
+const HOLDER_FILE: &str = "holder.pid";
+
 impl Drop for TemplateVault {
     fn drop(&mut self) {
         // Attempt to get a write lock and flush the database
         if let Ok(db) = self.db.try_write() {
+            let _ = self.persist_usage();
             let _ = db.flush();
-            let _ = db.flush_async(); // Ensure all async operations are flushed
+            drop(db.flush_async()); // Ensure all async operations are flushed
         }
     }
 }
 
 impl TemplateVault {
     /// Create a new template vault at the specified path
+    ///
+    /// The vault uses a fresh in-memory key, so its templates cannot be
+    /// read after a restart; use `open_with_key` for persistent vaults.
     pub async fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let db_config = sled::Config::new()
-            .mode(sled::Mode::HighThroughput)
-            .flush_every_ms(Some(1000))
-            .cache_capacity(1024 * 1024 * 128) // 128MB cache
-            .path(path);
+        Self::with_config(path, VaultConfig::default()).await
+    }
 
-        let db = db_config.open()?;
-        let key_manager = Arc::new(KeyManager::new().map_err(|e| StorageError::Encryption(e))?);
-        let encryption = Arc::new(EncryptionEngine::new(key_manager));
+    /// Create a vault that indexes the given metadata `extra` keys
+    ///
+    /// Keys added since the vault was last opened are indexed from the
+    /// stored metadata; keys no longer listed are dropped from the index.
+    pub async fn new_with_extra_index<P: AsRef<Path>>(path: P, extra_keys: &[&str]) -> Result<Self> {
+        Self::with_config(path, VaultConfig::default().extra_index(extra_keys.iter().copied())).await
+    }
 
-        Ok(Self {
+    /// Open a vault whose data key is protected by a master key
+    ///
+    /// The first open generates the data key and stores it wrapped by the
+    /// master key; later opens fail with `SecurityError::InvalidKey` if
+    /// `key_source` yields a different master key.
+    pub async fn open_with_key<P: AsRef<Path>>(path: P, key_source: KeySource) -> Result<Self> {
+        Self::with_config(path, VaultConfig::default().key_source(key_source)).await
+    }
+
+    /// Open a vault with explicit settings
+    pub async fn with_config<P: AsRef<Path>>(path: P, config: VaultConfig) -> Result<Self> {
+        let path = path.as_ref();
+        let db = config.sled_config(path).open().map_err(|e| Self::map_open_error(path, e))?;
+        std::fs::write(path.join(HOLDER_FILE), std::process::id().to_string())?;
+        let metadata = db.open_tree("metadata")?;
+        let type_index = db.open_tree("type_index")?;
+        let extra_index = db.open_tree("extra_index")?;
+        let extra_index_keys = db.open_tree("extra_index_keys")?;
+        let aliases = db.open_tree("aliases")?;
+        let alias_refs = db.open_tree("alias_refs")?;
+        let expiry = db.open_tree("expiry")?;
+        let tombstones = db.open_tree("tombstones")?;
+        let history = db.open_tree("history")?;
+        let revisions = db.open_tree("revisions")?;
+        let content_index = db.open_tree("content_index")?;
+        let content_hashes = db.open_tree("content_hashes")?;
+        let subjects = db.open_tree("subjects")?;
+        let subject_refs = db.open_tree("subject_refs")?;
+        let keyring = db.open_tree("keyring")?;
+        let audit = AuditLog::open(db.open_tree("audit")?)?;
+        let holds_data = db.iter().keys().next().is_some() || !tombstones.is_empty();
+        let (key_manager, key_provider) = load_keys(&keyring, config.key_source.as_ref(), holds_data).await?;
+        let key_manager = key_manager
+            .with_budget(config.key_budget.clone())
+            .with_limit(config.key_limit.clone());
+        if key_provider.is_some() {
+            load_usage(&keyring, &key_manager)?;
+        }
+        let encryption =
+            Arc::new(EncryptionEngine::new(Arc::new(key_manager)).with_cipher(config.cipher));
+        let hash_key = load_hash_key(&keyring, key_provider.as_ref()).await?;
+
+        let vault = Self {
             db: Arc::new(RwLock::new(db)),
             encryption,
-        })
+            metadata,
+            type_index,
+            extra_index,
+            extra_index_keys,
+            extra_keys: Arc::new(config.extra_index),
+            aliases,
+            alias_refs,
+            expiry,
+            tombstones,
+            history,
+            revisions,
+            content_index,
+            content_hashes,
+            subjects,
+            subject_refs,
+            keyring,
+            key_provider,
+            audit: Arc::new(audit),
+            max_versions: None,
+            max_template_size: config.max_template_size,
+            read_only: config.read_only,
+            rotation: Arc::new(RwLock::new(())),
+            rotation_chunk_size: config.rotation_chunk_size,
+            rotation_progress: Arc::new(Mutex::new(None)),
+            metrics: config.metrics,
+            hash_key: Arc::new(std::sync::RwLock::new(hash_key)),
+            deduplicate: config.deduplicate,
+            lookup_key: config.lookup_key,
+            upgrade_metadata: config.upgrade_metadata,
+            validation: config.validation,
+            compression: config.compression,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            clock: Arc::new(SystemClock),
+            activity: Arc::new(Activity::default()),
+        };
+        if !vault.read_only {
+            vault.init_type_index(&*vault.db.read().await)?;
+            vault.sync_extra_index().await?;
+            vault.sync_lookup_index().await?;
+            vault.sync_alias_index().await?;
+        }
+        vault.reset_entries(&*vault.db.read().await);
+        Ok(vault)
+    }
+
+    /// Key of the hashes indexing template contents and subject IDs
+    pub(super) fn hash_key(&self) -> ring::hmac::Key {
+        self.hash_key.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Use a custom time source for retention checks and statistics
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Turn sled's lock conflict into an error naming the current holder
+    fn map_open_error(path: &Path, error: sled::Error) -> StorageError {
+        match error {
+            sled::Error::Io(ref e) if e.to_string().contains("could not acquire lock") => {
+                let holder_pid = std::fs::read_to_string(path.join(HOLDER_FILE))
+                    .ok()
+                    .and_then(|pid| pid.trim().parse().ok());
+                StorageError::Locked {
+                    path: path.to_path_buf(),
+                    holder_pid,
+                }
+            }
+            other => StorageError::Storage(other),
+        }
     }
 
     /// Store a template securely
     pub async fn store(&self, template: Template) -> Result<Uuid> {
+        self.store_with_context(template, &AuditContext::default()).await
+    }
+
+    /// Store a template, attributing the operation in the audit log
+    pub async fn store_with_context(&self, template: Template, context: &AuditContext) -> Result<Uuid> {
+        self.store_entry(template, None, None, context)
+            .await
+            .map(|outcome| outcome.id)
+    }
+
+    /// Store a template, optionally with an expiry time and subject
+    ///
+    /// With deduplication enabled, identical data already stored yields the
+    /// existing ID; its expiry time is left unchanged.
+    pub(super) async fn store_entry(
+        &self,
+        template: Template,
+        expires_at: Option<DateTime<Utc>>,
+        subject_id: Option<&str>,
+        context: &AuditContext,
+    ) -> Result<StoreOutcome> {
+        self.store_entry_in(None, template, expires_at, subject_id, context).await
+    }
+
+    /// Store a template like `store_entry`, into `trees` if given instead
+    /// of the vault's own
+    pub(super) async fn store_entry_in(
+        &self,
+        trees: Option<&EntryTrees>,
+        mut template: Template,
+        expires_at: Option<DateTime<Utc>>,
+        subject_id: Option<&str>,
+        context: &AuditContext,
+    ) -> Result<StoreOutcome> {
+        let started = Instant::now();
+        self.ensure_writable()?;
+        self.check_template(&template)?;
+        let _rotation = self.rotation_guard().await?;
         let id = Uuid::new_v4();
-        let template_bytes = serde_json::to_vec(&template)
-            .map_err(|e| StorageError::Serialization(Box::new(bincode::ErrorKind::Custom(e.to_string()))))?;
-        
-        // Encrypt template data
-        let encrypted = self.encryption.encrypt(&template_bytes).await
-            .map_err(|e| StorageError::Encryption(e))?;
-        let storage_data = serde_json::to_vec(&encrypted)
-            .map_err(|e| StorageError::Serialization(Box::new(bincode::ErrorKind::Custom(e.to_string()))))?;
-        
-        // Use batch operation for atomic writes
-        let mut batch = sled::Batch::default();
-        batch.insert(id.as_bytes(), storage_data);
-        self.db.write().await.apply_batch(batch)?;
-
-        Ok(id)
-    }
-
-    /// Retrieve a template by ID
-    pub async fn get(&self, id: Uuid) -> Result<Template> {
-        let encrypted_data = self.db
-            .read().await
-            .get(id.as_bytes())?
-            .ok_or_else(|| StorageError::NotFound(id))?;
-
-        let encrypted: EncryptedData = serde_json::from_slice(&encrypted_data)
-            .map_err(|e| StorageError::Serialization(Box::new(bincode::ErrorKind::Custom(e.to_string()))))?;
-        let template_bytes = self.encryption.decrypt(&encrypted).await
-            .map_err(|e| StorageError::Encryption(e))?;
-        let template: Template = serde_json::from_slice(&template_bytes)
-            .map_err(|e| StorageError::Serialization(Box::new(bincode::ErrorKind::Custom(e.to_string()))))?;
-        
-        Ok(template)
+        template.id = Some(id);
+        let namespace = trees.and_then(|trees| trees.namespace.as_deref());
+        let storage_data = self.encode_template_in(namespace, id, &template).await?;
+        let metadata = self.encode_metadata(&template.metadata).await?;
+
+        let indexes = self.index_entries(id, &template.metadata).await?;
+        let hash = self.content_hash(&template);
+        let lookup_hash = self.lookup_hash(&template);
+        let subject = self.subject_entry(subject_id).await?;
+        let now = self.clock.now_utc();
+
+        // Template, metadata, index and audit entries are committed together
+        let db = self.db.write().await;
+        let trees = trees.cloned().unwrap_or_else(|| self.entry_trees(&db));
+        let mut head = self.audit.lock_head();
+        let provenance = template.metadata.provenance.clone();
+        let batch = head.chain([(AuditOperation::Store, Some(id), provenance.clone())], context, now)?;
+        let result: TransactionResult<Option<Uuid>, StorageError> = (
+            &trees.templates,
+            &trees.metadata,
+            &trees.type_index,
+            &trees.extra_index,
+            &trees.expiry,
+            &trees.revisions,
+            &trees.content_index,
+            &trees.content_hashes,
+            &trees.subjects,
+            &trees.subject_refs,
+            self.audit.tree(),
+        )
+            .transaction(
+                |(templates, metadata_tree, type_index, extra_index, expiry, revisions, content_index, content_hashes, subjects, subject_refs, audit)| {
+                    if let Some(hash) = &hash {
+                        // A soft-deleted or expired match no longer counts
+                        if let Some(existing) = content_index.get(hash.as_slice())? {
+                            let expired = expiry
+                                .get(&existing)?
+                                .and_then(|value| decode_expiry(&value))
+                                .is_some_and(|expires_at| expires_at <= now);
+                            if templates.get(&existing)?.is_some() && !expired {
+                                return Ok(Uuid::from_slice(&existing).ok());
+                            }
+                        }
+                        content_index.remove(hash.as_slice())?;
+                        self.write_content_hash(content_index, content_hashes, id, hash)?;
+                    }
+                    if let Some(lookup_hash) = &lookup_hash {
+                        self.write_lookup_hash(content_index, content_hashes, id, lookup_hash)?;
+                    }
+                    templates.insert(id.as_bytes(), storage_data.as_slice())?;
+                    metadata_tree.insert(id.as_bytes(), metadata.as_slice())?;
+                    revisions.insert(id.as_bytes(), &FIRST_REVISION.to_be_bytes())?;
+                    self.write_indexes(type_index, extra_index, id, &indexes)?;
+                    if let Some(expires_at) = expires_at {
+                        expiry.insert(id.as_bytes(), &encode_expiry(expires_at))?;
+                    }
+                    if let Some(subject) = &subject {
+                        self.write_subject(subjects, subject_refs, id, subject)?;
+                    }
+                    batch.write(audit)?;
+                    Ok(None)
+                },
+            );
+        if let Some(existing) = result? {
+            // Audited as a store of the template the data resolved to
+            drop(head);
+            self.audit
+                .record_with_provenance(AuditOperation::Store, Some(existing), context, now, provenance)?;
+            self.observe_operation("store", started);
+            return Ok(StoreOutcome {
+                id: existing,
+                deduplicated: true,
+            });
+        }
+        head.advance(&batch);
+        self.observe_template_size(template.data.len());
+        if trees.namespace.is_none() {
+            self.observe_entries(1);
+        }
+        self.observe_operation("store", started);
+        self.emit(VaultEvent::Stored(id));
+
+        Ok(StoreOutcome {
+            id,
+            deduplicated: false,
+        })
+    }
+
+    /// Replace the template stored under an existing ID
+    ///
+    /// Fails with `StorageError::NotFound` if the ID is not stored, so the
+    /// ID of a re-enrolled subject stays stable, and with
+    /// `StorageError::Expired` like `get` once its retention has passed. With history enabled the
+    /// replaced template is kept as a new version.
+    pub async fn update(&self, id: Uuid, template: Template) -> Result<()> {
+        self.update_with_context(id, template, &AuditContext::default()).await
+    }
+
+    /// Replace a stored template, attributing the operation in the audit log
+    pub async fn update_with_context(&self, id: Uuid, template: Template, context: &AuditContext) -> Result<()> {
+        self.replace_entry(id, template, None, context).await.map(|_| ())
+    }
+
+    /// Replace a stored template, optionally checking its current revision
+    ///
+    /// Returns the new revision.
+    pub(super) async fn replace_entry(
+        &self,
+        id: Uuid,
+        mut template: Template,
+        expected_revision: Option<u64>,
+        context: &AuditContext,
+    ) -> Result<u64> {
+        let started = Instant::now();
+        self.ensure_writable()?;
+        self.check_template(&template)?;
+        let _rotation = self.rotation_guard().await?;
+        template.id = Some(id);
+        let storage_data = self.encode_template(id, &template).await?;
+        let metadata = self.encode_metadata(&template.metadata).await?;
+
+        let indexes = self.index_entries(id, &template.metadata).await?;
+        let hash = self.content_hash(&template);
+        let lookup_hash = self.lookup_hash(&template);
+
+        let db = self.db.write().await;
+        // An expired template is gone to readers even before it is purged
+        self.check_expiry(id)?;
+        let history = self.plan_history(id)?;
+        let mut head = self.audit.lock_head();
+        let provenance = template.metadata.provenance.clone();
+        let batch = head.chain([(AuditOperation::Update, Some(id), provenance)], context, self.clock.now_utc())?;
+        let result: TransactionResult<u64, StorageError> = (
+            &**db,
+            &self.metadata,
+            &self.type_index,
+            &self.extra_index,
+            &self.history,
+            &self.revisions,
+            &self.content_index,
+            &self.content_hashes,
+            self.audit.tree(),
+        )
+            .transaction(|(templates, metadata_tree, type_index, extra_index, history_tree, revisions, content_index, content_hashes, audit)| {
+                let previous = templates
+                    .get(id.as_bytes())?
+                    .ok_or(ConflictableTransactionError::Abort(StorageError::NotFound(id)))?;
+                let current = revision_of(revisions.get(id.as_bytes())?);
+                if let Some(expected) = expected_revision {
+                    if expected != current {
+                        return Err(ConflictableTransactionError::Abort(StorageError::Conflict {
+                            id,
+                            expected,
+                            actual: current,
+                        }));
+                    }
+                }
+
+                if let Some(plan) = &history {
+                    history_tree.insert(plan.key.as_slice(), previous)?;
+                    for key in &plan.evict {
+                        history_tree.remove(key)?;
+                    }
+                }
+                templates.insert(id.as_bytes(), storage_data.as_slice())?;
+                metadata_tree.insert(id.as_bytes(), metadata.as_slice())?;
+                self.write_indexes(type_index, extra_index, id, &indexes)?;
+                // Data that another template already holds stays indexed
+                // under that template
+                self.clear_content_hash(content_index, content_hashes, id)?;
+                if let Some(hash) = &hash {
+                    self.write_content_hash(content_index, content_hashes, id, hash)?;
+                }
+                match &lookup_hash {
+                    Some(lookup_hash) => {
+                        self.write_lookup_hash(content_index, content_hashes, id, lookup_hash)?
+                    }
+                    None => self.clear_lookup_hash(content_index, content_hashes, id)?,
+                }
+                revisions.insert(id.as_bytes(), &(current + 1).to_be_bytes())?;
+                batch.write(audit)?;
+                Ok(current + 1)
+            });
+        let revision = result?;
+        head.advance(&batch);
+        self.observe_template_size(template.data.len());
+        self.observe_operation("update", started);
+        self.emit(VaultEvent::Updated(id));
+        Ok(revision)
+    }
+
+    /// Retrieve a template by ID, with its current revision
+    ///
+    /// Fails with `StorageError::Expired` once the template's retention
+    /// period has passed, even before it is purged.
+    pub async fn get(&self, id: Uuid) -> Result<VersionedTemplate> {
+        self.get_with_context(id, &AuditContext::default()).await
+    }
+
+    /// Retrieve a template, attributing the read in the audit log
+    pub async fn get_with_context(&self, id: Uuid, context: &AuditContext) -> Result<VersionedTemplate> {
+        let started = Instant::now();
+        let (encrypted_data, revision) = {
+            let db = self.db.read().await;
+            let encrypted_data = db
+                .get(id.as_bytes())?
+                .ok_or_else(|| StorageError::NotFound(id))?;
+            self.check_expiry(id)?;
+            (encrypted_data, revision_of(self.revisions.get(id.as_bytes())?))
+        };
+
+        let (template, schema) = self.decode_versioned_template(id, &encrypted_data).await?;
+        if schema < MetadataVersion::LATEST && self.upgrade_metadata && !self.read_only {
+            // The template was read either way; the next read migrates again
+            if let Err(e) = self.upgrade_entry(id, &encrypted_data, &template).await {
+                log::warn!("Failed to upgrade metadata of template {}: {}", id, e);
+            }
+        }
+        self.record_audit(AuditOperation::Read, Some(id), context)?;
+        self.observe_operation("get", started);
+        Ok(VersionedTemplate { template, revision })
+    }
+
+    /// Retrieve several templates at once, preserving input order
+    ///
+    /// All entries are read under a single lock acquisition; missing or
+    /// expired IDs yield `None` instead of failing the whole call.
+    pub async fn get_batch(&self, ids: &[Uuid]) -> Result<Vec<Option<Template>>> {
+        let templates = self.read_batch(ids).await?;
+        let read: Vec<Uuid> = ids
+            .iter()
+            .zip(&templates)
+            .filter_map(|(id, template)| template.as_ref().map(|_| *id))
+            .collect();
+        self.record_audit_all(AuditOperation::Read, &read, &AuditContext::default())?;
+        Ok(templates)
+    }
+
+    /// Decrypt several templates like `get_batch`, without auditing the reads
+    pub(super) async fn read_batch(&self, ids: &[Uuid]) -> Result<Vec<Option<Template>>> {
+        let raw = {
+            let db = self.db.read().await;
+            let mut raw = Vec::with_capacity(ids.len());
+            for id in ids {
+                raw.push(match self.check_expiry(*id) {
+                    Ok(()) => db.get(id.as_bytes())?,
+                    Err(StorageError::Expired { .. }) => None,
+                    Err(e) => return Err(e),
+                });
+            }
+            raw
+        };
+
+        let mut templates = Vec::with_capacity(raw.len());
+        for (id, value) in ids.iter().zip(raw) {
+            templates.push(match value {
+                Some(value) => Some(self.decode_template(*id, &value).await?),
+                None => None,
+            });
+        }
+        Ok(templates)
+    }
+
+    /// Encrypt plaintext into the stored value format
+    pub(super) async fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.seal_parts(&[], plaintext, None).await
+    }
+
+    /// Decrypt a stored value back into plaintext, wiped when dropped
+    pub(super) async fn open(&self, storage_data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        self.open_with(storage_data, None).await
+    }
+
+    /// Encrypt the plaintext of template `id`, stored in `namespace` if
+    /// given, keeping its format byte in the authenticated header
+    pub(super) async fn seal_template(
+        &self,
+        plaintext: &[u8],
+        id: Uuid,
+        namespace: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let (header, body) = split_template_plaintext(plaintext);
+        self.seal_parts(header, body, Some(&binding_aad(id, namespace))).await
+    }
+
+    /// Encrypt `body` with an authenticated `header`, bound by `binding`
+    /// if given
+    async fn seal_parts(&self, header: &[u8], body: &[u8], binding: Option<&[u8]>) -> Result<Vec<u8>> {
+        let aad = binding.unwrap_or_default();
+        let encrypted = self.encryption.encrypt_with_header_and_aad(body, header, aad).await
+            .map_err(StorageError::Encryption)?;
+        encode_envelope(&encrypted, binding.is_some())
+    }
+
+    /// Decrypt a stored value, checking its binding against `binding`
+    ///
+    /// Unbound values, written before binding existed, decrypt regardless.
+    /// The plaintext starts with the value's header, if it has one.
+    pub(super) async fn open_with(
+        &self,
+        storage_data: &[u8],
+        binding: Option<&[u8]>,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        let (header, body) = self.open_parts(storage_data, binding).await?;
+        if header.is_empty() {
+            return Ok(body);
+        }
+        let mut plaintext = Zeroizing::new(Vec::with_capacity(header.len() + body.len()));
+        plaintext.extend_from_slice(&header);
+        plaintext.extend_from_slice(&body);
+        Ok(plaintext)
+    }
+
+    /// Decrypt a stored value into its header and decrypted body
+    async fn open_parts(
+        &self,
+        storage_data: &[u8],
+        binding: Option<&[u8]>,
+    ) -> Result<(Vec<u8>, Zeroizing<Vec<u8>>)> {
+        let encrypted = decode_envelope(storage_data)?;
+        let aad = match (is_bound(storage_data), binding) {
+            (false, _) => &[][..],
+            (true, Some(aad)) => aad,
+            (true, None) => return Err(StorageError::corrupt(CodecError::MissingBinding)),
+        };
+        let body = self.encryption.decrypt_stored(&encrypted, aad).await
+            .map(Zeroizing::new)
+            .map_err(StorageError::Encryption)?;
+        Ok((encrypted.header, body))
+    }
+
+    /// Re-encrypt the stored value under `key` with the current key,
+    /// keeping its binding, to `namespace` if given, and header
+    pub(super) async fn reseal(
+        &self,
+        key: &[u8],
+        storage_data: &[u8],
+        namespace: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let binding = if is_bound(storage_data) {
+            Some(binding_aad(binding_of(key)?, namespace))
+        } else {
+            None
+        };
+        let (header, body) = self.open_parts(storage_data, binding.as_deref()).await?;
+        let sealed = self.seal_parts(&header, &body, binding.as_deref()).await?;
+        self.encryption.key_manager().discount_reencryption(body.len());
+        Ok(sealed)
+    }
+
+    /// Serialize and encrypt template `id` into its stored form
+    pub(super) async fn encode_template(&self, id: Uuid, template: &Template) -> Result<Vec<u8>> {
+        self.encode_template_in(None, id, template).await
+    }
+
+    /// Serialize and encrypt template `id` of `namespace`, if given
+    pub(super) async fn encode_template_in(
+        &self,
+        namespace: Option<&str>,
+        id: Uuid,
+        template: &Template,
+    ) -> Result<Vec<u8>> {
+        let template_bytes = encode_template_plaintext(template, self.compression)?;
+        self.seal_template(&template_bytes, id, namespace).await
+    }
+
+    /// Decrypt and deserialize the stored template `id`
+    ///
+    /// Fails if the value is bound to another template. The ID is set from
+    /// the key, since entries written before `store` recorded it carry none.
+    pub(super) async fn decode_template(&self, id: Uuid, storage_data: &[u8]) -> Result<Template> {
+        self.decode_template_in(None, id, storage_data).await
+    }
+
+    /// Decrypt and deserialize the stored template `id` of `namespace`, if
+    /// given
+    ///
+    /// Fails if the value is bound to another template or namespace.
+    pub(super) async fn decode_template_in(
+        &self,
+        namespace: Option<&str>,
+        id: Uuid,
+        storage_data: &[u8],
+    ) -> Result<Template> {
+        self.decode_versioned_template_in(namespace, id, storage_data)
+            .await
+            .map(|(template, _)| template)
+    }
+
+    /// Decrypt and deserialize a template, with the metadata schema version
+    /// it was stored in
+    pub(super) async fn decode_versioned_template(
+        &self,
+        id: Uuid,
+        storage_data: &[u8],
+    ) -> Result<(Template, MetadataVersion)> {
+        self.decode_versioned_template_in(None, id, storage_data).await
+    }
+
+    async fn decode_versioned_template_in(
+        &self,
+        namespace: Option<&str>,
+        id: Uuid,
+        storage_data: &[u8],
+    ) -> Result<(Template, MetadataVersion)> {
+        let template_bytes = self
+            .open_with(storage_data, Some(&binding_aad(id, namespace)))
+            .await
+            .map_err(|e| e.for_template(id))?;
+        let (mut template, schema) = decode_versioned_template_plaintext(&template_bytes, record_limit(self.max_template_size))?;
+        template.id = Some(id);
+        Ok((template, schema))
+    }
+
+    /// Serialize and encrypt template metadata for the metadata tree
+    pub(super) async fn encode_metadata(&self, metadata: &TemplateMetadata) -> Result<Vec<u8>> {
+        let metadata_bytes = serde_json::to_vec(metadata)
+            .map_err(StorageError::encode)?;
+        self.seal(&metadata_bytes).await
+    }
+
+    /// Decrypt and deserialize an entry of the metadata tree
+    pub(super) async fn decode_metadata(&self, storage_data: &[u8]) -> Result<TemplateMetadata> {
+        let metadata_bytes = self.open(storage_data).await?;
+        decode_metadata_json(&metadata_bytes).map(|(metadata, _)| metadata)
     }
 
     /// Delete a template by ID
-    pub async fn delete(&self, id: Uuid) -> Result<()> {
-        let mut batch = sled::Batch::default();
-        batch.remove(id.as_bytes());
-        self.db.write().await.apply_batch(batch)?;
-        Ok(())
+    ///
+    /// Returns `false` if nothing was stored under the ID, including when it
+    /// was already deleted.
+    pub async fn delete(&self, id: Uuid) -> Result<bool> {
+        self.delete_with_context(id, &AuditContext::default()).await
+    }
+
+    /// Delete a template, attributing the operation in the audit log
+    pub async fn delete_with_context(&self, id: Uuid, context: &AuditContext) -> Result<bool> {
+        let started = Instant::now();
+        let _rotation = self.rotation_guard().await?;
+        let db = self.db.write().await;
+        let removed = self.remove_entry(&db, id, context)?;
+        self.observe_operation("delete", started);
+        Ok(removed)
+    }
+
+    /// Delete every template whose metadata matches `predicate`
+    ///
+    /// Only the metadata tree is decrypted, not the templates themselves.
+    /// Matches are removed in one transaction; returns their IDs.
+    pub async fn delete_where(
+        &self,
+        predicate: impl Fn(&TemplateMetadata) -> bool,
+    ) -> Result<Vec<Uuid>> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation_guard().await?;
+        let db = self.db.write().await;
+
+        let mut matches = Vec::new();
+        for item in db.iter() {
+            let (key, value) = item?;
+            let Ok(id) = Uuid::from_slice(&key) else {
+                continue;
+            };
+            let metadata = match self.metadata.get(&key)? {
+                Some(metadata) => self.decode_metadata(&metadata).await?,
+                // Entries written before the metadata tree existed
+                None => self.decode_template(id, &value).await?.metadata,
+            };
+            if predicate(&metadata) {
+                matches.push(id);
+            }
+        }
+
+        self.remove_entries(&db, &matches, &AuditContext::default())
+    }
+
+    /// Remove a template with its metadata, aliases, history and any tombstone
+    /// in one transaction
+    ///
+    /// Returns whether a template or tombstone was removed; only then is the
+    /// deletion audited.
+    ///
+    /// The caller must hold the database write lock so no alias can be bound
+    /// between collecting the references and committing.
+    pub(super) fn remove_entry(&self, db: &Db, id: Uuid, context: &AuditContext) -> Result<bool> {
+        Ok(!self.remove_entries(db, &[id], context)?.is_empty())
+    }
+
+    /// Remove several templates like `remove_entry`, all in one transaction
+    ///
+    /// Returns the IDs of which a template or tombstone was removed.
+    pub(super) fn remove_entries(
+        &self,
+        db: &Db,
+        ids: &[Uuid],
+        context: &AuditContext,
+    ) -> Result<Vec<Uuid>> {
+        self.remove_entries_in(&self.entry_trees(db), ids, context)
+    }
+
+    /// Remove templates like `remove_entries`, from `trees`
+    ///
+    /// The caller must hold the database write lock.
+    pub(super) fn remove_entries_in(
+        &self,
+        trees: &EntryTrees,
+        ids: &[Uuid],
+        context: &AuditContext,
+    ) -> Result<Vec<Uuid>> {
+        self.ensure_writable()?;
+        let keys = |tree: &sled::Tree, id: &Uuid| {
+            tree.scan_prefix(id.as_bytes()).keys().collect::<std::result::Result<Vec<IVec>, _>>()
+        };
+        let mut related = Vec::with_capacity(ids.len());
+        for id in ids {
+            related.push((*id, keys(&trees.alias_refs, id)?, keys(&trees.history, id)?));
+        }
+
+        let namespaced = trees.namespace.is_some();
+        let mut trees = trees.all().to_vec();
+        trees.push(self.audit.tree());
+        let mut head = self.audit.lock_head();
+        let now = self.clock.now_utc();
+        // More trees than sled's tuple transactions take
+        let result: TransactionResult<(Vec<Uuid>, Vec<Uuid>, AuditBatch), StorageError> =
+            trees[..].transaction(|trees| {
+                let [templates, metadata, type_index, extra_index, expiry, tombstones, history, revisions, aliases, alias_refs, content_index, content_hashes, subjects, subject_refs, audit] =
+                    trees.as_slice()
+                else {
+                    unreachable!("one view per tree");
+                };
+                let mut removed = Vec::new();
+                let mut live = Vec::new();
+                for (id, refs, versions) in &related {
+                    let template = templates.remove(id.as_bytes())?;
+                    revisions.remove(id.as_bytes())?;
+                    let tombstone = tombstones.remove(id.as_bytes())?;
+                    for key in versions {
+                        history.remove(key)?;
+                    }
+                    metadata.remove(id.as_bytes())?;
+                    expiry.remove(id.as_bytes())?;
+                    self.clear_indexes(type_index, extra_index, *id)?;
+                    self.clear_content_hash(content_index, content_hashes, *id)?;
+                    self.clear_lookup_hash(content_index, content_hashes, *id)?;
+                    self.clear_subject(subjects, subject_refs, *id)?;
+                    for key in refs {
+                        aliases.remove(&key[16..])?;
+                        alias_refs.remove(key)?;
+                    }
+                    if template.is_some() {
+                        live.push(*id);
+                    }
+                    if template.is_some() || tombstone.is_some() {
+                        removed.push(*id);
+                    }
+                }
+                let records = removed.iter().map(|id| (AuditOperation::Delete, Some(*id), None));
+                let batch = head.chain(records, context, now).map_err(ConflictableTransactionError::Abort)?;
+                batch.write(audit)?;
+                Ok((removed, live, batch))
+            });
+        let (removed, live, batch) = result?;
+        head.advance(&batch);
+        if !namespaced {
+            self.observe_entries(-(live.len() as i64));
+        }
+        for id in live {
+            self.emit(VaultEvent::Deleted(id));
+        }
+        Ok(removed)
+    }
+
+    /// The vault's own entry trees
+    ///
+    /// The caller must hold the database lock.
+    pub(super) fn entry_trees(&self, db: &Db) -> EntryTrees {
+        EntryTrees {
+            namespace: None,
+            templates: (**db).clone(),
+            metadata: self.metadata.clone(),
+            type_index: self.type_index.clone(),
+            extra_index: self.extra_index.clone(),
+            expiry: self.expiry.clone(),
+            tombstones: self.tombstones.clone(),
+            history: self.history.clone(),
+            revisions: self.revisions.clone(),
+            aliases: self.aliases.clone(),
+            alias_refs: self.alias_refs.clone(),
+            content_index: self.content_index.clone(),
+            content_hashes: self.content_hashes.clone(),
+            subjects: self.subjects.clone(),
+            subject_refs: self.subject_refs.clone(),
+        }
+    }
+
+    /// List template metadata a page at a time, without decrypting payloads
+    ///
+    /// Entries are ordered by ID, matching `list_ids`.
+    pub async fn list_metadata(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(Uuid, TemplateMetadata)>> {
+        let listing = self.metadata_page(offset, limit).await?;
+        let ids: Vec<Uuid> = listing.iter().map(|(id, _)| *id).collect();
+        self.record_audit_all(AuditOperation::Read, &ids, &AuditContext::default())?;
+        Ok(listing)
+    }
+
+    /// A page of metadata like `list_metadata`, without auditing the reads
+    pub(super) async fn metadata_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(Uuid, TemplateMetadata)>> {
+        let page = {
+            let db = self.db.read().await;
+            let mut page = Vec::new();
+            for item in db.iter().skip(offset).take(limit) {
+                let (key, value) = item?;
+                if let Ok(id) = Uuid::from_slice(&key) {
+                    page.push((id, value, self.metadata.get(&key)?));
+                }
+            }
+            page
+        };
+
+        let mut listing = Vec::with_capacity(page.len());
+        for (id, value, metadata) in page {
+            let metadata = match metadata {
+                Some(metadata) => self.decode_metadata(&metadata).await?,
+                // Entries written before the metadata tree existed
+                None => self.decode_template(id, &value).await?.metadata,
+            };
+            listing.push((id, metadata));
+        }
+        Ok(listing)
+    }
+
+    /// Check whether a template is stored, without decrypting it
+    pub async fn exists(&self, id: Uuid) -> Result<bool> {
+        Ok(self.db.read().await.contains_key(id.as_bytes())?)
+    }
+
+    /// Number of stored templates
+    ///
+    /// Expired templates awaiting a purge are not counted, as `get` no
+    /// longer returns them.
+    pub async fn count(&self) -> Result<usize> {
+        let db = self.db.read().await;
+        let now = self.clock.now_utc();
+        let mut expired = 0;
+        for entry in self.expiry.iter() {
+            let (id, value) = entry?;
+            if decode_expiry(&value).is_some_and(|at| at <= now) && db.contains_key(&id)? {
+                expired += 1;
+            }
+        }
+        Ok(db.len() - expired)
     }
 
     /// List all template IDs
@@ -105,61 +1044,13 @@ impl TemplateVault {
         Ok(ids)
     }
 
-    /// Rotate encryption key and re-encrypt all templates
-    pub async fn rotate_key(&self) -> Result<()> {
-        // Start key rotation
-        self.encryption.rotate_key().await
-            .map_err(|e| StorageError::Encryption(e))?;
-
-        // Re-encrypt all templates with new key
-        let mut batch = sled::Batch::default();
-        let db = self.db.read().await;
-
-        // First collect all the data we need to re-encrypt
-        let mut items = Vec::new();
-        for item in db.iter() {
-            let (key, value) = item?;
-            items.push((key.to_vec(), value.to_vec()));
-        }
-
-        // Drop the read lock before processing
-        drop(db);
-
-        // Process each item
-        for (key, value) in items {
-            // Decrypt with old key
-            let encrypted: EncryptedData = serde_json::from_slice(&value)
-                .map_err(|e| StorageError::Serialization(Box::new(bincode::ErrorKind::Custom(e.to_string()))))?;
-            let template_bytes = self.encryption.decrypt(&encrypted).await
-                .map_err(|e| StorageError::Encryption(e))?;
-            
-            // Re-encrypt with new key
-            let reencrypted = self.encryption.encrypt(&template_bytes).await
-                .map_err(|e| StorageError::Encryption(e))?;
-            let storage_data = serde_json::to_vec(&reencrypted)
-                .map_err(|e| StorageError::Serialization(Box::new(bincode::ErrorKind::Custom(e.to_string()))))?;
-            
-            batch.insert(key, storage_data);
-        }
-
-        // Apply all re-encrypted data
-        let db = self.db.write().await;
-        db.apply_batch(batch)?;
-        db.flush()?;
-        drop(db);
-
-        // Finish key rotation
-        self.encryption.finish_rotation().await
-            .map_err(|e| StorageError::Encryption(e))?;
-        
-        Ok(())
-    }
-
     /// Flush all pending writes to disk
     pub async fn flush(&self) -> Result<()> {
         let db = self.db.write().await;
+        self.persist_usage()?;
         db.flush()?;
-        let _ = db.flush_async(); // No need to await this
+        drop(db.flush_async()); // No need to await this
+        self.activity.record_flush(self.clock.now_utc());
         Ok(())
     }
 }
@@ -176,19 +1067,17 @@ mod tests {
         let vault = TemplateVault::new(temp_dir.path()).await?;
 
         // Create test template
-        let template = Template::new(
-            vec![1, 2, 3, 4],
-            TemplateMetadata {
-                version: "1.0".to_string(),
-                template_type: TemplateType::Face,
-                quality_score: 0.95,
-                extra: serde_json::json!({}),
-            },
-        );
+        let template = Template::builder()
+            .data(vec![1, 2, 3, 4])
+            .template_type(TemplateType::Face)
+            .quality_score(0.95)
+            .build()
+            .unwrap();
 
         // Store and retrieve
         let id = vault.store(template.clone()).await?;
         let retrieved = vault.get(id).await?;
+        assert_eq!(retrieved.revision, 1);
         assert_eq!(retrieved.data, template.data);
 
         // Ensure data is flushed
@@ -196,4 +1085,142 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_legacy_json_entries_still_decrypt() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let vault = TemplateVault::new(temp_dir.path()).await?;
+
+        let template = Template::builder()
+            .data(vec![9, 8, 7])
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(0.5)
+            .extra_field("legacy", true)
+            .build()
+            .unwrap();
+        let id = vault.store(template.clone()).await?;
+
+        // Overwrite with an entry as written before the format byte existed
+        let plaintext = serde_json::to_vec(&template).unwrap();
+        let encrypted = vault.encryption.encrypt(&plaintext).await?;
+        let legacy = serde_json::to_vec(&encrypted).unwrap();
+        vault.db.read().await.insert(id.as_bytes(), legacy)?;
+
+        // Legacy entries carry no ID; it is filled in from the key
+        let legacy = vault.get(id).await?;
+        assert_eq!(legacy.data, vec![9, 8, 7]);
+        assert_eq!(legacy.id, Some(id));
+        assert_eq!(vault.verify_all().await?.checked, 1);
+
+        assert_eq!(vault.migrate_format().await?, 1);
+        let stored = vault.db.read().await.get(id.as_bytes())?.unwrap();
+        assert!(!crate::storage::format::is_legacy(&stored));
+        assert!(crate::storage::format::is_bound(&stored));
+        let migrated = vault.get(id).await?;
+        assert_eq!(migrated.data, vec![9, 8, 7]);
+        assert_eq!(migrated.metadata.extra, serde_json::json!({ "legacy": true }));
+        assert_eq!(vault.migrate_format().await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_old_metadata_migrated_on_read() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let config = VaultConfig::new().extra_index([crate::templates::LEGACY_EXTRA_FIELD]);
+        let mut vault = TemplateVault::with_config(temp_dir.path(), config).await?;
+
+        let template = Template::builder()
+            .data(vec![4, 5, 6])
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap();
+        let id = vault.store(template).await?;
+
+        // Overwrite with version 1 metadata, as stored before schema versions
+        let old_metadata: serde_json::Value =
+            serde_json::from_str(include_str!("../../tests/fixtures/metadata_v1_scalar_extra.json")).unwrap();
+        let old_template = serde_json::json!({ "id": id, "data": [4, 5, 6], "metadata": old_metadata });
+        let plaintext = serde_json::to_vec(&old_template).unwrap();
+        let stored = vault.seal_template(&plaintext, id, None).await?;
+        vault.db.read().await.insert(id.as_bytes(), stored.as_slice())?;
+        let sealed_metadata = vault.seal(&serde_json::to_vec(&old_metadata).unwrap()).await?;
+        vault.metadata.insert(id.as_bytes(), sealed_metadata)?;
+
+        // Reads migrate, but leave the stored value alone by default
+        let expected = serde_json::json!({ "value": "enrolled at branch 12" });
+        let read = vault.get(id).await?;
+        assert_eq!(read.metadata.extra, expected);
+        assert_eq!(read.metadata.schema_version, MetadataVersion::LATEST);
+        assert_eq!(vault.list_metadata(0, 10).await?[0].1.extra, expected);
+        assert_eq!(vault.db.read().await.get(id.as_bytes())?.unwrap(), stored.as_slice());
+
+        vault.upgrade_metadata = true;
+        let read = vault.get(id).await?;
+        assert_eq!(read.metadata.extra, expected);
+        assert_eq!(read.revision, 1);
+        let upgraded = vault.db.read().await.get(id.as_bytes())?.unwrap();
+        assert_ne!(upgraded, stored.as_slice());
+        let (_, schema) = vault.decode_versioned_template(id, &upgraded).await?;
+        assert_eq!(schema, MetadataVersion::LATEST);
+        let raw = vault.open(&vault.metadata.get(id.as_bytes())?.unwrap()).await?;
+        assert_eq!(decode_metadata_json(&raw)?.1, MetadataVersion::LATEST);
+        let value = serde_json::json!("enrolled at branch 12");
+        assert_eq!(vault.find_by_extra("value", &value).await?, vec![id]);
+
+        // Current templates are not rewritten
+        vault.get(id).await?;
+        assert_eq!(vault.db.read().await.get(id.as_bytes())?.unwrap(), upgraded);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_type_index_rebuilt_when_missing() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let vault = TemplateVault::new(temp_dir.path()).await?;
+
+        let template = Template::builder()
+            .data(vec![1, 2, 3, 4])
+            .template_type(TemplateType::Voice)
+            .quality_score(0.5)
+            .build()
+            .unwrap();
+        let id = vault.store(template).await?;
+
+        // Simulate a vault written before the index existed
+        vault.type_index.clear()?;
+        assert_eq!(vault.find_by_type(TemplateType::Voice).await?, vec![id]);
+        assert!(vault.find_by_type(TemplateType::Face).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metadata_tree_follows_templates() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let vault = TemplateVault::new(temp_dir.path()).await?;
+
+        let template = Template::builder()
+            .data(vec![1, 2, 3, 4])
+            .template_type(TemplateType::Iris)
+            .quality_score(0.5)
+            .build()
+            .unwrap();
+        let id = vault.store(template).await?;
+        assert!(vault.metadata.contains_key(id.as_bytes())?);
+
+        // Metadata is encrypted at rest like the template itself
+        let raw = vault.metadata.get(id.as_bytes())?.unwrap();
+        assert!(serde_json::from_slice::<TemplateMetadata>(&raw).is_err());
+
+        vault.rotate_key().await?;
+        assert_eq!(vault.list_metadata(0, 10).await?[0].1.template_type, TemplateType::Iris);
+
+        vault.delete(id).await?;
+        assert!(!vault.metadata.contains_key(id.as_bytes())?);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,63 @@
+use super::audit::{AuditContext, AuditOperation};
+use super::error::StorageError;
+use super::events::VaultEvent;
+use super::keyring::{generate_hash_key, shred_keys};
+use super::vault::TemplateVault;
+use super::Result;
+use uuid::Uuid;
+
+impl TemplateVault {
+    /// Destroy every stored template and replace the data key
+    ///
+    /// The wrapped keys in the keyring are overwritten and flushed first.
+    /// All templates, namespaces, indexes, aliases, history and tombstones
+    /// are then removed and flushed, and the data key is discarded in favor
+    /// of a fresh one, so values left in stale sled pages can no longer be
+    /// decrypted. The key of the content and subject hashes is replaced too,
+    /// so hashes left behind do not match those of data stored afterwards.
+    /// The audit log is kept and records the wipe. The vault stays usable
+    /// afterwards. Subscribers see every wiped template as deleted.
+    pub async fn wipe(&self) -> Result<()> {
+        self.ensure_writable()?;
+        let _rotation = self.rotation.write().await;
+        let namespaces = self.namespace_trees().await?;
+        let db = self.db.write().await;
+        let mut wiped = Vec::new();
+        for templates in std::iter::once(&**db).chain(namespaces.iter().map(|namespace| &namespace.templates)) {
+            for key in templates.iter().keys() {
+                if let Ok(id) = Uuid::from_slice(&key?) {
+                    wiped.push(id);
+                }
+            }
+        }
+
+        shred_keys(&self.keyring)?;
+        for tree in self.entry_trees(&db).all() {
+            tree.clear()?;
+        }
+        // Handles to a dropped namespace must not be used, so empty it first
+        for namespace in namespaces {
+            for tree in namespace.all() {
+                tree.clear()?;
+                db.drop_tree(tree.name())?;
+            }
+        }
+        self.init_type_index(&db)?;
+        self.init_lookup_index()?;
+        db.flush()?;
+        drop(db);
+
+        self.encryption.key_manager().reset().await
+            .map_err(StorageError::Encryption)?;
+        self.persist_keys().await?;
+        // Hashes of wiped data must not match those of re-enrolled data
+        let hash_key = generate_hash_key(&self.keyring, self.key_provider.as_ref()).await?;
+        *self.hash_key.write().unwrap_or_else(|e| e.into_inner()) = hash_key;
+        self.reset_entries(&*self.db.read().await);
+        for id in wiped {
+            self.emit(VaultEvent::Deleted(id));
+        }
+
+        self.record_audit(AuditOperation::Wipe, None, &AuditContext::default())
+    }
+}
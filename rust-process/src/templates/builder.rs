@@ -0,0 +1,133 @@
+use super::error::TemplateError;
+use super::migration::MetadataVersion;
+use super::quality::QualityAssessor;
+use super::template::{Provenance, Template, TemplateMetadata, TemplateType};
+use super::validation::ValidationPolicy;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+/// Version given to templates built without `TemplateBuilder::version`
+pub const DEFAULT_TEMPLATE_VERSION: &str = "1.0";
+
+/// Where `build` takes the quality score from
+#[derive(Debug, Clone)]
+enum Quality {
+    Score(f32),
+    Assessed(Arc<dyn QualityAssessor>),
+}
+
+/// Construction of a `Template` that only yields valid templates
+///
+/// Data, type and quality score, or an assessor computing it, are required. `build` checks the result
+/// with `Template::validate`, so a quality score outside 0.0..=1.0, empty
+/// data or a malformed version never make it into a template.
+#[derive(Debug, Clone)]
+pub struct TemplateBuilder {
+    data: Option<Vec<u8>>,
+    template_type: Option<TemplateType>,
+    quality: Option<Quality>,
+    version: String,
+    extra: Map<String, Value>,
+    provenance: Option<Provenance>,
+    policy: ValidationPolicy,
+}
+
+impl Default for TemplateBuilder {
+    fn default() -> Self {
+        Self {
+            data: None,
+            template_type: None,
+            quality: None,
+            version: DEFAULT_TEMPLATE_VERSION.to_string(),
+            extra: Map::new(),
+            provenance: None,
+            policy: ValidationPolicy::default(),
+        }
+    }
+}
+
+impl TemplateBuilder {
+    /// Binary template data
+    pub fn data(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    /// Modality the data belongs to
+    pub fn template_type(mut self, template_type: TemplateType) -> Self {
+        self.template_type = Some(template_type);
+        self
+    }
+
+    /// Quality score, which must lie within 0.0..=1.0
+    ///
+    /// Replaces any assessor set with `assess_quality`.
+    pub fn quality_score(mut self, score: f32) -> Self {
+        self.quality = Some(Quality::Score(score));
+        self
+    }
+
+    /// Compute the quality score from the data with `assessor` on `build`
+    ///
+    /// Replaces any score set with `quality_score`.
+    pub fn assess_quality(mut self, assessor: impl QualityAssessor + 'static) -> Self {
+        self.quality = Some(Quality::Assessed(Arc::new(assessor)));
+        self
+    }
+
+    /// Template version as `major[.minor[.patch]]`, `1.0` by default
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    /// Set `key` in the metadata `extra` object
+    pub fn extra_field(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.extra.insert(key.into(), value.into());
+        self
+    }
+
+    /// Device, SDK and operator the template was captured with
+    pub fn provenance(mut self, provenance: Provenance) -> Self {
+        self.provenance = Some(provenance);
+        self
+    }
+
+    /// Check the template against `policy` instead of the default, permissive
+    /// rules
+    pub fn validation(mut self, policy: ValidationPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Assemble and validate the template
+    ///
+    /// Fails with `TemplateError::MissingField` if data, type or quality
+    /// score was not set, with the error of the quality assessor, or with
+    /// the error of `Template::validate`.
+    pub fn build(self) -> Result<Template, TemplateError> {
+        let data = self.data.ok_or(TemplateError::MissingField("data"))?;
+        let template_type = self
+            .template_type
+            .ok_or(TemplateError::MissingField("template_type"))?;
+        let quality_score = match self.quality.ok_or(TemplateError::MissingField("quality_score"))? {
+            Quality::Score(score) => score,
+            Quality::Assessed(assessor) => assessor.assess(&data)?.score,
+        };
+        let template = Template {
+            id: None,
+            data,
+            metadata: TemplateMetadata {
+                version: self.version,
+                template_type,
+                quality_score,
+                extra: Value::Object(self.extra),
+                provenance: self.provenance,
+                schema_version: MetadataVersion::LATEST,
+                unknown: Map::new(),
+            },
+        };
+        template.validate(&self.policy)?;
+        Ok(template)
+    }
+}
@@ -0,0 +1,114 @@
+//! Compact binary encoding of templates for the wire
+//!
+//! A template is one CBOR array whose elements are, in order: the layout
+//! version, the ID as a 16-byte string or null, the data as a byte string,
+//! the metadata version, the template type name, the quality score, the
+//! `extra` value and the map of unknown metadata fields. Layout 2 appends
+//! the provenance as a map; templates without one are still written in
+//! layout 1. Positions never change meaning; a new layout gets a new
+//! version.
+
+use super::error::TemplateError;
+use super::migration::MetadataVersion;
+use super::template::{Provenance, Template, TemplateMetadata, TemplateType};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+/// Media type of CBOR-encoded templates
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Layout of templates without provenance, written first in the encoding
+const LAYOUT_V1: u8 = 1;
+
+/// Layout 1 followed by the provenance
+const LAYOUT_V2: u8 = 2;
+
+/// Layout written by `to_cbor`, borrowing from the template
+#[derive(Serialize)]
+struct CborTemplateRef<'a>(
+    u8,
+    #[serde(with = "serde_bytes")] Option<&'a [u8]>,
+    #[serde(with = "serde_bytes")] &'a [u8],
+    &'a str,
+    TemplateType,
+    f32,
+    &'a Value,
+    &'a Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")] Option<&'a Provenance>,
+);
+
+/// Layout read by `from_cbor`; must match `CborTemplateRef`
+#[derive(Deserialize)]
+struct CborTemplate(
+    u8,
+    #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+    #[serde(with = "serde_bytes")] Vec<u8>,
+    String,
+    TemplateType,
+    f32,
+    Value,
+    Map<String, Value>,
+    #[serde(default)] Option<Provenance>,
+);
+
+impl Template {
+    /// Encode the template as CBOR, with `data` as a byte string instead
+    /// of JSON's array of numbers
+    pub fn to_cbor(&self) -> Result<Vec<u8>, TemplateError> {
+        let metadata = &self.metadata;
+        let layout = match metadata.provenance {
+            None => LAYOUT_V1,
+            Some(_) => LAYOUT_V2,
+        };
+        let wire = CborTemplateRef(
+            layout,
+            self.id.as_ref().map(|id| &id.as_bytes()[..]),
+            &self.data,
+            &metadata.version,
+            metadata.template_type,
+            metadata.quality_score,
+            &metadata.extra,
+            &metadata.unknown,
+            metadata.provenance.as_ref(),
+        );
+        let mut encoded = Vec::with_capacity(self.data.len() + 64);
+        ciborium::into_writer(&wire, &mut encoded)
+            .map_err(|e| TemplateError::Cbor(e.to_string()))?;
+        Ok(encoded)
+    }
+
+    /// Decode a template encoded by `to_cbor`
+    ///
+    /// Like deserializing JSON, this does not validate the template.
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, TemplateError> {
+        let CborTemplate(layout, id, data, version, template_type, quality_score, extra, unknown, provenance) =
+            ciborium::from_reader(bytes).map_err(|e| TemplateError::Cbor(e.to_string()))?;
+        match (layout, &provenance) {
+            (LAYOUT_V1, None) | (LAYOUT_V2, Some(_)) => {}
+            _ => {
+                return Err(TemplateError::InvalidFormat(format!(
+                    "unsupported CBOR template layout {}",
+                    layout
+                )))
+            }
+        }
+        let id = id
+            .map(|id| Uuid::from_slice(&id))
+            .transpose()
+            .map_err(|e| TemplateError::Cbor(format!("invalid template ID: {}", e)))?;
+        Ok(Self {
+            id,
+            data,
+            metadata: TemplateMetadata {
+                version,
+                template_type,
+                quality_score,
+                extra,
+                provenance,
+                schema_version: MetadataVersion::LATEST,
+                unknown,
+            },
+        })
+    }
+}
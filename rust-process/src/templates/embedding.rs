@@ -0,0 +1,98 @@
+//! Templates holding float embeddings
+//!
+//! An embedding is stored as its components in order, each a little-endian
+//! IEEE 754 `f32`, so a `d`-dimensional embedding is `4 * d` bytes. The
+//! dimension is declared in `extra["embedding"]`, letting readers tell a
+//! truncated or mislabeled embedding from a valid one.
+
+use super::error::TemplateError;
+use super::template::{Template, TemplateMetadata};
+use super::validation::ValidationPolicy;
+use serde_json::{json, Map, Value};
+
+/// Field of the metadata `extra` object declaring the embedding layout
+pub const EMBEDDING_FIELD: &str = "embedding";
+
+/// Name of the component encoding in the declaration
+const ENCODING: &str = "f32le";
+
+/// Declaration of an embedding of `dimensions` components
+pub(crate) fn declaration(dimensions: usize) -> Value {
+    json!({
+        "encoding": ENCODING,
+        "dimensions": dimensions,
+    })
+}
+
+impl Template {
+    /// Template holding `embedding`, with its dimension declared in
+    /// `extra["embedding"]`
+    ///
+    /// An `extra` of `null` is taken as an empty object. The template is
+    /// checked against the default `ValidationPolicy`, as
+    /// `TemplateBuilder::build` does; an empty embedding is rejected as too
+    /// short.
+    pub fn from_embedding(embedding: Vec<f32>, mut metadata: TemplateMetadata) -> Result<Self, TemplateError> {
+        if metadata.extra.is_null() {
+            metadata.extra = Value::Object(Map::new());
+        }
+        let Some(extra) = metadata.extra.as_object_mut() else {
+            return Err(TemplateError::InvalidMetadata("metadata extra is not an object".into()));
+        };
+        extra.insert(EMBEDDING_FIELD.into(), declaration(embedding.len()));
+
+        let template = Self {
+            id: None,
+            data: embedding.iter().flat_map(|value| value.to_le_bytes()).collect(),
+            metadata,
+        };
+        template.validate(&ValidationPolicy::default())?;
+        Ok(template)
+    }
+
+    /// Read the data as an embedding of little-endian `f32`s
+    ///
+    /// Fails with `TemplateError::EmbeddingLength` if the data is not a whole
+    /// number of components, and `TemplateError::EmbeddingDimensions` if it
+    /// holds another number than `extra["embedding"]` declares. Data without
+    /// a declaration, such as templates stored before it was recorded, is
+    /// read as is.
+    pub fn as_embedding(&self) -> Result<Vec<f32>, TemplateError> {
+        let chunks = self.data.chunks_exact(4);
+        if !chunks.remainder().is_empty() {
+            return Err(TemplateError::EmbeddingLength(self.data.len()));
+        }
+        let actual = self.data.len() / 4;
+        if let Some(declared) = self.embedding_dimensions()? {
+            if declared != actual {
+                return Err(TemplateError::EmbeddingDimensions { declared, actual });
+            }
+        }
+        Ok(chunks
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().expect("4-byte chunk")))
+            .collect())
+    }
+
+    /// Dimension declared in `extra["embedding"]`, `None` if the template
+    /// declares none
+    ///
+    /// Fails with `TemplateError::InvalidMetadata` for a declaration of
+    /// another encoding or without a dimension.
+    pub fn embedding_dimensions(&self) -> Result<Option<usize>, TemplateError> {
+        let Some(declaration) = self.metadata.extra.get(EMBEDDING_FIELD) else {
+            return Ok(None);
+        };
+        let invalid = || {
+            TemplateError::InvalidMetadata(format!("invalid {} declaration: {}", EMBEDDING_FIELD, declaration))
+        };
+        if declaration.get("encoding").is_some_and(|encoding| encoding != ENCODING) {
+            return Err(invalid());
+        }
+        declaration
+            .get("dimensions")
+            .and_then(Value::as_u64)
+            .and_then(|dimensions| usize::try_from(dimensions).ok())
+            .map(Some)
+            .ok_or_else(invalid)
+    }
+}
@@ -1,3 +1,6 @@
+use super::migration::MetadataVersion;
+use super::template::TemplateType;
+use super::validation::TemplateFormat;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -11,8 +14,81 @@ pub enum TemplateError {
     #[error("Template validation failed: {0}")]
     ValidationFailed(String),
 
-    #[error("Template quality below threshold: {0}")]
-    QualityBelowThreshold(f32),
+    #[error("Template {0} is not set")]
+    MissingField(&'static str),
+
+    #[error("Unknown metadata fields: {}", .0.join(", "))]
+    UnknownFields(Vec<String>),
+
+    #[error("Template quality {score} is below the threshold of {min}")]
+    QualityBelowThreshold { score: f32, min: f32 },
+
+    #[error("Template quality score {0} is outside 0.0..=1.0")]
+    QualityOutOfRange(f32),
+
+    #[error("{} template data is {len} bytes, at least {min} required", .template_type.as_str())]
+    DataTooShort {
+        template_type: TemplateType,
+        len: usize,
+        min: usize,
+    },
+
+    #[error("{} template data is {len} bytes, at most {max} allowed", .template_type.as_str())]
+    DataTooLong {
+        template_type: TemplateType,
+        len: usize,
+        max: usize,
+    },
+
+    #[error("Invalid template version {0:?}: expected major[.minor[.patch]]")]
+    InvalidVersion(String),
+
+    #[error("{} template data has no recognized record header", .0.as_str())]
+    UnknownFormat(TemplateType),
+
+    #[error("{format} record stored as a {} template", .template_type.as_str())]
+    FormatMismatch {
+        format: TemplateFormat,
+        template_type: TemplateType,
+    },
+
+    #[error("Invalid {format} header: {reason}")]
+    InvalidHeader { format: TemplateFormat, reason: String },
+
+    #[error("{format} record of {len} bytes is truncated; {needed} bytes needed")]
+    TruncatedRecord {
+        format: TemplateFormat,
+        len: usize,
+        needed: usize,
+    },
+
+    #[error("Finger view {view} declares {count} minutiae, but the record has room for {room}")]
+    InvalidMinutiaeCount { view: usize, count: usize, room: usize },
+
+    #[error("Minutia {index} of finger view {view} is invalid: {reason}")]
+    InvalidMinutia {
+        view: usize,
+        index: usize,
+        reason: String,
+    },
+
+    #[error("Embedding data of {0} bytes is not a whole number of f32 components")]
+    EmbeddingLength(usize),
+
+    #[error("Embedding declares {declared} dimensions, but its data holds {actual}")]
+    EmbeddingDimensions { declared: usize, actual: usize },
+
+    #[error("Invalid metadata: {0}")]
+    InvalidMetadata(String),
+
+    #[error("Metadata schema {version} is newer than the supported {latest}")]
+    UnsupportedMetadataVersion {
+        version: MetadataVersion,
+        latest: MetadataVersion,
+    },
+
+    #[error("CBOR error: {0}")]
+    Cbor(String),
 
     #[error("Serialization error: {0}")]
     Serialization(#[from] bincode::Error),
@@ -0,0 +1,367 @@
+//! ISO/IEC 19794-2:2005 finger minutiae records
+//!
+//! A record is a 24-byte header followed by one or more finger views, each
+//! a 4-byte view header, 6 bytes per minutia and a block of extended data.
+//! All fields are big-endian.
+
+use crate::templates::error::TemplateError;
+use crate::templates::template::{Template, TemplateType};
+use crate::templates::validation::{TemplateFormat, FMR_HEADER_LEN};
+
+const FORMAT: TemplateFormat = TemplateFormat::IsoFingerMinutiae;
+
+/// Version field of the 2005 edition
+const VERSION: &[u8; 4] = b" 20\0";
+
+/// Finger view header: position, view and impression, quality, count
+const VIEW_HEADER_LEN: usize = 4;
+
+const MINUTIA_LEN: usize = 6;
+
+/// Length field of the extended data block closing each view
+const EXTENDED_LEN_LEN: usize = 2;
+
+/// Largest coordinate the 14-bit position fields hold
+const MAX_COORDINATE: u16 = 0x3fff;
+
+/// Largest quality value; higher ones are reserved
+const MAX_QUALITY: u8 = 100;
+
+/// Finger a view was captured from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FingerPosition {
+    Unknown = 0,
+    RightThumb = 1,
+    RightIndex = 2,
+    RightMiddle = 3,
+    RightRing = 4,
+    RightLittle = 5,
+    LeftThumb = 6,
+    LeftIndex = 7,
+    LeftMiddle = 8,
+    LeftRing = 9,
+    LeftLittle = 10,
+}
+
+impl TryFrom<u8> for FingerPosition {
+    type Error = u8;
+
+    fn try_from(code: u8) -> Result<Self, u8> {
+        use FingerPosition::*;
+        Ok(match code {
+            0 => Unknown,
+            1 => RightThumb,
+            2 => RightIndex,
+            3 => RightMiddle,
+            4 => RightRing,
+            5 => RightLittle,
+            6 => LeftThumb,
+            7 => LeftIndex,
+            8 => LeftMiddle,
+            9 => LeftRing,
+            10 => LeftLittle,
+            _ => return Err(code),
+        })
+    }
+}
+
+/// Kind of ridge feature a minutia marks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinutiaType {
+    Other,
+    RidgeEnding,
+    Bifurcation,
+}
+
+/// One minutia point of a finger view
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Minutia {
+    pub kind: MinutiaType,
+    /// Horizontal position in pixels, at most 14 bits
+    pub x: u16,
+    /// Vertical position in pixels, at most 14 bits
+    pub y: u16,
+    /// Ridge direction in units of 360/256 degrees
+    pub angle: u8,
+    /// Quality from 0 to 100, 0 if not reported
+    pub quality: u8,
+}
+
+impl Minutia {
+    /// Ridge direction in degrees
+    pub fn angle_degrees(&self) -> f32 {
+        self.angle as f32 * 360.0 / 256.0
+    }
+}
+
+/// Minutiae of one capture of one finger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FingerView {
+    pub finger_position: FingerPosition,
+    /// Number of the view among views of the same finger, at most 4 bits
+    pub view_number: u8,
+    /// How the image was captured, such as 0 for a live-scan plain
+    /// impression, at most 4 bits
+    pub impression_type: u8,
+    /// Finger quality from 0 to 100
+    pub quality: u8,
+    pub minutiae: Vec<Minutia>,
+    /// Extended data blocks such as ridge counts, kept unparsed
+    pub extended_data: Vec<u8>,
+}
+
+/// Parsed finger minutiae record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinutiaeRecord {
+    /// Standard the capture equipment is certified against, at most 4 bits
+    pub equipment_compliance: u8,
+    /// Vendor-assigned capture equipment ID, at most 12 bits
+    pub equipment_id: u16,
+    pub image_width: u16,
+    pub image_height: u16,
+    /// Horizontal resolution in pixels per centimeter
+    pub x_resolution: u16,
+    /// Vertical resolution in pixels per centimeter
+    pub y_resolution: u16,
+    pub views: Vec<FingerView>,
+}
+
+impl MinutiaeRecord {
+    /// Lowest finger quality of the views, as a score within 0.0..=1.0
+    pub fn quality_score(&self) -> f32 {
+        let quality = self.views.iter().map(|view| view.quality).min().unwrap_or(0);
+        quality as f32 / MAX_QUALITY as f32
+    }
+}
+
+impl Template {
+    /// Fingerprint template holding an ISO/IEC 19794-2 record as is
+    ///
+    /// The record is parsed first, so only well-formed records are
+    /// accepted. The quality score is the lowest finger quality of its
+    /// views, and `extra["format"]` names the format.
+    pub fn from_iso19794_2(bytes: &[u8]) -> Result<Self, TemplateError> {
+        let record = parse(bytes)?;
+        Self::builder()
+            .data(bytes)
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(record.quality_score())
+            .extra_field("format", FORMAT.to_string())
+            .build()
+    }
+}
+
+fn invalid(reason: String) -> TemplateError {
+    TemplateError::InvalidHeader {
+        format: FORMAT,
+        reason,
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+/// Parse a record, checking every field the standard constrains
+///
+/// Fails with `TemplateError::TruncatedRecord` if the data ends before the
+/// header, a view or the record length says it should,
+/// `TemplateError::InvalidMinutiaeCount` if a view declares more minutiae
+/// than the rest of the record holds, `TemplateError::InvalidMinutia` for
+/// minutiae of reserved types, qualities or outside the image, and
+/// `TemplateError::InvalidHeader` for any other malformed field.
+pub fn parse(data: &[u8]) -> Result<MinutiaeRecord, TemplateError> {
+    let truncated = |needed: usize| TemplateError::TruncatedRecord {
+        format: FORMAT,
+        len: data.len(),
+        needed,
+    };
+    if data.len() < FMR_HEADER_LEN {
+        return Err(truncated(FMR_HEADER_LEN));
+    }
+    if TemplateFormat::detect(data) != Some(FORMAT) {
+        return Err(TemplateError::InvalidFormat("missing FMR record identifier".into()));
+    }
+    if &data[4..8] != VERSION {
+        return Err(invalid(format!("unsupported version {:?}", &data[4..8])));
+    }
+    let record_len = u32::from_be_bytes(data[8..12].try_into().expect("4 bytes")) as usize;
+    if record_len < FMR_HEADER_LEN {
+        return Err(invalid(format!("record length {} is shorter than the header", record_len)));
+    }
+    if record_len > data.len() {
+        return Err(truncated(record_len));
+    }
+    if record_len < data.len() {
+        return Err(invalid(format!(
+            "{} bytes follow the record length of {}",
+            data.len() - record_len,
+            record_len
+        )));
+    }
+    let view_count = data[22];
+    if view_count == 0 {
+        return Err(invalid("record holds no finger views".into()));
+    }
+    if data[23] != 0 {
+        return Err(invalid("reserved header byte is set".into()));
+    }
+
+    let equipment = u16_at(data, 12);
+    let mut record = MinutiaeRecord {
+        equipment_compliance: (equipment >> 12) as u8,
+        equipment_id: equipment & 0x0fff,
+        image_width: u16_at(data, 14),
+        image_height: u16_at(data, 16),
+        x_resolution: u16_at(data, 18),
+        y_resolution: u16_at(data, 20),
+        views: Vec::with_capacity(view_count as usize),
+    };
+
+    let mut offset = FMR_HEADER_LEN;
+    for view in 0..view_count as usize {
+        if offset + VIEW_HEADER_LEN + EXTENDED_LEN_LEN > record_len {
+            return Err(truncated(offset + VIEW_HEADER_LEN + EXTENDED_LEN_LEN));
+        }
+        let header = &data[offset..offset + VIEW_HEADER_LEN];
+        let finger_position = FingerPosition::try_from(header[0])
+            .map_err(|code| invalid(format!("finger view {} has finger position {}", view, code)))?;
+        if header[2] > MAX_QUALITY {
+            return Err(invalid(format!("finger view {} has quality {}", view, header[2])));
+        }
+        let count = header[3] as usize;
+        offset += VIEW_HEADER_LEN;
+
+        let room = (record_len - offset - EXTENDED_LEN_LEN) / MINUTIA_LEN;
+        if count > room {
+            return Err(TemplateError::InvalidMinutiaeCount { view, count, room });
+        }
+        let mut minutiae = Vec::with_capacity(count);
+        for index in 0..count {
+            let minutia = parse_minutia(&data[offset..offset + MINUTIA_LEN], &record)
+                .map_err(|reason| TemplateError::InvalidMinutia { view, index, reason })?;
+            minutiae.push(minutia);
+            offset += MINUTIA_LEN;
+        }
+
+        let extended_len = u16_at(data, offset) as usize;
+        offset += EXTENDED_LEN_LEN;
+        if offset + extended_len > record_len {
+            return Err(truncated(offset + extended_len));
+        }
+        record.views.push(FingerView {
+            finger_position,
+            view_number: header[1] >> 4,
+            impression_type: header[1] & 0x0f,
+            quality: header[2],
+            minutiae,
+            extended_data: data[offset..offset + extended_len].to_vec(),
+        });
+        offset += extended_len;
+    }
+
+    if offset != record_len {
+        return Err(invalid(format!(
+            "{} bytes follow the last finger view",
+            record_len - offset
+        )));
+    }
+    Ok(record)
+}
+
+fn parse_minutia(bytes: &[u8], record: &MinutiaeRecord) -> Result<Minutia, String> {
+    let (x, y) = (u16_at(bytes, 0), u16_at(bytes, 2));
+    let kind = match x >> 14 {
+        0 => MinutiaType::Other,
+        1 => MinutiaType::RidgeEnding,
+        2 => MinutiaType::Bifurcation,
+        _ => return Err("reserved minutia type 3".into()),
+    };
+    if y >> 14 != 0 {
+        return Err("reserved position bits are set".into());
+    }
+    let (x, y) = (x & MAX_COORDINATE, y & MAX_COORDINATE);
+    // Image dimensions of 0 are unreported
+    let outside = |position: u16, size: u16| size != 0 && position >= size;
+    if outside(x, record.image_width) || outside(y, record.image_height) {
+        return Err(format!(
+            "position ({}, {}) lies outside the {}x{} image",
+            x, y, record.image_width, record.image_height
+        ));
+    }
+    if bytes[5] > MAX_QUALITY {
+        return Err(format!("quality {} exceeds {}", bytes[5], MAX_QUALITY));
+    }
+    Ok(Minutia {
+        kind,
+        x,
+        y,
+        angle: bytes[4],
+        quality: bytes[5],
+    })
+}
+
+/// Encode a record, computing its length and counts
+///
+/// # Panics
+///
+/// If the record cannot be represented: more than 255 views or minutiae in
+/// a view, more than 65535 bytes of extended data in a view, or a field
+/// wider than its documented number of bits.
+pub fn encode(record: &MinutiaeRecord) -> Vec<u8> {
+    assert!(record.equipment_compliance <= 0x0f, "equipment compliance exceeds 4 bits");
+    assert!(record.equipment_id <= 0x0fff, "equipment ID exceeds 12 bits");
+    let view_count = u8::try_from(record.views.len()).expect("at most 255 finger views");
+
+    let mut data = Vec::with_capacity(FMR_HEADER_LEN);
+    data.extend_from_slice(b"FMR\0");
+    data.extend_from_slice(VERSION);
+    // Record length, filled in once known
+    data.extend_from_slice(&[0; 4]);
+    let equipment = (record.equipment_compliance as u16) << 12 | record.equipment_id;
+    for field in [
+        equipment,
+        record.image_width,
+        record.image_height,
+        record.x_resolution,
+        record.y_resolution,
+    ] {
+        data.extend_from_slice(&field.to_be_bytes());
+    }
+    data.extend_from_slice(&[view_count, 0]);
+
+    for view in &record.views {
+        assert!(view.view_number <= 0x0f, "view number exceeds 4 bits");
+        assert!(view.impression_type <= 0x0f, "impression type exceeds 4 bits");
+        let count = u8::try_from(view.minutiae.len()).expect("at most 255 minutiae per view");
+        data.extend_from_slice(&[
+            view.finger_position as u8,
+            view.view_number << 4 | view.impression_type,
+            view.quality,
+            count,
+        ]);
+        for minutia in &view.minutiae {
+            assert!(
+                minutia.x <= MAX_COORDINATE && minutia.y <= MAX_COORDINATE,
+                "minutia position exceeds 14 bits"
+            );
+            let kind = match minutia.kind {
+                MinutiaType::Other => 0,
+                MinutiaType::RidgeEnding => 1,
+                MinutiaType::Bifurcation => 2,
+            };
+            data.extend_from_slice(&(kind << 14 | minutia.x).to_be_bytes());
+            data.extend_from_slice(&minutia.y.to_be_bytes());
+            data.extend_from_slice(&[minutia.angle, minutia.quality]);
+        }
+        let extended_len =
+            u16::try_from(view.extended_data.len()).expect("at most 65535 bytes of extended data");
+        data.extend_from_slice(&extended_len.to_be_bytes());
+        data.extend_from_slice(&view.extended_data);
+    }
+
+    let record_len = u32::try_from(data.len()).expect("record length fits 4 bytes");
+    data[8..12].copy_from_slice(&record_len.to_be_bytes());
+    data
+}
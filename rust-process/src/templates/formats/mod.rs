@@ -0,0 +1,3 @@
+//! Parsers and encoders of standard biometric record formats
+
+pub mod iso19794_2;
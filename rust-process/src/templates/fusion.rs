@@ -0,0 +1,307 @@
+//! Templates of several modalities enrolled as one record
+//!
+//! A `FusedTemplate` is stored in the vault as a `Template` of type
+//! `TemplateType::Multimodal` whose data is an envelope: the magic
+//! `FUS\0`, a version byte, then a CBOR array holding, for each modality,
+//! an array of its type name, its weight and its template encoded with
+//! `Template::to_cbor` as a byte string.
+
+use super::error::TemplateError;
+use super::matcher::{CosineMatcher, HammingMatcher, MatchScore, Matcher};
+use super::template::{Template, TemplateType};
+use super::validation::ValidationPolicy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// First bytes of the data of stored fused templates
+const ENVELOPE_MAGIC: &[u8; 4] = b"FUS\0";
+
+/// Version of the envelope layout, following the magic
+const ENVELOPE_VERSION: u8 = 1;
+
+/// One modality of a fused template
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FusionMember {
+    /// Relative weight of the modality in `FusionRule::WeightedSum`
+    pub weight: f32,
+    pub template: Template,
+}
+
+/// Templates of several modalities, at most one per modality
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FusedTemplate {
+    /// Identifier of the stored record, as for `Template`
+    pub id: Option<Uuid>,
+    members: BTreeMap<TemplateType, FusionMember>,
+}
+
+/// Member as encoded in the envelope
+#[derive(Serialize, Deserialize)]
+struct EnvelopeMember(TemplateType, f32, #[serde(with = "serde_bytes")] Vec<u8>);
+
+impl FusedTemplate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `template` under its modality with `weight`, replacing any
+    /// template of that modality
+    ///
+    /// Fails with `TemplateError::InvalidData` for multimodal templates and
+    /// weights that are not positive and finite.
+    pub fn with_member(mut self, template: Template, weight: f32) -> Result<Self, TemplateError> {
+        check_member(template.metadata.template_type, weight)?;
+        self.members
+            .insert(template.metadata.template_type, FusionMember { weight, template });
+        Ok(self)
+    }
+
+    /// Member of modality `template_type`
+    pub fn member(&self, template_type: TemplateType) -> Option<&FusionMember> {
+        self.members.get(&template_type)
+    }
+
+    /// Members by modality
+    pub fn members(&self) -> impl Iterator<Item = (TemplateType, &FusionMember)> {
+        self.members.iter().map(|(&template_type, member)| (template_type, member))
+    }
+
+    /// Remove the member of modality `template_type`
+    pub fn remove(&mut self, template_type: TemplateType) -> Option<FusionMember> {
+        self.members.remove(&template_type)
+    }
+
+    /// Check the members, as `with_member` does, that there is one, and
+    /// each template against the default `ValidationPolicy`
+    ///
+    /// Needed after deserializing, which takes members as they are.
+    pub fn validate(&self) -> Result<(), TemplateError> {
+        if self.members.is_empty() {
+            return Err(TemplateError::InvalidData("fused template has no members".into()));
+        }
+        for (&template_type, member) in &self.members {
+            check_member(template_type, member.weight)?;
+            if member.template.metadata.template_type != template_type {
+                return Err(TemplateError::InvalidData(format!(
+                    "{} template stored as the {} member",
+                    member.template.metadata.template_type.as_str(),
+                    template_type.as_str()
+                )));
+            }
+            member.template.validate(&ValidationPolicy::default())?;
+        }
+        Ok(())
+    }
+
+    /// Wrap the fused template for storage in the vault
+    ///
+    /// The result is a multimodal template whose quality score is the
+    /// weighted mean of the members' and whose `extra["modalities"]` lists
+    /// their types.
+    pub fn to_template(&self) -> Result<Template, TemplateError> {
+        self.validate()?;
+        let mut envelope = Vec::new();
+        let members = self
+            .members
+            .iter()
+            .map(|(&template_type, member)| {
+                Ok(EnvelopeMember(template_type, member.weight, member.template.to_cbor()?))
+            })
+            .collect::<Result<Vec<_>, TemplateError>>()?;
+        envelope.extend_from_slice(ENVELOPE_MAGIC);
+        envelope.push(ENVELOPE_VERSION);
+        ciborium::into_writer(&members, &mut envelope).map_err(|e| TemplateError::Cbor(e.to_string()))?;
+
+        let total_weight: f32 = self.members.values().map(|member| member.weight).sum();
+        let quality = self
+            .members
+            .values()
+            .map(|member| member.weight * member.template.metadata.quality_score)
+            .sum::<f32>()
+            / total_weight;
+        let modalities: Vec<&str> = self.members.keys().map(TemplateType::as_str).collect();
+        let mut template = Template::builder()
+            .data(envelope)
+            .template_type(TemplateType::Multimodal)
+            .quality_score(quality.clamp(0.0, 1.0))
+            .extra_field("modalities", modalities)
+            .build()?;
+        template.id = self.id;
+        Ok(template)
+    }
+
+    /// Unwrap a template written by `to_template`
+    pub fn from_template(template: &Template) -> Result<Self, TemplateError> {
+        if template.metadata.template_type != TemplateType::Multimodal {
+            return Err(TemplateError::InvalidFormat(format!(
+                "{} template is not a fused template",
+                template.metadata.template_type.as_str()
+            )));
+        }
+        let body = match template.data.strip_prefix(&ENVELOPE_MAGIC[..]).and_then(<[u8]>::split_first) {
+            Some((&ENVELOPE_VERSION, body)) => body,
+            Some((version, _)) => {
+                return Err(TemplateError::InvalidFormat(format!(
+                    "unsupported fused template envelope {}",
+                    version
+                )))
+            }
+            None => return Err(TemplateError::InvalidFormat("missing fused template envelope".into())),
+        };
+        let members: Vec<EnvelopeMember> =
+            ciborium::from_reader(body).map_err(|e| TemplateError::Cbor(e.to_string()))?;
+        let mut fused = Self {
+            id: template.id,
+            members: BTreeMap::new(),
+        };
+        for EnvelopeMember(template_type, weight, encoded) in members {
+            let member = FusionMember {
+                weight,
+                template: Template::from_cbor(&encoded)?,
+            };
+            fused.members.insert(template_type, member);
+        }
+        fused.validate()?;
+        Ok(fused)
+    }
+}
+
+fn check_member(template_type: TemplateType, weight: f32) -> Result<(), TemplateError> {
+    if template_type == TemplateType::Multimodal {
+        return Err(TemplateError::InvalidData("fused templates cannot be nested".into()));
+    }
+    if !(weight.is_finite() && weight > 0.0) {
+        return Err(TemplateError::InvalidData(format!(
+            "weight {} of the {} member is not positive",
+            weight,
+            template_type.as_str()
+        )));
+    }
+    Ok(())
+}
+
+/// How `FusionMatcher` combines the scores of the modalities
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FusionRule {
+    /// Mean of the scores weighted by the enrolled template's weights
+    #[default]
+    WeightedSum,
+    /// Best score of any modality, regardless of weights
+    Max,
+}
+
+/// Fused score with the scores it was combined from
+#[derive(Debug, Clone, PartialEq)]
+pub struct FusionScore {
+    pub score: MatchScore,
+    /// Scores of the modalities compared
+    pub modalities: BTreeMap<TemplateType, MatchScore>,
+}
+
+/// Comparison of fused templates, scoring each modality with its own
+/// matcher and combining the scores into one
+///
+/// Modalities missing from either template, without a matcher, or whose
+/// matcher cannot compare the two are left out. Matchers should score on
+/// the same scale for the combined score to be meaningful; the defaults,
+/// `CosineMatcher` for faces and voices and `HammingMatcher` for irises,
+/// score genuine pairs close to 1.0.
+///
+/// As a `Matcher`, it compares templates written by
+/// `FusedTemplate::to_template`, so `TemplateVault::identify` can search
+/// fused records.
+#[derive(Clone)]
+pub struct FusionMatcher {
+    rule: FusionRule,
+    threshold: f32,
+    matchers: BTreeMap<TemplateType, Arc<dyn Matcher>>,
+}
+
+impl fmt::Debug for FusionMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FusionMatcher")
+            .field("rule", &self.rule)
+            .field("threshold", &self.threshold)
+            .field("modalities", &self.matchers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Default for FusionMatcher {
+    fn default() -> Self {
+        Self::new(FusionRule::default(), 0.8)
+    }
+}
+
+impl FusionMatcher {
+    /// Matcher combining scores with `rule`, accepting a combined score of at
+    /// least `threshold`, with the default matchers
+    pub fn new(rule: FusionRule, threshold: f32) -> Self {
+        Self {
+            rule,
+            threshold,
+            matchers: BTreeMap::new(),
+        }
+        .with_matcher(TemplateType::Face, CosineMatcher)
+        .with_matcher(TemplateType::Voice, CosineMatcher)
+        .with_matcher(TemplateType::Iris, HammingMatcher)
+    }
+
+    /// Score modality `template_type` with `matcher`
+    pub fn with_matcher(mut self, template_type: TemplateType, matcher: impl Matcher + 'static) -> Self {
+        self.matchers.insert(template_type, Arc::new(matcher));
+        self
+    }
+
+    /// Combine the scores of the modalities `probe` and `enrolled` share, or
+    /// `None` if no modality could be compared
+    pub fn fuse(&self, probe: &FusedTemplate, enrolled: &FusedTemplate) -> Option<FusionScore> {
+        let mut modalities = BTreeMap::new();
+        let (mut weighted, mut total_weight) = (0.0, 0.0);
+        for (template_type, member) in enrolled.members() {
+            let Some(matcher) = self.matchers.get(&template_type) else {
+                continue;
+            };
+            let Some(probe) = probe.member(template_type) else {
+                continue;
+            };
+            let Some(score) = matcher
+                .score(&probe.template, &member.template)
+                .filter(|score| !score.0.is_nan())
+            else {
+                continue;
+            };
+            weighted += member.weight * score.0;
+            total_weight += member.weight;
+            modalities.insert(template_type, score);
+        }
+
+        let score = match self.rule {
+            _ if modalities.is_empty() => return None,
+            FusionRule::WeightedSum => weighted / total_weight,
+            FusionRule::Max => modalities.values().map(|score| score.0).fold(f32::MIN, f32::max),
+        };
+        Some(FusionScore {
+            score: MatchScore(score),
+            modalities,
+        })
+    }
+
+    /// Whether `probe` and `enrolled` belong to the same subject: their
+    /// fused score reaches the threshold
+    pub fn verify(&self, probe: &FusedTemplate, enrolled: &FusedTemplate) -> bool {
+        self.fuse(probe, enrolled)
+            .is_some_and(|fused| fused.score.0 >= self.threshold)
+    }
+}
+
+impl Matcher for FusionMatcher {
+    fn score(&self, probe: &Template, candidate: &Template) -> Option<MatchScore> {
+        let probe = FusedTemplate::from_template(probe).ok()?;
+        let candidate = FusedTemplate::from_template(candidate).ok()?;
+        self.fuse(&probe, &candidate).map(|fused| fused.score)
+    }
+}
@@ -0,0 +1,58 @@
+use super::template::Template;
+use super::transform;
+use serde::{Deserialize, Serialize};
+
+/// Similarity of two templates; higher scores are better matches
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct MatchScore(pub f32);
+
+/// Comparison of a probe against enrolled templates of the same type
+pub trait Matcher: Send + Sync {
+    /// Score `candidate` against `probe`, or `None` if the two cannot be
+    /// compared, such as embeddings of different dimensions
+    ///
+    /// Cancelable templates only compare to templates transformed alike
+    /// under the same key; see `transform::cancelable`.
+    fn score(&self, probe: &Template, candidate: &Template) -> Option<MatchScore>;
+}
+
+/// Cosine similarity of embeddings, read with `Template::as_embedding`
+///
+/// Scores range from -1.0 to 1.0, identical directions scoring 1.0.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CosineMatcher;
+
+impl Matcher for CosineMatcher {
+    fn score(&self, probe: &Template, candidate: &Template) -> Option<MatchScore> {
+        if !transform::comparable(probe, candidate) {
+            return None;
+        }
+        let (a, b) = (probe.as_embedding().ok()?, candidate.as_embedding().ok()?);
+        if a.len() != b.len() {
+            return None;
+        }
+        let dot: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let score = dot / (norm(&a) * norm(&b));
+        // Zero vectors and non-finite components have no direction
+        score.is_finite().then_some(MatchScore(score))
+    }
+}
+
+/// Fraction of equal bits between binary codes of the same length, such
+/// as iris codes
+///
+/// Scores range from 0.0 to 1.0, identical codes scoring 1.0.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HammingMatcher;
+
+impl Matcher for HammingMatcher {
+    fn score(&self, probe: &Template, candidate: &Template) -> Option<MatchScore> {
+        let (a, b) = (&probe.data, &candidate.data);
+        if a.is_empty() || a.len() != b.len() || !transform::comparable(probe, candidate) {
+            return None;
+        }
+        let differing: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+        Some(MatchScore(1.0 - differing as f32 / (a.len() * 8) as f32))
+    }
+}
@@ -0,0 +1,140 @@
+//! Upgrades of serialized metadata to the current schema
+//!
+//! Metadata records the shape it was written in as `schema_version`;
+//! metadata written before the field existed is version 1. Every
+//! registered migration rewrites the JSON object of one version into the
+//! next, so metadata of any known version reaches the latest by applying
+//! them in turn.
+
+use super::error::TemplateError;
+use super::template::TemplateMetadata;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Metadata field holding the schema version
+const VERSION_FIELD: &str = "schema_version";
+
+/// Field `extra` values that are not objects are moved to by version 2
+pub const LEGACY_EXTRA_FIELD: &str = "value";
+
+/// Schema of serialized `TemplateMetadata`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MetadataVersion(u32);
+
+impl MetadataVersion {
+    /// Metadata written before schema versions were recorded; `extra` may be
+    /// missing, null or any JSON value, and the quality score may be named
+    /// `quality`
+    pub const V1: Self = Self(1);
+
+    /// `extra` is always an object
+    pub const V2: Self = Self(2);
+
+    /// Version written by this release
+    pub const LATEST: Self = Self::V2;
+
+    pub fn new(version: u32) -> Self {
+        Self(version)
+    }
+
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for MetadataVersion {
+    fn default() -> Self {
+        Self::LATEST
+    }
+}
+
+impl fmt::Display for MetadataVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// Rewrite of metadata of version `from` into the next version
+struct Migration {
+    from: MetadataVersion,
+    to: MetadataVersion,
+    apply: fn(&mut Map<String, Value>) -> Result<(), TemplateError>,
+}
+
+/// Every migration, ordered by `from`
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: MetadataVersion::V1,
+    to: MetadataVersion::V2,
+    apply: v1_to_v2,
+}];
+
+fn v1_to_v2(fields: &mut Map<String, Value>) -> Result<(), TemplateError> {
+    if let Some(quality) = fields.remove("quality") {
+        if fields.contains_key("quality_score") {
+            return Err(TemplateError::InvalidMetadata(
+                "both quality and quality_score are set".into(),
+            ));
+        }
+        fields.insert("quality_score".into(), quality);
+    }
+    let extra = match fields.remove("extra") {
+        None | Some(Value::Null) => Value::Object(Map::new()),
+        Some(extra @ Value::Object(_)) => extra,
+        Some(other) => Value::Object(Map::from_iter([(LEGACY_EXTRA_FIELD.to_string(), other)])),
+    };
+    fields.insert("extra".into(), extra);
+    Ok(())
+}
+
+/// Schema version of serialized metadata, version 1 if it names none
+fn version_of(fields: &Map<String, Value>) -> Result<MetadataVersion, TemplateError> {
+    match fields.get(VERSION_FIELD) {
+        None => Ok(MetadataVersion::V1),
+        Some(value) => value
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|&version| version > 0)
+            .map(MetadataVersion)
+            .ok_or_else(|| TemplateError::InvalidMetadata(format!("invalid {}: {}", VERSION_FIELD, value))),
+    }
+}
+
+impl TemplateMetadata {
+    /// Deserialize metadata of any known schema version, upgrading it step
+    /// by step to `MetadataVersion::LATEST`
+    ///
+    /// Unlike plain deserialization, which takes metadata without a schema
+    /// version to be current, this reads it as version 1. Fails with
+    /// `TemplateError::UnsupportedMetadataVersion` for metadata written by a
+    /// newer release, and `TemplateError::InvalidMetadata` if `value` is not
+    /// metadata of the version it claims.
+    pub fn migrate_to_latest(value: Value) -> Result<Self, TemplateError> {
+        Self::migrate_with_version(value).map(|(metadata, _)| metadata)
+    }
+
+    /// `migrate_to_latest`, also returning the version `value` was read as
+    pub(crate) fn migrate_with_version(value: Value) -> Result<(Self, MetadataVersion), TemplateError> {
+        let Value::Object(mut fields) = value else {
+            return Err(TemplateError::InvalidMetadata("metadata is not a JSON object".into()));
+        };
+        let original = version_of(&fields)?;
+        if original > MetadataVersion::LATEST {
+            return Err(TemplateError::UnsupportedMetadataVersion {
+                version: original,
+                latest: MetadataVersion::LATEST,
+            });
+        }
+
+        let mut version = original;
+        for migration in MIGRATIONS.iter().filter(|migration| migration.from >= original) {
+            (migration.apply)(&mut fields)?;
+            version = migration.to;
+        }
+        fields.insert(VERSION_FIELD.into(), version.0.into());
+        let metadata = serde_json::from_value(Value::Object(fields))
+            .map_err(|e| TemplateError::InvalidMetadata(e.to_string()))?;
+        Ok((metadata, original))
+    }
+}
@@ -1,29 +1,905 @@
 mod template;
+mod builder;
+mod cbor;
+mod embedding;
 mod error;
+pub mod formats;
+mod fusion;
+mod matcher;
+mod migration;
+mod quality;
+pub mod transform;
+mod validation;
 
+pub use builder::{TemplateBuilder, DEFAULT_TEMPLATE_VERSION};
+pub use cbor::CBOR_CONTENT_TYPE;
+pub use embedding::EMBEDDING_FIELD;
 pub use error::TemplateError;
-pub use template::{Template, TemplateMetadata, TemplateType};
+pub use fusion::{FusedTemplate, FusionMatcher, FusionMember, FusionRule, FusionScore};
+pub use matcher::{CosineMatcher, HammingMatcher, MatchScore, Matcher};
+pub use migration::{MetadataVersion, LEGACY_EXTRA_FIELD};
+pub use quality::{GrayImage, ImageQualityAssessor, QualityAssessor, QualityReport, SubScore};
+pub use template::{Provenance, Template, TemplateMetadata, TemplateType};
+pub use validation::{ModalityRules, TemplateFormat, TemplateVersion, ValidationPolicy};
 
 pub type Result<T> = std::result::Result<T, TemplateError>;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use formats::iso19794_2::{self, FingerPosition, Minutia, MinutiaType};
     use template::{TemplateMetadata, TemplateType};
 
     #[test]
     fn test_template_creation() {
-        let template = Template::new(
-            vec![1, 2, 3, 4],
-            TemplateMetadata {
-                version: "1.0".to_string(),
-                template_type: TemplateType::Face,
-                quality_score: 0.95,
+        let template = Template::builder()
+            .data(vec![1, 2, 3, 4])
+            .template_type(TemplateType::Face)
+            .quality_score(0.95)
+            .build()
+            .unwrap();
+
+        assert_eq!(template.data, vec![1, 2, 3, 4]);
+        assert!(template.validate(&ValidationPolicy::default()).is_ok());
+    }
+
+    #[test]
+    fn test_unknown_metadata_fields_preserved() {
+        let json = serde_json::json!({
+            "version": "1.0",
+            "template_type": "face",
+            "quality_score": 0.5,
+            "extra": {},
+            "schema_version": 2,
+            "capture_fps": 30,
+            "sdk_hint": {"name": "next"}
+        });
+        let metadata: TemplateMetadata = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(metadata.unknown.len(), 2);
+        assert_eq!(metadata.unknown["capture_fps"], 30);
+
+        // Serializing puts the fields back at the top level unchanged
+        assert_eq!(serde_json::to_value(&metadata).unwrap(), json);
+
+        assert!(metadata.check_unknown_fields(false, 1024).is_ok());
+        assert!(matches!(
+            metadata.check_unknown_fields(false, 8),
+            Err(TemplateError::InvalidData(_))
+        ));
+        match metadata.check_unknown_fields(true, 1024) {
+            Err(TemplateError::UnknownFields(fields)) => {
+                assert_eq!(fields, vec!["capture_fps", "sdk_hint"]);
+            }
+            other => panic!("Expected unknown field rejection, got {:?}", other),
+        }
+    }
+
+    /// Record of `len` bytes with a 4-byte length field, as in every format
+    /// but the 2005 minutiae one
+    fn record(magic: &[u8; 4], version: &[u8; 4], len: usize) -> Vec<u8> {
+        let mut data = magic.to_vec();
+        data.extend_from_slice(version);
+        data.extend_from_slice(&(len as u32).to_be_bytes());
+        data.resize(len, 0);
+        data
+    }
+
+    #[test]
+    fn test_builder() {
+        let builder = || {
+            Template::builder()
+                .data(vec![1, 2, 3, 4])
+                .template_type(TemplateType::Iris)
+                .quality_score(0.7)
+        };
+        let template = builder()
+            .version("2.1")
+            .extra_field("eye", "left")
+            .extra_field("attempt", 2)
+            .build()
+            .unwrap();
+        assert_eq!(template.id, None);
+        assert_eq!(template.metadata.version, "2.1");
+        assert_eq!(template.metadata.extra, serde_json::json!({ "eye": "left", "attempt": 2 }));
+        assert_eq!(builder().build().unwrap().metadata.version, DEFAULT_TEMPLATE_VERSION);
+
+        // Every required field must be set
+        let missing = Template::builder().template_type(TemplateType::Iris).quality_score(0.7);
+        assert!(matches!(missing.build(), Err(TemplateError::MissingField("data"))));
+        let missing = Template::builder().data(vec![1]).quality_score(0.7);
+        assert!(matches!(missing.build(), Err(TemplateError::MissingField("template_type"))));
+        let missing = Template::builder().data(vec![1]).template_type(TemplateType::Iris);
+        assert!(matches!(missing.build(), Err(TemplateError::MissingField("quality_score"))));
+
+        for score in [7.3, -0.1, f32::NAN, f32::INFINITY] {
+            let result = builder().quality_score(score).build();
+            assert!(matches!(result, Err(TemplateError::QualityOutOfRange(_))), "{score}");
+        }
+        assert!(matches!(
+            builder().data(Vec::new()).build(),
+            Err(TemplateError::DataTooShort { len: 0, .. })
+        ));
+        assert!(matches!(
+            builder().version("1.x").build(),
+            Err(TemplateError::InvalidVersion(version)) if version == "1.x"
+        ));
+        assert!(matches!(
+            builder().validation(ValidationPolicy::new().min_quality(0.8)).build(),
+            Err(TemplateError::QualityBelowThreshold { .. })
+        ));
+    }
+
+    #[test]
+    fn test_validation_failure_modes() {
+        // Unchecked templates, as the builder would reject most of them
+        let template = |data: Vec<u8>, template_type, version: &str, quality_score| Template {
+            id: None,
+            data,
+            metadata: TemplateMetadata {
+                version: version.to_string(),
+                template_type,
+                quality_score,
                 extra: serde_json::json!({}),
+                provenance: None,
+                schema_version: MetadataVersion::LATEST,
+                unknown: serde_json::Map::new(),
             },
+        };
+        let face = |data: Vec<u8>| template(data, TemplateType::Face, "1.0", 0.8);
+        let default = ValidationPolicy::default();
+        let recommended = ValidationPolicy::recommended();
+
+        // Quality score
+        let result = template(vec![1; 4], TemplateType::Face, "1.0", 1.5).validate(&default);
+        assert!(matches!(result, Err(TemplateError::QualityOutOfRange(score)) if score == 1.5));
+        let result = template(vec![1; 4], TemplateType::Face, "1.0", f32::NAN).validate(&default);
+        assert!(matches!(result, Err(TemplateError::QualityOutOfRange(_))));
+        let strict = ValidationPolicy::new().min_quality(0.9);
+        assert!(matches!(
+            face(vec![1; 4]).validate(&strict),
+            Err(TemplateError::QualityBelowThreshold { min, .. }) if min == 0.9
+        ));
+
+        // Version
+        for version in ["1", "v2.3", "10.0.1"] {
+            assert!(template(vec![1; 4], TemplateType::Face, version, 0.8).validate(&default).is_ok());
+        }
+        for version in ["", "1.x", "1..2", "+1", "1.2.3.4", "latest"] {
+            let result = template(vec![1; 4], TemplateType::Face, version, 0.8).validate(&default);
+            assert!(
+                matches!(result, Err(TemplateError::InvalidVersion(ref v)) if v == version),
+                "{:?} was accepted",
+                version
+            );
+        }
+        assert_eq!(
+            "v1.2".parse::<TemplateVersion>().unwrap(),
+            TemplateVersion { major: 1, minor: 2, patch: 0 }
         );
 
-        assert_eq!(template.data, vec![1, 2, 3, 4]);
-        assert!(template.validate());
+        // Data length, per modality
+        assert!(matches!(
+            face(Vec::new()).validate(&default),
+            Err(TemplateError::DataTooShort { len: 0, min: 1, .. })
+        ));
+        assert!(face(vec![1; 4]).validate(&default).is_ok());
+        assert!(matches!(
+            face(vec![1; 4]).validate(&recommended),
+            Err(TemplateError::DataTooShort { template_type: TemplateType::Face, len: 4, min: 64 })
+        ));
+        assert!(matches!(
+            template(vec![1; 255], TemplateType::Iris, "1.0", 0.8).validate(&recommended),
+            Err(TemplateError::DataTooShort { template_type: TemplateType::Iris, .. })
+        ));
+        assert!(matches!(
+            face(vec![1; 1024 * 1024 + 1]).validate(&recommended),
+            Err(TemplateError::DataTooLong { max, .. }) if max == 1024 * 1024
+        ));
+        assert!(template(vec![1; 4], TemplateType::Other, "1.0", 0.8).validate(&recommended).is_ok());
+
+        // Record headers of known formats
+        let fingerprint = |data| template(data, TemplateType::Fingerprint, "1.0", 0.8);
+        assert!(fingerprint(record(b"FMR\0", b"030\0", 40)).validate(&recommended).is_ok());
+        assert!(fingerprint(record(b"FMR\0", b" 20\0", 30)).validate(&recommended).is_ok());
+        assert!(face(record(b"FAC\0", b"010\0", 80)).validate(&recommended).is_ok());
+
+        let mut truncated = record(b"FMR\0", b"030\0", 40);
+        truncated.truncate(30);
+        assert!(matches!(
+            fingerprint(truncated).validate(&default),
+            Err(TemplateError::InvalidHeader { format: TemplateFormat::IsoFingerMinutiae, .. })
+        ));
+        assert!(matches!(
+            face(record(b"FAC\0", b"1.0\0", 80)).validate(&default),
+            Err(TemplateError::InvalidHeader { format: TemplateFormat::IsoFace, .. })
+        ));
+        assert!(matches!(
+            face(b"IIR\0".to_vec()).validate(&default),
+            Err(TemplateError::FormatMismatch {
+                format: TemplateFormat::IsoIris,
+                template_type: TemplateType::Face
+            })
+        ));
+        let require_format = ValidationPolicy::new().with_rules(
+            TemplateType::Face,
+            ModalityRules {
+                require_known_format: true,
+                ..ModalityRules::default()
+            },
+        );
+        assert!(matches!(
+            face(vec![1; 4]).validate(&require_format),
+            Err(TemplateError::UnknownFormat(TemplateType::Face))
+        ));
+        assert!(face(record(b"FAC\0", b"010\0", 80)).validate(&require_format).is_ok());
+    }
+
+    /// Two-view ISO/IEC 19794-2 record, built by hand
+    fn minutiae_record() -> Vec<u8> {
+        let mut data = b"FMR\0 20\0".to_vec();
+        data.extend_from_slice(&0u32.to_be_bytes());
+        // Compliance 1, equipment 0x123, 400x500 image at 197 px/cm
+        data.extend_from_slice(&[0x11, 0x23, 0x01, 0x90, 0x01, 0xf4, 0x00, 0xc5, 0x00, 0xc5]);
+        data.extend_from_slice(&[2, 0]);
+        // Right index, view 1, live-scan rolled, quality 80, two minutiae
+        data.extend_from_slice(&[2, 0x11, 80, 2]);
+        data.extend_from_slice(&[0x40, 0x64, 0x00, 0xc8, 64, 90]);
+        data.extend_from_slice(&[0x81, 0x2c, 0x01, 0x2c, 200, 70]);
+        data.extend_from_slice(&[0, 3, 0xaa, 0xbb, 0xcc]);
+        // Left thumb, quality 60, no minutiae
+        data.extend_from_slice(&[6, 0x00, 60, 0, 0, 0]);
+        let len = data.len() as u32;
+        data[8..12].copy_from_slice(&len.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_iso19794_2_round_trip() {
+        let data = minutiae_record();
+        let record = iso19794_2::parse(&data).unwrap();
+        assert_eq!((record.equipment_compliance, record.equipment_id), (1, 0x123));
+        assert_eq!((record.image_width, record.image_height), (400, 500));
+        assert_eq!((record.x_resolution, record.y_resolution), (197, 197));
+        assert_eq!(record.views.len(), 2);
+
+        let view = &record.views[0];
+        assert_eq!(view.finger_position, FingerPosition::RightIndex);
+        assert_eq!((view.view_number, view.impression_type, view.quality), (1, 1, 80));
+        assert_eq!(
+            view.minutiae,
+            vec![
+                Minutia { kind: MinutiaType::RidgeEnding, x: 100, y: 200, angle: 64, quality: 90 },
+                Minutia { kind: MinutiaType::Bifurcation, x: 300, y: 300, angle: 200, quality: 70 },
+            ]
+        );
+        assert_eq!(view.minutiae[0].angle_degrees(), 90.0);
+        assert_eq!(view.extended_data, vec![0xaa, 0xbb, 0xcc]);
+        assert_eq!(record.views[1].finger_position, FingerPosition::LeftThumb);
+        assert!(record.views[1].minutiae.is_empty());
+
+        assert_eq!(iso19794_2::encode(&record), data);
+        let mut edited = record.clone();
+        edited.views[1].minutiae.push(Minutia {
+            kind: MinutiaType::Other,
+            x: 0,
+            y: 499,
+            angle: 255,
+            quality: 0,
+        });
+        assert_eq!(iso19794_2::parse(&iso19794_2::encode(&edited)).unwrap(), edited);
+
+        let template = Template::from_iso19794_2(&data).unwrap();
+        assert_eq!(template.data, data);
+        assert_eq!(template.metadata.template_type, TemplateType::Fingerprint);
+        assert_eq!(template.metadata.quality_score, 0.6);
+        assert_eq!(template.metadata.extra["format"], "ISO/IEC 19794-2");
+        assert!(template.validate(&ValidationPolicy::recommended()).is_ok());
+    }
+
+    #[test]
+    fn test_iso19794_2_malformed_records() {
+        let data = minutiae_record();
+        let with = |offset: usize, byte: u8| {
+            let mut data = data.clone();
+            data[offset] = byte;
+            data
+        };
+        let header_error = |data: &[u8]| match iso19794_2::parse(data) {
+            Err(TemplateError::InvalidHeader { reason, .. }) => reason,
+            other => panic!("expected a header error, got {:?}", other),
+        };
+
+        // Truncated records
+        for len in [0, 10, 23] {
+            assert!(matches!(
+                iso19794_2::parse(&data[..len]),
+                Err(TemplateError::TruncatedRecord { needed: 24, .. })
+            ));
+        }
+        assert!(matches!(
+            iso19794_2::parse(&data[..data.len() - 1]),
+            Err(TemplateError::TruncatedRecord { needed, .. }) if needed == data.len()
+        ));
+        let mut short_view = data[..30].to_vec();
+        short_view[8..12].copy_from_slice(&30u32.to_be_bytes());
+        assert!(matches!(
+            iso19794_2::parse(&short_view),
+            Err(TemplateError::InvalidMinutiaeCount { view: 0, count: 2, room: 0 })
+        ));
+        let mut no_room = data.clone();
+        no_room[27] = 40;
+        assert!(matches!(
+            iso19794_2::parse(&no_room),
+            Err(TemplateError::InvalidMinutiaeCount { view: 0, count: 40, room: 3 })
+        ));
+
+        // Malformed headers
+        assert!(matches!(iso19794_2::parse(&with(0, b'X')), Err(TemplateError::InvalidFormat(_))));
+        assert!(header_error(&with(5, b'3')).contains("version"));
+        assert!(header_error(&with(11, 20)).contains("shorter than the header"));
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(header_error(&trailing).contains("follow the record length"));
+        assert!(header_error(&with(22, 0)).contains("no finger views"));
+        assert!(header_error(&with(22, 1)).contains("follow the last finger view"));
+        assert!(header_error(&with(23, 1)).contains("reserved"));
+        assert!(header_error(&with(24, 11)).contains("finger position 11"));
+        assert!(header_error(&with(26, 101)).contains("quality 101"));
+
+        // Minutiae the standard rules out
+        let minutia_error = |data: &[u8]| match iso19794_2::parse(data) {
+            Err(TemplateError::InvalidMinutia { view: 0, index, reason }) => (index, reason),
+            other => panic!("expected a minutia error, got {:?}", other),
+        };
+        assert_eq!(minutia_error(&with(28, 0xc0)).1, "reserved minutia type 3");
+        assert_eq!(minutia_error(&with(36, 0x41)).0, 1);
+        assert!(minutia_error(&with(30, 0x07)).1.contains("outside the 400x500 image"));
+        assert!(minutia_error(&with(33, 101)).1.contains("quality 101"));
+        assert!(matches!(
+            Template::from_iso19794_2(&with(22, 0)),
+            Err(TemplateError::InvalidHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn test_cbor_encoding() {
+        // Layout 1 as written by an earlier build; it must keep decoding
+        // to the same template and encoding to the same bytes
+        let fixture = include_bytes!("../../tests/fixtures/template_v1.cbor");
+        let template = Template::from_cbor(fixture).unwrap();
+        let id = uuid::Uuid::parse_str("6f1c2a4e-8b3d-4c5e-9f70-1a2b3c4d5e6f").unwrap();
+        assert_eq!(template.id, Some(id));
+        assert_eq!(template.data, (0u8..32).collect::<Vec<u8>>());
+        assert_eq!(template.metadata.version, "1.2");
+        assert_eq!(template.metadata.template_type, TemplateType::Iris);
+        assert_eq!(template.metadata.quality_score, 0.75);
+        assert_eq!(template.metadata.extra, serde_json::json!({ "eye": "left", "attempt": 2 }));
+        assert_eq!(template.metadata.unknown["capture_site"], "lab");
+        assert_eq!(template.to_cbor().unwrap(), fixture);
+
+        // Data is a byte string, not JSON's array of numbers
+        let template = Template::builder()
+            .data((0..4096).map(|i| (i * 37 % 256) as u8).collect::<Vec<u8>>())
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap();
+        let cbor = template.to_cbor().unwrap();
+        let json = serde_json::to_vec(&template).unwrap();
+        assert!(cbor.len() <= template.data.len() + 64, "CBOR takes {} bytes", cbor.len());
+        assert!(cbor.len() * 3 < json.len(), "CBOR {} bytes, JSON {}", cbor.len(), json.len());
+        let decoded = Template::from_cbor(&cbor).unwrap();
+        assert_eq!((decoded.id, decoded.data), (None, template.data));
+
+        let mut newer = fixture.to_vec();
+        newer[1] = 3;
+        assert!(matches!(Template::from_cbor(&newer), Err(TemplateError::InvalidFormat(_))));
+        assert!(matches!(Template::from_cbor(&fixture[..50]), Err(TemplateError::Cbor(_))));
+    }
+
+    #[test]
+    fn test_metadata_migration() {
+        let migrate = |json: &str| {
+            TemplateMetadata::migrate_to_latest(serde_json::from_str(json).unwrap()).unwrap()
+        };
+
+        // Version 1 from before `extra` existed, with the score as `quality`
+        let metadata = migrate(include_str!("../../tests/fixtures/metadata_v1_without_extra.json"));
+        assert_eq!(metadata.schema_version, MetadataVersion::LATEST);
+        assert_eq!(metadata.template_type, TemplateType::Fingerprint);
+        assert_eq!(metadata.quality_score, 0.82);
+        assert_eq!(metadata.extra, serde_json::json!({}));
+        assert_eq!(metadata.unknown["capture_device"], "reader-7");
+        assert!(!metadata.unknown.contains_key("quality"));
+
+        // Version 1 with free text as `extra`
+        let metadata = migrate(include_str!("../../tests/fixtures/metadata_v1_scalar_extra.json"));
+        assert_eq!(metadata.version, "1.2");
+        assert_eq!(metadata.quality_score, 0.9);
+        assert_eq!(
+            metadata.extra,
+            serde_json::json!({ LEGACY_EXTRA_FIELD: "enrolled at branch 12" })
+        );
+        assert!(metadata.unknown.is_empty());
+
+        // Current metadata passes through, and migrating is idempotent
+        let current = serde_json::to_value(&metadata).unwrap();
+        assert_eq!(current["schema_version"], 2);
+        let again = TemplateMetadata::migrate_to_latest(current.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&again).unwrap(), current);
+
+        let mut newer = current.clone();
+        newer["schema_version"] = 3.into();
+        assert!(matches!(
+            TemplateMetadata::migrate_to_latest(newer),
+            Err(TemplateError::UnsupportedMetadataVersion { version, .. }) if version.get() == 3
+        ));
+        let mut ambiguous = serde_json::json!({ "quality": 0.5 });
+        ambiguous["quality_score"] = 0.6.into();
+        assert!(matches!(
+            TemplateMetadata::migrate_to_latest(ambiguous),
+            Err(TemplateError::InvalidMetadata(_))
+        ));
+        assert!(matches!(
+            TemplateMetadata::migrate_to_latest(serde_json::json!([1, 2])),
+            Err(TemplateError::InvalidMetadata(_))
+        ));
+    }
+
+    #[test]
+    fn test_quality_assessment() {
+        let image = |pixel: &dyn Fn(usize, usize) -> u8| {
+            let pixels = (0..128 * 128).map(|i| pixel(i % 128, i / 128)).collect();
+            GrayImage::new(128, 128, pixels).unwrap().to_pgm()
+        };
+        let blank = image(&|_, _| 128);
+        let noisy = image(&|x, y| {
+            // Hash of the position, so neighbors are uncorrelated
+            let mut h = ((y * 128 + x) as u32).wrapping_mul(0x9e37_79b9);
+            h = (h ^ h >> 16).wrapping_mul(0x85eb_ca6b);
+            h = (h ^ h >> 13).wrapping_mul(0xc2b2_ae35);
+            (h ^ h >> 16) as u8
+        });
+        // Repeated ramps with a sharp edge every 32 pixels
+        let sharp = image(&|x, _| (x % 32 * 8) as u8);
+
+        let assessor = ImageQualityAssessor::default();
+        let report = |data: &[u8]| assessor.assess(data).unwrap();
+        let (blank, noisy, sharp) = (report(&blank), report(&noisy), report(&sharp));
+        assert!(blank.score < noisy.score, "{:?} {:?}", blank, noisy);
+        assert!(noisy.score < sharp.score, "{:?} {:?}", noisy, sharp);
+        assert_eq!(blank.score, 0.0);
+        assert!(sharp.score > 0.8, "{:?}", sharp);
+        assert_eq!(blank.sub_score("contrast"), Some(0.0));
+        assert_eq!(noisy.sub_score("sharpness").unwrap().round(), 1.0);
+        assert!(noisy.sub_score("noise").unwrap() < 0.6);
+        assert_eq!(sharp.breakdown.len(), 5);
+
+        // Too small, too dark
+        let small = GrayImage::new(16, 16, (0..=255).collect()).unwrap();
+        assert_eq!(assessor.assess_image(&small).sub_score("resolution"), Some(0.25));
+        let dark = GrayImage::new(4, 4, vec![12; 16]).unwrap();
+        assert_eq!(assessor.assess_image(&dark).sub_score("brightness"), Some(0.25));
+
+        // Headers with comments and a smaller maximum value
+        let pgm = b"P5 # from a scanner\n2 1\n15\n\x00\x0f";
+        assert_eq!(GrayImage::from_pgm(pgm).unwrap().pixels(), &[0, 255]);
+        assert!(matches!(GrayImage::from_pgm(b"P5\n2 2\n255\n\x00"), Err(TemplateError::InvalidFormat(_))));
+        assert!(matches!(GrayImage::from_pgm(b"P6\n1 1\n255\n\x00"), Err(TemplateError::InvalidFormat(_))));
+
+        // The builder takes the score from the assessor
+        let template = Template::builder()
+            .data(image(&|x, _| (x % 32 * 8) as u8))
+            .template_type(TemplateType::Face)
+            .assess_quality(ImageQualityAssessor::default())
+            .build()
+            .unwrap();
+        assert_eq!(template.metadata.quality_score, sharp.score);
+        let result = Template::builder()
+            .data(vec![1, 2, 3])
+            .template_type(TemplateType::Face)
+            .assess_quality(ImageQualityAssessor::default())
+            .build();
+        assert!(matches!(result, Err(TemplateError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_cancelable_transform() {
+        use transform::{cancelable, transform_of, CancelableParams};
+
+        // Deterministic values in -1.0..1.0
+        let noise = |seed: u32| {
+            let mut h = seed.wrapping_mul(0x9e37_79b9);
+            h = (h ^ h >> 16).wrapping_mul(0x85eb_ca6b);
+            h = (h ^ h >> 13).wrapping_mul(0xc2b2_ae35);
+            (h ^ h >> 16) as f32 / u32::MAX as f32 * 2.0 - 1.0
+        };
+        let embedding = |values: Vec<f32>| {
+            Template::builder()
+                .data(values.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>())
+                .template_type(TemplateType::Face)
+                .quality_score(0.9)
+                .extra_field("camera", "lobby")
+                .build()
+                .unwrap()
+        };
+        // An enrollment and a noisy probe of each of 40 subjects
+        let subjects: Vec<(Template, Template)> = (0..40u32)
+            .map(|subject| {
+                let base: Vec<f32> = (0..128).map(|i| noise(subject * 1000 + i)).collect();
+                let probe = base
+                    .iter()
+                    .zip(0..)
+                    .map(|(x, i)| x + 0.3 * noise(subject * 1000 + i + 500_000))
+                    .collect();
+                (embedding(base), embedding(probe))
+            })
+            .collect();
+
+        let params = CancelableParams::default();
+        let scores = |enroll_key: &[u8], probe_key: &[u8], genuine: bool| -> Vec<f32> {
+            (0..subjects.len())
+                .map(|i| {
+                    let other = if genuine { i } else { (i + 1) % subjects.len() };
+                    let enrolled = cancelable(&subjects[i].0, enroll_key, &params).unwrap();
+                    let mut probe = cancelable(&subjects[other].1, probe_key, &params).unwrap();
+                    if enroll_key != probe_key {
+                        // Matchers refuse to compare across keys at all
+                        assert_eq!(HammingMatcher.score(&probe, &enrolled), None);
+                        probe.metadata.extra = enrolled.metadata.extra.clone();
+                    }
+                    HammingMatcher.score(&probe, &enrolled).unwrap().0
+                })
+                .collect()
+        };
+        let mean = |scores: &[f32]| scores.iter().sum::<f32>() / scores.len() as f32;
+
+        // Under one key, genuine pairs match and impostors sit at chance
+        let genuine = scores(b"key A", b"key A", true);
+        let impostor = scores(b"key A", b"key A", false);
+        assert!(mean(&genuine) > 0.8, "genuine mean {}", mean(&genuine));
+        assert!((mean(&impostor) - 0.5).abs() < 0.05, "impostor mean {}", mean(&impostor));
+        // After a new key, the revoked template is no better than an
+        // impostor: scores are chance-level (mean 0.5, deviation 1/32)
+        let revoked = scores(b"key A", b"key B", true);
+        assert!((mean(&revoked) - 0.5).abs() < 0.03, "revoked mean {}", mean(&revoked));
+        assert!(revoked.iter().all(|&score| score < 0.7), "{:?}", revoked);
+        assert!(genuine.iter().all(|&score| score > 0.7), "{:?}", genuine);
+
+        let transformed = cancelable(&subjects[0].0, b"key A", &params).unwrap();
+        assert_eq!(transformed.data.len(), 32);
+        assert_eq!(transformed.metadata.extra["camera"], "lobby");
+        let description = transform_of(&transformed).unwrap();
+        assert_eq!(description["dimensions"], 256);
+        let other = cancelable(&subjects[0].0, b"key B", &params).unwrap();
+        assert_ne!(description["id"], transform_of(&other).unwrap()["id"]);
+        assert_eq!(HammingMatcher.score(&transformed, &subjects[0].0), None);
+
+        // Projections without binarizing keep cosine similarity
+        let projected = CancelableParams {
+            dimensions: 96,
+            binarize: false,
+        };
+        let (enrolled, probe) = &subjects[0];
+        let original = CosineMatcher.score(probe, enrolled).unwrap().0;
+        let enrolled = cancelable(enrolled, b"key A", &projected).unwrap();
+        let probe = cancelable(probe, b"key A", &projected).unwrap();
+        let score = CosineMatcher.score(&probe, &enrolled).unwrap().0;
+        assert!((score - original).abs() < 0.1, "{} vs {}", score, original);
+
+        let invalid = |template: &Template, params: &CancelableParams| {
+            matches!(cancelable(template, b"key A", params), Err(TemplateError::InvalidData(_)))
+        };
+        assert!(invalid(&transformed, &params));
+        assert!(invalid(&embedding(vec![1.0; 8]), &CancelableParams { dimensions: 8, binarize: false }));
+        assert!(invalid(&embedding(vec![1.0; 8]), &CancelableParams { dimensions: 0, binarize: true }));
+        let mut odd = subjects[0].0.clone();
+        odd.data.push(0);
+        assert!(matches!(
+            cancelable(&odd, b"key A", &params),
+            Err(TemplateError::EmbeddingLength(513))
+        ));
+    }
+
+    #[test]
+    fn test_fusion() {
+        let noise = |seed: u32| {
+            let mut h = seed.wrapping_mul(0x9e37_79b9);
+            h = (h ^ h >> 16).wrapping_mul(0x85eb_ca6b);
+            h = (h ^ h >> 13).wrapping_mul(0xc2b2_ae35);
+            (h ^ h >> 16) as f32 / u32::MAX as f32 * 2.0 - 1.0
+        };
+        // Sample of a subject's modality: its base embedding plus noise,
+        // little for faces and a lot for voices
+        let sample = |subject: u32, template_type: TemplateType, capture: u32| {
+            let (offset, spread) = match template_type {
+                TemplateType::Face => (0, 0.4),
+                _ => (100_000, 2.0),
+            };
+            let seed = offset + subject * 1000;
+            let values: Vec<f32> = (0..64)
+                .map(|i| noise(seed + i) + spread * noise(seed + i + capture * 10_000_000))
+                .collect();
+            Template::builder()
+                .data(values.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>())
+                .template_type(template_type)
+                .quality_score(0.9)
+                .build()
+                .unwrap()
+        };
+        let fused = |subject: u32, capture: u32| {
+            FusedTemplate::new()
+                .with_member(sample(subject, TemplateType::Face, capture), 0.5)
+                .unwrap()
+                .with_member(sample(subject, TemplateType::Voice, capture), 0.5)
+                .unwrap()
+        };
+        let enrolled: Vec<FusedTemplate> = (0..30).map(|subject| fused(subject, 1)).collect();
+        let probes: Vec<FusedTemplate> = (0..30).map(|subject| fused(subject, 2)).collect();
+
+        // Fraction of genuine and impostor score pairs ranked correctly
+        let separation = |score: &dyn Fn(&FusedTemplate, &FusedTemplate) -> f32| {
+            let (mut genuine, mut impostor) = (Vec::new(), Vec::new());
+            for (i, probe) in probes.iter().enumerate() {
+                for (j, enrolled) in enrolled.iter().enumerate() {
+                    let scores = if i == j { &mut genuine } else { &mut impostor };
+                    scores.push(score(probe, enrolled));
+                }
+            }
+            let ordered = genuine
+                .iter()
+                .flat_map(|g| impostor.iter().map(move |i| (g > i) as u32))
+                .sum::<u32>();
+            ordered as f32 / (genuine.len() * impostor.len()) as f32
+        };
+        let matcher = FusionMatcher::default();
+        let voice_separation = separation(&|probe, enrolled| {
+            let (probe, enrolled) = (probe.member(TemplateType::Voice), enrolled.member(TemplateType::Voice));
+            CosineMatcher.score(&probe.unwrap().template, &enrolled.unwrap().template).unwrap().0
+        });
+        let fused_separation = separation(&|probe, enrolled| matcher.fuse(probe, enrolled).unwrap().score.0);
+        assert!(fused_separation > voice_separation, "fused {} vs voice {}", fused_separation, voice_separation);
+        assert!(fused_separation > 0.95, "fused {}", fused_separation);
+
+        // A probe missing a modality is scored on the rest
+        let mut face_only = probes[0].clone();
+        face_only.remove(TemplateType::Voice);
+        let score = matcher.fuse(&face_only, &enrolled[0]).unwrap();
+        assert_eq!(score.modalities.keys().copied().collect::<Vec<_>>(), vec![TemplateType::Face]);
+        assert_eq!(score.score, score.modalities[&TemplateType::Face]);
+        assert!(matcher.verify(&face_only, &enrolled[0]));
+        assert!(!matcher.verify(&face_only, &enrolled[1]));
+        let fingerprint = Template::builder()
+            .data(vec![1; 32])
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(0.9)
+            .build()
+            .unwrap();
+        let unrelated = FusedTemplate::new().with_member(fingerprint, 1.0).unwrap();
+        assert_eq!(matcher.fuse(&unrelated, &enrolled[0]), None);
+        assert!(!matcher.verify(&unrelated, &enrolled[0]));
+
+        let max = FusionMatcher::new(FusionRule::Max, 0.8);
+        let score = max.fuse(&probes[0], &enrolled[0]).unwrap();
+        let best = score.modalities.values().map(|s| s.0).fold(f32::MIN, f32::max);
+        assert_eq!(score.score.0, best);
+
+        // Stored as an envelope template
+        let template = enrolled[0].to_template().unwrap();
+        assert_eq!(template.metadata.template_type, TemplateType::Multimodal);
+        assert_eq!(template.metadata.extra["modalities"], serde_json::json!(["face", "voice"]));
+        let decoded = FusedTemplate::from_template(&template).unwrap();
+        assert_eq!(
+            decoded.member(TemplateType::Voice).unwrap().template.data,
+            enrolled[0].member(TemplateType::Voice).unwrap().template.data
+        );
+        let probe = probes[0].to_template().unwrap();
+        assert_eq!(matcher.score(&probe, &template), Some(matcher.fuse(&probes[0], &decoded).unwrap().score));
+
+        let nested = FusedTemplate::new().with_member(template, 1.0);
+        assert!(matches!(nested, Err(TemplateError::InvalidData(_))));
+        let weightless = FusedTemplate::new().with_member(sample(0, TemplateType::Face, 1), 0.0);
+        assert!(matches!(weightless, Err(TemplateError::InvalidData(_))));
+        assert!(matches!(FusedTemplate::new().to_template(), Err(TemplateError::InvalidData(_))));
+    }
+
+    #[test]
+    fn test_provenance() {
+        // Metadata stored before provenance was recorded
+        let old = serde_json::json!({
+            "version": "1.0",
+            "template_type": "iris",
+            "quality_score": 0.5,
+            "extra": {},
+            "schema_version": 2
+        });
+        let metadata: TemplateMetadata = serde_json::from_value(old.clone()).unwrap();
+        assert_eq!(metadata.provenance, None);
+        assert!(metadata.unknown.is_empty());
+        assert_eq!(serde_json::to_value(&metadata).unwrap(), old);
+        let migrated = TemplateMetadata::migrate_to_latest(serde_json::json!({
+            "version": "1.0",
+            "template_type": "iris",
+            "quality_score": 0.7
+        }))
+        .unwrap();
+        assert_eq!(migrated.provenance, None);
+
+        let provenance = Provenance {
+            device_id: Some("scanner-0042".into()),
+            captured_at: Some("2024-05-01T09:30:00Z".parse().unwrap()),
+            sdk_name: Some("irisync".into()),
+            sdk_version: Some("3.1.4".into()),
+            operator_id: None,
+        };
+        let template = Template::builder()
+            .data(vec![1; 16])
+            .template_type(TemplateType::Iris)
+            .quality_score(0.7)
+            .provenance(provenance.clone())
+            .build()
+            .unwrap();
+        let json = serde_json::to_value(&template.metadata).unwrap();
+        assert_eq!(json["provenance"]["device_id"], "scanner-0042");
+        assert!(json["provenance"].get("operator_id").is_none());
+        let decoded: TemplateMetadata = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.provenance.as_ref(), Some(&provenance));
+
+        // CBOR carries it in layout 2
+        let cbor = template.to_cbor().unwrap();
+        assert_eq!(cbor[1], 2);
+        assert_eq!(Template::from_cbor(&cbor).unwrap().metadata.provenance, Some(provenance));
+    }
+
+    #[test]
+    fn test_embedding() {
+        let metadata = || TemplateMetadata {
+            version: DEFAULT_TEMPLATE_VERSION.to_string(),
+            template_type: TemplateType::Face,
+            quality_score: 0.9,
+            extra: serde_json::json!({"camera": "lobby"}),
+            provenance: None,
+            schema_version: MetadataVersion::LATEST,
+            unknown: Default::default(),
+        };
+
+        // Components round-trip bit for bit, including signed zeros,
+        // subnormals and NaN payloads
+        let values = vec![
+            1.0,
+            -0.0,
+            f32::MIN_POSITIVE / 2.0,
+            f32::MAX,
+            f32::NEG_INFINITY,
+            f32::from_bits(0x7fc0_1234),
+            0.1,
+        ];
+        let template = Template::from_embedding(values.clone(), metadata()).unwrap();
+        assert_eq!(&template.data[..4], &[0x00, 0x00, 0x80, 0x3f]);
+        assert_eq!(template.data.len(), 4 * values.len());
+        assert_eq!(template.metadata.extra["embedding"]["dimensions"], 7);
+        assert_eq!(template.metadata.extra["camera"], "lobby");
+        assert_eq!(template.embedding_dimensions().unwrap(), Some(7));
+        let bits = |values: &[f32]| values.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&template.as_embedding().unwrap()), bits(&values));
+        let decoded = Template::from_cbor(&template.to_cbor().unwrap()).unwrap();
+        assert_eq!(bits(&decoded.as_embedding().unwrap()), bits(&values));
+
+        // Data that is not whole components, or disagrees with the declared
+        // dimension, is rejected by the accessor and by validation
+        let mut truncated = template.clone();
+        truncated.data.pop();
+        assert!(matches!(truncated.as_embedding(), Err(TemplateError::EmbeddingLength(27))));
+        let mut extended = template.clone();
+        extended.data.extend_from_slice(&2.0f32.to_le_bytes());
+        assert!(matches!(
+            extended.as_embedding(),
+            Err(TemplateError::EmbeddingDimensions { declared: 7, actual: 8 })
+        ));
+        assert!(matches!(
+            extended.validate(&ValidationPolicy::default()),
+            Err(TemplateError::EmbeddingDimensions { .. })
+        ));
+        let mut mislabeled = template.clone();
+        mislabeled.metadata.extra["embedding"]["encoding"] = "f16le".into();
+        assert!(matches!(mislabeled.as_embedding(), Err(TemplateError::InvalidMetadata(_))));
+
+        // Undeclared data is read as is, as long as it is whole components
+        let mut undeclared = template.clone();
+        undeclared.metadata.extra.as_object_mut().unwrap().remove(EMBEDDING_FIELD);
+        assert_eq!(undeclared.embedding_dimensions().unwrap(), None);
+        assert_eq!(bits(&undeclared.as_embedding().unwrap()), bits(&values));
+
+        assert!(matches!(
+            Template::from_embedding(Vec::new(), metadata()),
+            Err(TemplateError::DataTooShort { .. })
+        ));
+        let scalar_extra = TemplateMetadata {
+            extra: serde_json::json!(3),
+            ..metadata()
+        };
+        assert!(matches!(
+            Template::from_embedding(vec![1.0], scalar_extra),
+            Err(TemplateError::InvalidMetadata(_))
+        ));
+        let null_extra = TemplateMetadata {
+            extra: serde_json::Value::Null,
+            ..metadata()
+        };
+        let template = Template::from_embedding(vec![1.0], null_extra).unwrap();
+        assert_eq!(template.embedding_dimensions().unwrap(), Some(1));
+
+        // The cosine matcher reads the same representation, refusing
+        // embeddings whose data contradicts their declaration
+        let a = Template::from_embedding(vec![1.0, 0.0], metadata()).unwrap();
+        let b = Template::from_embedding(vec![3.0, 0.0], metadata()).unwrap();
+        assert_eq!(CosineMatcher.score(&a, &b), Some(MatchScore(1.0)));
+        let mut corrupt = b.clone();
+        corrupt.data.extend_from_slice(&0.0f32.to_le_bytes());
+        assert_eq!(CosineMatcher.score(&a, &corrupt), None);
+
+        // Projections declare their own dimension; sign bits declare none
+        let embedding = Template::from_embedding((0..32).map(|i| i as f32 - 16.0).collect(), metadata()).unwrap();
+        let projected = transform::cancelable(
+            &embedding,
+            b"key",
+            &transform::CancelableParams {
+                dimensions: 8,
+                binarize: false,
+            },
+        )
+        .unwrap();
+        assert_eq!(projected.embedding_dimensions().unwrap(), Some(8));
+        assert_eq!(projected.as_embedding().unwrap().len(), 8);
+        let binarized = transform::cancelable(&embedding, b"key", &Default::default()).unwrap();
+        assert_eq!(binarized.embedding_dimensions().unwrap(), None);
+    }
+
+    #[test]
+    fn test_matchers() {
+        let template = |data: Vec<u8>| {
+            Template::builder()
+                .data(data)
+                .template_type(TemplateType::Iris)
+                .quality_score(0.8)
+                .build()
+                .unwrap()
+        };
+        let embedding =
+            |values: &[f32]| template(values.iter().flat_map(|x| x.to_le_bytes()).collect());
+
+        let score = CosineMatcher.score(&embedding(&[1.0, 0.0]), &embedding(&[2.0, 0.0]));
+        assert_eq!(score, Some(MatchScore(1.0)));
+        let score = CosineMatcher.score(&embedding(&[1.0, 0.0]), &embedding(&[0.0, 1.0]));
+        assert_eq!(score, Some(MatchScore(0.0)));
+        assert_eq!(CosineMatcher.score(&embedding(&[1.0, 0.0]), &embedding(&[1.0])), None);
+        assert_eq!(CosineMatcher.score(&embedding(&[0.0, 0.0]), &embedding(&[1.0, 0.0])), None);
+        assert_eq!(CosineMatcher.score(&template(vec![1; 3]), &template(vec![1; 3])), None);
+
+        let codes = (vec![0b1010_1010; 2], vec![0b1010_1010, 0b0101_0101]);
+        let score = HammingMatcher.score(&template(codes.0), &template(codes.1));
+        assert_eq!(score, Some(MatchScore(0.5)));
+        let score = HammingMatcher.score(&template(vec![7; 2]), &template(vec![7; 2]));
+        assert_eq!(score, Some(MatchScore(1.0)));
+        assert_eq!(HammingMatcher.score(&template(vec![7; 2]), &template(vec![7; 3])), None);
+    }
+
+    #[test]
+    fn test_secure_hash() {
+        let template = |template_type, quality_score| {
+            Template::builder()
+                .data(vec![1, 2, 3, 4])
+                .template_type(template_type)
+                .quality_score(quality_score)
+                .build()
+                .unwrap()
+        };
+        let face = template(TemplateType::Face, 0.9);
+
+        // Re-enrollments of the same data hash the same
+        assert_eq!(face.secure_hash(b"key"), template(TemplateType::Face, 0.5).secure_hash(b"key"));
+        // Identical bytes of another type hash differently
+        assert_ne!(face.secure_hash(b"key"), template(TemplateType::Voice, 0.9).secure_hash(b"key"));
+        assert_ne!(face.secure_hash(b"key"), face.secure_hash(b"other key"));
     }
 }
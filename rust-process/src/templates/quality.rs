@@ -0,0 +1,282 @@
+//! Computed quality of raw samples
+//!
+//! A `QualityAssessor` scores sample data from 0.0 to 1.0, so quality
+//! scores mean the same whichever client enrolled a template.
+//! `ImageQualityAssessor` is a baseline for grayscale images.
+
+use super::error::TemplateError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// One aspect of a quality assessment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubScore {
+    pub name: String,
+    /// From 0.0, unusable, to 1.0
+    pub score: f32,
+}
+
+/// Result of a quality assessment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QualityReport {
+    /// Overall quality from 0.0 to 1.0, usable as a template's quality score
+    pub score: f32,
+    /// Sub-scores the overall score was computed from
+    pub breakdown: Vec<SubScore>,
+}
+
+impl QualityReport {
+    /// Sub-score called `name`, if the assessor computed one
+    pub fn sub_score(&self, name: &str) -> Option<f32> {
+        self.breakdown
+            .iter()
+            .find(|sub| sub.name == name)
+            .map(|sub| sub.score)
+    }
+}
+
+/// Computation of the quality of raw sample data
+pub trait QualityAssessor: fmt::Debug + Send + Sync {
+    /// Assess `data`, failing if it is not a sample this assessor reads
+    fn assess(&self, data: &[u8]) -> Result<QualityReport, TemplateError>;
+}
+
+/// 8-bit grayscale image, row by row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrayImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl GrayImage {
+    /// Image of `width` by `height` pixels, or `None` if either is 0 or
+    /// `pixels` holds another number of pixels
+    pub fn new(width: usize, height: usize, pixels: Vec<u8>) -> Option<Self> {
+        (width > 0 && height > 0 && width.checked_mul(height) == Some(pixels.len())).then_some(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> f64 {
+        self.pixels[y * self.width + x] as f64
+    }
+
+    /// Decode a binary PGM (`P5`) image
+    ///
+    /// Images of a maximum value below 255 are scaled to the full range;
+    /// 16-bit images are not supported.
+    pub fn from_pgm(data: &[u8]) -> Result<Self, TemplateError> {
+        let invalid = |reason: &str| TemplateError::InvalidFormat(format!("invalid PGM image: {}", reason));
+        if !data.starts_with(b"P5") {
+            return Err(invalid("missing P5 magic number"));
+        }
+
+        // Width, height and maximum value, separated by whitespace and
+        // comments running to the end of the line
+        let mut offset = 2;
+        let mut fields = [0usize; 3];
+        for field in &mut fields {
+            loop {
+                match data.get(offset) {
+                    Some(byte) if byte.is_ascii_whitespace() => offset += 1,
+                    Some(b'#') => {
+                        while data.get(offset).is_some_and(|&byte| byte != b'\n') {
+                            offset += 1;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+            let start = offset;
+            while data.get(offset).is_some_and(u8::is_ascii_digit) {
+                offset += 1;
+            }
+            *field = std::str::from_utf8(&data[start..offset])
+                .ok()
+                .and_then(|digits| digits.parse().ok())
+                .ok_or_else(|| invalid("malformed header"))?;
+        }
+        if !data.get(offset).is_some_and(u8::is_ascii_whitespace) {
+            return Err(invalid("malformed header"));
+        }
+        offset += 1;
+
+        let [width, height, max_value] = fields;
+        if !(1..=255).contains(&max_value) {
+            return Err(invalid("only 8-bit images are supported"));
+        }
+        let pixels = &data[offset..];
+        let image = Self::new(width, height, pixels.to_vec())
+            .ok_or_else(|| invalid(&format!("{} pixel bytes for a {}x{} image", pixels.len(), width, height)))?;
+        Ok(match max_value {
+            255 => image,
+            _ => Self {
+                pixels: image
+                    .pixels
+                    .iter()
+                    .map(|&pixel| (pixel as usize * 255 / max_value).min(255) as u8)
+                    .collect(),
+                ..image
+            },
+        })
+    }
+
+    /// Encode the image as binary PGM
+    pub fn to_pgm(&self) -> Vec<u8> {
+        let mut data = format!("P5\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend_from_slice(&self.pixels);
+        data
+    }
+}
+
+/// Baseline quality of grayscale images, given as binary PGM
+///
+/// The score is the product of five sub-scores, so a sample failing any
+/// check scores low overall:
+///
+/// - `sharpness`: variance of the Laplacian, `v / (v + sharpness_scale)`
+/// - `brightness`: 1.0 for a mean intensity within `brightness`, falling
+///   linearly to 0.0 at black and white
+/// - `contrast`: standard deviation of intensities relative to
+///   `min_contrast`, at most 1.0
+/// - `resolution`: the shorter side relative to `min_resolution`, at most
+///   1.0
+/// - `noise`: correlation of neighboring pixels, mapped from -1.0..=1.0 to
+///   0.0..=1.0, so uncorrelated noise scores 0.5
+///
+/// The Laplacian alone cannot tell noise from detail; the noise score keeps
+/// noisy samples below sharp ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageQualityAssessor {
+    /// Laplacian variance scoring 0.5 in `sharpness`
+    pub sharpness_scale: f32,
+    /// Range of mean intensities scoring 1.0 in `brightness`
+    pub brightness: (u8, u8),
+    /// Standard deviation of intensities scoring 1.0 in `contrast`
+    pub min_contrast: f32,
+    /// Length in pixels of the shorter side scoring 1.0 in `resolution`
+    pub min_resolution: usize,
+}
+
+impl Default for ImageQualityAssessor {
+    fn default() -> Self {
+        Self {
+            sharpness_scale: 100.0,
+            brightness: (48, 208),
+            min_contrast: 32.0,
+            min_resolution: 64,
+        }
+    }
+}
+
+impl ImageQualityAssessor {
+    /// Assess a decoded image
+    pub fn assess_image(&self, image: &GrayImage) -> QualityReport {
+        let count = image.pixels.len() as f64;
+        let mean = image.pixels.iter().map(|&pixel| pixel as f64).sum::<f64>() / count;
+        let variance = image
+            .pixels
+            .iter()
+            .map(|&pixel| (pixel as f64 - mean).powi(2))
+            .sum::<f64>()
+            / count;
+
+        let sharpness = laplacian_variance(image);
+        let (low, high) = (self.brightness.0 as f64, self.brightness.1 as f64);
+        let brightness = if mean < low {
+            mean / low
+        } else if mean > high {
+            (255.0 - mean) / (255.0 - high)
+        } else {
+            1.0
+        };
+        let breakdown = [
+            ("sharpness", sharpness / (sharpness + self.sharpness_scale as f64)),
+            ("brightness", brightness),
+            ("contrast", variance.sqrt() / self.min_contrast as f64),
+            (
+                "resolution",
+                image.width.min(image.height) as f64 / self.min_resolution.max(1) as f64,
+            ),
+            ("noise", (1.0 + neighbor_correlation(image, mean, variance)) / 2.0),
+        ]
+        .map(|(name, score)| SubScore {
+            name: name.to_string(),
+            score: score.clamp(0.0, 1.0) as f32,
+        });
+
+        QualityReport {
+            score: breakdown.iter().map(|sub| sub.score).product(),
+            breakdown: breakdown.to_vec(),
+        }
+    }
+}
+
+impl QualityAssessor for ImageQualityAssessor {
+    fn assess(&self, data: &[u8]) -> Result<QualityReport, TemplateError> {
+        Ok(self.assess_image(&GrayImage::from_pgm(data)?))
+    }
+}
+
+/// Variance of the 4-neighbor Laplacian over the interior of the image, 0.0
+/// for images too small to have one
+fn laplacian_variance(image: &GrayImage) -> f64 {
+    if image.width < 3 || image.height < 3 {
+        return 0.0;
+    }
+    let (mut sum, mut sum_sq) = (0.0, 0.0);
+    for y in 1..image.height - 1 {
+        for x in 1..image.width - 1 {
+            let laplacian = 4.0 * image.pixel(x, y)
+                - image.pixel(x - 1, y)
+                - image.pixel(x + 1, y)
+                - image.pixel(x, y - 1)
+                - image.pixel(x, y + 1);
+            sum += laplacian;
+            sum_sq += laplacian * laplacian;
+        }
+    }
+    let count = ((image.width - 2) * (image.height - 2)) as f64;
+    sum_sq / count - (sum / count).powi(2)
+}
+
+/// Mean correlation of horizontally and vertically adjacent pixels, 1.0 for
+/// uniform images
+fn neighbor_correlation(image: &GrayImage, mean: f64, variance: f64) -> f64 {
+    if variance == 0.0 {
+        return 1.0;
+    }
+    let (mut covariance, mut pairs) = (0.0, 0usize);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let centered = image.pixel(x, y) - mean;
+            if x + 1 < image.width {
+                covariance += centered * (image.pixel(x + 1, y) - mean);
+                pairs += 1;
+            }
+            if y + 1 < image.height {
+                covariance += centered * (image.pixel(x, y + 1) - mean);
+                pairs += 1;
+            }
+        }
+    }
+    match pairs {
+        0 => 1.0,
+        _ => covariance / pairs as f64 / variance,
+    }
+}
@@ -1,7 +1,17 @@
+use super::builder::TemplateBuilder;
+use super::error::TemplateError;
+use super::migration::MetadataVersion;
+use super::validation::{TemplateFormat, TemplateVersion, ValidationPolicy};
+use chrono::{DateTime, Utc};
+use ring::hmac;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use uuid::Uuid;
 
+/// Domain separation for `Template::secure_hash`, so its hashes never
+/// equal other HMACs computed with the same key
+const SECURE_HASH_CONTEXT: &[u8] = b"secure-biometric template hash v1\0";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Template {
     /// Unique identifier
@@ -27,9 +37,75 @@ pub struct TemplateMetadata {
     
     /// Additional metadata as JSON
     pub extra: Value,
+
+    /// Capture device, SDK and operator that produced the template, if
+    /// known; absent from metadata stored before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+
+    /// Schema the metadata was serialized in; taken to be the latest when
+    /// missing, see `TemplateMetadata::migrate_to_latest` for older ones
+    #[serde(default)]
+    pub schema_version: MetadataVersion,
+
+    /// Fields sent by newer clients that this version does not know about,
+    /// preserved verbatim so they survive a store/get round trip
+    #[serde(flatten, default, skip_serializing_if = "Map::is_empty")]
+    pub unknown: Map<String, Value>,
+}
+
+/// Where and how a template was captured
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Capture device, such as a scanner's serial number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+
+    /// When the sample was captured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<DateTime<Utc>>,
+
+    /// SDK that extracted the template from the sample
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sdk_name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sdk_version: Option<String>,
+
+    /// Operator who supervised the capture
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+impl TemplateMetadata {
+    /// Check preserved unknown fields against a deployment's policy
+    ///
+    /// With `strict` set any unknown field is rejected; otherwise their
+    /// serialized size must not exceed `max_bytes`.
+    pub fn check_unknown_fields(&self, strict: bool, max_bytes: usize) -> Result<(), TemplateError> {
+        if self.unknown.is_empty() {
+            return Ok(());
+        }
+        if strict {
+            let mut fields: Vec<String> = self.unknown.keys().cloned().collect();
+            fields.sort();
+            return Err(TemplateError::UnknownFields(fields));
+        }
+
+        let size = serde_json::to_vec(&self.unknown)
+            .map_err(|e| TemplateError::InvalidFormat(e.to_string()))?
+            .len();
+        if size > max_bytes {
+            return Err(TemplateError::InvalidData(format!(
+                "unknown metadata fields take {} bytes, limit is {}",
+                size, max_bytes
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum TemplateType {
     Face,
@@ -37,10 +113,45 @@ pub enum TemplateType {
     Iris,
     Voice,
     Other,
+    /// Several modalities enrolled together, see `FusedTemplate`
+    Multimodal,
+}
+
+impl TemplateType {
+    /// Every template type, in declaration order
+    pub const ALL: [TemplateType; 6] = [
+        TemplateType::Face,
+        TemplateType::Fingerprint,
+        TemplateType::Iris,
+        TemplateType::Voice,
+        TemplateType::Other,
+        TemplateType::Multimodal,
+    ];
+
+    /// Stable name of the type, matching its serialized form
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemplateType::Face => "face",
+            TemplateType::Fingerprint => "fingerprint",
+            TemplateType::Iris => "iris",
+            TemplateType::Voice => "voice",
+            TemplateType::Other => "other",
+            TemplateType::Multimodal => "multimodal",
+        }
+    }
 }
 
 impl Template {
+    /// Start building a template whose metadata is checked by `build`
+    pub fn builder() -> TemplateBuilder {
+        TemplateBuilder::default()
+    }
+
     /// Create a new template
+    ///
+    /// `metadata` is taken as is, even a quality score of 7.3; templates
+    /// built with `Template::builder` are validated instead.
+    #[deprecated(note = "use `Template::builder`, which validates the metadata")]
     pub fn new(data: Vec<u8>, metadata: TemplateMetadata) -> Self {
         Self {
             id: None,
@@ -49,9 +160,76 @@ impl Template {
         }
     }
     
-    /// Validate template data
-    pub fn validate(&self) -> bool {
-        // TODO: Implement proper validation
-        !self.data.is_empty() && self.metadata.quality_score >= 0.0 && self.metadata.quality_score <= 1.0
+    /// Keyed hash identifying the template's content, for looking it up
+    /// without decrypting stored templates
+    ///
+    /// HMAC-SHA256 under `key` of the template type and data, each length
+    /// prefixed. The ID and the rest of the metadata are left out, so a
+    /// re-enrollment of the same data hashes the same, while identical
+    /// bytes of another type hash differently. Without `key` the hash
+    /// reveals nothing about the data, and hashes under different keys
+    /// never match.
+    pub fn secure_hash(&self, key: &[u8]) -> [u8; 32] {
+        self.secure_hash_with(&hmac::Key::new(hmac::HMAC_SHA256, key))
+    }
+
+    /// `secure_hash` with a prepared key
+    pub(crate) fn secure_hash_with(&self, key: &hmac::Key) -> [u8; 32] {
+        let template_type = self.metadata.template_type.as_str().as_bytes();
+        let mut ctx = hmac::Context::with_key(key);
+        ctx.update(SECURE_HASH_CONTEXT);
+        ctx.update(&(template_type.len() as u64).to_be_bytes());
+        ctx.update(template_type);
+        ctx.update(&(self.data.len() as u64).to_be_bytes());
+        ctx.update(&self.data);
+        ctx.sign().as_ref().try_into().expect("HMAC-SHA256 output is 32 bytes")
+    }
+
+    /// Check the template against `policy`'s rules for its type
+    ///
+    /// The error names the first rule broken: quality score, version,
+    /// data length, record header, then the embedding dimension declared in
+    /// `extra["embedding"]`, if any.
+    pub fn validate(&self, policy: &ValidationPolicy) -> Result<(), TemplateError> {
+        let template_type = self.metadata.template_type;
+        let rules = policy.rules(template_type);
+
+        let score = self.metadata.quality_score;
+        if !(0.0..=1.0).contains(&score) {
+            return Err(TemplateError::QualityOutOfRange(score));
+        }
+        if score < rules.min_quality {
+            return Err(TemplateError::QualityBelowThreshold {
+                score,
+                min: rules.min_quality,
+            });
+        }
+        self.metadata.version.parse::<TemplateVersion>()?;
+
+        let len = self.data.len();
+        if len < rules.min_len.max(1) {
+            return Err(TemplateError::DataTooShort {
+                template_type,
+                len,
+                min: rules.min_len.max(1),
+            });
+        }
+        if let Some(max) = rules.max_len.filter(|&max| len > max) {
+            return Err(TemplateError::DataTooLong { template_type, len, max });
+        }
+
+        match TemplateFormat::detect(&self.data) {
+            Some(format) if format.template_type() != template_type => {
+                Err(TemplateError::FormatMismatch { format, template_type })
+            }
+            Some(format) => format.check_header(&self.data),
+            None if rules.require_known_format => Err(TemplateError::UnknownFormat(template_type)),
+            None => Ok(()),
+        }?;
+
+        if self.embedding_dimensions()?.is_some() {
+            self.as_embedding()?;
+        }
+        Ok(())
     }
 }
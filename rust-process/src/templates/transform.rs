@@ -0,0 +1,160 @@
+//! Cancelable templates
+//!
+//! A cancelable transform derives a template from an embedding under a
+//! secret key, so that a leaked template can be revoked: enrolling again
+//! under a new key yields templates that do not match the leaked one.
+//! Templates transformed under the same key match each other about as well
+//! as the embeddings they came from.
+
+use super::embedding::{self, EMBEDDING_FIELD};
+use super::error::TemplateError;
+use super::template::Template;
+use ring::hmac;
+use serde_json::{json, Value};
+
+/// Field of the metadata `extra` object describing the transform applied
+pub const TRANSFORM_FIELD: &str = "transform";
+
+/// Name of the scheme implemented by `cancelable`
+const SCHEME: &str = "sign-random-projection-v1";
+
+/// Domain separation of the values derived from a transform key
+const ID_CONTEXT: &[u8] = b"secure-biometric cancelable transform id\0";
+const MATRIX_CONTEXT: &[u8] = b"secure-biometric cancelable transform matrix\0";
+
+/// Parameters of `cancelable`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CancelableParams {
+    /// Number of random projections, which must be fewer than the input
+    /// dimensions unless the output is binarized
+    pub dimensions: usize,
+    /// Keep only the sign of each projection, packed into bits and compared
+    /// with `HammingMatcher`; otherwise projections are `f32`s compared with
+    /// `CosineMatcher`
+    pub binarize: bool,
+}
+
+impl Default for CancelableParams {
+    fn default() -> Self {
+        Self {
+            dimensions: 256,
+            binarize: true,
+        }
+    }
+}
+
+/// Transform the embedding held by `template` under `key`
+///
+/// Each output is the projection of the embedding onto a direction of
+/// random signs derived from the key. Projecting onto fewer dimensions
+/// than the input, or keeping only signs, loses information, so the
+/// embedding cannot be recovered from the result even with the key. Angles
+/// between embeddings are approximately preserved under the same key;
+/// under different keys the results are unrelated.
+///
+/// The result keeps the metadata of `template`, with `extra["transform"]`
+/// identifying the scheme, parameters and key, and `extra["embedding"]`
+/// declaring the projections, or removed for sign bits. Fails as
+/// `Template::as_embedding` does for data that is not an embedding, and
+/// with `TemplateError::InvalidData` for empty embeddings, already
+/// transformed templates, and parameters that would keep the transform
+/// invertible.
+pub fn cancelable(template: &Template, key: &[u8], params: &CancelableParams) -> Result<Template, TemplateError> {
+    let invalid = |reason: String| Err(TemplateError::InvalidData(reason));
+    let Some(extra) = template.metadata.extra.as_object() else {
+        return invalid("metadata extra is not an object".into());
+    };
+    if extra.contains_key(TRANSFORM_FIELD) {
+        return invalid("template is already transformed".into());
+    }
+    let embedding = template.as_embedding()?;
+    if embedding.is_empty() {
+        return invalid("template holds an empty embedding".into());
+    }
+    if params.dimensions == 0 {
+        return invalid("transform has no dimensions".into());
+    }
+    if !params.binarize && params.dimensions >= embedding.len() {
+        return invalid(format!(
+            "{} projections of a {}-dimensional embedding are invertible",
+            params.dimensions,
+            embedding.len()
+        ));
+    }
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let projections = (0..params.dimensions).map(|row| project(&key, row, &embedding));
+    let data = if params.binarize {
+        let mut bits = vec![0u8; params.dimensions.div_ceil(8)];
+        for (row, projection) in projections.enumerate() {
+            if projection > 0.0 {
+                bits[row / 8] |= 0x80 >> (row % 8);
+            }
+        }
+        bits
+    } else {
+        projections.flat_map(|projection| projection.to_le_bytes()).collect()
+    };
+
+    let mut extra = extra.clone();
+    if params.binarize {
+        extra.remove(EMBEDDING_FIELD);
+    } else {
+        extra.insert(EMBEDDING_FIELD.into(), embedding::declaration(params.dimensions));
+    }
+    extra.insert(
+        TRANSFORM_FIELD.into(),
+        json!({
+            "scheme": SCHEME,
+            "id": transform_id(&key),
+            "dimensions": params.dimensions,
+            "binarize": params.binarize,
+        }),
+    );
+    let mut metadata = template.metadata.clone();
+    metadata.extra = Value::Object(extra);
+    Ok(Template {
+        id: template.id,
+        data,
+        metadata,
+    })
+}
+
+/// Transform description of a template, `None` if it was not transformed
+pub fn transform_of(template: &Template) -> Option<&Value> {
+    template.metadata.extra.get(TRANSFORM_FIELD)
+}
+
+/// Whether two templates can be compared: both untransformed, or both
+/// transformed alike under the same key
+pub(crate) fn comparable(a: &Template, b: &Template) -> bool {
+    transform_of(a) == transform_of(b)
+}
+
+/// Public identifier of a transform key, revealing nothing about the key
+fn transform_id(key: &hmac::Key) -> String {
+    hmac::sign(key, ID_CONTEXT).as_ref()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Projection of `embedding` onto the direction of random signs of `row`
+///
+/// The signs are the bits of HMACs of the row and block number, 256 per
+/// block.
+fn project(key: &hmac::Key, row: usize, embedding: &[f32]) -> f32 {
+    let mut projection = 0.0;
+    for (block, values) in embedding.chunks(256).enumerate() {
+        let mut context = hmac::Context::with_key(key);
+        context.update(MATRIX_CONTEXT);
+        context.update(&(row as u64).to_be_bytes());
+        context.update(&(block as u64).to_be_bytes());
+        let signs = context.sign();
+        for (i, value) in values.iter().enumerate() {
+            let positive = signs.as_ref()[i / 8] & (0x80 >> (i % 8)) != 0;
+            projection += if positive { *value } else { -*value };
+        }
+    }
+    projection
+}
@@ -0,0 +1,196 @@
+use super::error::TemplateError;
+use super::template::TemplateType;
+use std::fmt;
+use std::str::FromStr;
+
+/// Limits a template of one type must meet
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModalityRules {
+    /// Fewest data bytes accepted; empty data is always rejected
+    pub min_len: usize,
+    /// Most data bytes accepted, if limited
+    pub max_len: Option<usize>,
+    /// Lowest quality score accepted
+    pub min_quality: f32,
+    /// Reject data that does not start with a recognized record header
+    pub require_known_format: bool,
+}
+
+impl Default for ModalityRules {
+    fn default() -> Self {
+        Self {
+            min_len: 1,
+            max_len: None,
+            min_quality: 0.0,
+            require_known_format: false,
+        }
+    }
+}
+
+/// Rules `Template::validate` checks templates against, by template type
+///
+/// The default accepts any non-empty data and quality score within
+/// 0.0..=1.0. Whatever the policy, data starting with the magic bytes of a
+/// known record format must carry a well-formed header of that format, and
+/// the metadata version must parse as a `TemplateVersion`.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationPolicy {
+    /// Rules by template type, in `TemplateType::ALL` order
+    rules: [ModalityRules; TemplateType::ALL.len()],
+}
+
+impl ValidationPolicy {
+    /// Start from the default, permissive rules
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Data length limits typical of each modality
+    ///
+    /// Fingerprint minutiae records hold at least a record header, iris
+    /// codes are at least 256 bytes, and face and voice templates at least
+    /// a small embedding.
+    pub fn recommended() -> Self {
+        let limits = |min_len, max_len| ModalityRules {
+            min_len,
+            max_len: Some(max_len),
+            ..ModalityRules::default()
+        };
+        Self::new()
+            .with_rules(TemplateType::Face, limits(64, 1024 * 1024))
+            .with_rules(TemplateType::Fingerprint, limits(FMR_HEADER_LEN, 64 * 1024))
+            .with_rules(TemplateType::Iris, limits(256, 1024 * 1024))
+            .with_rules(TemplateType::Voice, limits(64, 1024 * 1024))
+    }
+
+    /// Rules for templates of `template_type`
+    pub fn rules(&self, template_type: TemplateType) -> &ModalityRules {
+        &self.rules[template_type as usize]
+    }
+
+    /// Check templates of `template_type` against `rules`
+    pub fn with_rules(mut self, template_type: TemplateType, rules: ModalityRules) -> Self {
+        self.rules[template_type as usize] = rules;
+        self
+    }
+
+    /// Reject templates of every type scoring below `score`
+    pub fn min_quality(mut self, score: f32) -> Self {
+        for rules in &mut self.rules {
+            rules.min_quality = score;
+        }
+        self
+    }
+}
+
+/// Parsed template version: `major[.minor[.patch]]`, optionally prefixed
+/// with `v`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TemplateVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl FromStr for TemplateVersion {
+    type Err = TemplateError;
+
+    fn from_str(version: &str) -> Result<Self, TemplateError> {
+        let invalid = || TemplateError::InvalidVersion(version.to_string());
+        let numbers = version.strip_prefix(['v', 'V']).unwrap_or(version);
+        let mut parts = numbers.split('.').map(|part| {
+            // `u32::from_str` alone would also take a leading `+`
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            part.parse::<u32>().map_err(|_| invalid())
+        });
+
+        let major = parts.next().ok_or_else(invalid)??;
+        let minor = parts.next().transpose()?.unwrap_or(0);
+        let patch = parts.next().transpose()?.unwrap_or(0);
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Self { major, minor, patch })
+    }
+}
+
+impl fmt::Display for TemplateVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Shortest ISO/IEC 19794-2 record: its header
+pub(crate) const FMR_HEADER_LEN: usize = 24;
+
+/// Standard biometric record formats, recognized by their magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateFormat {
+    /// ISO/IEC 19794-2 finger minutiae record, starting `FMR\0`
+    IsoFingerMinutiae,
+    /// ISO/IEC 19794-5 face image record, starting `FAC\0`
+    IsoFace,
+    /// ISO/IEC 19794-6 iris image record, starting `IIR\0`
+    IsoIris,
+}
+
+impl TemplateFormat {
+    /// Format of `data`, if it starts with known magic bytes
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        match data.get(..4)? {
+            b"FMR\0" => Some(Self::IsoFingerMinutiae),
+            b"FAC\0" => Some(Self::IsoFace),
+            b"IIR\0" => Some(Self::IsoIris),
+            _ => None,
+        }
+    }
+
+    /// Type of the templates the format holds
+    pub fn template_type(self) -> TemplateType {
+        match self {
+            Self::IsoFingerMinutiae => TemplateType::Fingerprint,
+            Self::IsoFace => TemplateType::Face,
+            Self::IsoIris => TemplateType::Iris,
+        }
+    }
+
+    /// Check the version and record length fields of the header
+    ///
+    /// Every format starts with the magic, a version such as `030\0` and
+    /// the big-endian length of the whole record.
+    pub fn check_header(self, data: &[u8]) -> Result<(), TemplateError> {
+        let invalid = |reason: String| TemplateError::InvalidHeader {
+            format: self,
+            reason,
+        };
+        if data.len() < 12 {
+            return Err(invalid(format!("record of {} bytes is shorter than its header", data.len())));
+        }
+
+        let version = &data[4..8];
+        if version[3] != 0 || !version[..3].iter().all(|b| b.is_ascii_digit() || *b == b' ') {
+            return Err(invalid(format!("version field {:?} is not three digits", version)));
+        }
+        let record_len = u32::from_be_bytes(data[8..12].try_into().expect("4 bytes")) as usize;
+        if record_len != data.len() {
+            return Err(invalid(format!(
+                "header gives a record length of {} bytes, data holds {}",
+                record_len,
+                data.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TemplateFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::IsoFingerMinutiae => "ISO/IEC 19794-2",
+            Self::IsoFace => "ISO/IEC 19794-5",
+            Self::IsoIris => "ISO/IEC 19794-6",
+        })
+    }
+}
@@ -0,0 +1,79 @@
+use async_trait::async_trait;
+use secure_biometric::security::{MasterKeyProvider, SecretBytes, SecurityError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Distinguishes the keys wrapped by different mock providers
+static NEXT_MASTER_KEY: AtomicUsize = AtomicUsize::new(1);
+
+/// In-memory `MasterKeyProvider` that can be made to fail
+///
+/// Keys are "wrapped" by prefixing the provider's master key number and
+/// the wrapping context, so another mock provider, or another context,
+/// refuses to unwrap them.
+pub struct MockKeyProvider {
+    master_key: u64,
+    available: AtomicBool,
+    pub generated: AtomicUsize,
+    pub unwrapped: AtomicUsize,
+}
+
+impl MockKeyProvider {
+    pub fn new() -> Self {
+        Self {
+            master_key: NEXT_MASTER_KEY.fetch_add(1, Ordering::SeqCst) as u64,
+            available: AtomicBool::new(true),
+            generated: AtomicUsize::new(0),
+            unwrapped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Make every request fail with `SecurityError::ProviderUnavailable`
+    pub fn set_available(&self, available: bool) {
+        self.available.store(available, Ordering::SeqCst);
+    }
+
+    fn check_available(&self) -> Result<(), SecurityError> {
+        if self.available.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(SecurityError::ProviderUnavailable("mock provider is down".into()))
+        }
+    }
+}
+
+#[async_trait]
+impl MasterKeyProvider for MockKeyProvider {
+    async fn wrap_key(&self, key: &SecretBytes, context: &str) -> Result<Vec<u8>, SecurityError> {
+        self.check_available()?;
+        let mut wrapped = self.master_key.to_be_bytes().to_vec();
+        wrapped.push(context.len() as u8);
+        wrapped.extend_from_slice(context.as_bytes());
+        wrapped.extend_from_slice(key.expose());
+        Ok(wrapped)
+    }
+
+    async fn unwrap_key(&self, wrapped: &[u8], context: &str) -> Result<SecretBytes, SecurityError> {
+        self.check_available()?;
+        let wrong_key = || SecurityError::InvalidKey("wrapped by another master key".into());
+        let (master_key, rest) = wrapped.split_at_checked(8).ok_or_else(wrong_key)?;
+        if master_key != self.master_key.to_be_bytes() {
+            return Err(wrong_key());
+        }
+        let (&len, rest) = rest.split_first().ok_or_else(wrong_key)?;
+        let (wrapped_context, key) = rest.split_at_checked(len as usize).ok_or_else(wrong_key)?;
+        if wrapped_context != context.as_bytes() {
+            return Err(SecurityError::InvalidKey("wrapped for another slot".into()));
+        }
+        self.unwrapped.fetch_add(1, Ordering::SeqCst);
+        if key.len() != 32 {
+            return Err(wrong_key());
+        }
+        Ok(SecretBytes::from(key))
+    }
+
+    async fn generate_data_key(&self) -> Result<SecretBytes, SecurityError> {
+        self.check_available()?;
+        let count = self.generated.fetch_add(1, Ordering::SeqCst);
+        Ok(SecretBytes::new(vec![count as u8 + 1; 32]))
+    }
+}
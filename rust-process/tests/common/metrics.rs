@@ -32,6 +32,7 @@ impl TestMetrics {
         durations.push((name.to_string(), duration));
     }
 
+    #[allow(dead_code)] // Only test_runner prints a summary
     pub async fn print_summary(&self) {
         let total = self.total_tests.load(Ordering::SeqCst);
         let passed = self.passed_tests.load(Ordering::SeqCst);
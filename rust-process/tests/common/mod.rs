@@ -1,14 +1,16 @@
+mod key_provider;
 mod metrics;
 
 use secure_biometric::logging;
+use secure_biometric::storage::StorageError;
+pub use key_provider::MockKeyProvider;
 pub use metrics::{TestMetrics, TestTimer};
+use std::future::Future;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tempfile::TempDir;
-use env_logger::Builder;
 use log::LevelFilter;
-use std::io::Write;
-use chrono;
 
 /// Test utilities and common functionality
 pub struct TestContext {
@@ -22,18 +24,10 @@ impl TestContext {
         // Initialize logging only once
         static INIT: std::sync::Once = std::sync::Once::new();
         INIT.call_once(|| {
-            Builder::new()
-                .format(|buf, record| {
-                    writeln!(buf,
-                        "{} [{}] {}",
-                        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                        record.level(),
-                        record.args()
-                    )
-                })
-                .filter(None, LevelFilter::Info) // Set default to Info
-                .filter(Some("sled"), LevelFilter::Warn) // Reduce sled noise
-                .init();
+            if let Ok(guard) = logging::try_init(logging::level_from_env(LevelFilter::Info)) {
+                // Keep the logger installed for the whole test binary
+                std::mem::forget(guard);
+            }
         });
 
         Self {
@@ -47,6 +41,35 @@ impl TestContext {
         self.temp_dir.path().to_path_buf()
     }
 
+    /// Reopen the vault directory once the previous handle's lock is gone
+    ///
+    /// sled releases its file lock from background threads shortly after
+    /// the last handle is dropped, so an immediate reopen can find it held.
+    pub async fn reopen<T, F, Fut>(&self, mut open: F) -> Result<T, StorageError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, StorageError>>,
+    {
+        for _ in 0..100 {
+            match open().await {
+                Err(StorageError::Locked { .. }) => tokio::time::sleep(Duration::from_millis(20)).await,
+                result => return result,
+            }
+        }
+        open().await
+    }
+
+    /// Open the raw sled database of a closed vault
+    pub async fn open_db(&self) -> sled::Db {
+        for _ in 0..100 {
+            if let Ok(db) = sled::open(self.temp_path()) {
+                return db;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        sled::open(self.temp_path()).expect("Failed to reopen database")
+    }
+
     /// Create test template data
     pub fn create_test_template(&self) -> Vec<u8> {
         // TODO: Implement realistic template generation
@@ -1,6 +1,18 @@
-use crate::common::TestContext;
-use secure_biometric::storage::TemplateVault;
-use secure_biometric::templates::{Template, TemplateMetadata, TemplateType};
+use crate::common::{MockKeyProvider, TestContext};
+use chrono::{Duration, Utc};
+use secure_biometric::security::{
+    Argon2Params, Cipher, KeyBudget, KeySource, LocalFileKeyProvider, SecurityError,
+};
+use secure_biometric::storage::{
+    AuditContext, AuditOperation, CompressionAlgo, ImportOptions, StorageError, StoreOutcome, TemplateVault,
+    VaultConfig, VaultEvent, VaultMetrics, DEFAULT_MAX_TEMPLATE_SIZE,
+};
+use secure_biometric::templates::{
+    CosineMatcher, FusedTemplate, FusionMatcher, MetadataVersion, ModalityRules, Provenance,
+    Template, TemplateError, TemplateMetadata, TemplateType, ValidationPolicy,
+};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 #[tokio::test]
 async fn test_template_storage_basic() {
@@ -10,15 +22,12 @@ async fn test_template_storage_basic() {
         .expect("Failed to create vault");
 
     // Create test template
-    let template = Template::new(
-        ctx.create_test_template(),
-        TemplateMetadata {
-            version: "1.0".to_string(),
-            template_type: TemplateType::Face,
-            quality_score: 0.95,
-            extra: serde_json::json!({}),
-        },
-    );
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Face)
+        .quality_score(0.95)
+        .build()
+        .unwrap();
 
     // Store template
     let id = vault
@@ -30,6 +39,7 @@ async fn test_template_storage_basic() {
     let retrieved = vault.get(id).await.expect("Failed to retrieve template");
 
     assert_eq!(retrieved.data, template.data);
+    assert_eq!(retrieved.id, Some(id));
     assert_eq!(retrieved.metadata.template_type, TemplateType::Face);
     assert_eq!(retrieved.metadata.quality_score, 0.95);
 }
@@ -53,15 +63,12 @@ async fn test_template_deletion() {
         .expect("Failed to create vault");
 
     // Create and store template
-    let template = Template::new(
-        ctx.create_test_template(),
-        TemplateMetadata {
-            version: "1.0".to_string(),
-            template_type: TemplateType::Face,
-            quality_score: 0.95,
-            extra: serde_json::json!({}),
-        },
-    );
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Face)
+        .quality_score(0.95)
+        .build()
+        .unwrap();
 
     let id = vault
         .store(template)
@@ -69,9 +76,3134 @@ async fn test_template_deletion() {
         .expect("Failed to store template");
 
     // Delete template
-    vault.delete(id).await.expect("Failed to delete template");
+    assert!(vault.delete(id).await.expect("Failed to delete template"));
 
     // Verify template is gone
     let result = vault.get(id).await;
     assert!(result.is_err());
 }
+
+#[tokio::test]
+async fn test_delete_reports_missing_entries() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Iris)
+        .quality_score(0.8)
+        .build()
+        .unwrap();
+    let id = vault.store(template).await.unwrap();
+
+    // Deleting twice only removes something the first time
+    assert!(vault.delete(id).await.unwrap());
+    assert!(!vault.delete(id).await.unwrap());
+    assert!(!vault.delete(uuid::Uuid::new_v4()).await.unwrap());
+
+    // Metadata and index records went with the template
+    assert!(vault.list_metadata(0, 10).await.unwrap().is_empty());
+    assert!(vault.find_by_type(TemplateType::Iris).await.unwrap().is_empty());
+
+    // Only the delete that removed something is audited
+    let deletes = vault
+        .export_audit(..)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|entry| entry.operation == AuditOperation::Delete)
+        .count();
+    assert_eq!(deletes, 1);
+
+    // A soft-deleted template can still be deleted for good
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Iris)
+        .quality_score(0.8)
+        .build()
+        .unwrap();
+    let id = vault.store(template).await.unwrap();
+    vault.soft_delete(id).await.unwrap();
+    assert!(vault.delete(id).await.unwrap());
+    assert!(vault.restore(id).await.is_err());
+}
+
+#[tokio::test]
+async fn test_vault_already_open() {
+    let ctx = TestContext::new();
+    let _vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    // A second handle on the same directory must name the current holder
+    let err = match TemplateVault::new(ctx.temp_path()).await {
+        Ok(_) => panic!("Second open should fail while the vault is held"),
+        Err(err) => err,
+    };
+    match &err {
+        StorageError::Locked { path, holder_pid } => {
+            assert_eq!(path, &ctx.temp_path());
+            assert_eq!(*holder_pid, Some(std::process::id()));
+        }
+        other => panic!("Unexpected error: {:?}", other),
+    }
+    assert!(err
+        .to_string()
+        .contains(&format!("already open by process {}", std::process::id())));
+}
+
+#[tokio::test]
+async fn test_template_aliases() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Face)
+        .quality_score(0.95)
+        .build()
+        .unwrap();
+    let id = vault
+        .store(template.clone())
+        .await
+        .expect("Failed to store template");
+    let other = vault
+        .store(template)
+        .await
+        .expect("Failed to store template");
+
+    // Bind and resolve
+    vault
+        .add_alias(id, "badge", "B-1001")
+        .await
+        .expect("Failed to add alias");
+    vault
+        .add_alias(id, "hr", "E-42")
+        .await
+        .expect("Failed to add alias");
+    assert_eq!(
+        vault.resolve_alias("badge", "B-1001").await.unwrap(),
+        Some(id)
+    );
+    assert_eq!(vault.resolve_alias("badge", "B-9999").await.unwrap(), None);
+
+    let mut aliases = vault.aliases_of(id).await.unwrap();
+    aliases.sort();
+    assert_eq!(
+        aliases,
+        vec![
+            ("badge".to_string(), "B-1001".to_string()),
+            ("hr".to_string(), "E-42".to_string()),
+        ]
+    );
+
+    // Re-binding to the same template is fine, to another one is a conflict
+    vault
+        .add_alias(id, "badge", "B-1001")
+        .await
+        .expect("Idempotent re-bind failed");
+    match vault.add_alias(other, "badge", "B-1001").await {
+        Err(StorageError::AliasConflict { existing, .. }) => assert_eq!(existing, id),
+        other => panic!("Expected alias conflict, got {:?}", other),
+    }
+    assert!(vault.aliases_of(other).await.unwrap().is_empty());
+
+    // Unknown templates cannot be aliased
+    let missing = uuid::Uuid::new_v4();
+    assert!(matches!(
+        vault.add_alias(missing, "badge", "B-2002").await,
+        Err(StorageError::NotFound(_))
+    ));
+
+    // Remove one alias explicitly
+    assert!(vault.remove_alias("hr", "E-42").await.unwrap());
+    assert!(!vault.remove_alias("hr", "E-42").await.unwrap());
+    assert_eq!(vault.aliases_of(id).await.unwrap().len(), 1);
+
+    // Deleting the template drops its remaining aliases
+    vault.delete(id).await.expect("Failed to delete template");
+    assert_eq!(vault.resolve_alias("badge", "B-1001").await.unwrap(), None);
+    assert!(vault.aliases_of(id).await.unwrap().is_empty());
+    vault
+        .add_alias(other, "badge", "B-1001")
+        .await
+        .expect("Alias should be free after delete");
+}
+
+#[tokio::test]
+async fn test_aliases_not_stored_in_plaintext() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![3; 32]);
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Face)
+        .quality_score(0.95)
+        .build()
+        .unwrap();
+    let id = vault.store(template.clone()).await.unwrap();
+    let legacy = vault.store(template).await.unwrap();
+    vault.add_alias(id, "badge", "B-1001").await.unwrap();
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    let contains = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).any(|w| w == needle);
+    let plaintext_entries = |db: &sled::Db| {
+        let mut found = 0;
+        for name in ["aliases", "alias_refs"] {
+            for item in db.open_tree(name).unwrap().iter() {
+                let (key, value) = item.unwrap();
+                if [b"B-1001".as_slice(), b"E-7"].iter().any(|id| contains(&key, id) || contains(&value, id)) {
+                    found += 1;
+                }
+            }
+        }
+        found
+    };
+
+    // An alias as written before aliases were keyed by a hash
+    {
+        let db = ctx.open_db().await;
+        assert_eq!(plaintext_entries(&db), 0);
+        let mut alias = b"hr\0".to_vec();
+        alias.extend_from_slice(b"E-7");
+        let mut ref_key = legacy.as_bytes().to_vec();
+        ref_key.extend_from_slice(&alias);
+        db.open_tree("aliases").unwrap().insert(&alias, legacy.as_bytes()).unwrap();
+        db.open_tree("alias_refs").unwrap().insert(ref_key, &[]).unwrap();
+        db.flush().unwrap();
+    }
+
+    // It is re-indexed on open
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    assert_eq!(vault.resolve_alias("hr", "E-7").await.unwrap(), Some(legacy));
+    assert_eq!(vault.aliases_of(legacy).await.unwrap(), vec![("hr".to_string(), "E-7".to_string())]);
+    assert_eq!(vault.resolve_alias("badge", "B-1001").await.unwrap(), Some(id));
+    vault.rotate_key().await.unwrap();
+    assert_eq!(vault.aliases_of(id).await.unwrap(), vec![("badge".to_string(), "B-1001".to_string())]);
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    let db = ctx.open_db().await;
+    assert_eq!(plaintext_entries(&db), 0);
+}
+
+#[tokio::test]
+async fn test_unknown_metadata_fields_round_trip() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    // Metadata as sent by a newer client with fields we don't model
+    let metadata: TemplateMetadata = serde_json::from_value(serde_json::json!({
+        "version": "1.0",
+        "template_type": "iris",
+        "quality_score": 0.8,
+        "extra": {},
+        "eye": "left",
+        "capture": {"distance_cm": 30}
+    }))
+    .expect("Failed to parse metadata");
+
+    // The builder only sets known fields, so assemble the template directly
+    let template = Template {
+        id: None,
+        data: ctx.create_test_template(),
+        metadata: metadata.clone(),
+    };
+    let id = vault.store(template).await.expect("Failed to store template");
+    let retrieved = vault.get(id).await.expect("Failed to retrieve template");
+
+    assert_eq!(retrieved.metadata.unknown, metadata.unknown);
+    assert_eq!(retrieved.metadata.unknown["eye"], "left");
+}
+
+#[tokio::test]
+async fn test_template_batch_retrieval() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let mut ids = Vec::new();
+    for i in 0..3u8 {
+        let template = Template::builder()
+            .data(vec![i; 4])
+            .template_type(TemplateType::Face)
+            .quality_score(0.95)
+            .build()
+            .unwrap();
+        ids.push(vault.store(template).await.expect("Failed to store template"));
+    }
+
+    // Interleave absent ids with present ones
+    let missing = uuid::Uuid::new_v4();
+    let request = vec![ids[2], missing, ids[0], ids[1], missing];
+    let results = vault
+        .get_batch(&request)
+        .await
+        .expect("Failed to retrieve batch");
+
+    assert_eq!(results.len(), request.len());
+    assert_eq!(results[0].as_ref().unwrap().data, vec![2; 4]);
+    assert_eq!(results[0].as_ref().unwrap().id, Some(ids[2]));
+    assert!(results[1].is_none());
+    assert_eq!(results[2].as_ref().unwrap().data, vec![0; 4]);
+    assert_eq!(results[3].as_ref().unwrap().data, vec![1; 4]);
+    assert!(results[4].is_none());
+
+    assert!(vault.get_batch(&[]).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_template_update() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Face)
+        .quality_score(0.8)
+        .build()
+        .unwrap();
+    let id = vault
+        .store(template.clone())
+        .await
+        .expect("Failed to store template");
+
+    // Re-enroll under the same id
+    let mut reenrolled = template.clone();
+    reenrolled.data = vec![9, 8, 7];
+    reenrolled.metadata.quality_score = 0.97;
+    vault
+        .update(id, reenrolled)
+        .await
+        .expect("Failed to update template");
+
+    let retrieved = vault.get(id).await.expect("Failed to retrieve template");
+    assert_eq!(retrieved.data, vec![9, 8, 7]);
+    assert_eq!(retrieved.metadata.quality_score, 0.97);
+    assert_eq!(vault.list_ids().await.unwrap(), vec![id]);
+
+    // Updating a deleted template is rejected and does not resurrect it
+    vault.delete(id).await.expect("Failed to delete template");
+    match vault.update(id, template).await {
+        Err(StorageError::NotFound(missing)) => assert_eq!(missing, id),
+        other => panic!("Expected NotFound, got {:?}", other),
+    }
+    assert!(vault.get(id).await.is_err());
+}
+
+#[tokio::test]
+async fn test_template_exists_and_count() {
+    let ctx = TestContext::new();
+    let vault = Arc::new(
+        TemplateVault::new(ctx.temp_path())
+            .await
+            .expect("Failed to create vault"),
+    );
+    assert_eq!(vault.count().await.unwrap(), 0);
+
+    // Concurrent writers
+    let mut handles = Vec::new();
+    for writer in 0..4u8 {
+        let vault = vault.clone();
+        handles.push(tokio::spawn(async move {
+            let mut ids = Vec::new();
+            for i in 0..10u8 {
+                let template = Template::builder()
+                    .data(vec![writer, i])
+                    .template_type(TemplateType::Face)
+                    .quality_score(0.95)
+                    .build()
+                    .unwrap();
+                ids.push(vault.store(template).await.expect("Failed to store template"));
+            }
+            ids
+        }));
+    }
+    let mut ids = Vec::new();
+    for handle in handles {
+        ids.extend(handle.await.expect("Task failed"));
+    }
+    assert_eq!(vault.count().await.unwrap(), 40);
+
+    let id = ids[0];
+    assert!(vault.exists(id).await.unwrap());
+    vault.delete(id).await.expect("Failed to delete template");
+    assert!(!vault.exists(id).await.unwrap());
+    assert!(!vault.exists(uuid::Uuid::new_v4()).await.unwrap());
+    assert_eq!(vault.count().await.unwrap(), 39);
+}
+
+#[tokio::test]
+async fn test_metadata_listing_pagination() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let types = [
+        TemplateType::Face,
+        TemplateType::Fingerprint,
+        TemplateType::Iris,
+        TemplateType::Voice,
+        TemplateType::Other,
+    ];
+    for template_type in types {
+        let template = Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(template_type)
+            .quality_score(0.9)
+            .build()
+            .unwrap();
+        vault.store(template).await.expect("Failed to store template");
+    }
+
+    // Pages follow list_ids order and stop cleanly at the end
+    let ids = vault.list_ids().await.unwrap();
+    let first = vault.list_metadata(0, 2).await.unwrap();
+    let second = vault.list_metadata(2, 2).await.unwrap();
+    let last = vault.list_metadata(4, 2).await.unwrap();
+    assert_eq!(first.len(), 2);
+    assert_eq!(second.len(), 2);
+    assert_eq!(last.len(), 1);
+    assert!(vault.list_metadata(5, 2).await.unwrap().is_empty());
+    assert!(vault.list_metadata(0, 0).await.unwrap().is_empty());
+
+    let listed: Vec<_> = first.iter().chain(&second).chain(&last).map(|(id, _)| *id).collect();
+    assert_eq!(listed, ids);
+
+    let listed_types: Vec<_> = vault
+        .list_metadata(0, 10)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(_, metadata)| metadata.template_type)
+        .collect();
+    assert!(types.iter().all(|t| listed_types.contains(t)));
+
+    // Deleting a template drops it from the listing
+    vault.delete(ids[0]).await.expect("Failed to delete template");
+    let remaining = vault.list_metadata(0, 10).await.unwrap();
+    assert_eq!(remaining.len(), 4);
+    assert!(remaining.iter().all(|(id, _)| *id != ids[0]));
+}
+
+#[tokio::test]
+async fn test_find_by_type() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let mut stored = Vec::new();
+    for template_type in [
+        TemplateType::Face,
+        TemplateType::Fingerprint,
+        TemplateType::Face,
+        TemplateType::Iris,
+    ] {
+        let template = Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(template_type)
+            .quality_score(0.9)
+            .build()
+            .unwrap();
+        let id = vault.store(template).await.expect("Failed to store template");
+        stored.push((id, template_type));
+    }
+
+    for template_type in TemplateType::ALL {
+        let mut expected: Vec<_> = stored
+            .iter()
+            .filter(|(_, t)| *t == template_type)
+            .map(|(id, _)| *id)
+            .collect();
+        expected.sort();
+        let mut found = vault.find_by_type(template_type).await.unwrap();
+        found.sort();
+        assert_eq!(found, expected, "mismatch for {:?}", template_type);
+    }
+
+    // Changing modality on update moves the entry between filters
+    let (face_id, _) = stored[0];
+    let mut template = vault.get(face_id).await.unwrap().into_inner();
+    template.metadata.template_type = TemplateType::Voice;
+    vault.update(face_id, template).await.unwrap();
+    assert!(!vault.find_by_type(TemplateType::Face).await.unwrap().contains(&face_id));
+    assert_eq!(vault.find_by_type(TemplateType::Voice).await.unwrap(), vec![face_id]);
+
+    // The index is plaintext and survives rotation untouched
+    vault.rotate_key().await.expect("Failed to rotate key");
+    assert_eq!(vault.find_by_type(TemplateType::Iris).await.unwrap(), vec![stored[3].0]);
+
+    vault.delete(stored[3].0).await.unwrap();
+    assert!(vault.find_by_type(TemplateType::Iris).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_expired_templates_are_purged() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let template = || {
+        Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+    let expired_id = vault
+        .store_with_expiry(
+            template(),
+            Utc::now() - Duration::hours(1),
+        )
+        .await
+        .expect("Failed to store template");
+    let live_id = vault
+        .store_with_expiry(
+            template(),
+            Utc::now() + Duration::days(30),
+        )
+        .await
+        .expect("Failed to store template");
+    let permanent_id = vault
+        .store(template())
+        .await
+        .expect("Failed to store template");
+
+    assert!(matches!(
+        vault.get(expired_id).await,
+        Err(StorageError::Expired { id, .. }) if id == expired_id
+    ));
+    assert!(vault.get(live_id).await.is_ok());
+    // Expired entries awaiting a purge are neither counted nor writable
+    assert_eq!(vault.count().await.unwrap(), 2);
+    assert!(matches!(
+        vault.update(expired_id, template()).await,
+        Err(StorageError::Expired { id, .. }) if id == expired_id
+    ));
+
+    assert_eq!(vault.purge_expired().await.unwrap(), 1);
+    assert_eq!(vault.count().await.unwrap(), 2);
+    assert!(matches!(vault.get(expired_id).await, Err(StorageError::NotFound(_))));
+    assert!(!vault.exists(expired_id).await.unwrap());
+    assert!(vault.exists(live_id).await.unwrap());
+    assert!(vault.exists(permanent_id).await.unwrap());
+    assert_eq!(vault.purge_expired().await.unwrap(), 0);
+    drop(vault);
+
+    // The purged entry is physically gone from the database
+    let db = ctx.open_db().await;
+    assert!(db.get(expired_id.as_bytes()).unwrap().is_none());
+    assert!(db.open_tree("metadata").unwrap().get(expired_id.as_bytes()).unwrap().is_none());
+    assert!(db.open_tree("expiry").unwrap().get(expired_id.as_bytes()).unwrap().is_none());
+    assert!(db.get(live_id.as_bytes()).unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_export_import_round_trip() {
+    let ctx = TestContext::new();
+    let source = TemplateVault::new(ctx.temp_path().join("source"))
+        .await
+        .expect("Failed to create vault");
+    let target = TemplateVault::with_config(
+        ctx.temp_path().join("target"),
+        VaultConfig::new().deduplicate(true),
+    )
+    .await
+    .expect("Failed to create vault");
+
+    let mut ids = Vec::new();
+    for (i, template_type) in [TemplateType::Face, TemplateType::Iris, TemplateType::Voice]
+        .into_iter()
+        .enumerate()
+    {
+        let template = Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(template_type)
+            .quality_score(0.5)
+            .extra_field("index", i)
+            .build()
+            .unwrap();
+        let id = match i {
+            1 => source.store_for_subject("subject-1", template).await,
+            _ => source.store(template).await,
+        };
+        ids.push(id.expect("Failed to store template"));
+    }
+    source.add_alias(ids[0], "hr", "E-1").await.unwrap();
+
+    let mut archive = Vec::new();
+    assert_eq!(source.export(&mut archive, "correct horse").await.unwrap(), 3);
+
+    // A wrong passphrase fails before anything is written
+    let result = target
+        .import(archive.as_slice(), "wrong", ImportOptions::default())
+        .await;
+    assert!(matches!(result, Err(StorageError::Encryption(_))));
+    assert_eq!(target.count().await.unwrap(), 0);
+
+    let imported = target
+        .import(archive.as_slice(), "correct horse", ImportOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(imported, 3);
+
+    for id in &ids {
+        let original = source.get(*id).await.unwrap();
+        let restored = target.get(*id).await.unwrap();
+        assert_eq!(restored.data, original.data);
+        assert_eq!(
+            serde_json::to_value(&restored.metadata).unwrap(),
+            serde_json::to_value(&original.metadata).unwrap()
+        );
+    }
+    assert_eq!(target.resolve_alias("hr", "E-1").await.unwrap(), Some(ids[0]));
+    assert_eq!(target.find_by_type(TemplateType::Iris).await.unwrap(), vec![ids[1]]);
+    assert_eq!(target.find_by_subject("subject-1").await.unwrap(), vec![ids[1]]);
+
+    // Imported templates are indexed for deduplication
+    let outcome = target.store_with_outcome(source.get(ids[2]).await.unwrap().into_inner()).await.unwrap();
+    assert_eq!(outcome, StoreOutcome { id: ids[2], deduplicated: true });
+
+    // Duplicates are skipped unless overwriting is requested
+    let skipped = target
+        .import(archive.as_slice(), "correct horse", ImportOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(skipped, 0);
+    // Overwriting replaces aliases and drops the tombstone of an ID
+    target.add_alias(ids[1], "hr", "E-2").await.unwrap();
+    target.soft_delete(ids[2]).await.unwrap();
+    let overwritten = target
+        .import(
+            archive.as_slice(),
+            "correct horse",
+            ImportOptions { overwrite: true, ..Default::default() },
+        )
+        .await
+        .unwrap();
+    assert_eq!(overwritten, 3);
+    assert_eq!(target.count().await.unwrap(), 3);
+    assert_eq!(target.find_by_subject("subject-1").await.unwrap(), vec![ids[1]]);
+    assert_eq!(target.resolve_alias("hr", "E-1").await.unwrap(), Some(ids[0]));
+    assert_eq!(target.resolve_alias("hr", "E-2").await.unwrap(), None);
+    assert!(matches!(target.restore(ids[2]).await, Err(StorageError::NotFound(_))));
+    assert_eq!(target.get(ids[2]).await.unwrap().data, source.get(ids[2]).await.unwrap().data);
+    let outcome = target.store_with_outcome(source.get(ids[2]).await.unwrap().into_inner()).await.unwrap();
+    assert!(outcome.deduplicated);
+}
+
+#[tokio::test]
+async fn test_import_rejects_untrusted_archives() {
+    let ctx = TestContext::new();
+    let source = TemplateVault::new(ctx.temp_path().join("source"))
+        .await
+        .expect("Failed to create vault");
+    let target = TemplateVault::with_config(
+        ctx.temp_path().join("target"),
+        VaultConfig::new().max_template_size(4),
+    )
+    .await
+    .expect("Failed to create vault");
+
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Face)
+        .quality_score(0.5)
+        .build()
+        .unwrap();
+    source.store(template).await.unwrap();
+    let mut archive = Vec::new();
+    source.export(&mut archive, "passphrase").await.unwrap();
+
+    // Archives over the size cap are not read to the end
+    let options = ImportOptions { max_archive_size: archive.len() as u64 - 1, ..Default::default() };
+    let result = target.import(archive.as_slice(), "passphrase", options).await;
+    assert!(matches!(result, Err(StorageError::InvalidArchive(_))));
+
+    // An iteration count far from the default is refused before deriving a key
+    for iterations in [1u32, u32::MAX] {
+        let mut tampered = archive.clone();
+        tampered[5..9].copy_from_slice(&iterations.to_be_bytes());
+        let result = target
+            .import(tampered.as_slice(), "passphrase", ImportOptions::default())
+            .await;
+        assert!(matches!(result, Err(StorageError::InvalidArchive(_))));
+    }
+
+    // Templates are held to the target vault's limits
+    let result = target
+        .import(archive.as_slice(), "passphrase", ImportOptions::default())
+        .await;
+    assert!(matches!(result, Err(StorageError::TemplateTooLarge { max: 4, .. })));
+    assert_eq!(target.count().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_vault_stats() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let stats = vault.stats().await.unwrap();
+    assert_eq!(stats.entries, 0);
+    assert_eq!(stats.ciphertext_bytes, 0);
+    assert!(stats.last_flush.is_none());
+    assert!(stats.last_rotation.is_none());
+
+    vault.flush().await.unwrap();
+    let before = vault.stats().await.unwrap();
+    assert!(before.last_flush.is_some());
+
+    // A 1MB payload of non-repeating bytes
+    let data: Vec<u8> = (0..1024 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    let template = Template::builder()
+        .data(data)
+        .template_type(TemplateType::Face)
+        .quality_score(0.5)
+        .build()
+        .unwrap();
+    let id = vault.store(template).await.expect("Failed to store template");
+    vault.flush().await.unwrap();
+
+    let after = vault.stats().await.unwrap();
+    assert_eq!(after.entries, 1);
+    assert!(after.ciphertext_bytes > 1024 * 1024);
+    assert!(after.size_on_disk > before.size_on_disk + 1024 * 1024);
+
+    vault.rotate_key().await.unwrap();
+    assert!(vault.stats().await.unwrap().last_rotation.is_some());
+
+    vault.delete(id).await.unwrap();
+    let stats = vault.stats().await.unwrap();
+    assert_eq!(stats.entries, 0);
+    assert_eq!(stats.ciphertext_bytes, 0);
+}
+
+#[tokio::test]
+async fn test_find_by_extra() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new_with_extra_index(ctx.temp_path(), &["subject_id", "site"])
+        .await
+        .expect("Failed to create vault");
+
+    let make = |extra: serde_json::Value| {
+        let mut builder = Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(0.5);
+        for (key, value) in extra.as_object().expect("extra is an object") {
+            builder = builder.extra_field(key.clone(), value.clone());
+        }
+        builder.build().unwrap()
+    };
+
+    let alice_left = vault
+        .store(make(serde_json::json!({ "subject_id": "alice", "site": 1 })))
+        .await
+        .unwrap();
+    let alice_right = vault
+        .store(make(serde_json::json!({ "subject_id": "alice", "finger": "right" })))
+        .await
+        .unwrap();
+    let bob = vault
+        .store(make(serde_json::json!({ "subject_id": "bob", "site": "1" })))
+        .await
+        .unwrap();
+    vault.store(make(serde_json::json!({}))).await.unwrap();
+
+    let mut found = vault
+        .find_by_extra("subject_id", &serde_json::json!("alice"))
+        .await
+        .unwrap();
+    found.sort();
+    let mut expected = vec![alice_left, alice_right];
+    expected.sort();
+    assert_eq!(found, expected);
+
+    // Values match exactly: no substrings, no type coercion
+    assert!(vault
+        .find_by_extra("subject_id", &serde_json::json!("ali"))
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        vault.find_by_extra("site", &serde_json::json!(1)).await.unwrap(),
+        vec![alice_left]
+    );
+    assert_eq!(
+        vault.find_by_extra("site", &serde_json::json!("1")).await.unwrap(),
+        vec![bob]
+    );
+
+    assert!(matches!(
+        vault.find_by_extra("finger", &serde_json::json!("right")).await,
+        Err(StorageError::NotIndexed(key)) if key == "finger"
+    ));
+
+    // Updates and deletes keep the index current, across rotation too
+    vault
+        .update(bob, make(serde_json::json!({ "subject_id": "carol" })))
+        .await
+        .unwrap();
+    vault.rotate_key().await.unwrap();
+    assert!(vault
+        .find_by_extra("subject_id", &serde_json::json!("bob"))
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        vault.find_by_extra("subject_id", &serde_json::json!("carol")).await.unwrap(),
+        vec![bob]
+    );
+
+    vault.delete(alice_left).await.unwrap();
+    assert_eq!(
+        vault.find_by_extra("subject_id", &serde_json::json!("alice")).await.unwrap(),
+        vec![alice_right]
+    );
+}
+
+#[tokio::test]
+async fn test_soft_delete_and_restore() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Iris)
+        .quality_score(0.5)
+        .extra_field("note", "enrolled")
+        .build()
+        .unwrap();
+    let original_data = template.data.clone();
+    let original_metadata = serde_json::to_value(&template.metadata).unwrap();
+    let id = vault.store(template).await.expect("Failed to store template");
+    vault.add_alias(id, "hr", "E-7").await.unwrap();
+
+    vault.soft_delete(id).await.unwrap();
+    assert!(matches!(vault.get(id).await, Err(StorageError::NotFound(_))));
+    assert!(!vault.exists(id).await.unwrap());
+    assert!(vault.find_by_type(TemplateType::Iris).await.unwrap().is_empty());
+    assert!(matches!(vault.soft_delete(id).await, Err(StorageError::NotFound(_))));
+
+    // Tombstones are re-encrypted so they survive rotation
+    vault.rotate_key().await.unwrap();
+
+    vault.restore(id).await.unwrap();
+    let restored = vault.get(id).await.unwrap();
+    assert_eq!(restored.data, original_data);
+    assert_eq!(serde_json::to_value(&restored.metadata).unwrap(), original_metadata);
+    assert_eq!(vault.find_by_type(TemplateType::Iris).await.unwrap(), vec![id]);
+    assert_eq!(vault.resolve_alias("hr", "E-7").await.unwrap(), Some(id));
+    assert!(matches!(vault.restore(id).await, Err(StorageError::NotFound(_))));
+
+    // Purging honours the restore window
+    vault.soft_delete(id).await.unwrap();
+    assert_eq!(vault.purge_tombstones(Duration::hours(1)).await.unwrap(), 0);
+    assert_eq!(vault.purge_tombstones(Duration::zero()).await.unwrap(), 1);
+    assert!(matches!(vault.restore(id).await, Err(StorageError::NotFound(_))));
+    assert_eq!(vault.resolve_alias("hr", "E-7").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_template_version_history() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault")
+        .with_history(2);
+
+    let make = |data: Vec<u8>, version: &str| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .version(version)
+            .build()
+            .unwrap()
+    };
+
+    let id = vault.store(make(vec![0; 16], "v0")).await.unwrap();
+    assert!(vault.list_versions(id).await.unwrap().is_empty());
+
+    for i in 1..=3u8 {
+        vault.update(id, make(vec![i; 16], &format!("v{}", i))).await.unwrap();
+    }
+
+    // Three updates kept versions 1..=3; the cap of two evicted the first
+    assert_eq!(vault.list_versions(id).await.unwrap(), vec![2, 3]);
+    assert!(matches!(
+        vault.get_version(id, 1).await,
+        Err(StorageError::VersionNotFound { version: 1, .. })
+    ));
+
+    vault.rotate_key().await.unwrap();
+    for version in [2u64, 3] {
+        let old = vault.get_version(id, version).await.unwrap();
+        assert_eq!(old.data, vec![version as u8 - 1; 16]);
+        assert_eq!(old.id, Some(id));
+        assert_eq!(old.metadata.version, format!("v{}", version - 1));
+    }
+    assert_eq!(vault.get(id).await.unwrap().data, vec![3; 16]);
+
+    vault.delete(id).await.unwrap();
+    assert!(vault.list_versions(id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_history_disabled_by_default() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let template = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+    let id = vault.store(template(vec![1])).await.unwrap();
+    vault.update(id, template(vec![2])).await.unwrap();
+    assert!(vault.list_versions(id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_compare_and_swap_detects_lost_writes() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let make = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+
+    let id = vault.store(make(vec![0; 8])).await.unwrap();
+    assert_eq!(vault.get(id).await.unwrap().revision, 1);
+
+    // Both writers read the same revision before either writes
+    let first = vault.get(id).await.unwrap();
+    let second = vault.get(id).await.unwrap();
+
+    let revision = vault
+        .compare_and_swap(id, first.revision, make(vec![1; 8]))
+        .await
+        .unwrap();
+    assert_eq!(revision, 2);
+
+    let result = vault
+        .compare_and_swap(id, second.revision, make(vec![2; 8]))
+        .await;
+    assert!(matches!(
+        result,
+        Err(StorageError::Conflict { expected: 1, actual: 2, .. })
+    ));
+
+    let current = vault.get(id).await.unwrap();
+    assert_eq!(current.data, vec![1; 8]);
+    assert_eq!(current.revision, 2);
+
+    // Plain updates bump the revision too
+    vault.update(id, make(vec![3; 8])).await.unwrap();
+    assert_eq!(vault.get(id).await.unwrap().revision, 3);
+
+    assert!(matches!(
+        vault.compare_and_swap(uuid::Uuid::new_v4(), 1, make(vec![4; 8])).await,
+        Err(StorageError::NotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_key_usage_persists_and_forces_rotation() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![5; 32]);
+    let make = || {
+        Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+
+    let vault = TemplateVault::with_config(ctx.temp_path(), VaultConfig::new().key_source(key()))
+        .await
+        .expect("Failed to create vault");
+    let id = vault.store(make()).await.unwrap();
+    vault.get(id).await.unwrap();
+    let used = vault.key_status();
+    assert!(used.encryptions > 0 && used.decryptions > 0);
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    // Counters survive a reopen, and a limit they have not reached yet
+    // leaves the key in use
+    let operations = used.encryptions + used.decryptions;
+    let registry = prometheus::Registry::new();
+    let config = || {
+        VaultConfig::new()
+            .key_source(key())
+            .key_limit(KeyBudget {
+                max_operations: Some(operations + 1),
+                ..Default::default()
+            })
+    };
+    let vault = ctx
+        .reopen(|| {
+            TemplateVault::with_config(
+                ctx.temp_path(),
+                config().metrics(VaultMetrics::new(&registry).expect("Failed to register metrics")),
+            )
+        })
+        .await
+        .expect("Failed to reopen vault");
+    let restored = vault.key_status();
+    assert_eq!(restored.key_id, used.key_id);
+    assert_eq!(restored.created_at, used.created_at);
+    assert_eq!(restored.encryptions, used.encryptions);
+    assert_eq!(restored.bytes_decrypted, used.bytes_decrypted);
+    assert!(!restored.limit_reached);
+
+    let mut events = vault.subscribe();
+    let second = vault.store(make()).await.unwrap();
+    assert!(vault.key_status().limit_reached);
+    let exposition = |registry: &prometheus::Registry| {
+        let mut exposition = Vec::new();
+        prometheus::Encoder::encode(&prometheus::TextEncoder::new(), &registry.gather(), &mut exposition)
+            .unwrap();
+        String::from_utf8(exposition).unwrap()
+    };
+    let label = format!(r#"vault_key_operations{{key_id="{}"}}"#, used.key_id);
+    assert!(exposition(&registry).contains(&label));
+
+    // The next write rotates the key past its limit first
+    let third = vault.store(make()).await.unwrap();
+    assert!(matches!(events.try_recv(), Ok(VaultEvent::Stored(stored)) if stored == second));
+    assert!(matches!(events.try_recv(), Ok(VaultEvent::KeyRotated)));
+    assert!(matches!(events.try_recv(), Ok(VaultEvent::Stored(stored)) if stored == third));
+    let rotated = vault.key_status();
+    assert_ne!(rotated.key_id, used.key_id);
+    assert!(rotated.encryptions > 0);
+    for id in [id, second, third] {
+        assert_eq!(vault.get(id).await.unwrap().data, ctx.create_test_template());
+    }
+
+    // Gauges only cover keys still held
+    let exposition = exposition(&registry);
+    assert!(!exposition.contains(&label), "retired key in:\n{}", exposition);
+    assert!(exposition.contains(&format!(r#"vault_key_operations{{key_id="{}"}}"#, rotated.key_id)));
+}
+
+#[tokio::test]
+async fn test_rotation_reencryption_spares_the_new_key() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![5; 32]);
+    let make = || {
+        Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+
+    let vault = TemplateVault::with_config(ctx.temp_path(), VaultConfig::new().key_source(key()))
+        .await
+        .expect("Failed to create vault");
+    for _ in 0..30 {
+        vault.store(make()).await.unwrap();
+    }
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    // The vault holds more templates than the limit allows operations
+    let config = VaultConfig::new().key_source(key()).key_limit(KeyBudget {
+        max_operations: Some(10),
+        ..Default::default()
+    });
+    let vault = ctx
+        .reopen(|| TemplateVault::with_config(ctx.temp_path(), config.clone()))
+        .await
+        .expect("Failed to reopen vault");
+    assert!(vault.key_status().limit_reached);
+
+    // Re-encrypting them leaves the new key's budget to new writes, so
+    // only the first write rotates
+    let mut events = vault.subscribe();
+    let first = vault.store(make()).await.unwrap();
+    let second = vault.store(make()).await.unwrap();
+    assert!(matches!(events.try_recv(), Ok(VaultEvent::KeyRotated)));
+    assert!(matches!(events.try_recv(), Ok(VaultEvent::Stored(stored)) if stored == first));
+    assert!(matches!(events.try_recv(), Ok(VaultEvent::Stored(stored)) if stored == second));
+    assert!(events.try_recv().is_err());
+    let status = vault.key_status();
+    assert!(status.encryptions < 10, "{:?}", status);
+    assert!(!status.limit_reached);
+    assert_eq!(vault.count().await.unwrap(), 32);
+}
+
+#[tokio::test]
+async fn test_namespaces_are_isolated() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let make = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+
+    let acme = vault.namespace("acme").await.unwrap();
+    let globex = vault.namespace("globex").await.unwrap();
+
+    let acme_id = acme.store(make(vec![1; 8])).await.unwrap();
+    let globex_id = globex.store(make(vec![2; 8])).await.unwrap();
+    let default_id = vault.store(make(vec![3; 8])).await.unwrap();
+
+    // IDs do not leak across namespaces or into the default one
+    assert_eq!(acme.list_ids().await.unwrap(), vec![acme_id]);
+    assert_eq!(globex.list_ids().await.unwrap(), vec![globex_id]);
+    assert_eq!(vault.list_ids().await.unwrap(), vec![default_id]);
+    assert!(matches!(acme.get(globex_id).await, Err(StorageError::NotFound(_))));
+    assert!(matches!(vault.get(acme_id).await, Err(StorageError::NotFound(_))));
+
+    let mut names = vault.list_namespaces().await.unwrap();
+    names.sort();
+    assert_eq!(names, vec!["acme", "globex"]);
+
+    // Rotation through any handle re-encrypts every namespace
+    acme.rotate_key().await.unwrap();
+    assert_eq!(acme.get(acme_id).await.unwrap().data, vec![1; 8]);
+    assert_eq!(globex.get(globex_id).await.unwrap().data, vec![2; 8]);
+    assert_eq!(vault.get(default_id).await.unwrap().data, vec![3; 8]);
+
+    assert!(acme.delete(acme_id).await.unwrap());
+    assert!(!acme.delete(acme_id).await.unwrap());
+    assert!(acme.list_ids().await.unwrap().is_empty());
+
+    assert!(vault.drop_namespace("globex").await.unwrap());
+    assert!(!vault.drop_namespace("globex").await.unwrap());
+    assert_eq!(vault.list_namespaces().await.unwrap(), vec!["acme"]);
+    assert!(vault.namespace("globex").await.unwrap().list_ids().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_namespace_writes_are_observed() {
+    let ctx = TestContext::new();
+    let registry = prometheus::Registry::new();
+    let metrics = VaultMetrics::new(&registry).expect("Failed to register metrics");
+    let config = VaultConfig::new().metrics(metrics).deduplicate(true);
+    let vault = TemplateVault::with_config(ctx.temp_path(), config)
+        .await
+        .expect("Failed to create vault");
+    let make = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+
+    let acme = vault.namespace("acme").await.unwrap();
+    let globex = vault.namespace("globex").await.unwrap();
+    let mut events = vault.subscribe();
+    let id = acme.store(make(vec![1; 8])).await.unwrap();
+    // Deduplication stays within a namespace
+    assert_eq!(acme.store(make(vec![1; 8])).await.unwrap(), id);
+    let other = globex.store(make(vec![1; 8])).await.unwrap();
+    assert_ne!(other, id);
+    assert_eq!(acme.get(id).await.unwrap().data, vec![1; 8]);
+    assert!(acme.delete(id).await.unwrap());
+
+    assert_eq!(events.recv().await.unwrap(), VaultEvent::Stored(id));
+    assert_eq!(events.recv().await.unwrap(), VaultEvent::Stored(other));
+    assert_eq!(events.recv().await.unwrap(), VaultEvent::Deleted(id));
+    let recorded: Vec<_> = vault
+        .export_audit(..)
+        .await
+        .unwrap()
+        .iter()
+        .map(|e| (e.operation, e.template_id))
+        .collect();
+    assert_eq!(
+        recorded,
+        vec![
+            (AuditOperation::Store, Some(id)),
+            (AuditOperation::Store, Some(id)),
+            (AuditOperation::Store, Some(other)),
+            (AuditOperation::Read, Some(id)),
+            (AuditOperation::Delete, Some(id)),
+        ]
+    );
+
+    let mut exposition = Vec::new();
+    prometheus::Encoder::encode(&prometheus::TextEncoder::new(), &registry.gather(), &mut exposition)
+        .unwrap();
+    let exposition = String::from_utf8(exposition).unwrap();
+    for line in [
+        r#"vault_operations_total{operation="store"} 3"#,
+        r#"vault_operations_total{operation="get"} 1"#,
+        r#"vault_operations_total{operation="delete"} 1"#,
+        "vault_template_size_bytes_count 2",
+        // The gauge only counts templates outside namespaces
+        "vault_entries 0",
+    ] {
+        assert!(exposition.contains(line), "missing `{}` in:\n{}", line, exposition);
+    }
+
+    // Dropping a namespace drops its metadata and index trees too, and
+    // reports its templates deleted
+    assert!(vault.drop_namespace("globex").await.unwrap());
+    assert_eq!(events.recv().await.unwrap(), VaultEvent::Deleted(other));
+    let audit = vault.export_audit(..).await.unwrap();
+    let last = audit.last().unwrap();
+    assert_eq!((last.operation, last.template_id), (AuditOperation::Delete, Some(other)));
+    let globex = vault.namespace("globex").await.unwrap();
+    assert!(globex.list_ids().await.unwrap().is_empty());
+    assert_ne!(globex.store(make(vec![1; 8])).await.unwrap(), other);
+}
+
+#[tokio::test]
+async fn test_namespaced_values_are_bound_to_their_namespace() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![8; 32]);
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+    let make = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+    let acme_id = vault.namespace("acme").await.unwrap().store(make(vec![1; 8])).await.unwrap();
+    vault.namespace("globex").await.unwrap();
+    let default_id = vault.store(make(vec![2; 8])).await.unwrap();
+    drop(vault);
+
+    // Copy values between namespaces and the default tree, keeping their IDs
+    {
+        let db = ctx.open_db().await;
+        let acme = db.open_tree("ns:acme").unwrap();
+        let globex = db.open_tree("ns:globex").unwrap();
+        let acme_value = acme.get(acme_id.as_bytes()).unwrap().unwrap();
+        globex.insert(acme_id.as_bytes(), acme_value.clone()).unwrap();
+        db.insert(acme_id.as_bytes(), acme_value).unwrap();
+        let default_value = db.get(default_id.as_bytes()).unwrap().unwrap();
+        acme.insert(default_id.as_bytes(), default_value).unwrap();
+        db.flush().unwrap();
+    }
+
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    let acme = vault.namespace("acme").await.unwrap();
+    let globex = vault.namespace("globex").await.unwrap();
+    assert_eq!(acme.get(acme_id).await.unwrap().data, vec![1; 8]);
+    assert_eq!(vault.get(default_id).await.unwrap().data, vec![2; 8]);
+    assert!(matches!(
+        globex.get(acme_id).await,
+        Err(StorageError::AuthenticationFailed { id }) if id == acme_id
+    ));
+    assert!(matches!(
+        vault.get(acme_id).await,
+        Err(StorageError::AuthenticationFailed { id }) if id == acme_id
+    ));
+    assert!(matches!(
+        acme.get(default_id).await,
+        Err(StorageError::AuthenticationFailed { id }) if id == default_id
+    ));
+}
+
+#[tokio::test]
+async fn test_master_key_persists_across_restarts() {
+    let ctx = TestContext::new();
+    let passphrase = || KeySource::Passphrase("correct horse battery staple".into());
+
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Face)
+        .quality_score(0.5)
+        .build()
+        .unwrap();
+    let data = template.data.clone();
+
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), passphrase())
+        .await
+        .expect("Failed to create vault");
+    let id = vault.store(template).await.expect("Failed to store template");
+    vault.rotate_key().await.expect("Failed to rotate key");
+    drop(vault);
+
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), passphrase()))
+        .await
+        .expect("Failed to reopen vault");
+    assert_eq!(vault.get(id).await.unwrap().data, data);
+    drop(vault);
+
+    // A different master key is refused before any template is touched
+    let result = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), KeySource::Passphrase("wrong".into())))
+        .await;
+    assert!(matches!(
+        result,
+        Err(StorageError::Encryption(SecurityError::InvalidKey(_)))
+    ));
+    let result = ctx.reopen(|| TemplateVault::new(ctx.temp_path())).await;
+    assert!(matches!(
+        result,
+        Err(StorageError::Encryption(SecurityError::InvalidKey(_)))
+    ));
+}
+
+#[tokio::test]
+async fn test_wrapped_keys_are_bound_to_their_slot() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![8; 32]);
+
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+    vault.store(
+        Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap(),
+    )
+    .await
+    .unwrap();
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    // Wrapped by the same master key, but for the hashing key slot
+    let current = {
+        let db = ctx.open_db().await;
+        let keyring = db.open_tree("keyring").unwrap();
+        let hash_key = keyring.get("hash_key").unwrap().unwrap();
+        let current = keyring.insert("current", hash_key).unwrap().unwrap();
+        keyring.flush().unwrap();
+        current
+    };
+    let result = ctx.reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key())).await;
+    assert!(matches!(
+        result,
+        Err(StorageError::Encryption(SecurityError::InvalidKey(_)))
+    ));
+
+    // Wrapped for data key 1, read as data key 2
+    {
+        let db = ctx.open_db().await;
+        let keyring = db.open_tree("keyring").unwrap();
+        keyring.insert("current", current).unwrap();
+        keyring.insert("current_id", &2u32.to_be_bytes()).unwrap();
+        keyring.flush().unwrap();
+    }
+    let result = ctx.reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key())).await;
+    assert!(matches!(
+        result,
+        Err(StorageError::Encryption(SecurityError::InvalidKey(_)))
+    ));
+}
+
+#[tokio::test]
+async fn test_master_key_refused_for_vault_without_one() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    vault.store(
+        Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap(),
+    )
+    .await
+    .unwrap();
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    // Its templates were encrypted with a key that is gone
+    let result = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), KeySource::Bytes(vec![8; 32])))
+        .await;
+    assert!(matches!(
+        result,
+        Err(StorageError::Encryption(SecurityError::InvalidKey(_)))
+    ));
+}
+
+#[tokio::test]
+async fn test_master_key_from_bytes_and_env() {
+    let ctx = TestContext::new();
+    let key = [7u8; 32];
+    let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    std::env::set_var("SECURE_BIOMETRIC_TEST_MASTER_KEY", hex);
+
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), KeySource::Bytes(key.to_vec()))
+        .await
+        .expect("Failed to create vault");
+    let id = vault
+        .store(Template::builder()
+            .data(vec![1, 2, 3])
+            .template_type(TemplateType::Iris)
+            .quality_score(0.5)
+            .build()
+            .unwrap())
+        .await
+        .unwrap();
+    drop(vault);
+
+    // The same key supplied through the environment opens the vault
+    let vault = ctx
+        .reopen(|| {
+            TemplateVault::open_with_key(
+                ctx.temp_path(),
+                KeySource::Env("SECURE_BIOMETRIC_TEST_MASTER_KEY".into()),
+            )
+        })
+        .await
+        .expect("Failed to reopen vault");
+    assert_eq!(vault.get(id).await.unwrap().data, vec![1, 2, 3]);
+    drop(vault);
+
+    let result = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), KeySource::Bytes(vec![7; 16])))
+        .await;
+    assert!(matches!(
+        result,
+        Err(StorageError::Encryption(SecurityError::InvalidKey(_)))
+    ));
+}
+
+#[tokio::test]
+async fn test_argon2_passphrase_vault() {
+    let ctx = TestContext::new();
+    let params = Argon2Params {
+        memory_kib: 1024,
+        iterations: 1,
+        parallelism: 1,
+    };
+    let passphrase = |p: &str| KeySource::Argon2Passphrase(p.into(), params);
+
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), passphrase("hunter2"))
+        .await
+        .expect("Failed to create vault");
+    let id = vault
+        .store(Template::builder()
+            .data(vec![4, 5, 6])
+            .template_type(TemplateType::Voice)
+            .quality_score(0.5)
+            .build()
+            .unwrap())
+        .await
+        .unwrap();
+    drop(vault);
+
+    // The salt is kept in the vault, so the passphrase alone reopens it
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), passphrase("hunter2")))
+        .await
+        .expect("Failed to reopen vault");
+    assert_eq!(vault.get(id).await.unwrap().data, vec![4, 5, 6]);
+    drop(vault);
+
+    let result = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), passphrase("hunter3")))
+        .await;
+    match result {
+        Err(StorageError::Encryption(SecurityError::InvalidKey(message))) => {
+            assert!(message.contains("wrong passphrase"), "{}", message)
+        }
+        other => panic!("expected a wrong passphrase error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn test_vault_with_key_provider() {
+    let ctx = TestContext::new();
+    let provider = Arc::new(MockKeyProvider::new());
+    let source = || KeySource::Provider(provider.clone());
+    let template = || {
+        Template::builder()
+            .data(vec![7, 8, 9])
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), source())
+        .await
+        .expect("Failed to create vault");
+    assert_eq!(provider.generated.load(Ordering::SeqCst), 1);
+    let id = vault.store(template()).await.unwrap();
+
+    // Rotation asks the provider for the new key
+    vault.rotate_key().await.expect("Failed to rotate key");
+    assert_eq!(provider.generated.load(Ordering::SeqCst), 2);
+
+    // An unavailable provider fails the rotation without losing data
+    provider.set_available(false);
+    assert!(matches!(
+        vault.rotate_key().await,
+        Err(StorageError::Encryption(SecurityError::ProviderUnavailable(_)))
+    ));
+    assert_eq!(vault.get(id).await.unwrap().data, vec![7, 8, 9]);
+    drop(vault);
+
+    let result = ctx.reopen(|| TemplateVault::open_with_key(ctx.temp_path(), source())).await;
+    assert!(matches!(
+        result,
+        Err(StorageError::Encryption(SecurityError::ProviderUnavailable(_)))
+    ));
+
+    // Opening unwraps the stored data key
+    provider.set_available(true);
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), source()))
+        .await
+        .expect("Failed to reopen vault");
+    assert!(provider.unwrapped.load(Ordering::SeqCst) > 0);
+    assert_eq!(vault.get(id).await.unwrap().data, vec![7, 8, 9]);
+    drop(vault);
+
+    // Another provider cannot unwrap the keys
+    let result = ctx
+        .reopen(|| {
+            TemplateVault::open_with_key(
+                ctx.temp_path(),
+                KeySource::Provider(Arc::new(MockKeyProvider::new())),
+            )
+        })
+        .await;
+    assert!(matches!(
+        result,
+        Err(StorageError::Encryption(SecurityError::InvalidKey(_)))
+    ));
+}
+
+#[tokio::test]
+async fn test_local_file_key_provider() {
+    let ctx = TestContext::new();
+    let vault_dir = ctx.temp_path().join("vault");
+    let key_file = ctx.temp_path().join("master.key");
+    let source = || -> KeySource {
+        let provider = LocalFileKeyProvider::open(&key_file).expect("Failed to open key file");
+        KeySource::Provider(Arc::new(provider))
+    };
+
+    let vault = TemplateVault::open_with_key(&vault_dir, source())
+        .await
+        .expect("Failed to create vault");
+    assert_eq!(std::fs::read(&key_file).unwrap().len(), 32);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&key_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let id = vault
+        .store(Template::builder()
+            .data(vec![1, 1, 2])
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap())
+        .await
+        .unwrap();
+    drop(vault);
+
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(&vault_dir, source()))
+        .await
+        .expect("Failed to reopen vault");
+    assert_eq!(vault.get(id).await.unwrap().data, vec![1, 1, 2]);
+}
+
+#[tokio::test]
+async fn test_audit_log_detects_tampering() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![3; 32]);
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+
+    let alice = AuditContext::actor("alice");
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Face)
+        .quality_score(0.5)
+        .build()
+        .unwrap();
+    let id = vault.store_with_context(template, &alice).await.unwrap();
+    vault.get(id).await.unwrap();
+    vault.rotate_key_with_context(&alice).await.unwrap();
+    vault.delete_with_context(id, &alice).await.unwrap();
+
+    let entries = vault.export_audit(..).await.unwrap();
+    let operations: Vec<_> = entries.iter().map(|e| e.operation).collect();
+    assert_eq!(
+        operations,
+        vec![
+            AuditOperation::Store,
+            AuditOperation::Read,
+            AuditOperation::Rotate,
+            AuditOperation::Delete
+        ]
+    );
+    assert_eq!(entries[0].actor.as_deref(), Some("alice"));
+    assert_eq!(entries[1].actor, None);
+    assert_eq!(entries[3].template_id, Some(id));
+    assert_eq!(entries[1].prev_hash, entries[0].hash);
+    assert_eq!(vault.export_audit(1..3).await.unwrap().len(), 2);
+    vault.audit_log().verify_chain().unwrap();
+    drop(vault);
+
+    // Flip one byte of the second entry directly in sled
+    {
+        let db = ctx.open_db().await;
+        let audit = db.open_tree("audit").unwrap();
+        let key = 1u64.to_be_bytes();
+        let mut value = audit.get(key).unwrap().unwrap().to_vec();
+        let pos = value.len() / 2;
+        value[pos] ^= 0x01;
+        audit.insert(key, value).unwrap();
+        db.flush().unwrap();
+    }
+
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    assert!(matches!(
+        vault.audit_log().verify_chain(),
+        Err(StorageError::AuditChainBroken { seq: 1 })
+    ));
+}
+
+#[tokio::test]
+async fn test_audit_covers_reads_and_writes() {
+    use futures::TryStreamExt;
+
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let template = |embedding: &[f32]| {
+        Template::builder()
+            .data(embedding.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>())
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+    let bob = AuditContext::actor("bob");
+    let id = vault.store(template(&[1.0, 0.0])).await.unwrap();
+    vault.update_with_context(id, template(&[1.0, 0.5]), &bob).await.unwrap();
+
+    let mut since = vault.audit_log().export(..).unwrap().len() as u64;
+    let mut recorded = |vault: &TemplateVault| {
+        let entries = vault.audit_log().export(since..).unwrap();
+        since += entries.len() as u64;
+        entries.iter().map(|e| (e.operation, e.template_id)).collect::<Vec<_>>()
+    };
+    assert_eq!(recorded(&vault), vec![]);
+
+    vault.get_batch(&[id, uuid::Uuid::new_v4()]).await.unwrap();
+    assert_eq!(recorded(&vault), vec![(AuditOperation::Read, Some(id))]);
+    let _: Vec<_> = vault.iter().try_collect().await.unwrap();
+    assert_eq!(recorded(&vault), vec![(AuditOperation::Read, Some(id))]);
+    vault.find_by_type(TemplateType::Face).await.unwrap();
+    assert_eq!(recorded(&vault), vec![(AuditOperation::Find, Some(id))]);
+    vault.identify(&template(&[1.0, 0.4]), &CosineMatcher, 1).await.unwrap();
+    assert_eq!(recorded(&vault), vec![(AuditOperation::Identify, Some(id))]);
+
+    vault.add_alias(id, "hr", "E-1").await.unwrap();
+    vault.resolve_alias("hr", "E-1").await.unwrap();
+    vault.remove_alias("hr", "E-1").await.unwrap();
+    vault.set_expiry(id, None).await.unwrap();
+    assert_eq!(
+        recorded(&vault),
+        vec![
+            (AuditOperation::AddAlias, Some(id)),
+            (AuditOperation::Find, Some(id)),
+            (AuditOperation::RemoveAlias, Some(id)),
+            (AuditOperation::SetExpiry, Some(id)),
+        ]
+    );
+
+    let mut archive = Vec::new();
+    vault.export(&mut archive, "passphrase").await.unwrap();
+    vault
+        .import(archive.as_slice(), "passphrase", ImportOptions { overwrite: true, ..Default::default() })
+        .await
+        .unwrap();
+    vault.soft_delete(id).await.unwrap();
+    vault.restore(id).await.unwrap();
+    vault.delete(id).await.unwrap();
+    assert_eq!(
+        recorded(&vault),
+        vec![
+            (AuditOperation::Export, Some(id)),
+            (AuditOperation::Import, Some(id)),
+            (AuditOperation::SoftDelete, Some(id)),
+            (AuditOperation::Restore, Some(id)),
+            (AuditOperation::Delete, Some(id)),
+        ]
+    );
+
+    // Writes that fail leave no entry behind
+    assert!(vault.update(id, template(&[0.0, 1.0])).await.is_err());
+    assert!(vault.set_expiry(id, None).await.is_err());
+    assert!(!vault.remove_alias("hr", "E-1").await.unwrap());
+    assert_eq!(recorded(&vault), vec![]);
+
+    let entries = vault.export_audit(..).await.unwrap();
+    let update = entries.iter().find(|e| e.operation == AuditOperation::Update).unwrap();
+    assert_eq!(update.actor.as_deref(), Some("bob"));
+    vault.audit_log().verify_chain().unwrap();
+}
+
+#[tokio::test]
+async fn test_verify_all_flags_corrupted_entries() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![5; 32]);
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+
+    let mut ids = Vec::new();
+    for _ in 0..5 {
+        let template = Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap();
+        ids.push(vault.store(template).await.unwrap());
+    }
+    let report = vault.verify_all().await.unwrap();
+    assert_eq!(report.checked, 5);
+    assert!(report.is_clean());
+    drop(vault);
+
+    // Flip a ciphertext bit of one entry, truncate another, cut the
+    // ciphertext of a third short of its tag and name a missing key in the
+    // fourth
+    {
+        let db = ctx.open_db().await;
+        // The stored value ends with the authentication tag, then the
+        // length-prefixed 12-byte nonce, the key ID, the cipher and the
+        // length-prefixed 1-byte header
+        let mut stored = db.get(ids[0].as_bytes()).unwrap().unwrap().to_vec();
+        let tag_byte = stored.len() - 39;
+        stored[tag_byte] ^= 0x01;
+        db.insert(ids[0].as_bytes(), stored).unwrap();
+
+        let stored = db.get(ids[1].as_bytes()).unwrap().unwrap();
+        db.insert(ids[1].as_bytes(), &stored[..stored.len() / 2]).unwrap();
+
+        // The format byte and the ciphertext length come first
+        let stored = db.get(ids[2].as_bytes()).unwrap().unwrap();
+        let mut short = vec![stored[0]];
+        short.extend_from_slice(&4u64.to_le_bytes());
+        short.extend_from_slice(&stored[9..13]);
+        short.extend_from_slice(&stored[stored.len() - 38..]);
+        db.insert(ids[2].as_bytes(), short).unwrap();
+
+        let mut stored = db.get(ids[3].as_bytes()).unwrap().unwrap().to_vec();
+        let key_id = stored.len() - 17;
+        stored[key_id..key_id + 4].copy_from_slice(&99u32.to_le_bytes());
+        db.insert(ids[3].as_bytes(), stored).unwrap();
+        db.flush().unwrap();
+    }
+
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    assert!(matches!(
+        vault.get(ids[0]).await,
+        Err(StorageError::AuthenticationFailed { id }) if id == ids[0]
+    ));
+    assert!(matches!(vault.get(ids[1]).await, Err(StorageError::Corrupt(_))));
+    assert!(matches!(
+        vault.get(ids[2]).await,
+        Err(StorageError::MalformedCiphertext { id, .. }) if id == ids[2]
+    ));
+    assert!(matches!(
+        vault.get(ids[3]).await,
+        Err(StorageError::KeyUnavailable { id, key_id: 99 }) if id == ids[3]
+    ));
+    assert_eq!(vault.get(ids[4]).await.unwrap().id, Some(ids[4]));
+
+    let mut report = vault.verify_all().await.unwrap();
+    assert_eq!(report.checked, 5);
+    assert_eq!(report.auth_failures, vec![ids[0]]);
+    report.malformed.sort();
+    let mut malformed = vec![ids[1], ids[2]];
+    malformed.sort();
+    assert_eq!(report.malformed, malformed);
+    assert_eq!(report.key_unavailable, vec![ids[3]]);
+}
+
+#[tokio::test]
+async fn test_verify_all_covers_namespaces_history_and_tombstones() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![5; 32]);
+    let template = || {
+        Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Face)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault")
+        .with_history(2);
+    let namespaced = vault.namespace("tenant").await.unwrap().store(template()).await.unwrap();
+    let revised = vault.store(template()).await.unwrap();
+    vault.update(revised, template()).await.unwrap();
+    let hidden = vault.store(template()).await.unwrap();
+    vault.soft_delete(hidden).await.unwrap();
+    let report = vault.verify_all().await.unwrap();
+    assert_eq!(report.checked, 4);
+    assert!(report.is_clean());
+    drop(vault);
+
+    // Flip a tag byte of the namespaced template, the kept revision and
+    // the tombstone
+    {
+        let db = ctx.open_db().await;
+        let flip = |tree: &sled::Tree| {
+            let (key, value) = tree.iter().next().unwrap().unwrap();
+            let mut value = value.to_vec();
+            let tag_byte = value.len() - 39;
+            value[tag_byte] ^= 0x01;
+            tree.insert(key, value).unwrap();
+        };
+        flip(&db.open_tree("ns:tenant").unwrap());
+        flip(&db.open_tree("history").unwrap());
+        flip(&db.open_tree("tombstones").unwrap());
+        db.flush().unwrap();
+    }
+
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    let mut report = vault.verify_all().await.unwrap();
+    assert_eq!(report.checked, 4);
+    report.auth_failures.sort();
+    let mut corrupted = vec![namespaced, revised, hidden];
+    corrupted.sort();
+    assert_eq!(report.auth_failures, corrupted);
+    assert!(report.malformed.is_empty() && report.decryption_errors.is_empty());
+    assert_eq!(vault.get(revised).await.unwrap().id, Some(revised));
+}
+
+#[tokio::test]
+async fn test_swapped_values_fail_authentication() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![6; 32]);
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+    let template = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(0.5)
+            .build()
+            .unwrap()
+    };
+    let a = vault.store(template(vec![1; 8])).await.unwrap();
+    let b = vault.store(template(vec![2; 8])).await.unwrap();
+    drop(vault);
+
+    // Exchange the two stored values directly in sled
+    {
+        let db = ctx.open_db().await;
+        let value_a = db.get(a.as_bytes()).unwrap().unwrap();
+        let value_b = db.get(b.as_bytes()).unwrap().unwrap();
+        db.insert(a.as_bytes(), value_b).unwrap();
+        db.insert(b.as_bytes(), value_a).unwrap();
+        db.flush().unwrap();
+    }
+
+    // Each value is bound to the ID it was stored under
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    for id in [a, b] {
+        assert!(matches!(
+            vault.get(id).await,
+            Err(StorageError::AuthenticationFailed { id: failed }) if failed == id
+        ));
+    }
+    let mut report = vault.verify_all().await.unwrap();
+    report.auth_failures.sort();
+    let mut swapped = vec![a, b];
+    swapped.sort();
+    assert_eq!(report.auth_failures, swapped);
+}
+
+#[tokio::test]
+async fn test_config_tunes_sled() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![3; 32]);
+    let config = || {
+        VaultConfig::new()
+            .cache_capacity(1024 * 1024)
+            .flush_every_ms(None)
+            .mode(sled::Mode::LowSpace)
+            .key_source(key())
+    };
+
+    let vault = TemplateVault::with_config(ctx.temp_path(), config())
+        .await
+        .expect("Failed to create vault");
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Iris)
+        .quality_score(0.8)
+        .build()
+        .unwrap();
+    let id = vault.store(template.clone()).await.unwrap();
+    // Without background flushes the entry survives only an explicit flush
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    let vault = ctx
+        .reopen(|| TemplateVault::with_config(ctx.temp_path(), config()))
+        .await
+        .expect("Failed to reopen vault");
+    assert_eq!(vault.get(id).await.unwrap().data, template.data);
+}
+
+#[tokio::test]
+async fn test_config_max_template_size() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::with_config(ctx.temp_path(), VaultConfig::new().max_template_size(8))
+        .await
+        .expect("Failed to create vault");
+    let template = |len: usize| {
+        Template::builder()
+            .data(vec![0xAB; len])
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+
+    let id = vault.store(template(8)).await.expect("Template at the limit is accepted");
+    assert!(matches!(
+        vault.store(template(9)).await,
+        Err(StorageError::TemplateTooLarge { size: 9, max: 8 })
+    ));
+    assert!(matches!(
+        vault.update(id, template(64)).await,
+        Err(StorageError::TemplateTooLarge { size: 64, max: 8 })
+    ));
+    let namespace = vault.namespace("tenant").await.unwrap();
+    assert!(matches!(
+        namespace.store(template(9)).await,
+        Err(StorageError::TemplateTooLarge { .. })
+    ));
+
+    // Rejected writes leave nothing behind
+    assert_eq!(vault.count().await.unwrap(), 1);
+    assert_eq!(vault.get(id).await.unwrap().data.len(), 8);
+}
+
+#[tokio::test]
+async fn test_store_validates_templates() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    // Assembled directly, as the builder would reject invalid templates itself
+    let template = |data: Vec<u8>, quality_score: f32| Template {
+        id: None,
+        data,
+        metadata: TemplateMetadata {
+            version: "1.0".to_string(),
+            template_type: TemplateType::Face,
+            quality_score,
+            extra: serde_json::json!({}),
+            provenance: None,
+            schema_version: MetadataVersion::LATEST,
+            unknown: serde_json::Map::new(),
+        },
+    };
+
+    // Templates are limited to a few megabytes by default
+    let oversized = DEFAULT_MAX_TEMPLATE_SIZE + 1;
+    assert!(matches!(
+        vault.store(template(vec![0; oversized], 0.9)).await,
+        Err(StorageError::TemplateTooLarge { size, max: DEFAULT_MAX_TEMPLATE_SIZE }) if size == oversized
+    ));
+
+    assert!(matches!(
+        vault.store(template(Vec::new(), 0.9)).await,
+        Err(StorageError::InvalidTemplate(TemplateError::DataTooShort { len: 0, .. }))
+    ));
+    assert!(matches!(
+        vault.store(template(vec![1; 8], 1.5)).await,
+        Err(StorageError::InvalidTemplate(TemplateError::QualityOutOfRange(_)))
+    ));
+
+    let id = vault.store(template(vec![1; 8], 1.0)).await.unwrap();
+    assert!(matches!(
+        vault.update(id, template(vec![1; 8], -0.1)).await,
+        Err(StorageError::InvalidTemplate(_))
+    ));
+    let namespace = vault.namespace("tenant").await.unwrap();
+    assert!(matches!(
+        namespace.store(template(Vec::new(), 0.5)).await,
+        Err(StorageError::InvalidTemplate(_))
+    ));
+
+    // Nothing invalid was persisted
+    assert_eq!(vault.count().await.unwrap(), 1);
+    assert_eq!(vault.get(id).await.unwrap().metadata.quality_score, 1.0);
+}
+
+#[tokio::test]
+async fn test_store_applies_validation_policy() {
+    let ctx = TestContext::new();
+    let policy = ValidationPolicy::recommended()
+        .min_quality(0.5)
+        .with_rules(
+            TemplateType::Fingerprint,
+            ModalityRules {
+                require_known_format: true,
+                ..ModalityRules::default()
+            },
+        );
+    let vault = TemplateVault::with_config(ctx.temp_path(), VaultConfig::new().validation(policy))
+        .await
+        .expect("Failed to create vault");
+    let template = |data: Vec<u8>, template_type, quality_score| {
+        Template::builder()
+            .data(data)
+            .template_type(template_type)
+            .quality_score(quality_score)
+            .version("2.1")
+            .build()
+            .unwrap()
+    };
+
+    // Too short for a face, below the quality threshold, and not a known record
+    assert!(matches!(
+        vault.store(template(vec![1; 32], TemplateType::Face, 0.9)).await,
+        Err(StorageError::InvalidTemplate(TemplateError::DataTooShort { min: 64, .. }))
+    ));
+    assert!(matches!(
+        vault.store(template(vec![1; 64], TemplateType::Face, 0.4)).await,
+        Err(StorageError::InvalidTemplate(TemplateError::QualityBelowThreshold { .. }))
+    ));
+    assert!(matches!(
+        vault.store(template(vec![1; 64], TemplateType::Fingerprint, 0.9)).await,
+        Err(StorageError::InvalidTemplate(TemplateError::UnknownFormat(TemplateType::Fingerprint)))
+    ));
+
+    // A 2005 minutiae record, and a face template exactly at the limits
+    let mut record = b"FMR\0 20\0".to_vec();
+    record.extend_from_slice(&26u32.to_be_bytes());
+    record.resize(26, 0);
+    vault.store(template(record, TemplateType::Fingerprint, 0.9)).await.unwrap();
+    vault.store(template(vec![1; 64], TemplateType::Face, 0.5)).await.unwrap();
+    assert_eq!(vault.count().await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_identify_ranks_matching_template_first() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let template = |embedding: &[f32], template_type| {
+        Template::builder()
+            .data(embedding.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>())
+            .template_type(template_type)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+
+    // Pseudo-random 16-dimensional embeddings, components in -1.0..1.0
+    let mut state = 0x2545_f491u32;
+    let mut next = move || {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+    };
+    let embeddings: Vec<Vec<f32>> = (0..100).map(|_| (0..16).map(|_| next()).collect()).collect();
+    let mut ids = Vec::new();
+    for embedding in &embeddings {
+        ids.push(vault.store(template(embedding, TemplateType::Face)).await.unwrap());
+    }
+    // Same bytes as the target, but another modality
+    let target = 42;
+    let iris = vault.store(template(&embeddings[target], TemplateType::Iris)).await.unwrap();
+    // A different dimension cannot be compared and is skipped
+    vault.store(template(&[1.0; 8], TemplateType::Face)).await.unwrap();
+
+    // A noisy capture of the target
+    let probe: Vec<f32> = embeddings[target]
+        .iter()
+        .enumerate()
+        .map(|(d, x)| x + 0.02 * (d % 2) as f32)
+        .collect();
+    let probe = template(&probe, TemplateType::Face);
+    let matches = vault.identify(&probe, &CosineMatcher, 5).await.unwrap();
+    assert_eq!(matches.len(), 5);
+    assert_eq!(matches[0].0, ids[target]);
+    assert!(matches[0].1 .0 > 0.99);
+    assert!(matches.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    assert!(matches.iter().all(|(id, _)| *id != iris));
+
+    // Every comparable template is returned when fewer than asked for
+    let all = vault.identify(&probe, &CosineMatcher, 1000).await.unwrap();
+    assert_eq!(all.len(), embeddings.len());
+    assert_eq!(&all[..5], &matches[..]);
+    assert!(vault.identify(&probe, &CosineMatcher, 0).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_provenance_indexed_and_audited() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let template = |device_id: Option<&str>| {
+        let builder = Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(0.8);
+        match device_id {
+            Some(device_id) => builder.provenance(Provenance {
+                device_id: Some(device_id.to_string()),
+                sdk_name: Some("minex".to_string()),
+                operator_id: Some("op-7".to_string()),
+                ..Provenance::default()
+            }),
+            None => builder,
+        }
+        .build()
+        .unwrap()
+    };
+
+    let lobby = vault.store(template(Some("lobby-1"))).await.unwrap();
+    let gate = vault.store(template(Some("gate-2"))).await.unwrap();
+    let unknown = vault.store(template(None)).await.unwrap();
+    let stored = vault.get(lobby).await.unwrap();
+    assert_eq!(stored.metadata.provenance.as_ref().unwrap().sdk_name.as_deref(), Some("minex"));
+    assert_eq!(vault.find_by_device("lobby-1").await.unwrap(), vec![lobby]);
+    assert!(vault.find_by_device("nowhere").await.unwrap().is_empty());
+
+    // Updates move the template between devices
+    vault.update(gate, template(Some("lobby-1"))).await.unwrap();
+    let mut by_lobby = vault.find_by_device("lobby-1").await.unwrap();
+    by_lobby.sort();
+    let mut expected = vec![lobby, gate];
+    expected.sort();
+    assert_eq!(by_lobby, expected);
+    assert!(vault.find_by_device("gate-2").await.unwrap().is_empty());
+    vault.delete(lobby).await.unwrap();
+    assert_eq!(vault.find_by_device("lobby-1").await.unwrap(), vec![gate]);
+
+    // Writes record the provenance in the audit log, which still verifies
+    let entries = vault.export_audit(..).await.unwrap();
+    let written = |id| {
+        entries
+            .iter()
+            .find(|e| e.template_id == Some(id) && e.operation == AuditOperation::Store)
+            .unwrap()
+    };
+    let recorded = written(lobby).provenance.as_ref().unwrap();
+    assert_eq!(recorded.device_id.as_deref(), Some("lobby-1"));
+    assert_eq!(recorded.operator_id.as_deref(), Some("op-7"));
+    assert_eq!(written(unknown).provenance, None);
+    let update = entries.iter().find(|e| e.operation == AuditOperation::Update).unwrap();
+    assert_eq!(update.provenance.as_ref().unwrap().device_id.as_deref(), Some("lobby-1"));
+    assert!(entries
+        .iter()
+        .filter(|e| e.operation == AuditOperation::Read)
+        .all(|e| e.provenance.is_none()));
+    vault.audit_log().verify_chain().unwrap();
+}
+
+#[tokio::test]
+async fn test_fused_templates_stored_and_identified() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let template = |embedding: Vec<f32>, template_type| {
+        Template::builder()
+            .data(embedding.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>())
+            .template_type(template_type)
+            .quality_score(0.8)
+            .build()
+            .unwrap()
+    };
+    // Face and voice of each subject point in their own directions
+    let fused = |subject: usize, skew: f32| {
+        let axis = |offset: usize| (0..8).map(|d| if d == offset { 1.0 } else { skew }).collect();
+        FusedTemplate::new()
+            .with_member(template(axis(subject), TemplateType::Face), 0.6)
+            .unwrap()
+            .with_member(template(axis(7 - subject), TemplateType::Voice), 0.4)
+            .unwrap()
+    };
+    let mut ids = Vec::new();
+    for subject in 0..4 {
+        ids.push(vault.store(fused(subject, 0.0).to_template().unwrap()).await.unwrap());
+    }
+    vault
+        .store(template(vec![1.0; 8], TemplateType::Face))
+        .await
+        .unwrap();
+
+    let stored = vault.get(ids[2]).await.unwrap();
+    assert_eq!(stored.metadata.template_type, TemplateType::Multimodal);
+    let decoded = FusedTemplate::from_template(&stored).unwrap();
+    assert_eq!(decoded.id, Some(ids[2]));
+    assert_eq!(decoded.member(TemplateType::Voice).unwrap().weight, 0.4);
+    assert_eq!(vault.find_by_type(TemplateType::Multimodal).await.unwrap().len(), 4);
+
+    // Only fused records are searched, the closest first
+    let probe = fused(2, 0.1).to_template().unwrap();
+    let matches = vault.identify(&probe, &FusionMatcher::default(), 10).await.unwrap();
+    assert_eq!(matches.len(), 4);
+    assert_eq!(matches[0].0, ids[2]);
+    assert!(matches[0].1 .0 > 0.9);
+}
+
+#[tokio::test]
+async fn test_config_read_only() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![5; 32]);
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+    let template = Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Fingerprint)
+        .quality_score(0.7)
+        .build()
+        .unwrap();
+    let id = vault.store(template.clone()).await.unwrap();
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    let vault = ctx
+        .reopen(|| {
+            TemplateVault::with_config(
+                ctx.temp_path(),
+                VaultConfig::new().key_source(key()).read_only(true),
+            )
+        })
+        .await
+        .expect("Failed to reopen vault");
+
+    // Reads keep working
+    assert_eq!(vault.get(id).await.unwrap().data, template.data);
+    assert_eq!(vault.list_ids().await.unwrap(), vec![id]);
+    assert_eq!(vault.list_metadata(0, 10).await.unwrap().len(), 1);
+
+    // Every write is refused
+    assert!(matches!(vault.store(template.clone()).await, Err(StorageError::ReadOnly)));
+    assert!(matches!(vault.update(id, template).await, Err(StorageError::ReadOnly)));
+    assert!(matches!(vault.delete(id).await, Err(StorageError::ReadOnly)));
+    assert!(matches!(vault.soft_delete(id).await, Err(StorageError::ReadOnly)));
+    assert!(matches!(vault.rotate_key().await, Err(StorageError::ReadOnly)));
+    assert!(matches!(
+        vault.add_alias(id, "hr", "emp-1").await,
+        Err(StorageError::ReadOnly)
+    ));
+    assert!(vault.exists(id).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_metrics_track_operations() {
+    let ctx = TestContext::new();
+    let registry = prometheus::Registry::new();
+    let metrics = VaultMetrics::new(&registry).expect("Failed to register metrics");
+    let vault = TemplateVault::with_config(ctx.temp_path(), VaultConfig::new().metrics(metrics))
+        .await
+        .expect("Failed to create vault");
+
+    let mut ids = Vec::new();
+    for _ in 0..3 {
+        let template = Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap();
+        ids.push(vault.store(template).await.unwrap());
+    }
+    vault.get(ids[0]).await.unwrap();
+    vault.get(ids[1]).await.unwrap();
+    assert!(vault.delete(ids[2]).await.unwrap());
+    vault.rotate_key().await.unwrap();
+
+    // Failed operations are not counted
+    assert!(vault.get(ids[2]).await.is_err());
+
+    let mut exposition = Vec::new();
+    prometheus::Encoder::encode(&prometheus::TextEncoder::new(), &registry.gather(), &mut exposition)
+        .unwrap();
+    let exposition = String::from_utf8(exposition).unwrap();
+    for line in [
+        r#"vault_operations_total{operation="store"} 3"#,
+        r#"vault_operations_total{operation="get"} 2"#,
+        r#"vault_operations_total{operation="delete"} 1"#,
+        r#"vault_operations_total{operation="rotate"} 1"#,
+        r#"vault_operation_duration_seconds_count{operation="store"} 3"#,
+        "vault_template_size_bytes_count 3",
+        "vault_entries 2",
+    ] {
+        assert!(exposition.contains(line), "missing `{}` in:\n{}", line, exposition);
+    }
+}
+
+#[tokio::test]
+async fn test_delete_where_metadata_predicate() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let mut faces = Vec::new();
+    let mut fingerprints = Vec::new();
+    for i in 0..6 {
+        let template_type = if i % 2 == 0 {
+            TemplateType::Face
+        } else {
+            TemplateType::Fingerprint
+        };
+        let template = Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(template_type)
+            .quality_score(0.9)
+            .build()
+            .unwrap();
+        let id = vault.store(template).await.unwrap();
+        match template_type {
+            TemplateType::Face => faces.push(id),
+            _ => fingerprints.push(id),
+        }
+    }
+    vault.add_alias(fingerprints[0], "hr", "emp-1").await.unwrap();
+
+    let mut deleted = vault
+        .delete_where(|metadata| metadata.template_type == TemplateType::Fingerprint)
+        .await
+        .unwrap();
+    deleted.sort();
+    fingerprints.sort();
+    assert_eq!(deleted, fingerprints);
+
+    // Face templates remain, fingerprints are gone with their records
+    let mut remaining = vault.list_ids().await.unwrap();
+    remaining.sort();
+    faces.sort();
+    assert_eq!(remaining, faces);
+    assert!(vault.find_by_type(TemplateType::Fingerprint).await.unwrap().is_empty());
+    assert_eq!(vault.find_by_type(TemplateType::Face).await.unwrap().len(), 3);
+    assert_eq!(vault.resolve_alias("hr", "emp-1").await.unwrap(), None);
+
+    // Nothing matches a second time
+    assert!(vault
+        .delete_where(|metadata| metadata.template_type == TemplateType::Fingerprint)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_deduplicated_store() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![4; 32]);
+    let config = || VaultConfig::new().key_source(key()).deduplicate(true);
+    let vault = TemplateVault::with_config(ctx.temp_path(), config())
+        .await
+        .expect("Failed to create vault");
+    let template = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+
+    // A retried enrollment maps to the first entry
+    let first = vault.store_with_outcome(template(vec![1, 2, 3])).await.unwrap();
+    assert!(!first.deduplicated);
+    let retry = vault.store_with_outcome(template(vec![1, 2, 3])).await.unwrap();
+    assert_eq!(
+        retry,
+        StoreOutcome {
+            id: first.id,
+            deduplicated: true
+        }
+    );
+    assert_eq!(vault.store(template(vec![1, 2, 3])).await.unwrap(), first.id);
+    assert_eq!(vault.count().await.unwrap(), 1);
+
+    let other = vault.store_with_outcome(template(vec![4, 5, 6])).await.unwrap();
+    assert!(!other.deduplicated);
+    assert_ne!(other.id, first.id);
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    // The hashing key survives a restart
+    let vault = ctx
+        .reopen(|| TemplateVault::with_config(ctx.temp_path(), config()))
+        .await
+        .expect("Failed to reopen vault");
+    let retry = vault.store_with_outcome(template(vec![1, 2, 3])).await.unwrap();
+    assert_eq!(retry.id, first.id);
+    assert!(retry.deduplicated);
+
+    // Updated data is indexed under its new hash
+    vault.update(other.id, template(vec![7, 8, 9])).await.unwrap();
+    assert!(!vault.store_with_outcome(template(vec![4, 5, 6])).await.unwrap().deduplicated);
+    assert_eq!(vault.store(template(vec![7, 8, 9])).await.unwrap(), other.id);
+
+    // Deleting the entry removes its hash
+    assert!(vault.delete(first.id).await.unwrap());
+    let stored = vault.store_with_outcome(template(vec![1, 2, 3])).await.unwrap();
+    assert!(!stored.deduplicated);
+    assert_ne!(stored.id, first.id);
+    assert_eq!(vault.count().await.unwrap(), 3);
+
+    // The same bytes as another template type are not a duplicate
+    let mut voice = template(vec![1, 2, 3]);
+    voice.metadata.template_type = TemplateType::Voice;
+    let voice = vault.store_with_outcome(voice).await.unwrap();
+    assert!(!voice.deduplicated);
+    assert_ne!(voice.id, stored.id);
+
+    // Nor is a soft-deleted or expired template
+    vault.soft_delete(stored.id).await.unwrap();
+    let replacement = vault.store_with_outcome(template(vec![1, 2, 3])).await.unwrap();
+    assert!(!replacement.deduplicated);
+    vault.set_expiry(replacement.id, Some(Utc::now() - Duration::seconds(1))).await.unwrap();
+    let renewed = vault.store_with_outcome(template(vec![1, 2, 3])).await.unwrap();
+    assert!(!renewed.deduplicated);
+    assert_ne!(renewed.id, replacement.id);
+    assert_eq!(vault.store(template(vec![1, 2, 3])).await.unwrap(), renewed.id);
+
+    // Updating a template to the data of another leaves the index on the
+    // other one, so deleting the updated template does not drop it
+    let owner = vault.store(template(vec![10])).await.unwrap();
+    let copy = vault.store(template(vec![11])).await.unwrap();
+    vault.update(copy, template(vec![10])).await.unwrap();
+    let revision = vault.get(copy).await.unwrap().revision;
+    vault.compare_and_swap(copy, revision, template(vec![10])).await.unwrap();
+    assert_eq!(vault.store(template(vec![10])).await.unwrap(), owner);
+    assert!(vault.delete(copy).await.unwrap());
+    assert_eq!(vault.store(template(vec![10])).await.unwrap(), owner);
+
+    // Stores resolved to an existing template are audited under its ID
+    let stores = vault
+        .export_audit(..)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|entry| entry.operation == AuditOperation::Store && entry.template_id == Some(owner))
+        .count();
+    assert_eq!(stores, 3);
+}
+
+#[tokio::test]
+async fn test_find_by_hash() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![4; 32]);
+    let config = |lookup_key: &[u8]| VaultConfig::new().key_source(key()).lookup_key(lookup_key);
+    let vault = TemplateVault::with_config(ctx.temp_path(), config(b"lookup key"))
+        .await
+        .expect("Failed to create vault");
+    let template = |template_type, data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(template_type)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+    let face = template(TemplateType::Face, vec![1, 2, 3]);
+    let id = vault.store(face.clone()).await.unwrap();
+    let voice = vault.store(template(TemplateType::Voice, vec![1, 2, 3])).await.unwrap();
+
+    assert_eq!(vault.find_by_hash(&face.secure_hash(b"lookup key")).await.unwrap(), vec![id]);
+    // Another key or another type never matches
+    assert!(vault.find_by_hash(&face.secure_hash(b"wrong key")).await.unwrap().is_empty());
+    let voice_hash = template(TemplateType::Voice, vec![1, 2, 3]).secure_hash(b"lookup key");
+    assert_eq!(vault.find_by_hash(&voice_hash).await.unwrap(), vec![voice]);
+
+    // Updates move the entry, deletes remove it
+    vault.update(id, template(TemplateType::Face, vec![4, 5, 6])).await.unwrap();
+    assert!(vault.find_by_hash(&face.secure_hash(b"lookup key")).await.unwrap().is_empty());
+    let updated = template(TemplateType::Face, vec![4, 5, 6]);
+    assert_eq!(vault.find_by_hash(&updated.secure_hash(b"lookup key")).await.unwrap(), vec![id]);
+    assert!(vault.delete(id).await.unwrap());
+    assert!(vault.find_by_hash(&updated.secure_hash(b"lookup key")).await.unwrap().is_empty());
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    // A new key rebuilds the index on open
+    let vault = ctx
+        .reopen(|| TemplateVault::with_config(ctx.temp_path(), config(b"new key")))
+        .await
+        .expect("Failed to reopen vault");
+    assert!(vault.find_by_hash(&voice_hash).await.unwrap().is_empty());
+    let voice_hash = template(TemplateType::Voice, vec![1, 2, 3]).secure_hash(b"new key");
+    assert_eq!(vault.find_by_hash(&voice_hash).await.unwrap(), vec![voice]);
+    drop(vault);
+
+    let vault = ctx
+        .reopen(|| TemplateVault::with_config(ctx.temp_path(), VaultConfig::new().key_source(key())))
+        .await
+        .expect("Failed to reopen vault");
+    assert!(matches!(vault.find_by_hash(&voice_hash).await, Err(StorageError::NoLookupKey)));
+}
+
+#[tokio::test]
+async fn test_store_without_deduplication_keeps_duplicates() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let template = Template::builder()
+        .data(vec![1, 2, 3])
+        .template_type(TemplateType::Face)
+        .quality_score(0.9)
+        .build()
+        .unwrap();
+
+    let first = vault.store_with_outcome(template.clone()).await.unwrap();
+    let second = vault.store_with_outcome(template).await.unwrap();
+    assert!(!second.deduplicated);
+    assert_ne!(first.id, second.id);
+    assert_eq!(vault.count().await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_wipe_destroys_templates_and_keys() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![6; 32]);
+    let template = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+    let id = vault.store(template(vec![1; 8])).await.unwrap();
+    let tombstoned = vault.store(template(vec![2; 8])).await.unwrap();
+    vault.soft_delete(tombstoned).await.unwrap();
+    vault.add_alias(id, "hr", "emp-1").await.unwrap();
+    let namespaced = vault.namespace("acme").await.unwrap().store(template(vec![3; 8])).await.unwrap();
+    let subject = vault.store_for_subject("subject-1", template(vec![5; 8])).await.unwrap();
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    let subject_tag = |db: &sled::Db| {
+        let subjects = db.open_tree("subjects").unwrap();
+        let (key, _) = subjects.first().unwrap().unwrap();
+        key[..32].to_vec()
+    };
+    // Keep a copy of the ciphertext, as a stale sled page would
+    let (stale, stale_tag) = {
+        let db = ctx.open_db().await;
+        (db.get(id.as_bytes()).unwrap().unwrap(), subject_tag(&db))
+    };
+
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    let mut events = vault.subscribe();
+    vault.wipe().await.unwrap();
+
+    // Namespaced templates are reported deleted too
+    let mut deleted = Vec::new();
+    while let Ok(VaultEvent::Deleted(wiped)) = events.try_recv() {
+        deleted.push(wiped);
+    }
+    deleted.sort();
+    let mut live = vec![id, namespaced, subject];
+    live.sort();
+    assert_eq!(deleted, live);
+    assert_eq!(vault.count().await.unwrap(), 0);
+    assert!(matches!(vault.get(id).await, Err(StorageError::NotFound(_))));
+    assert!(matches!(vault.restore(tombstoned).await, Err(StorageError::NotFound(_))));
+    assert_eq!(vault.resolve_alias("hr", "emp-1").await.unwrap(), None);
+    assert!(vault.list_namespaces().await.unwrap().is_empty());
+    assert!(vault.find_by_type(TemplateType::Face).await.unwrap().is_empty());
+    let audit = vault.export_audit(..).await.unwrap();
+    assert_eq!(audit.last().unwrap().operation, AuditOperation::Wipe);
+
+    // The vault keeps working with its fresh key
+    let reused = vault.store(template(vec![4; 8])).await.unwrap();
+    assert_eq!(vault.get(reused).await.unwrap().data, vec![4; 8]);
+    let enrolled = vault.store_for_subject("subject-1", template(vec![5; 8])).await.unwrap();
+    vault.flush().await.unwrap();
+    drop(vault);
+
+    // Ciphertext from before the wipe no longer decrypts, and hashes from
+    // before it do not match those of the same subject
+    {
+        let db = ctx.open_db().await;
+        assert_ne!(subject_tag(&db), stale_tag);
+        db.insert(id.as_bytes(), stale).unwrap();
+        db.flush().unwrap();
+    }
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    assert_eq!(vault.get(reused).await.unwrap().data, vec![4; 8]);
+    // The new hashing key was persisted
+    assert_eq!(vault.find_by_subject("subject-1").await.unwrap(), vec![enrolled]);
+    // Its key was discarded by the wipe
+    assert!(matches!(vault.get(id).await, Err(StorageError::KeyUnavailable { .. })));
+}
+
+#[tokio::test]
+async fn test_iter_streams_all_templates() {
+    use futures::TryStreamExt;
+
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    for i in 0..300u16 {
+        let template = Template::builder()
+            .data(i.to_be_bytes().to_vec())
+            .template_type(TemplateType::ALL[i as usize % TemplateType::ALL.len()])
+            .quality_score(0.9)
+            .extra_field("n", i)
+            .build()
+            .unwrap();
+        vault.store(template).await.unwrap();
+    }
+    let expiring = Template::builder()
+        .data(vec![0xEE])
+        .template_type(TemplateType::Face)
+        .quality_score(0.9)
+        .build()
+        .unwrap();
+    let expired = vault
+        .store_with_expiry(expiring, Utc::now() - Duration::seconds(1))
+        .await
+        .unwrap();
+
+    let streamed: Vec<_> = vault.iter().try_collect().await.unwrap();
+    assert_eq!(streamed.len(), 300);
+    assert!(streamed.iter().all(|(id, _)| *id != expired));
+    let ids: Vec<_> = streamed.iter().map(|(id, _)| *id).collect();
+    let mut listed = vault.list_ids().await.unwrap();
+    listed.retain(|id| *id != expired);
+    assert_eq!(ids, listed);
+    for (id, template) in &streamed {
+        let stored = vault.get(*id).await.unwrap();
+        assert_eq!(template.data, stored.data);
+        assert_eq!(template.id, Some(*id));
+        assert_eq!(template.metadata.extra, stored.metadata.extra);
+    }
+
+    let metadata: Vec<_> = vault.iter_metadata().try_collect().await.unwrap();
+    assert_eq!(metadata.len(), 300);
+    for ((id, metadata), (streamed_id, template)) in metadata.iter().zip(&streamed) {
+        assert_eq!(id, streamed_id);
+        assert_eq!(metadata.template_type, template.metadata.template_type);
+        assert_eq!(metadata.extra, template.metadata.extra);
+    }
+}
+
+#[tokio::test]
+async fn test_subject_lookup_and_erasure() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let template = |template_type: TemplateType| {
+        Template::builder()
+            .data(ctx.create_test_template())
+            .template_type(template_type)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+
+    let alice_face = vault.store_for_subject("alice", template(TemplateType::Face)).await.unwrap();
+    let alice_iris = vault.store_for_subject("alice", template(TemplateType::Iris)).await.unwrap();
+    let bob_face = vault.store_for_subject("bob", template(TemplateType::Face)).await.unwrap();
+    let anonymous = vault.store(template(TemplateType::Face)).await.unwrap();
+
+    let mut alice = vault.find_by_subject("alice").await.unwrap();
+    alice.sort();
+    let mut expected = vec![alice_face, alice_iris];
+    expected.sort();
+    assert_eq!(alice, expected);
+    assert_eq!(vault.find_by_subject("bob").await.unwrap(), vec![bob_face]);
+    assert!(vault.find_by_subject("carol").await.unwrap().is_empty());
+
+    // Subject lookups survive key rotation
+    vault.rotate_key().await.unwrap();
+    assert_eq!(vault.find_by_subject("bob").await.unwrap(), vec![bob_face]);
+
+    // Erasure removes every template of the subject, soft-deleted ones too
+    vault.soft_delete(alice_iris).await.unwrap();
+    assert_eq!(vault.find_by_subject("alice").await.unwrap(), vec![alice_face]);
+    let mut erased = vault.delete_subject("alice").await.unwrap();
+    erased.sort();
+    assert_eq!(erased, expected);
+    assert!(vault.find_by_subject("alice").await.unwrap().is_empty());
+    assert!(matches!(vault.get(alice_face).await, Err(StorageError::NotFound(_))));
+    assert!(matches!(vault.restore(alice_iris).await, Err(StorageError::NotFound(_))));
+    assert!(vault.delete_subject("alice").await.unwrap().is_empty());
+
+    // Other subjects and unassigned templates are untouched
+    assert_eq!(vault.find_by_subject("bob").await.unwrap(), vec![bob_face]);
+    assert!(vault.exists(anonymous).await.unwrap());
+    assert_eq!(vault.count().await.unwrap(), 2);
+
+    // Deleting a template drops it from its subject
+    assert!(vault.delete(bob_face).await.unwrap());
+    assert!(vault.find_by_subject("bob").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_snapshot_and_restore() {
+    let ctx = TestContext::new();
+    let snapshot_dir = tempfile::TempDir::new().unwrap();
+    let snapshot_path = snapshot_dir.path().join("snapshot");
+    let key = || KeySource::Bytes(vec![8; 32]);
+    let template = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+    let kept = vault.store(template(vec![1; 8])).await.unwrap();
+    let updated = vault.store(template(vec![2; 8])).await.unwrap();
+    let deleted = vault.store(template(vec![3; 8])).await.unwrap();
+    vault.add_alias(kept, "hr", "emp-1").await.unwrap();
+
+    let info = vault.snapshot(&snapshot_path).await.unwrap();
+    assert_eq!(info.entries, 3);
+    assert_eq!(info.path, snapshot_path);
+
+    // Mutate the source and rotate its key past the snapshot's
+    vault.update(updated, template(vec![9; 8])).await.unwrap();
+    vault.delete(deleted).await.unwrap();
+    let added = vault.store(template(vec![4; 8])).await.unwrap();
+    vault.rotate_key().await.unwrap();
+    vault.rotate_key().await.unwrap();
+
+    let restored = ctx
+        .reopen(|| TemplateVault::restore_from(&snapshot_path, VaultConfig::new().key_source(key())))
+        .await
+        .expect("Failed to open snapshot");
+    assert_eq!(restored.count().await.unwrap(), 3);
+    assert_eq!(restored.get(kept).await.unwrap().data, vec![1; 8]);
+    assert_eq!(restored.get(updated).await.unwrap().data, vec![2; 8]);
+    assert_eq!(restored.get(deleted).await.unwrap().data, vec![3; 8]);
+    assert!(matches!(restored.get(added).await, Err(StorageError::NotFound(_))));
+    assert_eq!(restored.resolve_alias("hr", "emp-1").await.unwrap(), Some(kept));
+
+    // The source is unaffected by the restored copy
+    assert_eq!(vault.get(updated).await.unwrap().data, vec![9; 8]);
+
+    // A snapshot never overwrites an existing one
+    assert!(matches!(vault.snapshot(&snapshot_path).await, Err(StorageError::Io(_))));
+}
+
+#[tokio::test]
+async fn test_snapshot_requires_master_key() {
+    let ctx = TestContext::new();
+    let snapshot_dir = tempfile::TempDir::new().unwrap();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    let result = vault.snapshot(&snapshot_dir.path().join("snapshot")).await;
+    assert!(matches!(result, Err(StorageError::NoMasterKey)));
+    let result = TemplateVault::restore_from(snapshot_dir.path(), VaultConfig::new()).await;
+    assert!(matches!(result, Err(StorageError::NoMasterKey)));
+}
+
+#[tokio::test]
+async fn test_compressed_templates() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![4; 32]);
+    // Embeddings repeat a lot, so they compress well
+    let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 16) as u8).collect();
+    let template = || {
+        Template::builder()
+            .data(data.clone())
+            .template_type(TemplateType::Face)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+    let plain = vault.store(template()).await.unwrap();
+    drop(vault);
+
+    // Entries of every encoding read back from a mixed vault
+    let mut ids = vec![plain];
+    for algo in [CompressionAlgo::Zstd, CompressionAlgo::Lz4] {
+        let config = VaultConfig::new().key_source(key()).compression(algo);
+        let vault = ctx
+            .reopen(|| TemplateVault::with_config(ctx.temp_path(), config.clone()))
+            .await
+            .expect("Failed to reopen vault");
+        ids.push(vault.store(template()).await.unwrap());
+        for id in &ids {
+            assert_eq!(vault.get(*id).await.unwrap().data, data);
+        }
+    }
+
+    // Compressed entries take less space than the uncompressed one
+    let db = ctx.open_db().await;
+    let size = |id: &uuid::Uuid| db.get(id.as_bytes()).unwrap().unwrap().len();
+    assert!(size(&ids[1]) < size(&plain) / 4);
+    assert!(size(&ids[2]) < size(&plain) / 4);
+}
+
+#[tokio::test]
+async fn test_template_format_header_is_authenticated() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![0x42; 32]);
+    let config = VaultConfig::new().key_source(key()).compression(CompressionAlgo::Zstd);
+    let vault = TemplateVault::with_config(ctx.temp_path(), config)
+        .await
+        .expect("Failed to create vault");
+    let id = vault
+        .store(Template::builder()
+            .data(vec![9; 64])
+            .template_type(TemplateType::Iris)
+            .quality_score(0.9)
+            .build()
+            .unwrap())
+        .await
+        .unwrap();
+    drop(vault);
+
+    // The format byte, last in the stored value, claims no compression
+    {
+        let db = ctx.open_db().await;
+        let mut stored = db.get(id.as_bytes()).unwrap().unwrap().to_vec();
+        let format = stored.len() - 1;
+        assert_eq!(stored[format], 3, "expected the zstd format byte in the header");
+        stored[format] = 2;
+        db.insert(id.as_bytes(), stored).unwrap();
+        db.flush().unwrap();
+    }
+
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    match vault.get(id).await {
+        Err(StorageError::AuthenticationFailed { .. }) => {}
+        other => panic!("expected an authentication failure, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn test_xchacha20_vault_reads_older_entries() {
+    let ctx = TestContext::new();
+    let key = || KeySource::Bytes(vec![0x42; 32]);
+    let template = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+
+    let vault = TemplateVault::open_with_key(ctx.temp_path(), key())
+        .await
+        .expect("Failed to create vault");
+    let old = vault.store(template(vec![1, 2, 3])).await.unwrap();
+    drop(vault);
+
+    // Entries written with 12-byte nonces stay readable after switching
+    let config = VaultConfig::new().key_source(key()).cipher(Cipher::XChaCha20Poly1305);
+    let vault = ctx
+        .reopen(|| TemplateVault::with_config(ctx.temp_path(), config.clone()))
+        .await
+        .expect("Failed to reopen vault");
+    let new = vault.store(template(vec![4, 5, 6])).await.unwrap();
+    assert_eq!(vault.get(old).await.unwrap().data, vec![1, 2, 3]);
+    assert_eq!(vault.get(new).await.unwrap().data, vec![4, 5, 6]);
+
+    // Rotation re-encrypts every entry with the configured cipher
+    vault.rotate_key().await.unwrap();
+    assert_eq!(vault.get(old).await.unwrap().data, vec![1, 2, 3]);
+    drop(vault);
+
+    // Switching back still reads the XChaCha20-Poly1305 entries
+    let vault = ctx
+        .reopen(|| TemplateVault::open_with_key(ctx.temp_path(), key()))
+        .await
+        .expect("Failed to reopen vault");
+    assert_eq!(vault.get(old).await.unwrap().data, vec![1, 2, 3]);
+    assert_eq!(vault.get(new).await.unwrap().data, vec![4, 5, 6]);
+    assert!(vault.verify_all().await.unwrap().is_clean());
+}
+
+#[tokio::test]
+async fn test_subscribe_receives_changes_in_order() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let template = |data: Vec<u8>| {
+        Template::builder()
+            .data(data)
+            .template_type(TemplateType::Iris)
+            .quality_score(0.9)
+            .build()
+            .unwrap()
+    };
+
+    // Changes before subscribing are not delivered
+    let earlier = vault.store(template(vec![1])).await.unwrap();
+    let mut events = vault.subscribe();
+
+    let id = vault.store(template(vec![2])).await.unwrap();
+    vault.update(id, template(vec![3])).await.unwrap();
+    assert!(vault.delete(id).await.unwrap());
+    assert!(!vault.delete(id).await.unwrap());
+    vault.rotate_key().await.unwrap();
+    vault.delete(earlier).await.unwrap();
+
+    assert_eq!(events.recv().await.unwrap(), VaultEvent::Stored(id));
+    assert_eq!(events.recv().await.unwrap(), VaultEvent::Updated(id));
+    assert_eq!(events.recv().await.unwrap(), VaultEvent::Deleted(id));
+    assert_eq!(events.recv().await.unwrap(), VaultEvent::KeyRotated);
+    assert_eq!(events.recv().await.unwrap(), VaultEvent::Deleted(earlier));
+    assert!(events.try_recv().is_err());
+
+    // A subscriber that falls behind lags instead of blocking writes
+    let mut slow = vault.subscribe();
+    for i in 0..1100u32 {
+        vault.store(template(i.to_le_bytes().to_vec())).await.unwrap();
+    }
+    assert!(matches!(
+        slow.recv().await,
+        Err(tokio::sync::broadcast::error::RecvError::Lagged(_))
+    ));
+}
+
+/// Soft-delete `deleted` and expire `expired`, which finders must then skip
+async fn hide(vault: &TemplateVault, deleted: uuid::Uuid, expired: uuid::Uuid) {
+    vault.soft_delete(deleted).await.unwrap();
+    vault.set_expiry(expired, Some(Utc::now() - Duration::seconds(1))).await.unwrap();
+}
+
+fn face_template(ctx: &TestContext) -> Template {
+    Template::builder()
+        .data(ctx.create_test_template())
+        .template_type(TemplateType::Face)
+        .quality_score(0.8)
+        .build()
+        .unwrap()
+}
+
+fn sorted(mut ids: Vec<uuid::Uuid>) -> Vec<uuid::Uuid> {
+    ids.sort();
+    ids
+}
+
+#[tokio::test]
+async fn test_find_by_type_skips_hidden_templates() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let live = vault.store(face_template(&ctx)).await.unwrap();
+    let deleted = vault.store(face_template(&ctx)).await.unwrap();
+    let expired = vault.store(face_template(&ctx)).await.unwrap();
+    hide(&vault, deleted, expired).await;
+
+    assert_eq!(vault.find_by_type(TemplateType::Face).await.unwrap(), vec![live]);
+    vault.restore(deleted).await.unwrap();
+    assert_eq!(sorted(vault.find_by_type(TemplateType::Face).await.unwrap()), sorted(vec![live, deleted]));
+}
+
+#[tokio::test]
+async fn test_find_by_extra_skips_hidden_templates() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new_with_extra_index(ctx.temp_path(), &["site"])
+        .await
+        .expect("Failed to create vault");
+    let template = || {
+        let mut template = face_template(&ctx);
+        template.metadata.extra = serde_json::json!({ "site": "hq" });
+        template
+    };
+    let live = vault.store(template()).await.unwrap();
+    let deleted = vault.store(template()).await.unwrap();
+    let expired = vault.store(template()).await.unwrap();
+    hide(&vault, deleted, expired).await;
+
+    let site = serde_json::json!("hq");
+    assert_eq!(vault.find_by_extra("site", &site).await.unwrap(), vec![live]);
+    vault.restore(deleted).await.unwrap();
+    assert_eq!(sorted(vault.find_by_extra("site", &site).await.unwrap()), sorted(vec![live, deleted]));
+}
+
+#[tokio::test]
+async fn test_find_by_device_skips_hidden_templates() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let template = || {
+        let mut template = face_template(&ctx);
+        template.metadata.provenance = Some(Provenance {
+            device_id: Some("lobby-1".to_string()),
+            ..Provenance::default()
+        });
+        template
+    };
+    let live = vault.store(template()).await.unwrap();
+    let deleted = vault.store(template()).await.unwrap();
+    let expired = vault.store(template()).await.unwrap();
+    hide(&vault, deleted, expired).await;
+
+    assert_eq!(vault.find_by_device("lobby-1").await.unwrap(), vec![live]);
+    vault.restore(deleted).await.unwrap();
+    assert_eq!(sorted(vault.find_by_device("lobby-1").await.unwrap()), sorted(vec![live, deleted]));
+}
+
+#[tokio::test]
+async fn test_find_by_hash_skips_hidden_templates() {
+    let ctx = TestContext::new();
+    let config = VaultConfig::new().key_source(KeySource::Bytes(vec![4; 32])).lookup_key(b"lookup key");
+    let vault = TemplateVault::with_config(ctx.temp_path(), config)
+        .await
+        .expect("Failed to create vault");
+    let template = face_template(&ctx);
+    let hash = template.secure_hash(b"lookup key");
+    let live = vault.store(template.clone()).await.unwrap();
+    let deleted = vault.store(template.clone()).await.unwrap();
+    let expired = vault.store(template).await.unwrap();
+    hide(&vault, deleted, expired).await;
+
+    assert_eq!(vault.find_by_hash(&hash).await.unwrap(), vec![live]);
+    vault.restore(deleted).await.unwrap();
+    assert_eq!(vault.find_by_hash(&hash).await.unwrap(), sorted(vec![live, deleted]));
+}
+
+#[tokio::test]
+async fn test_find_by_subject_skips_hidden_templates() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let live = vault.store_for_subject("subject-1", face_template(&ctx)).await.unwrap();
+    let deleted = vault.store_for_subject("subject-1", face_template(&ctx)).await.unwrap();
+    let expired = vault.store_for_subject("subject-1", face_template(&ctx)).await.unwrap();
+    hide(&vault, deleted, expired).await;
+
+    assert_eq!(vault.find_by_subject("subject-1").await.unwrap(), vec![live]);
+    vault.restore(deleted).await.unwrap();
+    assert_eq!(sorted(vault.find_by_subject("subject-1").await.unwrap()), sorted(vec![live, deleted]));
+}
+
+#[tokio::test]
+async fn test_resolve_alias_skips_hidden_templates() {
+    let ctx = TestContext::new();
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+    let live = vault.store(face_template(&ctx)).await.unwrap();
+    let deleted = vault.store(face_template(&ctx)).await.unwrap();
+    let expired = vault.store(face_template(&ctx)).await.unwrap();
+    for (id, external_id) in [(live, "emp-1"), (deleted, "emp-2"), (expired, "emp-3")] {
+        vault.add_alias(id, "hr", external_id).await.unwrap();
+    }
+    hide(&vault, deleted, expired).await;
+
+    assert_eq!(vault.resolve_alias("hr", "emp-1").await.unwrap(), Some(live));
+    assert_eq!(vault.resolve_alias("hr", "emp-2").await.unwrap(), None);
+    assert_eq!(vault.resolve_alias("hr", "emp-3").await.unwrap(), None);
+    vault.restore(deleted).await.unwrap();
+    assert_eq!(vault.resolve_alias("hr", "emp-2").await.unwrap(), Some(deleted));
+}
@@ -1,6 +1,9 @@
 use crate::common::TestContext;
+use actix_web::http::{header, StatusCode};
 use actix_web::{test, web, App};
-use secure_biometric::storage::TemplateVault;
+use secure_biometric::api::TemplateBody;
+use secure_biometric::storage::{TemplateVault, VaultConfig};
+use secure_biometric::templates::{Template, TemplateType, CBOR_CONTENT_TYPE};
 
 #[actix_web::test]
 async fn test_template_upload() {
@@ -11,7 +14,7 @@ async fn test_template_upload() {
     let vault = web::Data::new(vault);
 
     // TODO: Implement API endpoint tests
-    let app = test::init_service(
+    let _app = test::init_service(
         App::new()
             .app_data(vault.clone())
             // Add API routes here
@@ -35,3 +38,129 @@ async fn test_template_matching() {
 async fn test_error_handling() {
     // TODO: Implement API error handling test
 }
+
+#[actix_web::test]
+async fn test_template_content_negotiation() {
+    let app = test::init_service(
+        App::new().route("/echo", web::post().to(|body: TemplateBody| async move { body })),
+    )
+    .await;
+    let template = Template::builder()
+        .data(vec![7; 64])
+        .template_type(TemplateType::Voice)
+        .quality_score(0.8)
+        .build()
+        .unwrap();
+    let cbor = template.to_cbor().unwrap();
+
+    // CBOR in and out
+    let request = test::TestRequest::post()
+        .uri("/echo")
+        .insert_header((header::CONTENT_TYPE, CBOR_CONTENT_TYPE))
+        .insert_header((header::ACCEPT, "application/json;q=0.5, application/cbor"))
+        .set_payload(cbor.clone())
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), CBOR_CONTENT_TYPE);
+    assert_eq!(test::read_body(response).await, cbor);
+
+    // JSON stays the default for both directions
+    let request = test::TestRequest::post()
+        .uri("/echo")
+        .set_json(&template)
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+    let echoed: Template = test::read_body_json(response).await;
+    assert_eq!(echoed.data, template.data);
+
+    let request = test::TestRequest::post()
+        .uri("/echo")
+        .insert_header((header::CONTENT_TYPE, CBOR_CONTENT_TYPE))
+        .set_payload(cbor[..10].to_vec())
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let request = test::TestRequest::post()
+        .uri("/echo")
+        .insert_header((header::CONTENT_TYPE, "text/plain"))
+        .set_payload("template")
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[actix_web::test]
+async fn test_template_accept_quality() {
+    let app = test::init_service(
+        App::new().route("/echo", web::post().to(|body: TemplateBody| async move { body })),
+    )
+    .await;
+    let template = Template::builder()
+        .data(vec![7; 64])
+        .template_type(TemplateType::Voice)
+        .quality_score(0.8)
+        .build()
+        .unwrap();
+
+    for (accept, expected) in [
+        ("application/cbor;q=0", "application/json"),
+        ("application/cbor; q=0.0, application/json;q=0.1", "application/json"),
+        ("application/cbor;q=0.4, application/json;q=0.9", "application/json"),
+        ("application/json;q=0.4, application/cbor;q=0.9", CBOR_CONTENT_TYPE),
+        ("application/cbor;charset=binary", CBOR_CONTENT_TYPE),
+    ] {
+        let request = test::TestRequest::post()
+            .uri("/echo")
+            .insert_header((header::ACCEPT, accept))
+            .set_json(&template)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), expected, "{}", accept);
+    }
+}
+
+#[actix_web::test]
+async fn test_template_body_limit() {
+    let ctx = TestContext::new();
+    let max = 512 * 1024;
+    let vault = TemplateVault::with_config(ctx.temp_path(), VaultConfig::new().max_template_size(max))
+        .await
+        .expect("Failed to create vault");
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(vault))
+            .route("/echo", web::post().to(|body: TemplateBody| async move { body })),
+    )
+    .await;
+    let template = |size| {
+        Template::builder()
+            .data(vec![7; size])
+            .template_type(TemplateType::Voice)
+            .quality_score(0.8)
+            .build()
+            .unwrap()
+    };
+    let cbor = |size| {
+        test::TestRequest::post()
+            .uri("/echo")
+            .insert_header((header::CONTENT_TYPE, CBOR_CONTENT_TYPE))
+            .set_payload(template(size).to_cbor().unwrap())
+            .to_request()
+    };
+
+    // Above the 256 KiB default payload limit of actix-web
+    let response = test::call_service(&app, cbor(max)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let request = test::TestRequest::post()
+        .uri("/echo")
+        .set_json(template(max))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = test::call_service(&app, cbor(2 * max)).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
@@ -1 +1,2 @@
 mod api_tests;
+mod store_tests;
@@ -0,0 +1,382 @@
+//! `StoreVault` against every `TemplateStore` implementation
+//!
+//! The Postgres variants are built with `--features postgres` and ignored
+//! by default; run them with `--ignored` and a database in
+//! `TEST_DATABASE_URL`.
+
+use crate::common::TestContext;
+use futures::future::join_all;
+use futures::TryStreamExt;
+use secure_biometric::security::{KeySource, SecurityError};
+use secure_biometric::storage::{SledTemplateStore, StorageError, StoreOp, StoreVault, TemplateStore};
+use secure_biometric::templates::{Template, TemplateType};
+
+fn template(data: Vec<u8>) -> Template {
+    Template::builder()
+        .data(data)
+        .template_type(TemplateType::Face)
+        .quality_score(0.9)
+        .build()
+        .unwrap()
+}
+
+fn sled_vault(ctx: &TestContext) -> StoreVault<SledTemplateStore> {
+    let db = sled::open(ctx.temp_path()).expect("Failed to open database");
+    let store = SledTemplateStore::open(&db, "templates").expect("Failed to open store");
+    StoreVault::new(store).expect("Failed to create vault")
+}
+
+async fn check_round_trip<S: TemplateStore>(vault: StoreVault<S>) {
+    let id = vault.store(template(vec![1, 2, 3])).await.unwrap();
+    assert_eq!(vault.get(id).await.unwrap().data, vec![1, 2, 3]);
+
+    // The store only holds ciphertext
+    let stored = vault.store_ref().get(id).await.unwrap().unwrap();
+    assert!(!stored.encrypted.ciphertext.windows(3).any(|w| w == [1, 2, 3]));
+    assert_eq!(stored.metadata["template_type"], "face");
+
+    vault.update(id, template(vec![4, 5])).await.unwrap();
+    assert_eq!(vault.get(id).await.unwrap().data, vec![4, 5]);
+
+    let missing = uuid::Uuid::new_v4();
+    assert!(matches!(vault.get(missing).await, Err(StorageError::NotFound(_))));
+    assert!(matches!(
+        vault.update(missing, template(vec![6])).await,
+        Err(StorageError::NotFound(_))
+    ));
+
+    assert!(vault.delete(id).await.unwrap());
+    assert!(!vault.delete(id).await.unwrap());
+    assert!(matches!(vault.get(id).await, Err(StorageError::NotFound(_))));
+}
+
+async fn check_not_found<S: TemplateStore>(vault: StoreVault<S>) {
+    let id = vault.store(template(vec![1])).await.unwrap();
+    let missing = uuid::Uuid::new_v4();
+
+    match vault.get(missing).await {
+        Err(StorageError::NotFound(not_found)) => assert_eq!(not_found, missing),
+        other => panic!("Expected NotFound, got {:?}", other.map(|_| ())),
+    }
+    // Updating a missing template does not create it
+    match vault.update(missing, template(vec![2])).await {
+        Err(StorageError::NotFound(not_found)) => assert_eq!(not_found, missing),
+        other => panic!("Expected NotFound, got {:?}", other),
+    }
+    assert!(vault.store_ref().get(missing).await.unwrap().is_none());
+    assert!(!vault.delete(missing).await.unwrap());
+    assert_eq!(vault.count().await.unwrap(), 1);
+    assert_eq!(vault.get(id).await.unwrap().data, vec![1]);
+}
+
+async fn check_update_and_delete<S: TemplateStore>(vault: StoreVault<S>) {
+    let id = vault.store(template(vec![1, 2, 3])).await.unwrap();
+    let other = vault.store(template(vec![4])).await.unwrap();
+
+    // Re-enroll under the same ID
+    let mut reenrolled = template(vec![9, 8, 7]);
+    reenrolled.metadata.quality_score = 0.97;
+    reenrolled.metadata.template_type = TemplateType::Iris;
+    vault.update(id, reenrolled).await.unwrap();
+    let retrieved = vault.get(id).await.unwrap();
+    assert_eq!(retrieved.id, Some(id));
+    assert_eq!(retrieved.data, vec![9, 8, 7]);
+    assert_eq!(retrieved.metadata.quality_score, 0.97);
+    assert_eq!(vault.store_ref().get(id).await.unwrap().unwrap().metadata["template_type"], "iris");
+    assert_eq!(vault.count().await.unwrap(), 2);
+
+    // Updating a deleted template is rejected and does not resurrect it
+    assert!(vault.delete(id).await.unwrap());
+    assert!(matches!(vault.update(id, template(vec![5])).await, Err(StorageError::NotFound(_))));
+    assert!(matches!(vault.get(id).await, Err(StorageError::NotFound(_))));
+    let entries: Vec<_> = vault.iter().try_collect().await.unwrap();
+    assert_eq!(entries.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![other]);
+    assert_eq!(vault.count().await.unwrap(), 1);
+}
+
+async fn check_concurrent_writes<S: TemplateStore>(vault: StoreVault<S>) {
+    let writers = (0..4u8).map(|writer| {
+        let vault = &vault;
+        async move {
+            let mut ids = Vec::new();
+            for i in 0..10u8 {
+                ids.push(vault.store(template(vec![writer, i])).await.unwrap());
+            }
+            ids
+        }
+    });
+    let ids: Vec<_> = join_all(writers).await.into_iter().flatten().collect();
+    assert_eq!(vault.count().await.unwrap(), 40);
+
+    // Updates and deletes of distinct templates interleave
+    let (updated, deleted) = ids.split_at(20);
+    let (updates, deletes) = futures::join!(
+        join_all(updated.iter().map(|id| vault.update(*id, template(vec![0xff])))),
+        join_all(deleted.iter().map(|id| vault.delete(*id)))
+    );
+    assert!(updates.into_iter().all(|result| result.is_ok()));
+    assert!(deletes.into_iter().all(|result| result.unwrap()));
+    for id in updated {
+        assert_eq!(vault.get(*id).await.unwrap().data, vec![0xff]);
+    }
+    assert_eq!(vault.count().await.unwrap(), 20);
+}
+
+async fn check_swapped_rows_fail_authentication<S: TemplateStore>(vault: StoreVault<S>) {
+    let a = vault.store(template(vec![1; 8])).await.unwrap();
+    let b = vault.store(template(vec![2; 8])).await.unwrap();
+
+    // Exchange the two stored rows directly in the store
+    let row_a = vault.store_ref().get(a).await.unwrap().unwrap();
+    let row_b = vault.store_ref().get(b).await.unwrap().unwrap();
+    vault.store_ref().put(a, row_b).await.unwrap();
+    vault.store_ref().put(b, row_a).await.unwrap();
+
+    // Each row is bound to the ID it was stored under
+    for id in [a, b] {
+        assert!(matches!(
+            vault.get(id).await,
+            Err(StorageError::AuthenticationFailed { id: failed }) if failed == id
+        ));
+    }
+}
+
+async fn check_batch_and_iter<S: TemplateStore>(vault: StoreVault<S>) {
+    let templates = (0..10u8).map(|i| template(vec![i; 4])).collect();
+    let ids = vault.store_batch(templates).await.unwrap();
+    assert_eq!(vault.count().await.unwrap(), 10);
+
+    // Entries stream back ordered by ID
+    let entries: Vec<_> = vault.iter().try_collect().await.unwrap();
+    let mut sorted = ids.clone();
+    sorted.sort();
+    assert_eq!(entries.iter().map(|(id, _)| *id).collect::<Vec<_>>(), sorted);
+    for (i, id) in ids.iter().enumerate() {
+        let (_, template) = entries.iter().find(|(entry, _)| entry == id).unwrap();
+        assert_eq!(template.data, vec![i as u8; 4]);
+    }
+}
+
+async fn check_rotation<S: TemplateStore>(vault: StoreVault<S>) {
+    let mut stored = Vec::new();
+    for i in 0..300u16 {
+        let data = i.to_le_bytes().to_vec();
+        stored.push((vault.store(template(data.clone())).await.unwrap(), data));
+    }
+    let before = vault.store_ref().get(stored[0].0).await.unwrap().unwrap();
+
+    vault.rotate_key().await.unwrap();
+    vault.rotate_key().await.unwrap();
+
+    // Only the current key is left, so every entry was re-encrypted
+    let after = vault.store_ref().get(stored[0].0).await.unwrap().unwrap();
+    assert_ne!(before.encrypted.ciphertext, after.encrypted.ciphertext);
+    for (id, data) in stored {
+        assert_eq!(vault.get(id).await.unwrap().data, data);
+    }
+}
+
+async fn check_delete_during_rotation<S: TemplateStore>(vault: StoreVault<S>) {
+    let mut ids = Vec::new();
+    for i in 0..300u16 {
+        ids.push(vault.store(template(i.to_le_bytes().to_vec())).await.unwrap());
+    }
+
+    // Rotation writes back only rows that still exist
+    let stored = vault.store_ref().get(ids[0]).await.unwrap().unwrap();
+    assert!(vault.store_ref().delete(ids[0]).await.unwrap());
+    assert!(!vault.store_ref().replace(ids[0], stored.clone()).await.unwrap());
+    vault
+        .store_ref()
+        .apply_batch(vec![StoreOp::Replace(ids[0], stored)])
+        .await
+        .unwrap();
+    assert!(vault.store_ref().get(ids[0]).await.unwrap().is_none());
+
+    // Deletes racing a rotation stay deleted
+    let (rotated, ()) = tokio::join!(vault.rotate_key(), async {
+        for id in &ids[1..] {
+            tokio::task::yield_now().await;
+            assert!(vault.delete(*id).await.unwrap());
+        }
+    });
+    rotated.unwrap();
+    assert_eq!(vault.count().await.unwrap(), 0);
+    for id in &ids {
+        assert!(matches!(vault.update(*id, template(vec![1])).await, Err(StorageError::NotFound(_))));
+    }
+    assert_eq!(vault.count().await.unwrap(), 0);
+}
+
+/// Reopen the store returned by `open` with a master key, as after a restart
+async fn check_master_key<S: TemplateStore>(open: impl Fn() -> S) {
+    let passphrase = || KeySource::Passphrase("correct horse battery staple".into());
+
+    // Templates written with an in-memory key cannot be read any more
+    let vault = StoreVault::new(open()).unwrap();
+    let id = vault.store(template(vec![9])).await.unwrap();
+    assert!(matches!(
+        StoreVault::open_with_key(open(), passphrase()).await,
+        Err(StorageError::Encryption(SecurityError::InvalidKey(_)))
+    ));
+    assert!(vault.delete(id).await.unwrap());
+
+    let vault = StoreVault::open_with_key(open(), passphrase()).await.unwrap();
+    let first = vault.store(template(vec![1, 2, 3])).await.unwrap();
+    vault.rotate_key().await.unwrap();
+    let second = vault.store(template(vec![4, 5])).await.unwrap();
+    drop(vault);
+
+    let vault = StoreVault::open_with_key(open(), passphrase()).await.unwrap();
+    assert_eq!(vault.get(first).await.unwrap().data, vec![1, 2, 3]);
+    assert_eq!(vault.get(second).await.unwrap().data, vec![4, 5]);
+    drop(vault);
+
+    // A different master key is refused before any template is touched
+    assert!(matches!(
+        StoreVault::open_with_key(open(), KeySource::Passphrase("wrong".into())).await,
+        Err(StorageError::Encryption(SecurityError::InvalidKey(_)))
+    ));
+}
+
+#[tokio::test]
+async fn test_sled_store_round_trip() {
+    let ctx = TestContext::new();
+    check_round_trip(sled_vault(&ctx)).await;
+}
+
+#[tokio::test]
+async fn test_sled_store_not_found() {
+    let ctx = TestContext::new();
+    check_not_found(sled_vault(&ctx)).await;
+}
+
+#[tokio::test]
+async fn test_sled_store_update_and_delete() {
+    let ctx = TestContext::new();
+    check_update_and_delete(sled_vault(&ctx)).await;
+}
+
+#[tokio::test]
+async fn test_sled_store_concurrent_writes() {
+    let ctx = TestContext::new();
+    check_concurrent_writes(sled_vault(&ctx)).await;
+}
+
+#[tokio::test]
+async fn test_sled_store_swapped_rows_fail_authentication() {
+    let ctx = TestContext::new();
+    check_swapped_rows_fail_authentication(sled_vault(&ctx)).await;
+}
+
+#[tokio::test]
+async fn test_sled_store_batch_and_iter() {
+    let ctx = TestContext::new();
+    check_batch_and_iter(sled_vault(&ctx)).await;
+}
+
+#[tokio::test]
+async fn test_sled_store_rotation() {
+    let ctx = TestContext::new();
+    check_rotation(sled_vault(&ctx)).await;
+}
+
+#[tokio::test]
+async fn test_sled_store_delete_during_rotation() {
+    let ctx = TestContext::new();
+    check_delete_during_rotation(sled_vault(&ctx)).await;
+}
+
+#[tokio::test]
+async fn test_sled_store_master_key() {
+    let ctx = TestContext::new();
+    let db = sled::open(ctx.temp_path()).expect("Failed to open database");
+    check_master_key(|| SledTemplateStore::open(&db, "templates").expect("Failed to open store")).await;
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use super::*;
+    use secure_biometric::storage::PostgresTemplateStore;
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+    use std::str::FromStr;
+
+    /// Vault on a fresh schema named `schema` of the test database
+    async fn pg_vault(schema: &str) -> StoreVault<PostgresTemplateStore> {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let admin = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&url)
+            .await
+            .expect("Failed to connect to test database");
+        for statement in [
+            format!("DROP SCHEMA IF EXISTS {} CASCADE", schema),
+            format!("CREATE SCHEMA {}", schema),
+        ] {
+            sqlx::query(&statement).execute(&admin).await.unwrap();
+        }
+
+        let options = PgConnectOptions::from_str(&url)
+            .unwrap()
+            .options([("search_path", schema)]);
+        let pool = PgPoolOptions::new().connect_with(options).await.unwrap();
+        let store = PostgresTemplateStore::new(pool);
+        store.migrate().await.expect("Failed to create table");
+        StoreVault::new(store).expect("Failed to create vault")
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn test_postgres_store_round_trip() {
+        check_round_trip(pg_vault("store_round_trip").await).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn test_postgres_store_not_found() {
+        check_not_found(pg_vault("store_not_found").await).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn test_postgres_store_update_and_delete() {
+        check_update_and_delete(pg_vault("store_update_and_delete").await).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn test_postgres_store_concurrent_writes() {
+        check_concurrent_writes(pg_vault("store_concurrent_writes").await).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn test_postgres_store_swapped_rows_fail_authentication() {
+        check_swapped_rows_fail_authentication(pg_vault("store_swapped_rows").await).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn test_postgres_store_batch_and_iter() {
+        check_batch_and_iter(pg_vault("store_batch_and_iter").await).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn test_postgres_store_rotation() {
+        check_rotation(pg_vault("store_rotation").await).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn test_postgres_store_delete_during_rotation() {
+        check_delete_during_rotation(pg_vault("store_delete_during_rotation").await).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn test_postgres_store_master_key() {
+        let store = pg_vault("store_master_key").await.store_ref().clone();
+        check_master_key(|| store.clone()).await;
+    }
+}
@@ -1,6 +1,6 @@
 use crate::common::TestContext;
 use secure_biometric::storage::TemplateVault;
-use secure_biometric::templates::{Template, TemplateMetadata, TemplateType};
+use secure_biometric::templates::{Template, TemplateType};
 use std::time::Instant;
 use tokio::task;
 use std::sync::Arc;
@@ -28,15 +28,12 @@ async fn test_concurrent_storage() {
         handles.push(task::spawn(async move {
             let mut batch_ids = Vec::new();
             for i in batch_start..batch_end {
-                let template = Template::new(
-                    vec![i as u8],
-                    TemplateMetadata {
-                        version: "1.0".to_string(),
-                        template_type: TemplateType::Face,
-                        quality_score: 0.95,
-                        extra: serde_json::json!({}),
-                    },
-                );
+                let template = Template::builder()
+                    .data(vec![i as u8])
+                    .template_type(TemplateType::Face)
+                    .quality_score(0.95)
+                    .build()
+                    .unwrap();
                 let id = vault.store(template).await.expect("Failed to store template");
                 batch_ids.push(id);
             }
@@ -82,15 +79,12 @@ async fn test_bulk_retrieval() {
     let mut ids = Vec::new();
 
     for i in 0..NUM_TEMPLATES {
-        let template = Template::new(
-            vec![i as u8],
-            TemplateMetadata {
-                version: "1.0".to_string(),
-                template_type: TemplateType::Face,
-                quality_score: 0.95,
-                extra: serde_json::json!({}),
-            },
-        );
+        let template = Template::builder()
+            .data(vec![i as u8])
+            .template_type(TemplateType::Face)
+            .quality_score(0.95)
+            .build()
+            .unwrap();
         let id = vault.store(template).await.expect("Failed to store template");
         ids.push(id);
     }
@@ -140,15 +134,12 @@ async fn test_memory_usage() {
 
     // Store templates
     for i in 0..NUM_TEMPLATES {
-        let template = Template::new(
-            vec![i as u8; 1024], // 1KB of data per template
-            TemplateMetadata {
-                version: "1.0".to_string(),
-                template_type: TemplateType::Face,
-                quality_score: 0.95,
-                extra: serde_json::json!({}),
-            },
-        );
+        let template = Template::builder()
+            .data(vec![i as u8; 1024]) // 1KB of data per template
+            .template_type(TemplateType::Face)
+            .quality_score(0.95)
+            .build()
+            .unwrap();
         let id = vault.store(template).await.expect("Failed to store template");
         ids.push(id);
 
@@ -176,15 +167,12 @@ async fn test_database_performance() {
     let start = Instant::now();
 
     for i in 0..NUM_WRITES {
-        let template = Template::new(
-            vec![i as u8],
-            TemplateMetadata {
-                version: "1.0".to_string(),
-                template_type: TemplateType::Face,
-                quality_score: 0.95,
-                extra: serde_json::json!({}),
-            },
-        );
+        let template = Template::builder()
+            .data(vec![i as u8])
+            .template_type(TemplateType::Face)
+            .quality_score(0.95)
+            .build()
+            .unwrap();
         vault.store(template).await.expect("Failed to store template");
 
         // Periodically flush
@@ -1,11 +1,15 @@
 use crate::common::TestContext;
 use log::{debug, info};
-use secure_biometric::security::{EncryptionEngine, KeyManager};
-use secure_biometric::storage::TemplateVault;
-use secure_biometric::templates::{Template, TemplateMetadata, TemplateType};
+use secure_biometric::security::{
+    generate_salt, Argon2Params, BatchItem, Cipher, EncryptedData, EncryptionEngine, KeyBudget, KeyManager,
+    KeyShare, SecretBytes, SecurityError, WrappedKeySet, STREAM_FRAME_LEN,
+};
+use secure_biometric::storage::{TemplateVault, VaultConfig};
+use secure_biometric::templates::{Template, TemplateType};
 use std::sync::Arc;
 use tokio::time::timeout;
 use std::time::Duration;
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 const TEST_TIMEOUT: Duration = Duration::from_secs(10);
 
@@ -80,15 +84,12 @@ async fn test_template_vault_encryption() {
         .expect("Failed to create vault");
 
     // Create and store template
-    let template = Template::new(
-        vec![0xDE, 0xAD, 0xBE, 0xEF],
-        TemplateMetadata {
-            version: "1.0".to_string(),
-            template_type: TemplateType::Face,
-            quality_score: 0.95,
-            extra: serde_json::json!({}),
-        },
-    );
+    let template = Template::builder()
+        .data(vec![0xDE, 0xAD, 0xBE, 0xEF])
+        .template_type(TemplateType::Face)
+        .quality_score(0.95)
+        .build()
+        .unwrap();
 
     debug!("Storing template in vault");
     let id = timeout(TEST_TIMEOUT, vault.store(template.clone()))
@@ -124,24 +125,18 @@ async fn test_vault_key_rotation() {
 
     // Store multiple templates
     let templates = vec![
-        Template::new(
-            vec![1, 2, 3],
-            TemplateMetadata {
-                version: "1.0".to_string(),
-                template_type: TemplateType::Face,
-                quality_score: 0.95,
-                extra: serde_json::json!({}),
-            },
-        ),
-        Template::new(
-            vec![4, 5, 6],
-            TemplateMetadata {
-                version: "1.0".to_string(),
-                template_type: TemplateType::Fingerprint,
-                quality_score: 0.98,
-                extra: serde_json::json!({}),
-            },
-        ),
+        Template::builder()
+            .data(vec![1, 2, 3])
+            .template_type(TemplateType::Face)
+            .quality_score(0.95)
+            .build()
+            .unwrap(),
+        Template::builder()
+            .data(vec![4, 5, 6])
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(0.98)
+            .build()
+            .unwrap(),
     ];
 
     debug!("Storing multiple templates in vault");
@@ -269,6 +264,271 @@ async fn test_concurrent_encryption() {
     timer.stop(true).await;
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_store_concurrent_with_rotation() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("store_concurrent_with_rotation");
+
+    info!("Starting concurrent store and rotation test");
+    let vault = TemplateVault::new(ctx.temp_path())
+        .await
+        .expect("Failed to create vault");
+
+    // Writers store and update templates while keys rotate underneath them
+    let mut writers = Vec::new();
+    for writer in 0..4u8 {
+        let vault = vault.clone();
+        writers.push(tokio::spawn(async move {
+            let mut stored = Vec::new();
+            for i in 0..50u8 {
+                let template = Template::builder()
+                    .data(vec![writer, i, 0xAA, 0x55])
+                    .template_type(TemplateType::Face)
+                    .quality_score(0.9)
+                    .extra_field("writer", writer)
+                    .build()
+                    .unwrap();
+                let id = vault.store(template.clone()).await.expect("Failed to store");
+                if i % 5 == 0 {
+                    let mut updated = template;
+                    updated.data.push(0xFF);
+                    vault.update(id, updated.clone()).await.expect("Failed to update");
+                    stored.push((id, updated.data));
+                } else {
+                    stored.push((id, template.data));
+                }
+            }
+            stored
+        }));
+    }
+
+    let rotator = {
+        let vault = vault.clone();
+        tokio::spawn(async move {
+            for _ in 0..10 {
+                vault.rotate_key().await.expect("Failed to rotate key");
+                tokio::task::yield_now().await;
+            }
+        })
+    };
+
+    let mut stored = Vec::new();
+    for writer in writers {
+        stored.extend(
+            timeout(Duration::from_secs(60), writer)
+                .await
+                .expect("Test timed out")
+                .expect("Writer failed"),
+        );
+    }
+    timeout(Duration::from_secs(60), rotator)
+        .await
+        .expect("Test timed out")
+        .expect("Rotator failed");
+
+    // Every entry, whenever it was written, decrypts with the final key
+    debug!("Verifying {} templates after rotation", stored.len());
+    assert_eq!(vault.count().await.unwrap(), stored.len());
+    for (id, data) in stored {
+        let retrieved = vault.get(id).await.expect("Failed to retrieve");
+        assert_eq!(retrieved.data, data);
+    }
+    assert!(vault.verify_all().await.unwrap().is_clean());
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_rotation_in_bounded_chunks() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("rotation_in_bounded_chunks");
+
+    info!("Starting chunked rotation test");
+    let config = VaultConfig::new().rotation_chunk_size(100);
+    let vault = TemplateVault::with_config(ctx.temp_path(), config)
+        .await
+        .expect("Failed to create vault");
+    assert!(vault.rotation_progress().is_none());
+
+    let mut stored = Vec::new();
+    for i in 0..2000u32 {
+        let template = Template::builder()
+            .data(i.to_le_bytes().to_vec())
+            .template_type(TemplateType::Fingerprint)
+            .quality_score(0.9)
+            .build()
+            .unwrap();
+        let id = vault.store(template.clone()).await.expect("Failed to store");
+        stored.push((id, template.data));
+    }
+
+    debug!("Rotating key over {} templates", stored.len());
+    vault.rotate_key().await.expect("Failed to rotate key");
+
+    // Templates and their metadata were re-encrypted, never more than one
+    // chunk of them in memory at once
+    let progress = vault.rotation_progress().expect("No rotation progress");
+    assert_eq!(progress.entries_done, progress.total);
+    assert!(progress.total >= 2 * stored.len() as u64);
+    assert!(progress.largest_chunk > 0 && progress.largest_chunk <= 100);
+
+    debug!("Verifying all templates decrypt after rotation");
+    for (id, data) in stored {
+        let retrieved = vault.get(id).await.expect("Failed to retrieve");
+        assert_eq!(retrieved.data, data);
+    }
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_aad_must_match() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("aad_must_match");
+
+    info!("Starting AAD binding test");
+    let key_manager = Arc::new(KeyManager::new().expect("Failed to create key manager"));
+    let engine = EncryptionEngine::new(key_manager);
+
+    let data = b"bound biometric data";
+    let encrypted = engine
+        .encrypt_with_aad(data, b"template-a")
+        .await
+        .expect("Failed to encrypt");
+    let decrypted = engine
+        .decrypt_with_aad(&encrypted, b"template-a")
+        .await
+        .expect("Failed to decrypt");
+    assert_eq!(&decrypted[..], data);
+
+    debug!("Verifying mismatched AAD is rejected");
+    assert!(engine.decrypt_with_aad(&encrypted, b"template-b").await.is_err());
+    assert!(engine.decrypt(&encrypted).await.is_err());
+
+    // The AAD is still checked with the old key during a rotation
+    engine.rotate_key().await.expect("Failed to rotate key");
+    assert!(engine.decrypt_with_aad(&encrypted, b"template-b").await.is_err());
+    assert_eq!(
+        engine.decrypt_with_aad(&encrypted, b"template-a").await.unwrap(),
+        data
+    );
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_key_ids_select_decryption_key() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("key_ids_select_decryption_key");
+
+    info!("Starting key ID test");
+    let key_manager = Arc::new(KeyManager::new().expect("Failed to create key manager"));
+    let engine = EncryptionEngine::new(key_manager.clone());
+
+    let first = engine.encrypt(b"first generation").await.expect("Failed to encrypt");
+    assert_eq!(first.key_id, Some(key_manager.current_key_id()));
+
+    engine.rotate_key().await.expect("Failed to rotate key");
+    let second = engine.encrypt(b"second generation").await.expect("Failed to encrypt");
+    assert_ne!(second.key_id, first.key_id);
+
+    // Both generations decrypt while the rotation is unfinished
+    assert_eq!(engine.decrypt(&first).await.unwrap(), b"first generation");
+    assert_eq!(engine.decrypt(&second).await.unwrap(), b"second generation");
+
+    debug!("Rotating again before anything was re-encrypted");
+    engine.rotate_key().await.expect("Failed to rotate key");
+    let third = engine.encrypt(b"third generation").await.expect("Failed to encrypt");
+
+    // All three generations still decrypt
+    assert_eq!(engine.decrypt(&first).await.unwrap(), b"first generation");
+    assert_eq!(engine.decrypt(&second).await.unwrap(), b"second generation");
+    assert_eq!(engine.decrypt(&third).await.unwrap(), b"third generation");
+    let old_ids: Vec<_> = [first.key_id, second.key_id].into_iter().flatten().collect();
+    assert_eq!(key_manager.old_key_ids(), old_ids);
+
+    // Data without a key ID falls back to trying the current and old keys
+    for (encrypted, data) in [(&first, "first"), (&second, "second")] {
+        let unkeyed = EncryptedData { key_id: None, ..encrypted.clone() };
+        let expected = format!("{} generation", data);
+        assert_eq!(engine.decrypt(&unkeyed).await.unwrap(), expected.as_bytes());
+    }
+
+    debug!("Retiring the first key");
+    key_manager.retire_key(old_ids[0]).await.expect("Failed to retire key");
+    assert!(matches!(
+        engine.decrypt(&first).await,
+        Err(SecurityError::KeyUnavailable { key_id: id }) if id == old_ids[0]
+    ));
+    assert_eq!(engine.decrypt(&second).await.unwrap(), b"second generation");
+    assert!(matches!(
+        key_manager.retire_key(old_ids[0]).await,
+        Err(SecurityError::KeyUnavailable { .. })
+    ));
+    assert!(matches!(
+        key_manager.retire_key(key_manager.current_key_id()).await,
+        Err(SecurityError::InvalidKey(_))
+    ));
+
+    engine.finish_rotation().await.expect("Failed to finish rotation");
+    assert!(matches!(
+        engine.decrypt(&second).await,
+        Err(SecurityError::KeyUnavailable { .. })
+    ));
+    assert_eq!(engine.decrypt(&third).await.unwrap(), b"third generation");
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_passphrase_derived_keys() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("passphrase_derived_keys");
+
+    // Cheap parameters keep the test fast
+    let params = Argon2Params {
+        memory_kib: 1024,
+        iterations: 1,
+        parallelism: 1,
+    };
+    let salt = generate_salt().expect("Failed to generate salt");
+    let engine = |passphrase: &str| {
+        let key_manager = KeyManager::from_passphrase(passphrase, &salt, params)
+            .expect("Failed to derive key");
+        EncryptionEngine::new(Arc::new(key_manager))
+    };
+
+    info!("Starting passphrase derivation test");
+    let encrypted = engine("open sesame")
+        .encrypt(b"passphrase protected")
+        .await
+        .expect("Failed to encrypt");
+
+    // The same passphrase and salt derive the same key
+    assert_eq!(
+        engine("open sesame").decrypt(&encrypted).await.unwrap(),
+        b"passphrase protected"
+    );
+
+    debug!("Verifying a wrong passphrase or salt is rejected");
+    assert!(matches!(
+        engine("open sesame!").decrypt(&encrypted).await,
+        Err(SecurityError::AuthenticationFailed)
+    ));
+    let other_salt = generate_salt().expect("Failed to generate salt");
+    let other = KeyManager::from_passphrase("open sesame", &other_salt, params).unwrap();
+    assert!(EncryptionEngine::new(Arc::new(other)).decrypt(&encrypted).await.is_err());
+
+    // Parameters Argon2 cannot use are refused
+    let invalid = Argon2Params { parallelism: 0, ..params };
+    assert!(matches!(
+        KeyManager::from_passphrase("open sesame", &salt, invalid),
+        Err(SecurityError::KeyGeneration(_))
+    ));
+
+    timer.stop(true).await;
+}
+
 #[tokio::test]
 async fn test_large_data_encryption() {
     let ctx = TestContext::new();
@@ -296,3 +556,431 @@ async fn test_large_data_encryption() {
     
     timer.stop(true).await;
 }
+
+#[tokio::test]
+async fn test_key_usage_tracking() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("key_usage_tracking");
+
+    info!("Starting key usage tracking test");
+    let key_manager = Arc::new(
+        KeyManager::new()
+            .expect("Failed to create key manager")
+            .with_budget(KeyBudget {
+                max_operations: Some(3),
+                ..Default::default()
+            }),
+    );
+    let engine = EncryptionEngine::new(key_manager.clone());
+
+    let data = b"sensitive data";
+    let encrypted = engine.encrypt(data).await.expect("Failed to encrypt");
+    engine.decrypt(&encrypted).await.expect("Failed to decrypt");
+
+    let status = key_manager.key_status();
+    assert_eq!(status.encryptions, 1);
+    assert_eq!(status.decryptions, 1);
+    assert_eq!(status.bytes_encrypted, data.len() as u64);
+    assert_eq!(status.bytes_decrypted, data.len() as u64);
+    assert!(!status.budget_exceeded);
+
+    // Push the key past its operation budget
+    debug!("Exceeding key operation budget");
+    engine.encrypt(data).await.expect("Failed to encrypt");
+    assert!(key_manager.key_status().budget_exceeded);
+
+    // Rotation starts a fresh key with fresh counters
+    debug!("Verifying counters reset after rotation");
+    engine.rotate_key().await.expect("Failed to rotate key");
+    let status = key_manager.key_status();
+    assert_eq!(status.encryptions, 0);
+    assert_eq!(status.decryptions, 0);
+    assert!(!status.budget_exceeded);
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_stream_encryption() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("stream_encryption");
+
+    let key_manager = Arc::new(KeyManager::new().expect("Failed to create key manager"));
+    let engine = EncryptionEngine::new(key_manager);
+
+    // Two and a half frames, plus the empty and frame-aligned edge cases
+    for len in [0, STREAM_FRAME_LEN, STREAM_FRAME_LEN * 5 / 2] {
+        debug!("Round-tripping a {} byte stream", len);
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let mut encrypted = Vec::new();
+        let written = engine
+            .encrypt_stream(&data[..], &mut encrypted)
+            .await
+            .expect("Failed to encrypt stream");
+        assert_eq!(written, len as u64);
+
+        let mut decrypted = Vec::new();
+        let read = engine
+            .decrypt_stream(&encrypted[..], &mut decrypted)
+            .await
+            .expect("Failed to decrypt stream");
+        assert_eq!(read, len as u64);
+        assert_eq!(decrypted, data);
+    }
+
+    let data = vec![0x5A; STREAM_FRAME_LEN * 5 / 2];
+    let mut encrypted = Vec::new();
+    engine.encrypt_stream(&data[..], &mut encrypted).await.expect("Failed to encrypt stream");
+    let header_len = encrypted.len() - data.len() - 3 * 16;
+    let frame_len = STREAM_FRAME_LEN + 16;
+    let frame = |i: usize| header_len + i * frame_len..header_len + (i + 1) * frame_len;
+
+    // A flipped bit in the middle frame
+    debug!("Verifying a tampered frame fails");
+    let mut tampered = encrypted.clone();
+    tampered[frame(1).start + 100] ^= 0x01;
+    let result = engine.decrypt_stream(&tampered[..], Vec::new()).await;
+    assert!(matches!(result, Err(SecurityError::AuthenticationFailed)));
+
+    // The first two frames swapped
+    debug!("Verifying reordered frames fail");
+    let mut reordered = encrypted.clone();
+    reordered[frame(0)].copy_from_slice(&encrypted[frame(1)]);
+    reordered[frame(1)].copy_from_slice(&encrypted[frame(0)]);
+    let result = engine.decrypt_stream(&reordered[..], Vec::new()).await;
+    assert!(matches!(result, Err(SecurityError::AuthenticationFailed)));
+
+    // Cut at a frame boundary, or too short to hold a tag
+    debug!("Verifying truncated streams fail");
+    for end in [frame(1).end, frame(1).start + 10] {
+        let result = engine.decrypt_stream(&encrypted[..end], Vec::new()).await;
+        assert!(
+            matches!(result, Err(SecurityError::MalformedCiphertext(_))),
+            "truncated at {}",
+            end
+        );
+    }
+    // A shortened last frame no longer authenticates
+    let result = engine.decrypt_stream(&encrypted[..encrypted.len() - 1], Vec::new()).await;
+    assert!(matches!(result, Err(SecurityError::AuthenticationFailed)));
+
+    // Trailing garbage is read as part of the last frame
+    let mut extended = encrypted.clone();
+    extended.push(0);
+    let result = engine.decrypt_stream(&extended[..], Vec::new()).await;
+    assert!(matches!(result, Err(SecurityError::AuthenticationFailed)));
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_xchacha20_encryption() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("xchacha20_encryption");
+
+    let key_manager = Arc::new(KeyManager::new().expect("Failed to create key manager"));
+    let engine = EncryptionEngine::new(key_manager.clone());
+    let extended = EncryptionEngine::new(key_manager.clone()).with_cipher(Cipher::XChaCha20Poly1305);
+    let data = b"face template";
+
+    debug!("Round-tripping with extended nonces");
+    let encrypted = extended.encrypt_with_aad(data, b"id").await.expect("Failed to encrypt");
+    assert_eq!(encrypted.cipher, Cipher::XChaCha20Poly1305);
+    assert_eq!(encrypted.nonce.len(), 24);
+    assert_ne!(encrypted.ciphertext[..data.len()], data[..]);
+    assert_eq!(extended.decrypt_with_aad(&encrypted, b"id").await.unwrap(), data);
+    assert!(extended.decrypt_with_aad(&encrypted, b"other id").await.is_err());
+
+    // Each blob names its cipher, so either engine decrypts either kind
+    debug!("Verifying interop with 12-byte nonces");
+    let classic = engine.encrypt(data).await.expect("Failed to encrypt");
+    assert_eq!(classic.cipher, Cipher::ChaCha20Poly1305);
+    assert_eq!(classic.nonce.len(), 12);
+    assert_eq!(extended.decrypt(&classic).await.unwrap(), data);
+    assert_eq!(engine.decrypt_with_aad(&encrypted, b"id").await.unwrap(), data);
+
+    // Old keys decrypt extended-nonce data until retired
+    debug!("Verifying rotation");
+    extended.rotate_key().await.expect("Failed to rotate key");
+    assert_eq!(extended.decrypt_with_aad(&encrypted, b"id").await.unwrap(), data);
+    extended.finish_rotation().await.expect("Failed to finish rotation");
+    assert!(matches!(
+        extended.decrypt_with_aad(&encrypted, b"id").await,
+        Err(SecurityError::KeyUnavailable { .. })
+    ));
+
+    // A nonce of the wrong length is rejected rather than misused
+    let mut truncated = extended.encrypt(data).await.expect("Failed to encrypt");
+    truncated.nonce.truncate(12);
+    assert!(matches!(
+        extended.decrypt(&truncated).await,
+        Err(SecurityError::MalformedCiphertext(_))
+    ));
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_wrapped_key_export() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("wrapped_key_export");
+
+    let key_manager = Arc::new(KeyManager::new().expect("Failed to create key manager"));
+    let engine = EncryptionEngine::new(key_manager.clone());
+    let before = engine.encrypt(b"before rotation").await.expect("Failed to encrypt");
+    engine.rotate_key().await.expect("Failed to rotate key");
+    let after = engine.encrypt(b"after rotation").await.expect("Failed to encrypt");
+
+    // The set survives serialization, as when written next to the vault
+    debug!("Exporting keys");
+    let kek = [0x17; 32];
+    let set = key_manager.export_wrapped(&kek).expect("Failed to export keys");
+    assert_eq!(set.keys.len(), 2);
+    let json = serde_json::to_vec(&set).unwrap();
+    let set: WrappedKeySet = serde_json::from_slice(&json).unwrap();
+
+    debug!("Importing keys");
+    let imported = KeyManager::import_wrapped(&kek, set.clone()).expect("Failed to import keys");
+    let imported = Arc::new(imported);
+    assert_eq!(imported.current_key_id(), key_manager.current_key_id());
+    assert_eq!(imported.old_key_ids(), key_manager.old_key_ids());
+    let imported_engine = EncryptionEngine::new(imported);
+    assert_eq!(imported_engine.decrypt(&before).await.unwrap(), b"before rotation");
+    assert_eq!(imported_engine.decrypt(&after).await.unwrap(), b"after rotation");
+    let new = imported_engine.encrypt(b"imported").await.unwrap();
+    assert_eq!(engine.decrypt(&new).await.unwrap(), b"imported");
+
+    debug!("Verifying a wrong key-encryption key fails");
+    let result = KeyManager::import_wrapped(&[0x18; 32], set.clone());
+    assert!(matches!(result, Err(SecurityError::InvalidKey(_))));
+
+    // Relabelling the old key as the current one is detected
+    debug!("Verifying tampered sets fail");
+    let mut tampered = set.clone();
+    tampered.current_id = tampered.keys[1].id;
+    let result = KeyManager::import_wrapped(&kek, tampered);
+    assert!(matches!(result, Err(SecurityError::InvalidKey(_))));
+
+    let mut tampered = set;
+    tampered.keys.remove(0);
+    let result = KeyManager::import_wrapped(&kek, tampered);
+    assert!(matches!(result, Err(SecurityError::InvalidKey(_))));
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_key_shares() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("key_shares");
+
+    let key_manager = Arc::new(KeyManager::new().expect("Failed to create key manager"));
+    let engine = EncryptionEngine::new(key_manager.clone());
+    let before = engine.encrypt(b"before rotation").await.expect("Failed to encrypt");
+    engine.rotate_key().await.expect("Failed to rotate key");
+    let after = engine.encrypt(b"after rotation").await.expect("Failed to encrypt");
+
+    debug!("Splitting keys into 3-of-5 shares");
+    let shares = key_manager.split_key(5, 3).expect("Failed to split keys");
+    assert_eq!(shares.len(), 5);
+    assert!(!format!("{:?}", shares[0]).contains(&format!("{:?}", shares[0].data)));
+    // Shares survive serialization, as when handed to their holders
+    let json = serde_json::to_vec(&shares).unwrap();
+    let shares: Vec<KeyShare> = serde_json::from_slice(&json).unwrap();
+
+    debug!("Reconstructing from any 3 shares");
+    for picked in [[0, 1, 2], [0, 2, 4], [4, 3, 1]] {
+        let subset: Vec<KeyShare> = picked.iter().map(|&i| shares[i].clone()).collect();
+        let rebuilt = Arc::new(KeyManager::from_shares(&subset).expect("Failed to rebuild keys"));
+        assert_eq!(rebuilt.current_key_id(), key_manager.current_key_id());
+        assert_eq!(rebuilt.old_key_ids(), key_manager.old_key_ids());
+        let rebuilt_engine = EncryptionEngine::new(rebuilt);
+        assert_eq!(rebuilt_engine.decrypt(&before).await.unwrap(), b"before rotation");
+        assert_eq!(rebuilt_engine.decrypt(&after).await.unwrap(), b"after rotation");
+    }
+    let rebuilt = KeyManager::from_shares(&shares).expect("Failed to rebuild from all shares");
+    assert_eq!(rebuilt.current_key_id(), key_manager.current_key_id());
+
+    debug!("Verifying a corrupted share is detected");
+    let mut corrupted = shares[..3].to_vec();
+    corrupted[1].data[0] ^= 0x01;
+    let result = KeyManager::from_shares(&corrupted);
+    assert!(matches!(result, Err(SecurityError::InvalidShares(_))));
+
+    debug!("Verifying 2 shares reveal nothing usable");
+    for _ in 0..3 {
+        let result = KeyManager::from_shares(&shares[..2]);
+        assert!(matches!(result, Err(SecurityError::InvalidShares(_))));
+    }
+    // Claiming a lower threshold only interpolates the wrong keys
+    let mut forged = shares[..2].to_vec();
+    for share in &mut forged {
+        share.threshold = 2;
+    }
+    let result = KeyManager::from_shares(&forged);
+    assert!(matches!(result, Err(SecurityError::InvalidShares(_))));
+
+    debug!("Verifying invalid splits and mixed shares fail");
+    assert!(matches!(key_manager.split_key(5, 1), Err(SecurityError::InvalidShares(_))));
+    assert!(matches!(key_manager.split_key(2, 3), Err(SecurityError::InvalidShares(_))));
+    let other = key_manager.split_key(5, 2).expect("Failed to split keys");
+    let mixed = vec![shares[0].clone(), shares[1].clone(), other[2].clone()];
+    let result = KeyManager::from_shares(&mixed);
+    assert!(matches!(result, Err(SecurityError::InvalidShares(_))));
+    let duplicated = vec![shares[0].clone(), shares[0].clone(), shares[1].clone()];
+    let result = KeyManager::from_shares(&duplicated);
+    assert!(matches!(result, Err(SecurityError::InvalidShares(_))));
+
+    timer.stop(true).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_batch_encryption() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("batch_encryption");
+
+    let key_manager = Arc::new(KeyManager::new().expect("Failed to create key manager"));
+    let engine = EncryptionEngine::new(key_manager.clone());
+    assert!(engine.encrypt_batch(Vec::new()).await.unwrap().is_empty());
+
+    debug!("Encrypting a batch");
+    let items: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_be_bytes().repeat(i as usize + 1)).collect();
+    let encrypted = engine.encrypt_batch(items.clone()).await.expect("Failed to encrypt batch");
+    assert_eq!(encrypted.len(), items.len());
+    assert_eq!(key_manager.key_status().encryptions, items.len() as u64);
+
+    // Results come back in input order, each under its own nonce
+    for (item, encrypted) in items.iter().zip(&encrypted) {
+        assert_eq!(&engine.decrypt(encrypted).await.unwrap(), item);
+    }
+    let mut nonces: Vec<_> = encrypted.iter().map(|e| e.nonce.clone()).collect();
+    nonces.sort();
+    nonces.dedup();
+    assert_eq!(nonces.len(), items.len());
+
+    debug!("Encrypting a batch with headers and associated data");
+    let engine = engine.with_cipher(Cipher::XChaCha20Poly1305);
+    let items = (0..10u8).map(|i| BatchItem {
+        data: vec![i; 32],
+        header: vec![i],
+        aad: vec![i, i],
+    });
+    let encrypted = engine
+        .encrypt_batch_with_header_and_aad(items.collect())
+        .await
+        .expect("Failed to encrypt batch");
+    for (i, encrypted) in (0..10u8).zip(&encrypted) {
+        assert_eq!(encrypted.cipher, Cipher::XChaCha20Poly1305);
+        assert_eq!(encrypted.header, vec![i]);
+        assert_eq!(engine.decrypt_with_aad(encrypted, &[i, i]).await.unwrap(), vec![i; 32]);
+        let result = engine.decrypt(encrypted).await;
+        assert!(matches!(result, Err(SecurityError::AuthenticationFailed)));
+    }
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_authenticated_header() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("authenticated_header");
+
+    let key_manager = Arc::new(KeyManager::new().expect("Failed to create key manager"));
+    let engine = EncryptionEngine::new(key_manager);
+    let data = b"fingerprint minutiae";
+
+    debug!("Round-tripping with a header");
+    let encrypted = engine
+        .encrypt_with_header(data, b"v1;zstd;k1")
+        .await
+        .expect("Failed to encrypt");
+    assert_eq!(encrypted.header, b"v1;zstd;k1");
+    assert_eq!(engine.decrypt(&encrypted).await.unwrap(), data);
+
+    debug!("Verifying a modified header fails");
+    for header in [&b"v2;zstd;k1"[..], b"v1;zstd;k", b""] {
+        let tampered = EncryptedData { header: header.to_vec(), ..encrypted.clone() };
+        assert!(matches!(engine.decrypt(&tampered).await, Err(SecurityError::AuthenticationFailed)));
+    }
+
+    // The header and the AAD are authenticated separately: moving bytes
+    // from one to the other is detected
+    debug!("Verifying header and AAD are not interchangeable");
+    let encrypted = engine
+        .encrypt_with_header_and_aad(data, b"head", b"id")
+        .await
+        .expect("Failed to encrypt");
+    assert_eq!(engine.decrypt_with_aad(&encrypted, b"id").await.unwrap(), data);
+    let moved = EncryptedData { header: b"headi".to_vec(), ..encrypted.clone() };
+    assert!(engine.decrypt_with_aad(&moved, b"d").await.is_err());
+    assert!(engine.decrypt_with_aad(&encrypted, b"other").await.is_err());
+
+    timer.stop(true).await;
+}
+
+#[tokio::test]
+async fn test_decryption_failure_causes() {
+    let ctx = TestContext::new();
+    let timer = ctx.timer("decryption_failure_causes");
+
+    let key_manager = Arc::new(KeyManager::new().expect("Failed to create key manager"));
+    let engine = EncryptionEngine::new(key_manager.clone());
+    let encrypted = engine.encrypt(b"voice print").await.expect("Failed to encrypt");
+
+    debug!("Verifying a flipped byte fails authentication");
+    let mut flipped = encrypted.clone();
+    flipped.ciphertext[0] ^= 0x01;
+    assert!(matches!(engine.decrypt(&flipped).await, Err(SecurityError::AuthenticationFailed)));
+
+    debug!("Verifying a ciphertext shorter than its tag is malformed");
+    let mut truncated = encrypted.clone();
+    truncated.ciphertext.truncate(15);
+    assert!(matches!(
+        engine.decrypt(&truncated).await,
+        Err(SecurityError::MalformedCiphertext(_))
+    ));
+
+    debug!("Verifying data of a dropped key reports the key");
+    let old_id = key_manager.current_key_id();
+    engine.rotate_key().await.expect("Failed to rotate key");
+    assert_eq!(engine.decrypt(&encrypted).await.unwrap(), b"voice print");
+    engine.finish_rotation().await.expect("Failed to finish rotation");
+    assert!(matches!(
+        engine.decrypt(&encrypted).await,
+        Err(SecurityError::KeyUnavailable { key_id }) if key_id == old_id
+    ));
+
+    // Without a key ID every key is tried, so only authentication can fail
+    let unkeyed = EncryptedData { key_id: None, ..encrypted };
+    assert!(matches!(engine.decrypt(&unkeyed).await, Err(SecurityError::AuthenticationFailed)));
+
+    timer.stop(true).await;
+}
+
+/// Compiles only for types that do not implement `Clone`: for a `Clone`
+/// type both impls apply and the call below is ambiguous
+trait AmbiguousIfClone<A> {
+    fn check() {}
+}
+impl<T: ?Sized> AmbiguousIfClone<()> for T {}
+impl<T: Clone> AmbiguousIfClone<u8> for T {}
+
+fn assert_wiped_on_drop<T: Zeroize + ZeroizeOnDrop>() {}
+
+#[test]
+fn test_secret_bytes_are_wiped() {
+    assert_wiped_on_drop::<SecretBytes>();
+    <SecretBytes as AmbiguousIfClone<_>>::check();
+
+    let mut secret = SecretBytes::new(vec![0xAB; 32]);
+    assert_eq!(secret.len(), 32);
+    assert!(!format!("{:?}", secret).contains("171"));
+    assert_eq!(format!("{:?}", secret), "SecretBytes(32 bytes)");
+
+    secret.zeroize();
+    assert!(secret.is_empty());
+
+    let random = SecretBytes::random(32).expect("Failed to generate secret");
+    assert_eq!(random.len(), 32);
+    assert!(random.expose().iter().any(|&b| b != 0));
+}
@@ -1,3 +1,5 @@
+// Shared with the suite; the runner only uses its metrics
+#[allow(dead_code, unused_imports)]
 mod common;
 
 use common::TestMetrics;